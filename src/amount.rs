@@ -0,0 +1,481 @@
+//! Amounts of bitcoins, with arithmetic that can not silently overflow.
+
+use core::fmt;
+use core::str::FromStr;
+
+use alloc::format;
+use alloc::string::String;
+
+use crate::encode::{Decodable, Encodable, Error, Read, Write};
+use serde::{Deserialize, Serialize};
+
+/// The number of satoshis in one bitcoin.
+pub const COIN: u64 = 100_000_000;
+
+/// No amount larger than this (in satoshis) is valid, as defined by
+/// `MAX_MONEY` in the reference implementation. It is the total supply, 21
+/// millions bitcoins, rounded up.
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+
+/// The units in which amounts can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Denomination {
+    /// BTC, 10^8 satoshis.
+    Bitcoin,
+    /// mBTC, 10^5 satoshis.
+    MilliBitcoin,
+    /// bits, or µBTC, 100 satoshis.
+    Bit,
+    /// sat, the base unit.
+    Satoshi,
+}
+
+impl Denomination {
+    /// The number of decimals of an amount written in this unit.
+    pub fn decimals(self) -> u32 {
+        match self {
+            Denomination::Bitcoin => 8,
+            Denomination::MilliBitcoin => 5,
+            Denomination::Bit => 2,
+            Denomination::Satoshi => 0,
+        }
+    }
+}
+
+impl fmt::Display for Denomination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Denomination::Bitcoin => write!(f, "BTC"),
+            Denomination::MilliBitcoin => write!(f, "mBTC"),
+            Denomination::Bit => write!(f, "bits"),
+            Denomination::Satoshi => write!(f, "sat"),
+        }
+    }
+}
+
+impl FromStr for Denomination {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BTC" | "btc" => Ok(Denomination::Bitcoin),
+            "mBTC" | "mbtc" => Ok(Denomination::MilliBitcoin),
+            "bits" | "bit" | "uBTC" | "µBTC" => Ok(Denomination::Bit),
+            "sat" | "sats" | "satoshi" | "satoshis" => Ok(Denomination::Satoshi),
+            _ => Err(ParseAmountError::UnknownDenomination),
+        }
+    }
+}
+
+/// Errors that can occur while parsing an amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseAmountError {
+    /// The string is not a decimal number.
+    InvalidFormat,
+    /// The amount has more decimals than the unit allows.
+    TooPrecise,
+    /// The amount is larger than [MAX_MONEY].
+    TooBig,
+    /// The amount is negative, while only positive amounts are accepted.
+    Negative,
+    UnknownDenomination,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseAmountError::InvalidFormat => write!(f, "invalid number format"),
+            ParseAmountError::TooPrecise => write!(f, "amount has a too high precision"),
+            ParseAmountError::TooBig => write!(f, "amount is larger than the maximum"),
+            ParseAmountError::Negative => write!(f, "amount is negative"),
+            ParseAmountError::UnknownDenomination => write!(f, "unknown denomination"),
+        }
+    }
+}
+
+/// Parse a positive decimal number in the given unit into satoshis.
+fn parse_satoshis(s: &str, denomination: Denomination) -> Result<u64, ParseAmountError> {
+    let decimals = denomination.decimals();
+    let (integer, fraction) = match s.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (s, ""),
+    };
+    let is_number = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (integer.is_empty() && fraction.is_empty()) || !is_number(integer) || !is_number(fraction) {
+        return Err(ParseAmountError::InvalidFormat);
+    }
+    // Decimals beyond the precision of the unit must be zeros
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > decimals as usize {
+        return Err(ParseAmountError::TooPrecise);
+    }
+    let mut value: u64 = 0;
+    for digit in integer.bytes().chain(fraction.bytes()) {
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((digit - b'0') as u64))
+            .ok_or(ParseAmountError::TooBig)?;
+    }
+    let value = value
+        .checked_mul(10u64.pow(decimals - fraction.len() as u32))
+        .ok_or(ParseAmountError::TooBig)?;
+    if value > MAX_MONEY {
+        return Err(ParseAmountError::TooBig);
+    }
+    Ok(value)
+}
+
+/// Write a number of satoshis in the given unit, without trailing zeros.
+fn format_satoshis(satoshis: u64, denomination: Denomination) -> String {
+    let unit = 10u64.pow(denomination.decimals());
+    let integer = satoshis / unit;
+    let fraction = satoshis % unit;
+    if fraction == 0 {
+        return format!("{}", integer);
+    }
+    let fraction = format!(
+        "{:0width$}",
+        fraction,
+        width = denomination.decimals() as usize
+    );
+    format!("{}.{}", integer, fraction.trim_end_matches('0'))
+}
+
+/// Split an amount like "1.5 BTC" into its value and its unit.
+fn split_denomination(s: &str) -> Result<(&str, Denomination), ParseAmountError> {
+    let (value, denomination) = s
+        .split_once(' ')
+        .ok_or(ParseAmountError::UnknownDenomination)?;
+    Ok((value, denomination.parse()?))
+}
+
+/// A positive amount, in satoshis.
+///
+/// Any value can be decoded from a transaction, but arithmetic operations
+/// fail when the result is larger than [MAX_MONEY].
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const ONE_SAT: Amount = Amount(1);
+    pub const ONE_BTC: Amount = Amount(COIN);
+    pub const MAX_MONEY: Amount = Amount(MAX_MONEY);
+
+    pub const fn from_sat(satoshis: u64) -> Self {
+        Amount(satoshis)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    /// Whether the amount is at most [MAX_MONEY], as `MoneyRange` in the
+    /// reference implementation.
+    pub fn is_valid(self) -> bool {
+        self.0 <= MAX_MONEY
+    }
+
+    /// Parse a positive decimal number in the given unit.
+    pub fn from_str_in(s: &str, denomination: Denomination) -> Result<Self, ParseAmountError> {
+        if s.starts_with('-') {
+            return Err(ParseAmountError::Negative);
+        }
+        parse_satoshis(s, denomination).map(Amount)
+    }
+
+    /// Write the amount in the given unit, without the unit.
+    pub fn to_string_in(self, denomination: Denomination) -> String {
+        format_satoshis(self.0, denomination)
+    }
+
+    /// The sum, or `None` if it is larger than [MAX_MONEY].
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .filter(|sum| *sum <= MAX_MONEY)
+            .map(Amount)
+    }
+
+    /// The difference, or `None` if it is negative.
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// The product, or `None` if it is larger than [MAX_MONEY].
+    pub fn checked_mul(self, factor: u64) -> Option<Amount> {
+        self.0
+            .checked_mul(factor)
+            .filter(|product| *product <= MAX_MONEY)
+            .map(Amount)
+    }
+
+    /// The quotient rounded down, or `None` if `divisor` is zero.
+    pub fn checked_div(self, divisor: u64) -> Option<Amount> {
+        self.0.checked_div(divisor).map(Amount)
+    }
+
+    /// The sum of the amounts, or `None` if it is larger than [MAX_MONEY].
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |sum, amount| sum.checked_add(amount))
+    }
+
+    /// The amount as a signed amount, or `None` if it is too large.
+    pub fn to_signed(self) -> Option<SignedAmount> {
+        i64::try_from(self.0).ok().map(SignedAmount)
+    }
+}
+
+/// Written in bitcoins, e.g. "0.001 BTC".
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.to_string_in(Denomination::Bitcoin),
+            Denomination::Bitcoin
+        )
+    }
+}
+
+/// Parse an amount followed by its unit, e.g. "1.5 mBTC".
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, denomination) = split_denomination(s)?;
+        Amount::from_str_in(value, denomination)
+    }
+}
+
+impl Encodable for Amount {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        self.0.consensus_encode(w)
+    }
+}
+
+impl Decodable for Amount {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(Amount(u64::consensus_decode(r)?))
+    }
+}
+
+/// An amount which can be negative, in satoshis, e.g. the difference between
+/// two balances.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+pub struct SignedAmount(i64);
+
+impl SignedAmount {
+    pub const ZERO: SignedAmount = SignedAmount(0);
+    pub const MAX_MONEY: SignedAmount = SignedAmount(MAX_MONEY as i64);
+
+    pub const fn from_sat(satoshis: i64) -> Self {
+        SignedAmount(satoshis)
+    }
+
+    pub const fn to_sat(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Whether the absolute value is at most [MAX_MONEY].
+    pub fn is_valid(self) -> bool {
+        self.0.unsigned_abs() <= MAX_MONEY
+    }
+
+    /// Parse a decimal number, possibly negative, in the given unit.
+    pub fn from_str_in(s: &str, denomination: Denomination) -> Result<Self, ParseAmountError> {
+        match s.strip_prefix('-') {
+            Some(s) => parse_satoshis(s, denomination).map(|v| SignedAmount(-(v as i64))),
+            None => parse_satoshis(s, denomination).map(|v| SignedAmount(v as i64)),
+        }
+    }
+
+    /// Write the amount in the given unit, without the unit.
+    pub fn to_string_in(self, denomination: Denomination) -> String {
+        let value = format_satoshis(self.0.unsigned_abs(), denomination);
+        if self.is_negative() {
+            format!("-{}", value)
+        } else {
+            value
+        }
+    }
+
+    /// The sum, or `None` if its absolute value is larger than [MAX_MONEY].
+    pub fn checked_add(self, other: SignedAmount) -> Option<SignedAmount> {
+        self.0
+            .checked_add(other.0)
+            .map(SignedAmount)
+            .filter(|sum| sum.is_valid())
+    }
+
+    /// The difference, or `None` if its absolute value is larger than
+    /// [MAX_MONEY].
+    pub fn checked_sub(self, other: SignedAmount) -> Option<SignedAmount> {
+        self.0
+            .checked_sub(other.0)
+            .map(SignedAmount)
+            .filter(|difference| difference.is_valid())
+    }
+
+    pub fn checked_neg(self) -> Option<SignedAmount> {
+        self.0.checked_neg().map(SignedAmount)
+    }
+
+    pub fn abs(self) -> Amount {
+        Amount(self.0.unsigned_abs())
+    }
+
+    /// The amount as a positive amount, or `None` if it is negative.
+    pub fn to_unsigned(self) -> Option<Amount> {
+        u64::try_from(self.0).ok().map(Amount)
+    }
+}
+
+/// Written in bitcoins, e.g. "-0.001 BTC".
+impl fmt::Display for SignedAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.to_string_in(Denomination::Bitcoin),
+            Denomination::Bitcoin
+        )
+    }
+}
+
+impl FromStr for SignedAmount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (value, denomination) = split_denomination(s)?;
+        SignedAmount::from_str_in(value, denomination)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    pub fn test_parse_amounts() {
+        let parse = |s: &str, d: Denomination| Amount::from_str_in(s, d).map(Amount::to_sat);
+        assert_eq!(parse("1", Denomination::Bitcoin), Ok(COIN));
+        assert_eq!(parse("0.00000001", Denomination::Bitcoin), Ok(1));
+        assert_eq!(parse(".5", Denomination::Bitcoin), Ok(COIN / 2));
+        assert_eq!(parse("1.", Denomination::Bitcoin), Ok(COIN));
+        assert_eq!(parse("1.5", Denomination::MilliBitcoin), Ok(150_000));
+        assert_eq!(parse("12.34", Denomination::Bit), Ok(1234));
+        assert_eq!(parse("42", Denomination::Satoshi), Ok(42));
+        assert_eq!(parse("42.000", Denomination::Satoshi), Ok(42));
+        assert_eq!(parse("21000000", Denomination::Bitcoin), Ok(MAX_MONEY));
+        assert_eq!(
+            parse("0.000000001", Denomination::Bitcoin),
+            Err(ParseAmountError::TooPrecise)
+        );
+        assert_eq!(
+            parse("0.5", Denomination::Satoshi),
+            Err(ParseAmountError::TooPrecise)
+        );
+        assert_eq!(
+            parse("21000000.00000001", Denomination::Bitcoin),
+            Err(ParseAmountError::TooBig)
+        );
+        assert_eq!(
+            parse("99999999999999999999", Denomination::Satoshi),
+            Err(ParseAmountError::TooBig)
+        );
+        assert_eq!(
+            parse("-1", Denomination::Bitcoin),
+            Err(ParseAmountError::Negative)
+        );
+        for invalid in ["", ".", "1.2.3", "1,5", " 1", "+1", "1e3"] {
+            assert_eq!(
+                parse(invalid, Denomination::Bitcoin),
+                Err(ParseAmountError::InvalidFormat)
+            );
+        }
+        assert_eq!("1.5 mBTC".parse(), Ok(Amount::from_sat(150_000)));
+        assert_eq!("100 sat".parse(), Ok(Amount::from_sat(100)));
+        assert_eq!(
+            "1 XBT".parse::<Amount>(),
+            Err(ParseAmountError::UnknownDenomination)
+        );
+        assert_eq!(
+            "-0.5 BTC".parse(),
+            Ok(SignedAmount::from_sat(-(COIN as i64) / 2))
+        );
+    }
+
+    #[test]
+    pub fn test_format_amounts() {
+        let amount = Amount::from_sat(123_456_789);
+        assert_eq!(amount.to_string(), "1.23456789 BTC");
+        assert_eq!(
+            amount.to_string_in(Denomination::MilliBitcoin),
+            "1234.56789"
+        );
+        assert_eq!(amount.to_string_in(Denomination::Bit), "1234567.89");
+        assert_eq!(amount.to_string_in(Denomination::Satoshi), "123456789");
+        assert_eq!(Amount::from_sat(100_000).to_string(), "0.001 BTC");
+        assert_eq!(Amount::ONE_BTC.to_string(), "1 BTC");
+        assert_eq!(SignedAmount::from_sat(-100_000).to_string(), "-0.001 BTC");
+        for denomination in [
+            Denomination::Bitcoin,
+            Denomination::MilliBitcoin,
+            Denomination::Bit,
+            Denomination::Satoshi,
+        ] {
+            let s = amount.to_string_in(denomination);
+            assert_eq!(Amount::from_str_in(&s, denomination), Ok(amount));
+        }
+    }
+
+    #[test]
+    pub fn test_checked_arithmetic() {
+        let one = Amount::ONE_BTC;
+        assert_eq!(one.checked_add(one), Some(Amount::from_sat(2 * COIN)));
+        assert_eq!(Amount::MAX_MONEY.checked_add(Amount::ONE_SAT), None);
+        assert_eq!(Amount::from_sat(u64::MAX).checked_add(one), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::ONE_SAT), None);
+        assert_eq!(one.checked_mul(21_000_000), Some(Amount::MAX_MONEY));
+        assert_eq!(one.checked_mul(21_000_001), None);
+        assert_eq!(one.checked_div(3), Some(Amount::from_sat(33_333_333)));
+        assert_eq!(one.checked_div(0), None);
+        assert_eq!(
+            Amount::checked_sum([one, one, Amount::ONE_SAT]),
+            Some(Amount::from_sat(2 * COIN + 1))
+        );
+        assert_eq!(
+            Amount::checked_sum([Amount::MAX_MONEY, Amount::ONE_SAT]),
+            None
+        );
+        assert!(!Amount::from_sat(u64::MAX).is_valid());
+
+        let minus_one = SignedAmount::from_sat(-(COIN as i64));
+        assert_eq!(
+            minus_one.checked_add(one.to_signed().unwrap()),
+            Some(SignedAmount::ZERO)
+        );
+        assert_eq!(
+            SignedAmount::MAX_MONEY
+                .checked_neg()
+                .unwrap()
+                .checked_sub(SignedAmount::from_sat(1)),
+            None
+        );
+        assert_eq!(minus_one.abs(), one);
+        assert_eq!(minus_one.to_unsigned(), None);
+        assert_eq!(Amount::from_sat(u64::MAX).to_signed(), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Guest program verifying a range of Bitcoin headers, see
+//! [bitcoin_rs::zkvm].
+//!
+//! Built for `riscv32i-unknown-none-elf`, it reads its input and writes its
+//! output through system calls. Built natively, it uses the standard input and
+//! output, which is convenient to prepare inputs and check outputs.
+#![cfg_attr(target_os = "none", no_std, no_main)]
+
+#[cfg(not(target_os = "none"))]
+fn main() {
+    use std::io::{Read, Write};
+
+    let mut input = vec![];
+    std::io::stdin()
+        .read_to_end(&mut input)
+        .expect("Failed to read the input");
+    let mut output = vec![];
+    match bitcoin_rs::zkvm::run(&mut &input[..], &mut output) {
+        Ok(_) => std::io::stdout()
+            .write_all(&output)
+            .expect("Failed to write the output"),
+        Err(e) => {
+            eprintln!("Verification failed: {:?}", e);
+            std::process::exit(1)
+        }
+    }
+}
+
+#[cfg(target_os = "none")]
+mod guest {
+    use bitcoin_rs::zkvm::{self, syscall};
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::cell::UnsafeCell;
+
+    const HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+    /// A bump allocator never freeing memory, which is enough for a program
+    /// running once.
+    struct BumpAllocator {
+        heap: UnsafeCell<[u8; HEAP_SIZE]>,
+        next: UnsafeCell<usize>,
+    }
+
+    // The guest is single threaded
+    unsafe impl Sync for BumpAllocator {}
+
+    unsafe impl GlobalAlloc for BumpAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let next = &mut *self.next.get();
+            let base = self.heap.get() as usize;
+            let start = (base + *next).next_multiple_of(layout.align());
+            let end = start + layout.size();
+            if end > base + HEAP_SIZE {
+                return core::ptr::null_mut();
+            }
+            *next = end - base;
+            start as *mut u8
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: BumpAllocator = BumpAllocator {
+        heap: UnsafeCell::new([0; HEAP_SIZE]),
+        next: UnsafeCell::new(0),
+    };
+
+    #[panic_handler]
+    fn panic(_info: &core::panic::PanicInfo) -> ! {
+        syscall::exit(101)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn _start() -> ! {
+        match zkvm::run(&mut syscall::Stdin, &mut syscall::Stdout) {
+            Ok(_) => syscall::exit(0),
+            Err(_) => syscall::exit(1),
+        }
+    }
+}
@@ -0,0 +1,943 @@
+//! The encrypted transport of BIP 324, also named v2 transport.
+//!
+//! Both sides send an ElligatorSwift encoded public key, followed by up to
+//! [MAX_GARBAGE_SIZE] random bytes. The keys of the session are derived from
+//! the shared secret of the key exchange with HKDF-SHA256. Each side then sends
+//! a garbage terminator, and packets whose lengths are encrypted with
+//! FSChaCha20 and contents with FSChaCha20Poly1305. The first packet
+//! authenticates the garbage, and the first packet which is not a decoy
+//! negotiates the version of the transport.
+//!
+//! Messages are identified by a single byte for the common commands, instead
+//! of the 12 bytes of the v1 header.
+//!
+//! [Transport] is sans-IO: the caller passes it the received bytes and sends
+//! the bytes it returns. The randomness (secret key, auxiliary randomness and
+//! garbage) is provided by the caller, as the crate has no source of entropy.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use secp256k1::{Secp256k1, SecretKey};
+use sha2::Sha256;
+
+use crate::p2p::{MessageError, NetworkMessage, MAX_MESSAGE_SIZE};
+use crate::params::Network;
+
+/// The size of an ElligatorSwift encoded public key.
+pub const ELLSWIFT_SIZE: usize = 64;
+
+/// The maximum number of garbage bytes sent after the public key.
+pub const MAX_GARBAGE_SIZE: usize = 4095;
+
+pub const GARBAGE_TERMINATOR_SIZE: usize = 16;
+
+/// The size of the encrypted length of a packet.
+pub const LENGTH_SIZE: usize = 3;
+
+/// The size of the header of the contents, containing the ignore bit.
+pub const HEADER_SIZE: usize = 1;
+
+/// The size of the Poly1305 authentication tag.
+pub const TAG_SIZE: usize = 16;
+
+/// The number of bytes added to the contents of each packet.
+pub const EXPANSION: usize = LENGTH_SIZE + HEADER_SIZE + TAG_SIZE;
+
+/// The largest contents accepted: a short ID or a 12 bytes command, followed by
+/// the payload.
+pub const MAX_CONTENTS_SIZE: usize = 1 + 12 + MAX_MESSAGE_SIZE;
+
+/// The number of packets encrypted with a key before it is replaced.
+const REKEY_INTERVAL: u32 = 224;
+
+/// The bit of the header marking decoy packets.
+const IGNORE_BIT: u8 = 0x80;
+
+/// The commands with a short ID, which is their index. The ID 0 is followed by
+/// the 12 bytes command.
+const SHORT_IDS: [&str; 29] = [
+    "",
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The side opening the connection.
+    Initiator,
+    Responder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    /// The secret key is zero or not lower than the order of the curve.
+    InvalidSecretKey,
+    /// More than [MAX_GARBAGE_SIZE] bytes of garbage are given.
+    GarbageTooLong,
+    /// The peer sent the version message of the v1 protocol.
+    V1Protocol,
+    /// The garbage terminator is not found after [MAX_GARBAGE_SIZE] bytes.
+    MissingGarbageTerminator,
+    /// The contents of a packet are larger than [MAX_CONTENTS_SIZE].
+    Oversized(usize),
+    /// The authentication of a packet failed.
+    Decryption,
+    /// The contents of a packet are not a valid message.
+    Message(MessageError),
+}
+
+impl core::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            TransportError::InvalidSecretKey => write!(f, "invalid secret key"),
+            TransportError::GarbageTooLong => write!(f, "garbage too long"),
+            TransportError::V1Protocol => write!(f, "peer uses the v1 protocol"),
+            TransportError::MissingGarbageTerminator => write!(f, "missing garbage terminator"),
+            TransportError::Oversized(size) => write!(f, "packet of {} bytes too large", size),
+            TransportError::Decryption => write!(f, "packet authentication failed"),
+            TransportError::Message(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// The 12 bytes nonce of ChaCha20, from a 32 bits and a 64 bits counters.
+fn nonce(low: u32, high: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&low.to_le_bytes());
+    nonce[4..].copy_from_slice(&high.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20 whose key is replaced every [REKEY_INTERVAL] chunks by the next 32
+/// bytes of the keystream. It encrypts the lengths of the packets.
+struct FsChaCha20 {
+    cipher: ChaCha20,
+    chunk_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FsChaCha20 {
+    fn new(key: [u8; 32]) -> Self {
+        FsChaCha20 {
+            cipher: ChaCha20::new(&key.into(), &nonce(0, 0).into()),
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut key = [0; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.chunk_counter = 0;
+            self.rekey_counter += 1;
+            self.cipher = ChaCha20::new(&key.into(), &nonce(0, self.rekey_counter).into());
+        }
+    }
+}
+
+/// ChaCha20Poly1305 whose key is replaced every [REKEY_INTERVAL] packets. It
+/// encrypts the contents of the packets.
+struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        FsChaCha20Poly1305 {
+            key,
+            packet_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn encrypt(&mut self, aad: &[u8], buffer: &mut [u8]) -> [u8; TAG_SIZE] {
+        let nonce = nonce(self.packet_counter, self.rekey_counter);
+        let tag = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt_in_place_detached(&nonce.into(), aad, buffer)
+            .expect("packets are smaller than the limit of the cipher");
+        self.next_packet();
+        tag.into()
+    }
+
+    fn decrypt(&mut self, aad: &[u8], buffer: &mut [u8], tag: &[u8]) -> Result<(), ()> {
+        let nonce = nonce(self.packet_counter, self.rekey_counter);
+        let result = ChaCha20Poly1305::new(&self.key.into())
+            .decrypt_in_place_detached(&nonce.into(), aad, buffer, tag.into())
+            .map_err(|_| ());
+        self.next_packet();
+        result
+    }
+
+    fn next_packet(&mut self) {
+        self.packet_counter += 1;
+        if self.packet_counter == REKEY_INTERVAL {
+            // The new key is the encryption of zeros with a nonce never used
+            // for packets
+            let mut key = [0; 32];
+            let nonce = nonce(u32::MAX, self.rekey_counter);
+            ChaCha20Poly1305::new(&self.key.into())
+                .encrypt_in_place_detached(&nonce.into(), &[], &mut key)
+                .unwrap();
+            self.key = key;
+            self.packet_counter = 0;
+            self.rekey_counter += 1;
+        }
+    }
+}
+
+/// The x-only ECDH shared secret of the key exchange, hashed with both
+/// encoded public keys.
+pub fn shared_secret(
+    secret_key: &[u8; 32],
+    ours: &[u8; ELLSWIFT_SIZE],
+    theirs: &[u8; ELLSWIFT_SIZE],
+    role: Role,
+) -> Result<[u8; 32], TransportError> {
+    let secret_key =
+        SecretKey::from_slice(secret_key).map_err(|_| TransportError::InvalidSecretKey)?;
+    let ours = ElligatorSwift::from_array(*ours);
+    let theirs = ElligatorSwift::from_array(*theirs);
+    let secret = match role {
+        Role::Initiator => {
+            ElligatorSwift::shared_secret(ours, theirs, secret_key, ElligatorSwiftParty::A, None)
+        }
+        Role::Responder => {
+            ElligatorSwift::shared_secret(theirs, ours, secret_key, ElligatorSwiftParty::B, None)
+        }
+    };
+    Ok(secret.to_secret_bytes())
+}
+
+/// A decrypted packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    /// Decoy packets are ignored by the receiver.
+    pub ignore: bool,
+    pub contents: Vec<u8>,
+}
+
+/// The ciphers of a session, in both directions.
+pub struct Cipher {
+    send_length: FsChaCha20,
+    send_contents: FsChaCha20Poly1305,
+    receive_length: FsChaCha20,
+    receive_contents: FsChaCha20Poly1305,
+    session_id: [u8; 32],
+    send_garbage_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+    receive_garbage_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+}
+
+impl Cipher {
+    /// Derive the keys of the session from the shared secret.
+    pub fn new(shared_secret: &[u8; 32], role: Role, network: Network) -> Self {
+        let mut salt = Vec::from(&b"bitcoin_v2_shared_secret"[..]);
+        salt.extend(network.magic());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+        let expand = |label: &str| {
+            let mut key = [0; 32];
+            hkdf.expand(label.as_bytes(), &mut key).unwrap();
+            key
+        };
+        let initiator_length = expand("initiator_L");
+        let initiator_contents = expand("initiator_P");
+        let responder_length = expand("responder_L");
+        let responder_contents = expand("responder_P");
+        let terminators = expand("garbage_terminators");
+        let (initiator_terminator, responder_terminator) = terminators.split_at(16);
+        let initiator_terminator = initiator_terminator.try_into().unwrap();
+        let responder_terminator = responder_terminator.try_into().unwrap();
+        let session_id = expand("session_id");
+        match role {
+            Role::Initiator => Cipher {
+                send_length: FsChaCha20::new(initiator_length),
+                send_contents: FsChaCha20Poly1305::new(initiator_contents),
+                receive_length: FsChaCha20::new(responder_length),
+                receive_contents: FsChaCha20Poly1305::new(responder_contents),
+                session_id,
+                send_garbage_terminator: initiator_terminator,
+                receive_garbage_terminator: responder_terminator,
+            },
+            Role::Responder => Cipher {
+                send_length: FsChaCha20::new(responder_length),
+                send_contents: FsChaCha20Poly1305::new(responder_contents),
+                receive_length: FsChaCha20::new(initiator_length),
+                receive_contents: FsChaCha20Poly1305::new(initiator_contents),
+                session_id,
+                send_garbage_terminator: responder_terminator,
+                receive_garbage_terminator: initiator_terminator,
+            },
+        }
+    }
+
+    /// The identifier of the session, which both sides can compare to detect
+    /// a man in the middle.
+    pub fn session_id(&self) -> [u8; 32] {
+        self.session_id
+    }
+
+    pub fn send_garbage_terminator(&self) -> [u8; GARBAGE_TERMINATOR_SIZE] {
+        self.send_garbage_terminator
+    }
+
+    pub fn receive_garbage_terminator(&self) -> [u8; GARBAGE_TERMINATOR_SIZE] {
+        self.receive_garbage_terminator
+    }
+
+    /// Encrypt a packet, authenticating the additional data `aad` with it.
+    pub fn encrypt(&mut self, contents: &[u8], aad: &[u8], ignore: bool) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(EXPANSION + contents.len());
+        packet.extend(&(contents.len() as u32).to_le_bytes()[..LENGTH_SIZE]);
+        self.send_length.crypt(&mut packet);
+        packet.push(if ignore { IGNORE_BIT } else { 0 });
+        packet.extend(contents);
+        let tag = self.send_contents.encrypt(aad, &mut packet[LENGTH_SIZE..]);
+        packet.extend(tag);
+        packet
+    }
+
+    /// Decrypt the length of the contents of the next packet. The rest of the
+    /// packet is made of `HEADER_SIZE + length + TAG_SIZE` bytes.
+    pub fn decrypt_length(&mut self, mut length: [u8; LENGTH_SIZE]) -> usize {
+        self.receive_length.crypt(&mut length);
+        u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize
+    }
+
+    /// Decrypt the rest of a packet, after its length.
+    pub fn decrypt(&mut self, packet: &[u8], aad: &[u8]) -> Result<Packet, TransportError> {
+        if packet.len() < HEADER_SIZE + TAG_SIZE {
+            return Err(TransportError::Decryption);
+        }
+        let (ciphertext, tag) = packet.split_at(packet.len() - TAG_SIZE);
+        let mut plaintext = ciphertext.to_vec();
+        self.receive_contents
+            .decrypt(aad, &mut plaintext, tag)
+            .map_err(|_| TransportError::Decryption)?;
+        Ok(Packet {
+            ignore: plaintext[0] & IGNORE_BIT != 0,
+            contents: plaintext.split_off(HEADER_SIZE),
+        })
+    }
+}
+
+/// Encode a message as the contents of a packet, with its short ID if it has
+/// one.
+pub fn encode_contents(message: &NetworkMessage) -> Vec<u8> {
+    let command = message.command();
+    let mut contents = match SHORT_IDS.iter().skip(1).position(|id| *id == command) {
+        Some(i) => alloc::vec![i as u8 + 1],
+        None => {
+            let mut contents = alloc::vec![0; 13];
+            let length = command.len().min(12);
+            contents[1..1 + length].copy_from_slice(&command.as_bytes()[..length]);
+            contents
+        }
+    };
+    contents.extend(message.encode_payload());
+    contents
+}
+
+/// Decode a message from the contents of a packet. Messages with an unknown
+/// short ID are decoded as [NetworkMessage::Unknown] with an empty command.
+pub fn decode_contents(contents: &[u8]) -> Result<NetworkMessage, MessageError> {
+    let (command, payload) = match contents.split_first() {
+        None => return Err(MessageError::BadCommand),
+        Some((0, rest)) => {
+            if rest.len() < 12 {
+                return Err(MessageError::BadCommand);
+            }
+            let (command, payload) = rest.split_at(12);
+            let end = command.iter().position(|b| *b == 0).unwrap_or(12);
+            if command[end..].iter().any(|b| *b != 0) || !command[..end].is_ascii() {
+                return Err(MessageError::BadCommand);
+            }
+            // Checked to be ASCII
+            (core::str::from_utf8(&command[..end]).unwrap(), payload)
+        }
+        Some((&id, payload)) => match SHORT_IDS.get(id as usize) {
+            Some(command) => (*command, payload),
+            None => {
+                return Ok(NetworkMessage::Unknown {
+                    command: String::new(),
+                    payload: payload.to_vec(),
+                })
+            }
+        },
+    };
+    NetworkMessage::decode_payload(command, payload).map_err(|error| MessageError::Decode {
+        command: command.into(),
+        error,
+    })
+}
+
+/// The first bytes of a version message of the v1 protocol.
+fn v1_prefix(network: Network) -> [u8; 16] {
+    let mut prefix = [0; 16];
+    prefix[..4].copy_from_slice(&network.magic());
+    prefix[4..11].copy_from_slice(b"version");
+    prefix
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The responder checks that the peer does not use the v1 protocol.
+    MaybeV1,
+    /// Waiting for the public key of the peer.
+    Key,
+    /// Waiting for the garbage terminator of the peer.
+    Garbage,
+    /// Waiting for the version packet of the peer.
+    Version,
+    Ready,
+}
+
+/// One side of a v2 connection.
+pub struct Transport {
+    network: Network,
+    role: Role,
+    secret_key: [u8; 32],
+    ellswift: [u8; ELLSWIFT_SIZE],
+    garbage: Vec<u8>,
+    state: State,
+    cipher: Option<Cipher>,
+    /// The garbage of the peer, authenticated by its first packet.
+    received_garbage: Option<Vec<u8>>,
+    /// The decrypted length of the packet being received.
+    packet_length: Option<usize>,
+    received: Vec<u8>,
+    transmit: Vec<u8>,
+    /// Messages sent before the keys are known.
+    pending: Vec<NetworkMessage>,
+    messages: VecDeque<NetworkMessage>,
+}
+
+impl Transport {
+    /// Create one side of a connection, from a random secret key, 32 random
+    /// bytes to encode the public key, and random garbage of at most
+    /// [MAX_GARBAGE_SIZE] bytes. The initiator sends its public key right
+    /// away, the responder once it knows the peer uses the v2 protocol.
+    pub fn new(
+        network: Network,
+        role: Role,
+        secret_key: [u8; 32],
+        aux_rand: [u8; 32],
+        garbage: Vec<u8>,
+    ) -> Result<Self, TransportError> {
+        let key =
+            SecretKey::from_slice(&secret_key).map_err(|_| TransportError::InvalidSecretKey)?;
+        if garbage.len() > MAX_GARBAGE_SIZE {
+            return Err(TransportError::GarbageTooLong);
+        }
+        let mut transport = Transport {
+            network,
+            role,
+            secret_key,
+            ellswift: ElligatorSwift::from_seckey(&Secp256k1::new(), key, Some(aux_rand))
+                .to_array(),
+            garbage,
+            state: State::Key,
+            cipher: None,
+            received_garbage: None,
+            packet_length: None,
+            received: Vec::new(),
+            transmit: Vec::new(),
+            pending: Vec::new(),
+            messages: VecDeque::new(),
+        };
+        match role {
+            Role::Initiator => transport.send_key(),
+            Role::Responder => transport.state = State::MaybeV1,
+        }
+        Ok(transport)
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Whether the version packet of the peer was received.
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    /// The identifier of the session, once the public key of the peer is
+    /// received.
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        self.cipher.as_ref().map(Cipher::session_id)
+    }
+
+    fn send_key(&mut self) {
+        self.transmit.extend(self.ellswift);
+        self.transmit.extend(&self.garbage);
+    }
+
+    /// Queue a message, which is sent once the keys are known.
+    pub fn send(&mut self, message: &NetworkMessage) {
+        match &mut self.cipher {
+            Some(cipher) => {
+                let packet = cipher.encrypt(&encode_contents(message), &[], false);
+                self.transmit.extend(packet);
+            }
+            None => self.pending.push(message.clone()),
+        }
+    }
+
+    /// Take the bytes to send to the peer.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        if self.transmit.is_empty() {
+            None
+        } else {
+            Some(core::mem::take(&mut self.transmit))
+        }
+    }
+
+    /// Take the next message received from the peer.
+    pub fn poll_message(&mut self) -> Option<NetworkMessage> {
+        self.messages.pop_front()
+    }
+
+    /// Handle bytes received from the peer. After an error, the connection
+    /// must be closed. [TransportError::V1Protocol] is only returned to the
+    /// responder, which may then handle the received bytes as a v1 connection.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<(), TransportError> {
+        self.received.extend(bytes);
+        loop {
+            match self.state {
+                State::MaybeV1 => {
+                    let prefix = v1_prefix(self.network);
+                    let length = self.received.len().min(prefix.len());
+                    if self.received[..length] != prefix[..length] {
+                        self.state = State::Key;
+                        self.send_key();
+                    } else if length == prefix.len() {
+                        return Err(TransportError::V1Protocol);
+                    } else {
+                        return Ok(());
+                    }
+                }
+                State::Key => {
+                    if self.received.len() < ELLSWIFT_SIZE {
+                        return Ok(());
+                    }
+                    let theirs: [u8; ELLSWIFT_SIZE] =
+                        self.received[..ELLSWIFT_SIZE].try_into().unwrap();
+                    self.received.drain(..ELLSWIFT_SIZE);
+                    self.start_session(&theirs)?;
+                    self.state = State::Garbage;
+                }
+                State::Garbage => {
+                    let cipher = self.cipher.as_ref().unwrap();
+                    let terminator = cipher.receive_garbage_terminator();
+                    let end = self
+                        .received
+                        .len()
+                        .min(MAX_GARBAGE_SIZE + GARBAGE_TERMINATOR_SIZE);
+                    match self.received[..end]
+                        .windows(GARBAGE_TERMINATOR_SIZE)
+                        .position(|window| window == terminator)
+                    {
+                        Some(size) => {
+                            let garbage = self.received[..size].to_vec();
+                            self.received.drain(..size + GARBAGE_TERMINATOR_SIZE);
+                            self.received_garbage = Some(garbage);
+                            self.state = State::Version;
+                        }
+                        None if end == MAX_GARBAGE_SIZE + GARBAGE_TERMINATOR_SIZE => {
+                            return Err(TransportError::MissingGarbageTerminator)
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                State::Version | State::Ready => {
+                    let Some(packet) = self.next_packet()? else {
+                        return Ok(());
+                    };
+                    if packet.ignore {
+                        continue;
+                    }
+                    if self.state == State::Version {
+                        // The contents are reserved for future versions of
+                        // the transport
+                        self.state = State::Ready;
+                    } else {
+                        let message =
+                            decode_contents(&packet.contents).map_err(TransportError::Message)?;
+                        self.messages.push_back(message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Derive the keys, then send the garbage terminator, the version packet
+    /// and the pending messages.
+    fn start_session(&mut self, theirs: &[u8; ELLSWIFT_SIZE]) -> Result<(), TransportError> {
+        let secret = shared_secret(&self.secret_key, &self.ellswift, theirs, self.role)?;
+        let mut cipher = Cipher::new(&secret, self.role, self.network);
+        self.transmit.extend(cipher.send_garbage_terminator());
+        let version = cipher.encrypt(&[], &self.garbage, false);
+        self.transmit.extend(version);
+        self.cipher = Some(cipher);
+        for message in core::mem::take(&mut self.pending) {
+            self.send(&message);
+        }
+        Ok(())
+    }
+
+    fn next_packet(&mut self) -> Result<Option<Packet>, TransportError> {
+        let cipher = self.cipher.as_mut().unwrap();
+        let length = match self.packet_length {
+            Some(length) => length,
+            None => {
+                if self.received.len() < LENGTH_SIZE {
+                    return Ok(None);
+                }
+                let length =
+                    cipher.decrypt_length(self.received[..LENGTH_SIZE].try_into().unwrap());
+                self.received.drain(..LENGTH_SIZE);
+                if length > MAX_CONTENTS_SIZE {
+                    return Err(TransportError::Oversized(length));
+                }
+                self.packet_length = Some(length);
+                length
+            }
+        };
+        let size = HEADER_SIZE + length + TAG_SIZE;
+        if self.received.len() < size {
+            return Ok(None);
+        }
+        let aad = self.received_garbage.take().unwrap_or_default();
+        let packet = cipher.decrypt(&self.received[..size], &aad)?;
+        self.received.drain(..size);
+        self.packet_length = None;
+        Ok(Some(packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::{Inventory, VersionMessage};
+    use alloc::vec;
+
+    fn decode_hex<const N: usize>(s: &str) -> [u8; N] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    /// Exchange the bytes of both sides until they have nothing to send.
+    fn exchange(a: &mut Transport, b: &mut Transport) {
+        loop {
+            let mut idle = true;
+            if let Some(bytes) = a.poll_transmit() {
+                b.receive(&bytes).unwrap();
+                idle = false;
+            }
+            if let Some(bytes) = b.poll_transmit() {
+                a.receive(&bytes).unwrap();
+                idle = false;
+            }
+            if idle {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_shared_secret() {
+        // Vectors of the ECDH of BIP 324: secret key, our and their encoded
+        // keys, whether we initiate, shared secret
+        let vectors = [
+            (
+                "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+                "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+                "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+                Role::Initiator,
+                "c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592",
+            ),
+            (
+                "1f9c581b35231838f0f17cf0c979835baccb7f3abbbb96ffcc318ab71e6e126f",
+                "a1855e10e94e00baa23041d916e259f7044e491da6171269694763f018c7e63693d29575dcb464ac816baa1be353ba12e3876cba7628bd0bd8e755e721eb0140",
+                "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f0000000000000000000000000000000000000000000000000000000000000000",
+                Role::Responder,
+                "a0138f564f74d0ad70bc337dacc9d0bf1d2349364caf1188a1e6e8ddb3b7b184",
+            ),
+            (
+                "0286c41cd30913db0fdff7a64ebda5c8e3e7cef10f2aebc00a7650443cf4c60d",
+                "d1ee8a93a01130cbf299249a258f94feb5f469e7d0f2f28f69ee5e9aa8f9b54a60f2c3ff2d023634ec7f4127a96cc11662e402894cf1f694fb9a7eaa5f1d9244",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffff22d5e441524d571a52b3def126189d3f416890a99d4da6ede2b0cde1760ce2c3f98457ae",
+                Role::Initiator,
+                "250b93570d411149105ab8cb0bc5079914906306368c23e9d77c2a33265b994c",
+            ),
+            (
+                "6c77432d1fda31e9f942f8af44607e10f3ad38a65f8a4bddae823e5eff90dc38",
+                "d2685070c1e6376e633e825296634fd461fa9e5bdf2109bcebd735e5a91f3e587c5cb782abb797fbf6bb5074fd1542a474f2a45b673763ec2db7fb99b737bbb9",
+                "56bd0c06f10352c3a1a9f4b4c92f6fa2b26df124b57878353c1fc691c51abea77c8817daeeb9fa546b77c8daf79d89b22b0e1b87574ece42371f00237aa9d83a",
+                Role::Responder,
+                "1918b741ef5f9d1d7670b050c152b4a4ead2c31be9aecb0681c0cd4324150853",
+            ),
+            (
+                "a6ec25127ca1aa4cf16b20084ba1e6516baae4d32422288e9b36d8bddd2de35a",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffff053d7ecca53e33e185a8b9be4e7699a97c6ff4c795522e5918ab7cd6b6884f67e683f3dc",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffa7730be30000000000000000000000000000000000000000000000000000000000000000",
+                Role::Initiator,
+                "dd210aa6629f20bb328e5d89daa6eb2ac3d1c658a725536ff154f31b536c23b2",
+            ),
+            (
+                "0af952659ed76f80f585966b95ab6e6fd68654672827878684c8b547b1b94f5a",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffc81017fd92fd31637c26c906b42092e11cc0d3afae8d9019d2578af22735ce7bc469c72d",
+                "9652d78baefc028cd37a6a92625b8b8f85fde1e4c944ad3f20e198bef8c02f19fffffffffffffffffffffffffffffffffffffffffffffffffffffffff2e91870",
+                Role::Responder,
+                "3568f2aea2e14ef4ee4a3c2a8b8d31bc5e3187ba86db10739b4ff8ec92ff6655",
+            ),
+            (
+                "f90e080c64b05824c5a24b2501d5aeaf08af3872ee860aa80bdcd430f7b63494",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffff115173765dc202cf029ad3f15479735d57697af12b0131dd21430d5772e4ef11474d58b9",
+                "12a50f3fafea7c1eeada4cf8d33777704b77361453afc83bda91eef349ae044d20126c6200547ea5a6911776c05dee2a7f1a9ba7dfbabbbd273c3ef29ef46e46",
+                Role::Initiator,
+                "e25461fb0e4c162e18123ecde88342d54d449631e9b75a266fd9260c2bb2f41d",
+            ),
+        ];
+        for (secret_key, ours, theirs, role, expected) in vectors {
+            let secret = shared_secret(
+                &decode_hex(secret_key),
+                &decode_hex(ours),
+                &decode_hex(theirs),
+                role,
+            )
+            .unwrap();
+            assert_eq!(hex::encode(secret), expected);
+        }
+    }
+
+    #[test]
+    pub fn test_packet_vector() {
+        // First packet vector of BIP 324
+        let secret = decode_hex("c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592");
+        let mut cipher = Cipher::new(&secret, Role::Initiator, Network::Mainnet);
+        assert_eq!(
+            hex::encode(cipher.session_id()),
+            "ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5"
+        );
+        assert_eq!(
+            hex::encode(cipher.send_garbage_terminator()),
+            "faef555dfcdb936425d84aba524758f3"
+        );
+        assert_eq!(
+            hex::encode(cipher.receive_garbage_terminator()),
+            "02cb8ff24307a6e27de3b4e7ea3fa65b"
+        );
+        // The packet of index 1
+        cipher.encrypt(&[], &[], false);
+        assert_eq!(
+            hex::encode(cipher.encrypt(&[0x8e], &[], false)),
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3"
+        );
+    }
+
+    #[test]
+    pub fn test_cipher_round_trip() {
+        let secret = [7; 32];
+        let mut initiator = Cipher::new(&secret, Role::Initiator, Network::Regtest);
+        let mut responder = Cipher::new(&secret, Role::Responder, Network::Regtest);
+        assert_eq!(
+            initiator.send_garbage_terminator(),
+            responder.receive_garbage_terminator()
+        );
+        // Past the rekeying of both ciphers
+        for i in 0..500usize {
+            let contents = vec![i as u8; i];
+            let aad = if i == 0 { &b"garbage"[..] } else { &[] };
+            let packet = initiator.encrypt(&contents, aad, i % 3 == 0);
+            assert_eq!(packet.len(), EXPANSION + i);
+            let length = responder.decrypt_length(packet[..LENGTH_SIZE].try_into().unwrap());
+            assert_eq!(length, i);
+            let decrypted = responder.decrypt(&packet[LENGTH_SIZE..], aad).unwrap();
+            assert_eq!(
+                decrypted,
+                Packet {
+                    ignore: i % 3 == 0,
+                    contents
+                }
+            );
+        }
+
+        let mut packet = responder.encrypt(b"contents", &[], false);
+        packet[5] ^= 1;
+        initiator.decrypt_length(packet[..LENGTH_SIZE].try_into().unwrap());
+        assert_eq!(
+            initiator.decrypt(&packet[LENGTH_SIZE..], &[]),
+            Err(TransportError::Decryption)
+        );
+    }
+
+    #[test]
+    pub fn test_contents() {
+        let messages = [
+            NetworkMessage::Ping(42),
+            NetworkMessage::GetData(vec![Inventory::WitnessBlock([3; 32])]),
+            NetworkMessage::Verack,
+            NetworkMessage::WtxidRelay,
+        ];
+        for message in messages {
+            let contents = encode_contents(&message);
+            assert_eq!(decode_contents(&contents), Ok(message));
+        }
+        assert_eq!(encode_contents(&NetworkMessage::Ping(1))[0], 18);
+        let contents = encode_contents(&NetworkMessage::Verack);
+        assert_eq!(&contents[..], b"\0verack\0\0\0\0\0\0");
+        assert!(matches!(
+            decode_contents(&[200, 1, 2]),
+            Ok(NetworkMessage::Unknown { command, payload }) if command.is_empty() && payload == [1, 2]
+        ));
+        assert_eq!(decode_contents(&[]), Err(MessageError::BadCommand));
+        assert_eq!(decode_contents(b"\0verack"), Err(MessageError::BadCommand));
+    }
+
+    #[test]
+    pub fn test_handshake() {
+        let mut initiator = Transport::new(
+            Network::Regtest,
+            Role::Initiator,
+            [1; 32],
+            [2; 32],
+            vec![9; 100],
+        )
+        .unwrap();
+        let mut responder = Transport::new(
+            Network::Regtest,
+            Role::Responder,
+            [3; 32],
+            [4; 32],
+            vec![8; 4095],
+        )
+        .unwrap();
+        let version = NetworkMessage::Version(VersionMessage {
+            version: 70016,
+            services: 0,
+            timestamp: 0,
+            receiver: Default::default(),
+            sender: Default::default(),
+            nonce: 5,
+            user_agent: "/test/".into(),
+            start_height: 0,
+            relay: true,
+        });
+        // Queued until the keys are known
+        initiator.send(&version);
+        assert_eq!(initiator.poll_transmit().map(|b| b.len()), Some(64 + 100));
+
+        // Received one byte at a time
+        initiator = Transport::new(
+            Network::Regtest,
+            Role::Initiator,
+            [1; 32],
+            [2; 32],
+            vec![9; 100],
+        )
+        .unwrap();
+        initiator.send(&version);
+        for byte in initiator.poll_transmit().unwrap() {
+            responder.receive(&[byte]).unwrap();
+        }
+        exchange(&mut initiator, &mut responder);
+        assert!(initiator.is_ready() && responder.is_ready());
+        assert_eq!(initiator.session_id(), responder.session_id());
+        assert_eq!(responder.poll_message(), Some(version));
+
+        responder.send(&NetworkMessage::Verack);
+        responder.send(&NetworkMessage::Ping(7));
+        exchange(&mut initiator, &mut responder);
+        assert_eq!(initiator.poll_message(), Some(NetworkMessage::Verack));
+        assert_eq!(initiator.poll_message(), Some(NetworkMessage::Ping(7)));
+        assert_eq!(initiator.poll_message(), None);
+    }
+
+    #[test]
+    pub fn test_invalid_handshakes() {
+        assert!(matches!(
+            Transport::new(Network::Regtest, Role::Initiator, [0; 32], [0; 32], vec![]),
+            Err(TransportError::InvalidSecretKey)
+        ));
+        assert!(matches!(
+            Transport::new(
+                Network::Regtest,
+                Role::Initiator,
+                [1; 32],
+                [0; 32],
+                vec![0; 4096]
+            ),
+            Err(TransportError::GarbageTooLong)
+        ));
+
+        // The responder detects a v1 version message
+        let mut responder =
+            Transport::new(Network::Regtest, Role::Responder, [3; 32], [4; 32], vec![]).unwrap();
+        let bytes = crate::p2p::encode_message(Network::Regtest, &NetworkMessage::Verack);
+        assert_eq!(responder.receive(&bytes[..12]), Ok(()));
+        assert_eq!(responder.receive(&bytes[12..]), Ok(()));
+        assert_eq!(responder.poll_transmit().map(|b| b.len()), Some(64));
+        let mut responder =
+            Transport::new(Network::Regtest, Role::Responder, [3; 32], [4; 32], vec![]).unwrap();
+        let mut version = v1_prefix(Network::Regtest).to_vec();
+        version.extend([0; 10]);
+        assert_eq!(responder.receive(&version[..10]), Ok(()));
+        assert_eq!(
+            responder.receive(&version[10..]),
+            Err(TransportError::V1Protocol)
+        );
+        assert_eq!(responder.poll_transmit(), None);
+
+        // No garbage terminator
+        let mut initiator =
+            Transport::new(Network::Regtest, Role::Initiator, [1; 32], [2; 32], vec![]).unwrap();
+        let mut responder =
+            Transport::new(Network::Regtest, Role::Responder, [3; 32], [4; 32], vec![]).unwrap();
+        responder
+            .receive(&initiator.poll_transmit().unwrap())
+            .unwrap();
+        let key = responder.poll_transmit().unwrap();
+        initiator.receive(&key[..ELLSWIFT_SIZE]).unwrap();
+        assert_eq!(
+            initiator.receive(&[0; MAX_GARBAGE_SIZE + GARBAGE_TERMINATOR_SIZE]),
+            Err(TransportError::MissingGarbageTerminator)
+        );
+    }
+}
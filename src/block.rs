@@ -1,9 +1,22 @@
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::fee_rate::WITNESS_SCALE_FACTOR;
+use crate::merkle::merkle_root;
+use crate::params::ChainParams;
+use crate::script::{Opcode, ScriptRef};
 use crate::transaction::Transaction;
-use crate::utils::CompactBytes;
+use crate::utils::{sha256d, VarInt};
+use crate::utxo::UtxoView;
+use crate::validation::{self, BlockValidationError};
+use alloc::vec;
+use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Block {
+/// The prefix of the coinbase output committing to the witnesses of the block
+/// (BIP 141): OP_RETURN, a push of 36 bytes and the tag `aa21a9ed`.
+pub const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BlockHeader {
     /// The version number for the block.
     pub version: [u8; 4],
     /// The block hash of a previous block this block is building on top of.
@@ -13,9 +26,302 @@ pub struct Block {
     /// The current time as a Unix timestamp.
     pub time: [u8; 4],
     /// A compact representation of the current target.
-    pub bits: u8,
-    /// How many upcoming transactions are included in the block.
-    pub transaction_count: CompactBytes,
+    pub bits: [u8; 4],
+    /// The field miners change in order to find a hash below the target.
+    pub nonce: [u8; 4],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Block {
+    pub header: BlockHeader,
     /// All of the raw transactions included in the block concatenated together.
     pub transactions: Vec<Transaction>,
 }
+
+impl BlockHeader {
+    /// The hash of the header, in internal byte order, i.e. reversed compared
+    /// to the usual hexadecimal representation.
+    pub fn block_hash(&self) -> [u8; 32] {
+        sha256d(&encode::serialize(self))
+    }
+
+    /// The time of the block as a Unix timestamp.
+    pub fn timestamp(&self) -> u32 {
+        u32::from_le_bytes(self.time)
+    }
+
+    /// The compact representation of the target, as a number.
+    pub fn compact_target(&self) -> u32 {
+        u32::from_le_bytes(self.bits)
+    }
+}
+
+impl Block {
+    /// The weight of the block as defined in BIP 141: the sum of the weights of
+    /// its transactions, plus the header and the number of transactions which
+    /// count as non-witness data.
+    pub fn weight(&self) -> u64 {
+        let base = 80 + VarInt(self.transactions.len() as u64).size() as u64;
+        base * WITNESS_SCALE_FACTOR + self.transactions.iter().map(|tx| tx.weight()).sum::<u64>()
+    }
+
+    /// The coinbase transaction, if the first transaction is one.
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first().filter(|tx| tx.is_coinbase())
+    }
+
+    /// The height of the block encoded at the start of the coinbase script
+    /// (BIP 34), or `None` if it does not start with a minimal push of a
+    /// positive number.
+    pub fn bip34_height(&self) -> Option<u32> {
+        let script_sig = self.coinbase()?.inputs[0].script_sig.to_bytes();
+        let (opcode, data) = ScriptRef::new(&script_sig).instructions().next()?.ok()?;
+        let height = match (u8::from(opcode), data) {
+            (0x00, _) => 0,
+            (n @ 0x51..=0x60, _) => (n - 0x50) as u32,
+            (_, Some(data)) if data.len() <= 5 && data.last().is_some_and(|b| b & 0x80 == 0) => {
+                let value = data
+                    .iter()
+                    .rev()
+                    .fold(0u64, |value, b| (value << 8) | *b as u64);
+                u32::try_from(value).ok()?
+            }
+            _ => return None,
+        };
+        // Other encodings of the same number are not accepted
+        script_sig
+            .starts_with(&coinbase_height_prefix(height))
+            .then_some(height)
+    }
+
+    /// The witness commitment of the block (BIP 141): the 32 bytes following
+    /// [WITNESS_COMMITMENT_HEADER] in the last coinbase output starting with
+    /// it, if any.
+    pub fn witness_commitment(&self) -> Option<[u8; 32]> {
+        self.coinbase()?.outputs.iter().rev().find_map(|output| {
+            let script = output.script_pubkey.to_bytes();
+            (script.len() >= 38 && script[..6] == WITNESS_COMMITMENT_HEADER).then(|| {
+                let mut commitment = [0; 32];
+                commitment.copy_from_slice(&script[6..38]);
+                commitment
+            })
+        })
+    }
+
+    /// The witness reserved value, i.e. the witness of the coinbase input if
+    /// it is a single item of 32 bytes.
+    pub fn witness_reserved_value(&self) -> Option<[u8; 32]> {
+        match &self.coinbase()?.witnesses.first()?[..] {
+            [reserved] => reserved.as_slice().try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// The merkle root of the wtxids of the transactions, where the one of the
+    /// coinbase, which can not commit to itself, is replaced by zero.
+    pub fn witness_root(&self) -> Option<[u8; 32]> {
+        let wtxids: Vec<[u8; 32]> = self
+            .transactions
+            .iter()
+            .enumerate()
+            .map(|(i, tx)| if i == 0 { [0; 32] } else { tx.wtxid() })
+            .collect();
+        merkle_root(&wtxids)
+    }
+
+    /// The witness commitment to put in the coinbase, given the witness root
+    /// and the witness reserved value.
+    pub fn compute_witness_commitment(
+        witness_root: &[u8; 32],
+        reserved_value: &[u8; 32],
+    ) -> [u8; 32] {
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(witness_root);
+        bytes[32..].copy_from_slice(reserved_value);
+        sha256d(&bytes)
+    }
+
+    /// Validate the block as the one at the given height, spending outputs
+    /// from `utxo_view`. See [validation::check_block] for the rules which are
+    /// checked.
+    pub fn validate<U: UtxoView + ?Sized>(
+        &self,
+        params: &ChainParams,
+        height: u32,
+        utxo_view: &U,
+    ) -> Result<(), BlockValidationError> {
+        validation::check_block(self, params, height, utxo_view)
+    }
+}
+
+/// The push of the block height which must start the coinbase script (BIP 34),
+/// as a minimally encoded number.
+pub fn coinbase_height_prefix(height: u32) -> Vec<u8> {
+    match height {
+        0 => vec![Opcode::OP_0.into()],
+        1..=16 => vec![0x50 + height as u8],
+        _ => {
+            let mut number: Vec<u8> = height.to_le_bytes().to_vec();
+            while number.last() == Some(&0) {
+                number.pop();
+            }
+            // The number is positive, so the sign bit must be clear
+            if number.last().is_some_and(|byte| byte & 0x80 != 0) {
+                number.push(0x00);
+            }
+            let mut prefix = vec![number.len() as u8];
+            prefix.extend(number);
+            prefix
+        }
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.version.consensus_encode(w)?
+            + self.previous_block.consensus_encode(w)?
+            + self.merkle_root.consensus_encode(w)?
+            + self.time.consensus_encode(w)?
+            + self.bits.consensus_encode(w)?
+            + self.nonce.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(BlockHeader {
+            version: Decodable::consensus_decode(r)?,
+            previous_block: Decodable::consensus_decode(r)?,
+            merkle_root: Decodable::consensus_decode(r)?,
+            time: Decodable::consensus_decode(r)?,
+            bits: Decodable::consensus_decode(r)?,
+            nonce: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+impl Encodable for Block {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.header.consensus_encode(w)? + encode::encode_vec(&self.transactions, w)?)
+    }
+}
+
+impl Decodable for Block {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(Block {
+            header: Decodable::consensus_decode(r)?,
+            transactions: encode::decode_vec(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::amount::{Amount, COIN};
+    use crate::encode::{deserialize, serialize};
+    use crate::script::Script;
+    use crate::transaction::TransactionOutput;
+
+    pub const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    #[test]
+    pub fn test_genesis_block_round_trip() {
+        let bytes = hex::decode(GENESIS_BLOCK).unwrap();
+        let block: Block = deserialize(&bytes).unwrap();
+        assert_eq!(block.header.bits, [0xff, 0xff, 0x00, 0x1d]);
+        assert_eq!(block.header.nonce, 2083236893u32.to_le_bytes());
+        assert_eq!(block.transactions.len(), 1);
+        let coinbase = &block.transactions[0];
+        assert!(!coinbase.is_segregated_witness());
+        assert_eq!(coinbase.outputs[0].amount, Amount::from_sat(50 * COIN));
+        assert_eq!(serialize(&block), bytes);
+        assert_eq!(serialize(&block.header).len(), 80);
+        assert_eq!(block.weight(), bytes.len() as u64 * 4);
+        let mut hash = block.header.block_hash();
+        hash.reverse();
+        assert_eq!(
+            hex::encode(hash),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+    }
+
+    fn coinbase_block(script_sig: Vec<u8>) -> Block {
+        let mut block: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        block.transactions[0].inputs[0].script_sig = Script::of_bytes(script_sig);
+        block
+    }
+
+    #[test]
+    pub fn test_coinbase_height() {
+        assert_eq!(coinbase_height_prefix(0), vec![0x00]);
+        assert_eq!(coinbase_height_prefix(16), vec![0x60]);
+        assert_eq!(coinbase_height_prefix(17), vec![0x01, 0x11]);
+        assert_eq!(coinbase_height_prefix(128), vec![0x02, 0x80, 0x00]);
+        // The first block enforcing BIP 34 on mainnet
+        assert_eq!(
+            coinbase_height_prefix(227_931),
+            vec![0x03, 0x5b, 0x7a, 0x03]
+        );
+
+        for height in [0, 1, 16, 17, 128, 227_931, u32::MAX] {
+            let mut script_sig = coinbase_height_prefix(height);
+            script_sig.extend([0x04, 0xde, 0xad, 0xbe, 0xef]);
+            assert_eq!(coinbase_block(script_sig).bip34_height(), Some(height));
+        }
+        // The genesis block predates BIP 34, and its coinbase starts with the
+        // compact target instead
+        let genesis: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        assert_eq!(genesis.bip34_height(), Some(0x1d00ffff));
+        // Negative, non-minimal, or not pushed
+        assert_eq!(coinbase_block(vec![0x01, 0x81, 0x00]).bip34_height(), None);
+        assert_eq!(coinbase_block(vec![0x02, 0x11, 0x00]).bip34_height(), None);
+        assert_eq!(coinbase_block(vec![0x4c, 0x01, 0x11]).bip34_height(), None);
+        assert_eq!(coinbase_block(vec![0x76, 0x51]).bip34_height(), None);
+    }
+
+    #[test]
+    pub fn test_witness_commitment() {
+        let mut block: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        assert_eq!(block.witness_commitment(), None);
+        assert_eq!(block.witness_reserved_value(), None);
+        // The coinbase does not commit to itself
+        assert_eq!(block.witness_root(), Some([0; 32]));
+
+        // The well-known commitment of blocks without other transactions
+        let commitment = Block::compute_witness_commitment(&[0; 32], &[0; 32]);
+        let script_pubkey = hex::decode(
+            "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9",
+        )
+        .unwrap();
+        assert_eq!(script_pubkey[..6], WITNESS_COMMITMENT_HEADER);
+        assert_eq!(script_pubkey[6..], commitment);
+        let coinbase = &mut block.transactions[0];
+        coinbase.outputs.push(TransactionOutput {
+            amount: Amount::ZERO,
+            script_pubkey: Script::of_bytes(script_pubkey),
+        });
+        coinbase.witnesses = vec![vec![vec![0; 32]]];
+        assert_eq!(block.witness_commitment(), Some(commitment));
+        assert_eq!(block.witness_reserved_value(), Some([0; 32]));
+
+        // The last matching output is the commitment
+        let mut other = WITNESS_COMMITMENT_HEADER.to_vec();
+        other.extend([0x42; 33]);
+        block.transactions[0].outputs.push(TransactionOutput {
+            amount: Amount::ZERO,
+            script_pubkey: Script::of_bytes(other),
+        });
+        assert_eq!(block.witness_commitment(), Some([0x42; 32]));
+
+        let segwit: Transaction =
+            deserialize(&hex::decode(crate::transaction::tests::SEGWIT_TX).unwrap()).unwrap();
+        block.transactions.push(segwit.clone());
+        assert_eq!(
+            block.witness_root(),
+            merkle_root(&[[0; 32], segwit.wtxid()])
+        );
+        block.transactions[0].witnesses[0].push(vec![]);
+        assert_eq!(block.witness_reserved_value(), None);
+    }
+}
@@ -0,0 +1,607 @@
+//! Reader for the files where the reference implementation stores the blocks,
+//! to scan a data directory offline.
+//!
+//! The `blocks` directory contains `blk*.dat` files with the raw blocks, and
+//! `rev*.dat` files with their undo data, i.e. the outputs spent by each
+//! block. Each record starts with the magic of the network and the size of
+//! the data; undo records are followed by a checksum. Since version 28, the
+//! files are obfuscated with the key stored in `xor.dat`.
+//!
+//! Parsing works on the content of a file, so that it does not require
+//! `std`. With the `std` feature, helpers read the files of a directory.
+//! Undo data spending outputs paid to uncompressed public keys can only be
+//! decoded with the `secp256k1` feature, as the keys are stored compressed.
+
+use alloc::vec::Vec;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::encode::{self, Decodable, Error, Read};
+use crate::params::Network;
+use crate::script::{Script, MAX_SCRIPT_SIZE};
+use crate::transaction::TransactionOutput;
+use crate::utils::sha256d;
+use crate::utxo::{BlockUndo, Coin};
+
+/// The key obfuscating the files, read from `xor.dat`. The files written
+/// before it was introduced are not obfuscated, which is the same as a zero
+/// key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XorKey(pub [u8; 8]);
+
+impl XorKey {
+    /// Obfuscate or deobfuscate bytes read at the given offset of a file.
+    pub fn apply(&self, data: &mut [u8], offset: u64) {
+        if self.0 == [0; 8] {
+            return;
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= self.0[((offset + i as u64) % 8) as usize];
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockFileError {
+    /// The record at the given offset does not start with the magic of the
+    /// network.
+    BadMagic { offset: usize },
+    /// The record at the given offset goes past the end of the file.
+    Truncated { offset: usize },
+    /// The data of the record at the given offset can not be decoded.
+    Decode { offset: usize, error: Error },
+}
+
+impl core::fmt::Display for BlockFileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            BlockFileError::BadMagic { offset } => write!(f, "bad magic at offset {}", offset),
+            BlockFileError::Truncated { offset } => {
+                write!(f, "truncated record at offset {}", offset)
+            }
+            BlockFileError::Decode { offset, error } => {
+                write!(f, "invalid record at offset {}: {}", offset, error)
+            }
+        }
+    }
+}
+
+/// Records of a file: the magic of the network, the size of the data as 4
+/// bytes, the data, and a trailer of `trailer_size` bytes.
+#[derive(Debug, Clone)]
+struct Records<'a> {
+    data: &'a [u8],
+    offset: usize,
+    magic: [u8; 4],
+    trailer_size: usize,
+}
+
+impl<'a> Iterator for Records<'a> {
+    /// The offset of the record, its data and its trailer.
+    type Item = Result<(usize, &'a [u8], &'a [u8]), BlockFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        let rest = &self.data[offset..];
+        // Files are allocated by chunks, so that they end with zeros
+        if rest.len() < 8 || rest[..4] == [0; 4] {
+            return None;
+        }
+        // Nothing can be read after an invalid record
+        self.offset = self.data.len();
+        if rest[..4] != self.magic {
+            return Some(Err(BlockFileError::BadMagic { offset }));
+        }
+        let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        let end = 8 + size + self.trailer_size;
+        if rest.len() < end {
+            return Some(Err(BlockFileError::Truncated { offset }));
+        }
+        self.offset = offset + end;
+        Some(Ok((offset, &rest[8..8 + size], &rest[8 + size..end])))
+    }
+}
+
+/// Iterator over the blocks of the content of a `blk*.dat` file, once
+/// deobfuscated. It stops after the first invalid record.
+#[derive(Debug, Clone)]
+pub struct BlockRecords<'a>(Records<'a>);
+
+impl<'a> BlockRecords<'a> {
+    pub fn new(data: &'a [u8], network: Network) -> Self {
+        BlockRecords(Records {
+            data,
+            offset: 0,
+            magic: network.magic(),
+            trailer_size: 0,
+        })
+    }
+}
+
+impl Iterator for BlockRecords<'_> {
+    type Item = Result<Block, BlockFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.and_then(|(offset, data, _)| {
+            encode::deserialize(data).map_err(|error| BlockFileError::Decode { offset, error })
+        }))
+    }
+}
+
+/// The undo data of a block, read from a `rev*.dat` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub undo: BlockUndo,
+    /// The double SHA-256 of the hash of the previous block and the undo data.
+    pub checksum: [u8; 32],
+    data: Vec<u8>,
+}
+
+impl UndoRecord {
+    /// Whether the record belongs to the given block, as far as the checksum
+    /// over the hash of its parent can tell. The undo data are written when
+    /// the blocks are connected, so that they are not necessarily in the same
+    /// order as the blocks of the matching `blk*.dat` file.
+    pub fn matches_block(&self, block: &Block) -> bool {
+        let mut bytes = block.header.previous_block.to_vec();
+        bytes.extend_from_slice(&self.data);
+        sha256d(&bytes) == self.checksum
+    }
+}
+
+/// Iterator over the undo records of the content of a `rev*.dat` file, once
+/// deobfuscated. It stops after the first invalid record.
+#[derive(Debug, Clone)]
+pub struct UndoRecords<'a>(Records<'a>);
+
+impl<'a> UndoRecords<'a> {
+    pub fn new(data: &'a [u8], network: Network) -> Self {
+        UndoRecords(Records {
+            data,
+            offset: 0,
+            magic: network.magic(),
+            trailer_size: 32,
+        })
+    }
+}
+
+impl Iterator for UndoRecords<'_> {
+    type Item = Result<UndoRecord, BlockFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.and_then(|(offset, data, checksum)| {
+            let undo = decode_block_undo(data)
+                .map_err(|error| BlockFileError::Decode { offset, error })?;
+            Ok(UndoRecord {
+                undo,
+                checksum: checksum.try_into().unwrap(),
+                data: data.to_vec(),
+            })
+        }))
+    }
+}
+
+fn decode_block_undo(mut data: &[u8]) -> Result<BlockUndo, Error> {
+    let r = &mut data;
+    let count = encode::read_length(r)?;
+    let mut spent_coins = Vec::new();
+    for _ in 0..count {
+        let inputs = encode::read_length(r)?;
+        let mut coins = Vec::new();
+        for _ in 0..inputs {
+            coins.push(decode_coin(r)?);
+        }
+        spent_coins.push(coins);
+    }
+    if !data.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(BlockUndo { spent_coins })
+}
+
+/// Read a number encoded with the variable length format of the reference
+/// implementation, which is different from the compact size: each byte holds
+/// 7 bits, the most significant first, and the high bit tells whether another
+/// byte follows.
+fn read_varint<R: Read + ?Sized>(r: &mut R) -> Result<u64, Error> {
+    let mut n: u64 = 0;
+    loop {
+        let byte = u8::consensus_decode(r)?;
+        if n > u64::MAX >> 7 {
+            return Err(Error::ParseFailed("variable length integer too large"));
+        }
+        n = (n << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n
+            .checked_add(1)
+            .ok_or(Error::ParseFailed("variable length integer too large"))?;
+    }
+}
+
+/// A spent output, stored with its height and coinbase flag, and the amount
+/// and the script compressed.
+fn decode_coin<R: Read + ?Sized>(r: &mut R) -> Result<Coin, Error> {
+    let code = read_varint(r)?;
+    let height = u32::try_from(code >> 1).map_err(|_| Error::ParseFailed("height too large"))?;
+    if height > 0 {
+        // The version of the transaction, which is not used anymore
+        read_varint(r)?;
+    }
+    let amount = Amount::from_sat(decompress_amount(read_varint(r)?));
+    Ok(Coin {
+        output: TransactionOutput {
+            amount,
+            script_pubkey: Script::of_bytes(decode_compressed_script(r)?),
+        },
+        height,
+        is_coinbase: code & 1 == 1,
+    })
+}
+
+/// Decompress an amount compressed by exploiting the trailing zeros of the
+/// amounts usually paid, as `DecompressAmount` in the reference
+/// implementation.
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let exponent = x % 10;
+    x /= 10;
+    let mut n = if exponent < 9 {
+        let digit = x % 9 + 1;
+        x /= 9;
+        x * 10 + digit
+    } else {
+        x + 1
+    };
+    for _ in 0..exponent {
+        n = n.wrapping_mul(10);
+    }
+    n
+}
+
+/// Decode a script compressed as `ScriptCompression` in the reference
+/// implementation: the first number tells the kind of the script for the
+/// usual ones, which only store a hash or a public key, or the size of the
+/// script plus 6.
+fn decode_compressed_script<R: Read + ?Sized>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let kind = read_varint(r)?;
+    match kind {
+        0 => {
+            let hash = <[u8; 20]>::consensus_decode(r)?;
+            let mut script = alloc::vec![0x76, 0xa9, 0x14];
+            script.extend(hash);
+            script.extend([0x88, 0xac]);
+            Ok(script)
+        }
+        1 => {
+            let hash = <[u8; 20]>::consensus_decode(r)?;
+            let mut script = alloc::vec![0xa9, 0x14];
+            script.extend(hash);
+            script.push(0x87);
+            Ok(script)
+        }
+        2 | 3 => {
+            let x = <[u8; 32]>::consensus_decode(r)?;
+            let mut script = alloc::vec![0x21, kind as u8];
+            script.extend(x);
+            script.push(0xac);
+            Ok(script)
+        }
+        4 | 5 => {
+            let x = <[u8; 32]>::consensus_decode(r)?;
+            let y = decompress_public_key(&x, kind == 5)?;
+            let mut script = alloc::vec![0x41, 0x04];
+            script.extend(x);
+            script.extend(y);
+            script.push(0xac);
+            Ok(script)
+        }
+        _ => {
+            let size = usize::try_from(kind - 6)
+                .ok()
+                .filter(|size| *size as u64 <= crate::utils::MAX_SIZE)
+                .ok_or(Error::OversizedLength(kind - 6))?;
+            let mut script = alloc::vec![0; size];
+            r.read_exact(&mut script)?;
+            // Scripts too large to be spent are replaced by a shorter one
+            if size > MAX_SCRIPT_SIZE {
+                script = alloc::vec![0x6a];
+            }
+            Ok(script)
+        }
+    }
+}
+
+/// The y coordinate of the point of secp256k1 with the given x coordinate and
+/// parity, both as 32 big-endian bytes.
+#[cfg(feature = "secp256k1")]
+fn decompress_public_key(x: &[u8; 32], odd: bool) -> Result<[u8; 32], Error> {
+    let mut compressed = [0; 33];
+    compressed[0] = if odd { 0x03 } else { 0x02 };
+    compressed[1..].copy_from_slice(x);
+    let key = secp256k1::PublicKey::from_slice(&compressed)
+        .map_err(|_| Error::ParseFailed("invalid compressed public key"))?;
+    Ok(key.serialize_uncompressed()[33..].try_into().unwrap())
+}
+
+#[cfg(not(feature = "secp256k1"))]
+fn decompress_public_key(_x: &[u8; 32], _odd: bool) -> Result<[u8; 32], Error> {
+    Err(Error::ParseFailed(
+        "uncompressed public keys require the secp256k1 feature",
+    ))
+}
+
+#[cfg(feature = "std")]
+pub use files::{block_file_paths, read_file, read_xor_key, undo_file_paths};
+
+#[cfg(feature = "std")]
+mod files {
+    use super::XorKey;
+    use alloc::vec::Vec;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    /// Read the obfuscation key of the `blocks` directory, which is zero if
+    /// there is no `xor.dat` file.
+    pub fn read_xor_key<P: AsRef<Path>>(blocks_dir: P) -> io::Result<XorKey> {
+        match std::fs::read(blocks_dir.as_ref().join("xor.dat")) {
+            Ok(bytes) => bytes.try_into().map(XorKey).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "xor.dat is not 8 bytes long")
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(XorKey::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read and deobfuscate a file of the `blocks` directory.
+    pub fn read_file<P: AsRef<Path>>(path: P, key: &XorKey) -> io::Result<Vec<u8>> {
+        let mut bytes = std::fs::read(path)?;
+        key.apply(&mut bytes, 0);
+        Ok(bytes)
+    }
+
+    /// The files with the given prefix followed by their number, in order.
+    fn numbered_files(blocks_dir: &Path, prefix: &str) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(blocks_dir)? {
+            let path = entry?.path();
+            let number = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(prefix))
+                .and_then(|name| name.strip_suffix(".dat"))
+                .and_then(|number| number.parse::<u32>().ok());
+            if let Some(number) = number {
+                files.push((number, path));
+            }
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// The `blk*.dat` files of the `blocks` directory, in order.
+    pub fn block_file_paths<P: AsRef<Path>>(blocks_dir: P) -> io::Result<Vec<PathBuf>> {
+        numbered_files(blocks_dir.as_ref(), "blk")
+    }
+
+    /// The `rev*.dat` files of the `blocks` directory, in order.
+    pub fn undo_file_paths<P: AsRef<Path>>(blocks_dir: P) -> io::Result<Vec<PathBuf>> {
+        numbered_files(blocks_dir.as_ref(), "rev")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::COIN;
+    use crate::block::tests::GENESIS_BLOCK;
+    use crate::chain::tests::mine_regtest_header;
+    use crate::params::ChainParams;
+    use alloc::vec;
+
+    /// The x coordinate of the generator of secp256k1.
+    const GENERATOR_X: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn record(network: Network, data: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut bytes = network.magic().to_vec();
+        bytes.extend((data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes.extend(trailer);
+        bytes
+    }
+
+    #[test]
+    pub fn test_decompress_amounts() {
+        // From the tests of the reference implementation
+        assert_eq!(decompress_amount(0), 0);
+        assert_eq!(decompress_amount(1), 1);
+        assert_eq!(decompress_amount(7), 1_000_000);
+        assert_eq!(decompress_amount(9), COIN);
+        assert_eq!(decompress_amount(50), 50 * COIN);
+        assert_eq!(decompress_amount(0x1406f40), 21_000_000 * COIN);
+        assert_eq!(decompress_amount(4), 1000);
+    }
+
+    #[cfg(feature = "secp256k1")]
+    #[test]
+    pub fn test_decompress_public_key() {
+        let x: [u8; 32] = hex::decode(GENERATOR_X).unwrap().try_into().unwrap();
+        let y = hex::decode("483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8")
+            .unwrap();
+        assert_eq!(decompress_public_key(&x, false).unwrap().to_vec(), y);
+        // The other point with the same x coordinate is -G
+        assert_eq!(
+            hex::encode(decompress_public_key(&x, true).unwrap()),
+            "b7c52588d95c3b9aa25b0403f1eef75702e84bb7597aabe663b82f6f04ef2777"
+        );
+        // x = 5 is not on the curve, and the field size is out of bounds
+        let mut five = [0; 32];
+        five[31] = 5;
+        assert!(decompress_public_key(&five, false).is_err());
+        assert!(decompress_public_key(&[0xff; 32], false).is_err());
+
+        // A pay-to-pubkey script with an uncompressed key
+        let mut data = vec![0x04];
+        data.extend(&x);
+        let mut p2pk = vec![0x41, 0x04];
+        p2pk.extend(&x);
+        p2pk.extend(&y);
+        p2pk.push(0xac);
+        assert_eq!(decode_compressed_script(&mut &data[..]).unwrap(), p2pk);
+    }
+
+    #[cfg(not(feature = "secp256k1"))]
+    #[test]
+    pub fn test_decompress_public_key_unsupported() {
+        let mut data = vec![0x04];
+        data.extend(hex::decode(GENERATOR_X).unwrap());
+        assert!(decode_compressed_script(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    pub fn test_read_blocks() {
+        let network = Network::Regtest;
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let mut block: Block = encode::deserialize(&genesis).unwrap();
+        block.header = mine_regtest_header(
+            &ChainParams::regtest().genesis_header,
+            block.header.merkle_root,
+        );
+        let mut file = record(network, &genesis, &[]);
+        file.extend(record(network, &encode::serialize(&block), &[]));
+        // Preallocated space
+        file.extend([0; 64]);
+
+        let key = XorKey([0x81, 0x4f, 0x3e, 0x21, 0x9c, 0x05, 0x77, 0xea]);
+        let mut obfuscated = file.clone();
+        key.apply(&mut obfuscated[..100], 0);
+        key.apply(&mut obfuscated[100..], 100);
+        assert_ne!(obfuscated, file);
+        let mut deobfuscated = obfuscated.clone();
+        key.apply(&mut deobfuscated, 0);
+        assert_eq!(deobfuscated, file);
+
+        let blocks: Vec<_> = BlockRecords::new(&deobfuscated, network).collect();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[0].as_ref().unwrap(),
+            &encode::deserialize::<Block>(&genesis).unwrap()
+        );
+        assert_eq!(blocks[1], Ok(block));
+
+        let mut records = BlockRecords::new(&deobfuscated, Network::Mainnet);
+        assert_eq!(
+            records.next(),
+            Some(Err(BlockFileError::BadMagic { offset: 0 }))
+        );
+        assert_eq!(records.next(), None);
+        let truncated = &file[..file.len() - 65];
+        let mut records = BlockRecords::new(truncated, network);
+        assert!(records.next().unwrap().is_ok());
+        assert_eq!(
+            records.next(),
+            Some(Err(BlockFileError::Truncated {
+                offset: genesis.len() + 8
+            }))
+        );
+        let mut invalid = record(network, &genesis[..100], &[]);
+        invalid[12] = 0xff;
+        assert!(matches!(
+            BlockRecords::new(&invalid, network).next(),
+            Some(Err(BlockFileError::Decode { offset: 0, .. }))
+        ));
+    }
+
+    #[test]
+    pub fn test_read_undo() {
+        let network = Network::Mainnet;
+        let x = hex::decode(GENERATOR_X).unwrap();
+        // Two transactions: one spending a coinbase output paying 50 BTC to
+        // a compressed public key at height 1, one spending a P2PKH output of
+        // 1000 satoshis at height 300 and a raw script at height 0
+        let mut data = vec![0x02, 0x01, 0x03, 0x00, 0x32, 0x02];
+        data.extend(&x);
+        data.extend([0x02, 0x83, 0x58, 0x00, 0x04, 0x00]);
+        data.extend([0x42; 20]);
+        data.extend([0x00, 0x09, 0x08, 0x51, 0x87]);
+        // The checksum of the undo data of block 1 covers the genesis hash
+        let genesis: Block = encode::deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let mut block = genesis.clone();
+        block.header = mine_regtest_header(&genesis.header, genesis.header.merkle_root);
+        let mut checksum = genesis.header.block_hash().to_vec();
+        checksum.extend(&data);
+        let file = record(network, &data, &sha256d(&checksum));
+
+        let records: Vec<_> = UndoRecords::new(&file, network).collect();
+        assert_eq!(records.len(), 1);
+        let record = records[0].clone().unwrap();
+        assert!(record.matches_block(&block));
+        assert!(!record.matches_block(&genesis));
+
+        let coins = &record.undo.spent_coins;
+        assert_eq!(coins.len(), 2);
+        assert_eq!(coins[0].len(), 1);
+        assert_eq!(coins[0][0].height, 1);
+        assert!(coins[0][0].is_coinbase);
+        assert_eq!(coins[0][0].output.amount, Amount::from_sat(50 * COIN));
+        let mut p2pk = vec![0x21, 0x02];
+        p2pk.extend(&x);
+        p2pk.push(0xac);
+        assert_eq!(coins[0][0].output.script_pubkey.to_bytes(), p2pk);
+
+        assert_eq!(coins[1][0].height, 300);
+        assert!(!coins[1][0].is_coinbase);
+        assert_eq!(coins[1][0].output.amount, Amount::from_sat(1000));
+        let mut p2pkh = vec![0x76, 0xa9, 0x14];
+        p2pkh.extend([0x42; 20]);
+        p2pkh.extend([0x88, 0xac]);
+        assert_eq!(coins[1][0].output.script_pubkey.to_bytes(), p2pkh);
+        assert_eq!(coins[1][1].height, 0);
+        assert_eq!(coins[1][1].output.amount, Amount::ONE_BTC);
+        assert_eq!(
+            coins[1][1].output.script_pubkey.to_bytes(),
+            vec![0x51, 0x87]
+        );
+
+        // The checksum is required
+        let truncated = &file[..file.len() - 1];
+        assert_eq!(
+            UndoRecords::new(truncated, network).next(),
+            Some(Err(BlockFileError::Truncated { offset: 0 }))
+        );
+    }
+
+    #[test]
+    pub fn test_read_directory() {
+        let dir = std::env::temp_dir().join(std::format!("blocks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read_xor_key(&dir).unwrap(), XorKey::default());
+        let key = XorKey([1, 2, 3, 4, 5, 6, 7, 8]);
+        std::fs::write(dir.join("xor.dat"), key.0).unwrap();
+        assert_eq!(read_xor_key(&dir).unwrap(), key);
+
+        let genesis = hex::decode(GENESIS_BLOCK).unwrap();
+        let mut file = record(Network::Mainnet, &genesis, &[]);
+        key.apply(&mut file, 0);
+        for name in ["blk00010.dat", "blk00002.dat", "rev00002.dat", "index"] {
+            std::fs::write(dir.join(name), &file).unwrap();
+        }
+        let paths = block_file_paths(&dir).unwrap();
+        assert_eq!(
+            paths,
+            vec![dir.join("blk00002.dat"), dir.join("blk00010.dat")]
+        );
+        assert_eq!(
+            undo_file_paths(&dir).unwrap(),
+            vec![dir.join("rev00002.dat")]
+        );
+        let bytes = read_file(&paths[0], &key).unwrap();
+        let block = BlockRecords::new(&bytes, Network::Mainnet).next().unwrap();
+        assert_eq!(encode::serialize(&block.unwrap()), genesis);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+//! Compact block filters of BIP 158, served by nodes following BIP 157.
+//!
+//! A basic filter commits to the scripts of the outputs created and spent by
+//! a block. Each script is hashed with SipHash, keyed by the hash of the
+//! block, to a number lower than `N * M`, and the sorted hashes are encoded as
+//! their differences with Golomb-Rice coding. A light client checks whether a
+//! block may concern its scripts from the filter, with a false positive rate
+//! of about `1 / M`, without revealing them to the node.
+//!
+//! Filters are committed to by a chain of filter headers, each one hashing
+//! the hash of the filter with the previous header.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use crate::block::Block;
+use crate::encode::{self, Decodable, Error};
+use crate::utils::{sha256d, VarInt};
+use crate::utxo::BlockUndo;
+
+/// The type of the basic filters, the only one defined.
+pub const BASIC_FILTER: u8 = 0;
+
+/// The number of bits of the remainders of the Golomb-Rice coding of basic
+/// filters.
+pub const BASIC_FILTER_P: u8 = 19;
+
+/// The inverse of the false positive rate of basic filters.
+pub const BASIC_FILTER_M: u64 = 784_931;
+
+/// The number of blocks between the filter headers of a `cfcheckpt` message.
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+
+/// SipHash-2-4 of the data with the key `(k0, k1)`.
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    let mut compress = |m: u64| {
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    };
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Map an element to a number lower than `range`, with the key derived from
+/// the hash of the block.
+fn hash_to_range(block_hash: &[u8; 32], range: u64, element: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(block_hash[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(block_hash[8..16].try_into().unwrap());
+    ((siphash(k0, k1, element) as u128 * range as u128) >> 64) as u64
+}
+
+/// Writes bits, most significant first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// The number of bits used in the last byte, from 1 to 8.
+    used: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            if self.used == 8 {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            let bit = (value >> i) as u8 & 1;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.used);
+            self.used += 1;
+        }
+    }
+
+    fn write_golomb_rice(&mut self, value: u64, p: u8) {
+        let mut quotient = value >> p;
+        while quotient > 0 {
+            let ones = quotient.min(64);
+            self.write(u64::MAX, ones as u8);
+            quotient -= ones;
+        }
+        self.write(0, 1);
+        self.write(value, p);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// The index of the next bit.
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u8) -> Result<u64, Error> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self
+                .bytes
+                .get(self.position / 8)
+                .ok_or(Error::UnexpectedEof)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn read_golomb_rice(&mut self, p: u8) -> Result<u64, Error> {
+        let mut quotient = 0u64;
+        while self.read(1)? == 1 {
+            quotient += 1;
+        }
+        Ok((quotient << p) | self.read(p)?)
+    }
+}
+
+/// A filter of the scripts of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// The number of elements, followed by their Golomb-Rice coded hashes.
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new(content: Vec<u8>) -> Self {
+        BlockFilter { content }
+    }
+
+    /// Build the basic filter of a block, from the scripts of its outputs and
+    /// of the outputs it spends, given by its undo data. Empty scripts and
+    /// the outputs starting with OP_RETURN are left out.
+    pub fn new_basic(block: &Block, undo: &BlockUndo) -> Self {
+        let mut elements = BTreeSet::new();
+        for transaction in &block.transactions {
+            for output in &transaction.outputs {
+                let script = output.script_pubkey.to_bytes();
+                if !script.is_empty() && script[0] != 0x6a {
+                    elements.insert(script);
+                }
+            }
+        }
+        for coin in undo.spent_coins.iter().flatten() {
+            let script = coin.output.script_pubkey.to_bytes();
+            if !script.is_empty() {
+                elements.insert(script);
+            }
+        }
+        let block_hash = block.header.block_hash();
+        let range = elements.len() as u64 * BASIC_FILTER_M;
+        let mut hashes: Vec<u64> = elements
+            .iter()
+            .map(|element| hash_to_range(&block_hash, range, element))
+            .collect();
+        hashes.sort_unstable();
+
+        let mut content = encode::serialize(&VarInt(elements.len() as u64));
+        let mut writer = BitWriter {
+            bytes: Vec::new(),
+            used: 8,
+        };
+        let mut previous = 0;
+        for hash in hashes {
+            writer.write_golomb_rice(hash - previous, BASIC_FILTER_P);
+            previous = hash;
+        }
+        content.extend(writer.bytes);
+        BlockFilter { content }
+    }
+
+    /// The hash of the filter, committed to by its header.
+    pub fn filter_hash(&self) -> [u8; 32] {
+        sha256d(&self.content)
+    }
+
+    /// The header of the filter, following the header of the filter of the
+    /// previous block, which is zero for the genesis block.
+    pub fn filter_header(&self, previous_header: &[u8; 32]) -> [u8; 32] {
+        filter_header(&self.filter_hash(), previous_header)
+    }
+
+    /// Whether one of the scripts may be in the filter of the block with the
+    /// given hash. False positives happen with a rate of about `1 / M`.
+    pub fn match_any<'a, I>(&self, block_hash: &[u8; 32], scripts: I) -> Result<bool, Error>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let r = &mut &self.content[..];
+        let count = VarInt::consensus_decode(r)?.0;
+        let range = count
+            .checked_mul(BASIC_FILTER_M)
+            .ok_or(Error::OversizedLength(count))?;
+        let mut queries: Vec<u64> = scripts
+            .into_iter()
+            .map(|script| hash_to_range(block_hash, range, script))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader {
+            bytes: r,
+            position: 0,
+        };
+        let mut queries = queries.into_iter().peekable();
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = value
+                .checked_add(reader.read_golomb_rice(BASIC_FILTER_P)?)
+                .ok_or(Error::ParseFailed("filter values overflow"))?;
+            while let Some(query) = queries.next_if(|query| *query <= value) {
+                if query == value {
+                    return Ok(true);
+                }
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Whether the script may be in the filter of the block with the given
+    /// hash.
+    pub fn match_script(&self, block_hash: &[u8; 32], script: &[u8]) -> Result<bool, Error> {
+        self.match_any(block_hash, [script])
+    }
+}
+
+/// The header of a filter, from its hash and the header of the filter of the
+/// previous block.
+pub fn filter_header(filter_hash: &[u8; 32], previous_header: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = [0; 64];
+    bytes[..32].copy_from_slice(filter_hash);
+    bytes[32..].copy_from_slice(previous_header);
+    sha256d(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::block::tests::GENESIS_BLOCK;
+    use crate::p2p::CFHeadersMessage;
+    use crate::params::ChainParams;
+    use crate::script::Script;
+    use crate::transaction::TransactionOutput;
+    use crate::utxo::Coin;
+    use alloc::vec;
+
+    fn testnet_genesis() -> Block {
+        let mut block: Block = encode::deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        block.header = ChainParams::testnet().genesis_header;
+        block
+    }
+
+    fn output(script: &[u8]) -> TransactionOutput {
+        TransactionOutput {
+            amount: Amount::from_sat(1000),
+            script_pubkey: Script::of_bytes(script.to_vec()),
+        }
+    }
+
+    #[test]
+    pub fn test_siphash() {
+        // Reference vector of SipHash-2-4, with the key 00 01 .. 0f
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash(k0, k1, &[]), 0x726fdb47dd0e0e31);
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash(k0, k1, &data), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    pub fn test_genesis_filter() {
+        // Vector of BIP 158 for the testnet genesis block
+        let block = testnet_genesis();
+        let filter = BlockFilter::new_basic(&block, &BlockUndo::default());
+        assert_eq!(hex::encode(&filter.content), "019dfca8");
+        let mut header = filter.filter_header(&[0; 32]);
+        header.reverse();
+        assert_eq!(
+            hex::encode(header),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+
+        let hash = block.header.block_hash();
+        let script = block.transactions[0].outputs[0].script_pubkey.to_bytes();
+        assert_eq!(filter.match_script(&hash, &script), Ok(true));
+        assert_eq!(filter.match_script(&hash, &[0x51]), Ok(false));
+        // The key depends on the block
+        let other = ChainParams::mainnet().genesis_header.block_hash();
+        assert_eq!(filter.match_script(&other, &script), Ok(false));
+    }
+
+    #[test]
+    pub fn test_match() {
+        let mut block = testnet_genesis();
+        let scripts: Vec<Vec<u8>> = (0..50u8).map(|i| vec![0x00, 0x14, i]).collect();
+        let outputs = &mut block.transactions[0].outputs;
+        outputs.extend(scripts[..40].iter().map(|s| output(s)));
+        outputs.push(output(&[0x6a, 0x01, 0x02]));
+        outputs.push(output(&[]));
+        let undo = BlockUndo {
+            spent_coins: vec![scripts[35..45]
+                .iter()
+                .map(|s| Coin {
+                    output: output(s),
+                    height: 1,
+                    is_coinbase: false,
+                })
+                .collect()],
+        };
+        let filter = BlockFilter::new_basic(&block, &undo);
+        // The genesis output and 45 distinct scripts
+        assert_eq!(filter.content[0], 46);
+
+        let hash = block.header.block_hash();
+        for script in &scripts[..45] {
+            assert_eq!(filter.match_script(&hash, script), Ok(true));
+        }
+        assert_eq!(filter.match_script(&hash, &[0x6a, 0x01, 0x02]), Ok(false));
+        let missing = scripts[45..].iter().map(|s| &s[..]);
+        assert_eq!(filter.match_any(&hash, missing), Ok(false));
+        let queries = [&scripts[47][..], &scripts[3][..]];
+        assert_eq!(filter.match_any(&hash, queries), Ok(true));
+        assert_eq!(filter.match_any(&hash, []), Ok(false));
+
+        let empty = BlockFilter::new(vec![0]);
+        assert_eq!(empty.match_script(&hash, &scripts[0]), Ok(false));
+        let truncated = BlockFilter::new(filter.content[..10].to_vec());
+        assert_eq!(
+            truncated.match_script(&hash, &scripts[44]),
+            Err(Error::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    pub fn test_filter_headers() {
+        let filters = [vec![0], vec![1, 0x9d, 0xfc, 0xa8]];
+        let filters: Vec<BlockFilter> = filters.into_iter().map(BlockFilter::new).collect();
+        let first = filters[0].filter_header(&[0; 32]);
+        let second = filters[1].filter_header(&first);
+        let message = CFHeadersMessage {
+            filter_type: BASIC_FILTER,
+            stop_hash: [0; 32],
+            previous_filter_header: [0; 32],
+            filter_hashes: filters.iter().map(BlockFilter::filter_hash).collect(),
+        };
+        assert_eq!(message.filter_headers(), vec![first, second]);
+    }
+}
@@ -0,0 +1,411 @@
+//! Bloom filters of BIP 37, sent with `filterload` messages so that a node
+//! only relays the matching transactions, and `merkleblock` messages proving
+//! their inclusion in blocks.
+//!
+//! The filter is made of bits set by hashing each inserted element with
+//! several seeds of MurmurHash3. Elements are data pushed by the scripts of
+//! transactions, transaction IDs and serialized outpoints. Depending on the
+//! update flags, the node inserts the outpoints of the matching outputs, so
+//! that the transactions spending them also match.
+
+use alloc::vec::Vec;
+
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::script::{ScriptRef, MAX_SCRIPT_ELEMENT_SIZE};
+use crate::transaction::Transaction;
+use crate::utxo::OutPoint;
+
+/// The maximum size of the filter in bytes.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// The maximum number of hash functions.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// The maximum size of the data of a `filteradd` message.
+pub const MAX_FILTER_ADD_SIZE: usize = MAX_SCRIPT_ELEMENT_SIZE;
+
+/// The outpoints of matching outputs are not inserted.
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+/// The outpoints of all matching outputs are inserted.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+/// Only the outpoints of matching pay-to-pubkey and bare multisig outputs are
+/// inserted.
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+/// The bits of the flags selecting the update mode.
+pub const BLOOM_UPDATE_MASK: u8 = 3;
+
+/// MurmurHash3 (x86, 32 bits) of the data with the given seed.
+pub fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap());
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+/// Whether the size of a public key matches its first byte, as
+/// `CPubKey::ValidSize` in the reference implementation.
+fn is_valid_public_key_size(key: &[u8]) -> bool {
+    match key.first() {
+        Some(2 | 3) => key.len() == 33,
+        Some(4 | 6 | 7) => key.len() == 65,
+        _ => false,
+    }
+}
+
+/// Whether the script is a pay-to-pubkey or a bare multisig output.
+fn is_p2pk_or_multisig(script: &[u8]) -> bool {
+    if let [size @ (33 | 65), key @ .., 0xac] = script {
+        if key.len() == *size as usize {
+            return is_valid_public_key_size(key);
+        }
+    }
+    let Some(instructions) = ScriptRef::new(script)
+        .instructions()
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+    else {
+        return false;
+    };
+    let opcodes: Vec<u8> = instructions.iter().map(|(op, _)| u8::from(*op)).collect();
+    let small_integer = |opcode: u8| (0x51..=0x60).contains(&opcode).then(|| opcode - 0x50);
+    match &instructions[..] {
+        [_, keys @ .., _, _] if !keys.is_empty() => {
+            let count = opcodes.len();
+            let (Some(required), Some(total)) =
+                (small_integer(opcodes[0]), small_integer(opcodes[count - 2]))
+            else {
+                return false;
+            };
+            opcodes[count - 1] == 0xae
+                && keys.len() == total as usize
+                && required <= total
+                && keys
+                    .iter()
+                    .all(|(_, key)| key.is_some_and(is_valid_public_key_size))
+        }
+        _ => false,
+    }
+}
+
+/// A bloom filter, as sent in a `filterload` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    /// The bits of the filter, the lowest of each byte first.
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    /// Added to the seeds of the hash functions, so that filters of different
+    /// clients set different bits.
+    pub tweak: u32,
+    /// One of the `BLOOM_UPDATE_` modes.
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    /// Create an empty filter for the given number of elements, whose false
+    /// positive rate is about `fp_rate` once they are inserted. The size is
+    /// bounded by [MAX_BLOOM_FILTER_SIZE] and [MAX_HASH_FUNCS].
+    #[cfg(feature = "std")]
+    pub fn new(elements: u32, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        use core::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let bits = (-1.0 / (LN_2 * LN_2) * elements * fp_rate.ln()) as usize;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let hash_funcs = ((size * 8) as f64 / elements * LN_2) as u32;
+        BloomFilter {
+            data: alloc::vec![0; size],
+            hash_funcs: hash_funcs.min(MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        }
+    }
+
+    /// Whether the filter is accepted by nodes.
+    pub fn is_within_size_constraints(&self) -> bool {
+        self.data.len() <= MAX_BLOOM_FILTER_SIZE && self.hash_funcs <= MAX_HASH_FUNCS
+    }
+
+    /// The index of the bit set by the hash function `n`.
+    fn bit(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4c795).wrapping_add(self.tweak);
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&encode::serialize(outpoint));
+    }
+
+    /// Whether the element may have been inserted. As in the reference
+    /// implementation, a filter without data matches everything.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&encode::serialize(outpoint))
+    }
+
+    /// Whether the transaction matches the filter, as a node serving the
+    /// filter checks it. The outpoints of the matching outputs are inserted
+    /// according to the update flags.
+    pub fn is_relevant_and_update(&mut self, transaction: &Transaction) -> bool {
+        let txid = transaction.txid();
+        let mut found = self.contains(&txid);
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            let script = output.script_pubkey.to_bytes();
+            let matches = ScriptRef::new(&script)
+                .instructions()
+                .map_while(Result::ok)
+                .any(|(_, data)| data.is_some_and(|data| !data.is_empty() && self.contains(data)));
+            if !matches {
+                continue;
+            }
+            found = true;
+            let update = match self.flags & BLOOM_UPDATE_MASK {
+                BLOOM_UPDATE_ALL => true,
+                BLOOM_UPDATE_P2PUBKEY_ONLY => is_p2pk_or_multisig(&script),
+                _ => false,
+            };
+            if update {
+                self.insert_outpoint(&OutPoint::new(txid, vout as u32));
+            }
+        }
+        if found {
+            return true;
+        }
+        transaction.inputs.iter().any(|input| {
+            let outpoint = OutPoint::new(input.txid, u32::from_le_bytes(input.vout));
+            let script_sig = input.script_sig.to_bytes();
+            self.contains_outpoint(&outpoint)
+                || ScriptRef::new(&script_sig)
+                    .instructions()
+                    .map_while(Result::ok)
+                    .any(|(_, data)| {
+                        data.is_some_and(|data| !data.is_empty() && self.contains(data))
+                    })
+        })
+    }
+}
+
+impl Encodable for BloomFilter {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(encode::encode_bytes(&self.data, w)?
+            + self.hash_funcs.consensus_encode(w)?
+            + self.tweak.consensus_encode(w)?
+            + self.flags.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for BloomFilter {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let size = encode::read_length(r)?;
+        if size > MAX_BLOOM_FILTER_SIZE {
+            return Err(Error::OversizedLength(size as u64));
+        }
+        let mut data = alloc::vec![0; size];
+        r.read_exact(&mut data)?;
+        let hash_funcs = u32::consensus_decode(r)?;
+        if hash_funcs > MAX_HASH_FUNCS {
+            return Err(Error::ParseFailed("too many hash functions"));
+        }
+        Ok(BloomFilter {
+            data,
+            hash_funcs,
+            tweak: Decodable::consensus_decode(r)?,
+            flags: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::script::Script;
+    use crate::transaction::tests::spend;
+    use alloc::vec;
+
+    #[test]
+    pub fn test_murmur3() {
+        // Vectors of the reference implementation
+        let vectors: [(u32, u32, &str); 14] = [
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xfba4c795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xfba4c795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+            (0xfc2e4a15, 0x00000000, "001122334455"),
+            (0xb074502c, 0x00000000, "00112233445566"),
+            (0x8034d2a0, 0x00000000, "0011223344556677"),
+            (0xb4698def, 0x00000000, "001122334455667788"),
+        ];
+        for (expected, seed, data) in vectors {
+            assert_eq!(murmur3(seed, &hex::decode(data).unwrap()), expected);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    pub fn test_insert_and_serialize() {
+        // Vectors of the reference implementation
+        for (tweak, expected) in [
+            (0, "03614e9b050000000000000001"),
+            (2147483649, "03ce4299050000000100008001"),
+        ] {
+            let mut filter = BloomFilter::new(3, 0.01, tweak, BLOOM_UPDATE_ALL);
+            let element = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+            filter.insert(&element);
+            assert!(filter.contains(&element));
+            let other = hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+            assert!(!filter.contains(&other));
+            filter.insert(&hex::decode("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap());
+            filter.insert(&hex::decode("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap());
+            assert_eq!(hex::encode(encode::serialize(&filter)), expected);
+            assert!(filter.is_within_size_constraints());
+        }
+        let filter = BloomFilter::new(1_000_000, 0.0001, 0, BLOOM_UPDATE_NONE);
+        assert_eq!(filter.data.len(), MAX_BLOOM_FILTER_SIZE);
+        assert!(filter.hash_funcs <= MAX_HASH_FUNCS);
+    }
+
+    fn empty_filter(flags: u8) -> BloomFilter {
+        BloomFilter {
+            data: vec![0; 128],
+            hash_funcs: 10,
+            tweak: 7,
+            flags,
+        }
+    }
+
+    fn transaction(txid: [u8; 32], vout: u32, script_sig: Vec<u8>) -> Transaction {
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x42; 20]);
+        let mut p2pk = vec![33, 0x02];
+        p2pk.extend([0x11; 32]);
+        p2pk.push(0xac);
+        let amount = Amount::from_sat(1000);
+        let mut tx = spend(&[OutPoint::new(txid, vout)], &[amount, amount]);
+        tx.inputs[0].script_sig = Script::of_bytes(script_sig);
+        tx.outputs[0].script_pubkey = Script::of_bytes(p2wpkh);
+        tx.outputs[1].script_pubkey = Script::of_bytes(p2pk);
+        tx
+    }
+
+    #[test]
+    pub fn test_is_relevant_and_update() {
+        let tx = transaction([1; 32], 0, vec![]);
+        let txid = tx.txid();
+        let mut key = vec![0x02];
+        key.extend([0x11; 32]);
+
+        let mut filter = empty_filter(BLOOM_UPDATE_NONE);
+        assert!(!filter.is_relevant_and_update(&tx));
+        filter.insert(&[0x42; 20]);
+        assert!(filter.is_relevant_and_update(&tx));
+        assert!(!filter.contains_outpoint(&OutPoint::new(txid, 0)));
+
+        let mut filter = empty_filter(BLOOM_UPDATE_ALL);
+        filter.insert(&[0x42; 20]);
+        assert!(filter.is_relevant_and_update(&tx));
+        assert!(filter.contains_outpoint(&OutPoint::new(txid, 0)));
+        // The transaction spending the output now matches
+        assert!(filter.is_relevant_and_update(&transaction(txid, 0, vec![])));
+
+        // Only the outpoints of pay-to-pubkey outputs are inserted
+        let mut filter = empty_filter(BLOOM_UPDATE_P2PUBKEY_ONLY);
+        filter.insert(&[0x42; 20]);
+        filter.insert(&key);
+        assert!(filter.is_relevant_and_update(&tx));
+        assert!(!filter.contains_outpoint(&OutPoint::new(txid, 0)));
+        assert!(filter.contains_outpoint(&OutPoint::new(txid, 1)));
+
+        // Matched by the transaction ID or a push of the input script
+        let mut filter = empty_filter(BLOOM_UPDATE_NONE);
+        filter.insert(&txid);
+        assert!(filter.is_relevant_and_update(&tx));
+        let mut filter = empty_filter(BLOOM_UPDATE_NONE);
+        filter.insert(&[0x33; 33]);
+        let mut script_sig = vec![33];
+        script_sig.extend([0x33; 33]);
+        assert!(filter.is_relevant_and_update(&transaction([2; 32], 0, script_sig)));
+    }
+
+    #[test]
+    pub fn test_filter_without_data() {
+        let mut filter = BloomFilter {
+            data: vec![],
+            hash_funcs: 0,
+            tweak: 0,
+            flags: BLOOM_UPDATE_ALL,
+        };
+        filter.insert(&[0x42; 20]);
+        assert!(filter.data.is_empty());
+        assert!(filter.contains(&[0x42; 20]));
+        assert!(filter.contains(&[]));
+        assert!(filter.is_relevant_and_update(&transaction([1; 32], 0, vec![])));
+        let filter: BloomFilter = encode::deserialize(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(filter.contains_outpoint(&OutPoint::new([1; 32], 0)));
+    }
+
+    #[test]
+    pub fn test_p2pk_or_multisig() {
+        let mut key = vec![0x02];
+        key.extend([0x11; 32]);
+        let mut p2pk = vec![33];
+        p2pk.extend(&key);
+        p2pk.push(0xac);
+        assert!(is_p2pk_or_multisig(&p2pk));
+        p2pk[1] = 0x04;
+        assert!(!is_p2pk_or_multisig(&p2pk));
+
+        let mut multisig = vec![0x51];
+        for _ in 0..2 {
+            multisig.push(33);
+            multisig.extend(&key);
+        }
+        multisig.extend([0x52, 0xae]);
+        assert!(is_p2pk_or_multisig(&multisig));
+        multisig[0] = 0x53;
+        assert!(!is_p2pk_or_multisig(&multisig));
+        assert!(!is_p2pk_or_multisig(&[0x51, 0x51, 0xae]));
+    }
+}
@@ -0,0 +1,342 @@
+//! Verification of deposits to the Bitcoin to Mina bridge.
+//!
+//! A deposit is a Bitcoin transaction paying the bridge script. To settle it on
+//! Mina, a [DepositProof] shows that the transaction is included in a block
+//! which is buried under enough confirmations, in a chain of headers verified
+//! from a trusted checkpoint. The verification only requires `alloc`, so it can
+//! run in the zkVM guest.
+
+use alloc::vec::Vec;
+
+use crate::amount::Amount;
+use crate::block::BlockHeader;
+use crate::chain::{Checkpoint, HeaderChain, HeaderError};
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::merkle::{MerkleBlock, MerkleBlockError, MerkleBranch};
+use crate::params::ChainParams;
+use crate::script::Script;
+use crate::transaction::Transaction;
+
+/// A proof that a transaction paid the bridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepositProof {
+    /// The deposit transaction.
+    pub transaction: Transaction,
+    /// The index of the output paying the bridge.
+    pub output_index: u32,
+    /// The branch of the transaction ID in the merkle tree of the block.
+    pub merkle_branch: MerkleBranch,
+    /// The height of the block including the transaction.
+    pub block_height: u32,
+    /// The headers following the trusted checkpoint, from the one after it up
+    /// to the last confirmation.
+    pub headers: Vec<BlockHeader>,
+}
+
+/// A verified deposit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deposit {
+    pub txid: [u8; 32],
+    pub output_index: u32,
+    /// The amount paid to the bridge.
+    pub amount: Amount,
+    /// The hash of the block including the transaction.
+    pub block_hash: [u8; 32],
+    /// The number of blocks from the one including the transaction up to the
+    /// tip of the headers, both included.
+    pub confirmations: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositError {
+    /// The header at the given height is invalid.
+    InvalidHeader {
+        height: u32,
+        error: HeaderError,
+    },
+    /// The block height is not in the verified headers.
+    BlockNotInSegment,
+    /// The merkle branch does not lead to the merkle root of the block.
+    MerkleRootMismatch,
+    /// The transaction is 64 bytes long without its witnesses, so that it can
+    /// not be told apart from an inner node of the merkle tree.
+    AmbiguousTransactionSize,
+    /// The transaction has no output at the given index.
+    OutputNotFound,
+    /// The output does not pay the bridge script.
+    ScriptMismatch,
+    NotEnoughConfirmations {
+        required: u32,
+        got: u32,
+    },
+}
+
+impl DepositProof {
+    /// Build a proof from a `merkleblock` matching the transaction, as served
+    /// by nodes supporting bloom filters.
+    pub fn from_merkle_block(
+        transaction: Transaction,
+        output_index: u32,
+        merkle_block: &MerkleBlock,
+        block_height: u32,
+        headers: Vec<BlockHeader>,
+    ) -> Result<Self, MerkleBlockError> {
+        merkle_block.extract_matches()?;
+        let merkle_branch = merkle_block.txn.merkle_branch(&transaction.txid())?;
+        Ok(DepositProof {
+            transaction,
+            output_index,
+            merkle_branch,
+            block_height,
+            headers,
+        })
+    }
+
+    /// Verify the proof against the trusted checkpoint, and return the deposit
+    /// if the transaction pays `bridge_script` and has at least
+    /// `min_confirmations` confirmations.
+    pub fn verify(
+        &self,
+        params: &ChainParams,
+        checkpoint: &Checkpoint,
+        bridge_script: &Script,
+        min_confirmations: u32,
+    ) -> Result<Deposit, DepositError> {
+        let mut chain = HeaderChain::new(params.clone(), checkpoint.clone());
+        for header in &self.headers {
+            chain
+                .push(*header)
+                .map_err(|error| DepositError::InvalidHeader {
+                    height: chain.tip_height() + 1,
+                    error,
+                })?;
+        }
+        // The checkpoint itself is not accepted, as it is not part of the
+        // segment which is verified.
+        if self.block_height <= checkpoint.height {
+            return Err(DepositError::BlockNotInSegment);
+        }
+        let header = chain
+            .header_at(self.block_height)
+            .ok_or(DepositError::BlockNotInSegment)?;
+
+        let mut stripped = self.transaction.clone();
+        stripped.witnesses.clear();
+        if encode::serialize(&stripped).len() == 64 {
+            return Err(DepositError::AmbiguousTransactionSize);
+        }
+        let txid = self.transaction.txid();
+        if self.merkle_branch.compute_root(&txid) != header.merkle_root {
+            return Err(DepositError::MerkleRootMismatch);
+        }
+
+        let output = self
+            .transaction
+            .outputs
+            .get(self.output_index as usize)
+            .ok_or(DepositError::OutputNotFound)?;
+        if output.script_pubkey.to_bytes() != bridge_script.to_bytes() {
+            return Err(DepositError::ScriptMismatch);
+        }
+
+        let confirmations = chain.tip_height() - self.block_height + 1;
+        if confirmations < min_confirmations {
+            return Err(DepositError::NotEnoughConfirmations {
+                required: min_confirmations,
+                got: confirmations,
+            });
+        }
+        Ok(Deposit {
+            txid,
+            output_index: self.output_index,
+            amount: output.amount,
+            block_hash: chain.hash_at(self.block_height).unwrap(),
+            confirmations,
+        })
+    }
+}
+
+impl Encodable for DepositProof {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.transaction.consensus_encode(w)?
+            + self.output_index.consensus_encode(w)?
+            + self.merkle_branch.consensus_encode(w)?
+            + self.block_height.consensus_encode(w)?
+            + encode::encode_vec(&self.headers, w)?)
+    }
+}
+
+impl Decodable for DepositProof {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(DepositProof {
+            transaction: Decodable::consensus_decode(r)?,
+            output_index: Decodable::consensus_decode(r)?,
+            merkle_branch: Decodable::consensus_decode(r)?,
+            block_height: Decodable::consensus_decode(r)?,
+            headers: encode::decode_vec(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::chain::tests::mine_regtest_header;
+    use crate::encode::{deserialize, serialize};
+    use crate::merkle::merkle_root;
+    use crate::transaction::tests::spend;
+    use crate::utxo::OutPoint;
+    use alloc::vec;
+
+    fn bridge_script() -> Script {
+        // A P2WSH output
+        let mut bytes = vec![0x00, 0x20];
+        bytes.extend([0xb7; 32]);
+        Script::of_bytes(bytes)
+    }
+
+    fn transaction(previous: u8, amount: Amount) -> Transaction {
+        let mut change = vec![0x00, 0x14];
+        change.extend([0x42; 20]);
+        let mut tx = spend(
+            &[OutPoint::new([previous; 32], 0)],
+            &[Amount::from_sat(1000), amount],
+        );
+        tx.outputs[0].script_pubkey = Script::of_bytes(change);
+        tx.outputs[1].script_pubkey = bridge_script();
+        tx.witnesses = vec![vec![vec![0x30; 72], vec![0x02; 33]]];
+        tx
+    }
+
+    /// A deposit included in the block 2 of a regtest chain, with the given
+    /// number of confirmations.
+    fn deposit_proof(confirmations: u32) -> (ChainParams, DepositProof) {
+        let params = ChainParams::regtest();
+        let transactions: Vec<Transaction> = (0..3)
+            .map(|i| transaction(i, Amount::from_sat(50_000 + i as u64)))
+            .collect();
+        let txids: Vec<[u8; 32]> = transactions.iter().map(|tx| tx.txid()).collect();
+        let mut headers = vec![mine_regtest_header(&params.genesis_header, [1; 32])];
+        headers.push(mine_regtest_header(
+            &headers[0],
+            merkle_root(&txids).unwrap(),
+        ));
+        for i in 0..confirmations - 1 {
+            headers.push(mine_regtest_header(headers.last().unwrap(), [i as u8; 32]));
+        }
+        let proof = DepositProof {
+            transaction: transactions[2].clone(),
+            output_index: 1,
+            merkle_branch: MerkleBranch::from_hashes(&txids, 2).unwrap(),
+            block_height: 2,
+            headers,
+        };
+        (params, proof)
+    }
+
+    #[test]
+    pub fn test_verify_deposit() {
+        let (params, proof) = deposit_proof(6);
+        let checkpoint = Checkpoint::genesis(&params);
+        let deposit = proof.verify(&params, &checkpoint, &bridge_script(), 6);
+        assert_eq!(
+            deposit,
+            Ok(Deposit {
+                txid: proof.transaction.txid(),
+                output_index: 1,
+                amount: Amount::from_sat(50_002),
+                block_hash: proof.headers[1].block_hash(),
+                confirmations: 6,
+            })
+        );
+        let decoded: DepositProof = deserialize(&serialize(&proof)).unwrap();
+        assert_eq!(
+            decoded.verify(&params, &checkpoint, &bridge_script(), 6),
+            deposit
+        );
+        assert_eq!(
+            proof.verify(&params, &checkpoint, &bridge_script(), 7),
+            Err(DepositError::NotEnoughConfirmations {
+                required: 7,
+                got: 6
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_reject_invalid_deposits() {
+        let (params, proof) = deposit_proof(3);
+        let checkpoint = Checkpoint::genesis(&params);
+        let verify = |proof: &DepositProof| proof.verify(&params, &checkpoint, &bridge_script(), 1);
+
+        let mut tampered = proof.clone();
+        tampered.transaction.outputs[1].amount = Amount::from_sat(50_003);
+        assert_eq!(verify(&tampered), Err(DepositError::MerkleRootMismatch));
+
+        let mut tampered = proof.clone();
+        tampered.merkle_branch.index = 1;
+        assert_eq!(verify(&tampered), Err(DepositError::MerkleRootMismatch));
+
+        let mut tampered = proof.clone();
+        tampered.block_height = 3;
+        assert_eq!(verify(&tampered), Err(DepositError::MerkleRootMismatch));
+        tampered.block_height = 5;
+        assert_eq!(verify(&tampered), Err(DepositError::BlockNotInSegment));
+        tampered.block_height = 0;
+        assert_eq!(verify(&tampered), Err(DepositError::BlockNotInSegment));
+
+        let mut tampered = proof.clone();
+        tampered.output_index = 0;
+        assert_eq!(verify(&tampered), Err(DepositError::ScriptMismatch));
+        tampered.output_index = 2;
+        assert_eq!(verify(&tampered), Err(DepositError::OutputNotFound));
+
+        let mut tampered = proof.clone();
+        tampered.headers.remove(0);
+        assert_eq!(
+            verify(&tampered),
+            Err(DepositError::InvalidHeader {
+                height: 1,
+                error: HeaderError::PreviousBlockMismatch
+            })
+        );
+
+        // Witnesses are not committed to by the transaction ID
+        let mut stripped = proof.clone();
+        stripped.transaction.witnesses = vec![vec![]];
+        assert!(verify(&stripped).is_ok());
+    }
+
+    #[test]
+    pub fn test_deposit_from_merkle_block() {
+        let (params, proof) = deposit_proof(2);
+        let block = Block {
+            header: proof.headers[1],
+            transactions: (0..3)
+                .map(|i| transaction(i, Amount::from_sat(50_000 + i as u64)))
+                .collect(),
+        };
+        let txid = proof.transaction.txid();
+        let merkle_block = MerkleBlock::from_block(&block, |hash| *hash == txid);
+        let from_merkle_block = DepositProof::from_merkle_block(
+            proof.transaction.clone(),
+            1,
+            &merkle_block,
+            2,
+            proof.headers.clone(),
+        );
+        assert_eq!(from_merkle_block.as_ref(), Ok(&proof));
+        let checkpoint = Checkpoint::genesis(&params);
+        assert!(from_merkle_block
+            .unwrap()
+            .verify(&params, &checkpoint, &bridge_script(), 2)
+            .is_ok());
+
+        let other = MerkleBlock::from_block(&block, |hash| *hash != txid);
+        assert_eq!(
+            DepositProof::from_merkle_block(proof.transaction, 1, &other, 2, proof.headers),
+            Err(MerkleBlockError::NotMatched)
+        );
+    }
+}
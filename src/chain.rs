@@ -0,0 +1,547 @@
+//! Validation of a chain of block headers, starting from a trusted
+//! checkpoint. It is the core of a light client: it only needs the headers to
+//! follow the chain with the most work.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::BlockHeader;
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::params::ChainParams;
+use crate::pow::{block_proof, calculate_next_work_required, check_proof_of_work};
+use crate::uint::U256;
+use crate::utils::sha256d;
+
+/// Number of blocks used to compute the median time past.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// A trusted block from which the headers are verified. Besides the header of
+/// the block, it contains what is needed to verify the next headers without
+/// knowing the previous ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub header: BlockHeader,
+    /// The total work of the chain up to and including the block.
+    pub chain_work: U256,
+    /// The times of the last blocks, up to and including this one, the oldest
+    /// first. At most [MEDIAN_TIME_SPAN] are needed.
+    pub recent_times: Vec<u32>,
+    /// The time of the first block of the difficulty adjustment period
+    /// containing the block.
+    pub epoch_start_time: u32,
+    /// The target of the last block up to this one which was not mined with
+    /// the lowest difficulty, or of the first block of the period. It is
+    /// needed by the networks allowing blocks with the lowest difficulty.
+    pub last_bits: u32,
+}
+
+impl Checkpoint {
+    /// The genesis block of the network.
+    pub fn genesis(params: &ChainParams) -> Self {
+        let header = params.genesis_header;
+        Checkpoint {
+            height: 0,
+            header,
+            chain_work: block_proof(header.compact_target()),
+            recent_times: vec![header.timestamp()],
+            epoch_start_time: header.timestamp(),
+            last_bits: header.compact_target(),
+        }
+    }
+
+    /// The double SHA-256 of the encoded checkpoint, committing to all its
+    /// fields.
+    pub fn commitment(&self) -> [u8; 32] {
+        sha256d(&encode::serialize(self))
+    }
+}
+
+impl Encodable for Checkpoint {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.height.consensus_encode(w)?
+            + self.header.consensus_encode(w)?
+            + self.chain_work.consensus_encode(w)?
+            + encode::encode_vec(&self.recent_times, w)?
+            + self.epoch_start_time.consensus_encode(w)?
+            + self.last_bits.consensus_encode(w)?)
+    }
+}
+
+/// The median time past can not be computed without the time of at least one
+/// block, so a checkpoint without recent times is rejected.
+impl Decodable for Checkpoint {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let height = Decodable::consensus_decode(r)?;
+        let header = Decodable::consensus_decode(r)?;
+        let chain_work = Decodable::consensus_decode(r)?;
+        let recent_times: Vec<u32> = encode::decode_vec(r)?;
+        if recent_times.is_empty() || recent_times.len() > MEDIAN_TIME_SPAN {
+            return Err(Error::ParseFailed("invalid number of recent block times"));
+        }
+        Ok(Checkpoint {
+            height,
+            header,
+            chain_work,
+            recent_times,
+            epoch_start_time: Decodable::consensus_decode(r)?,
+            last_bits: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// Reasons for a header to be rejected, named after the reject reasons of the
+/// reference implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The header does not build on top of the tip (`prev-blk-not-found`).
+    PreviousBlockMismatch,
+    /// The target is not the one required by the difficulty adjustment
+    /// (`bad-diffbits`).
+    BadDifficultyBits { expected: u32, got: u32 },
+    /// The hash does not satisfy the target (`high-hash`).
+    HighHash,
+    /// The time is not after the median time of the previous blocks
+    /// (`time-too-old`).
+    TimeTooOld,
+}
+
+/// A chain of headers verified from a checkpoint.
+///
+/// The headers are checked for their link to the previous block, their proof
+/// of work, the difficulty adjustments and the median time past. The check
+/// against the current time of the reference implementation is not done, as
+/// there is no reliable clock in a zkVM.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    params: ChainParams,
+    checkpoint: Checkpoint,
+    /// The headers after the checkpoint, with their hashes.
+    headers: Vec<(BlockHeader, [u8; 32])>,
+    checkpoint_hash: [u8; 32],
+    chain_work: U256,
+}
+
+impl HeaderChain {
+    pub fn new(params: ChainParams, checkpoint: Checkpoint) -> Self {
+        HeaderChain {
+            params,
+            checkpoint_hash: checkpoint.header.block_hash(),
+            chain_work: checkpoint.chain_work,
+            checkpoint,
+            headers: vec![],
+        }
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn checkpoint(&self) -> &Checkpoint {
+        &self.checkpoint
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        self.checkpoint.height + self.headers.len() as u32
+    }
+
+    pub fn tip_hash(&self) -> [u8; 32] {
+        self.headers
+            .last()
+            .map_or(self.checkpoint_hash, |(_, hash)| *hash)
+    }
+
+    pub fn tip_header(&self) -> &BlockHeader {
+        self.headers
+            .last()
+            .map_or(&self.checkpoint.header, |(header, _)| header)
+    }
+
+    /// The total work of the chain up to the tip.
+    pub fn chain_work(&self) -> U256 {
+        self.chain_work
+    }
+
+    /// The header at the given height, if known.
+    pub fn header_at(&self, height: u32) -> Option<&BlockHeader> {
+        match height.checked_sub(self.checkpoint.height)? {
+            0 => Some(&self.checkpoint.header),
+            i => self.headers.get(i as usize - 1).map(|(header, _)| header),
+        }
+    }
+
+    /// The hash of the block at the given height, if known.
+    pub fn hash_at(&self, height: u32) -> Option<[u8; 32]> {
+        match height.checked_sub(self.checkpoint.height)? {
+            0 => Some(self.checkpoint_hash),
+            i => self.headers.get(i as usize - 1).map(|(_, hash)| *hash),
+        }
+    }
+
+    /// The height of the block with the given hash, if known.
+    pub fn height_of(&self, hash: &[u8; 32]) -> Option<u32> {
+        if *hash == self.checkpoint_hash {
+            return Some(self.checkpoint.height);
+        }
+        self.headers
+            .iter()
+            .position(|(_, h)| h == hash)
+            .map(|i| self.checkpoint.height + i as u32 + 1)
+    }
+
+    /// The times of the last [MEDIAN_TIME_SPAN] blocks, the oldest first.
+    fn recent_times(&self) -> Vec<u32> {
+        let mut times: Vec<u32> = self.checkpoint.recent_times.clone();
+        times.extend(self.headers.iter().map(|(header, _)| header.timestamp()));
+        let start = times.len().saturating_sub(MEDIAN_TIME_SPAN);
+        times.split_off(start)
+    }
+
+    /// The median time of the last [MEDIAN_TIME_SPAN] blocks.
+    pub fn median_time_past(&self) -> u32 {
+        let mut times = self.recent_times();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// The time of the first block of the difficulty adjustment period starting
+    /// at the given height.
+    fn epoch_start_time(&self, height: u32) -> u32 {
+        match self.header_at(height) {
+            Some(header) if height > self.checkpoint.height => header.timestamp(),
+            _ => self.checkpoint.epoch_start_time,
+        }
+    }
+
+    /// The target required for a block built on top of the tip, as
+    /// `GetNextWorkRequired` in the reference implementation.
+    pub fn next_work_required(&self, header: &BlockHeader) -> u32 {
+        let tip = self.tip_header();
+        let height = self.tip_height() + 1;
+        let interval = self.params.difficulty_adjustment_interval();
+        if !height.is_multiple_of(interval) {
+            if self.params.allow_min_difficulty_blocks {
+                // If no block has been found for twice the target spacing, a
+                // block with the lowest difficulty is allowed.
+                let limit = tip
+                    .timestamp()
+                    .saturating_add(self.params.pow_target_spacing.saturating_mul(2));
+                if header.timestamp() > limit {
+                    return self.params.pow_limit_bits;
+                }
+                // Otherwise, use the last target which was not the lowest
+                // difficulty
+                return self.last_bits(height - 1);
+            }
+            return tip.compact_target();
+        }
+        calculate_next_work_required(
+            tip.compact_target(),
+            tip.timestamp(),
+            self.epoch_start_time(height - interval),
+            &self.params,
+        )
+    }
+
+    /// Verify a header and add it on top of the tip.
+    pub fn push(&mut self, header: BlockHeader) -> Result<[u8; 32], HeaderError> {
+        if header.previous_block != self.tip_hash() {
+            return Err(HeaderError::PreviousBlockMismatch);
+        }
+        let hash = header.block_hash();
+        if !check_proof_of_work(&hash, header.compact_target(), &self.params) {
+            return Err(HeaderError::HighHash);
+        }
+        let expected = self.next_work_required(&header);
+        if header.compact_target() != expected {
+            return Err(HeaderError::BadDifficultyBits {
+                expected,
+                got: header.compact_target(),
+            });
+        }
+        if header.timestamp() <= self.median_time_past() {
+            return Err(HeaderError::TimeTooOld);
+        }
+        self.chain_work = self.chain_work + block_proof(header.compact_target());
+        self.headers.push((header, hash));
+        Ok(hash)
+    }
+
+    /// Verify and add the headers one by one, stopping at the first invalid
+    /// one.
+    pub fn extend<I: IntoIterator<Item = BlockHeader>>(
+        &mut self,
+        headers: I,
+    ) -> Result<(), HeaderError> {
+        for header in headers {
+            self.push(header)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the headers above the given height, to follow another branch
+    /// from there. The checkpoint itself can not be removed.
+    pub fn truncate(&mut self, height: u32) {
+        let height = height.max(self.checkpoint.height);
+        let len = (height - self.checkpoint.height) as usize;
+        for (header, _) in self.headers.drain(len.min(self.headers.len())..) {
+            self.chain_work = self.chain_work - block_proof(header.compact_target());
+        }
+    }
+
+    /// Hashes of blocks from the tip back to the checkpoint, to find the last
+    /// common block with a peer. As `GetLocator` in the reference
+    /// implementation, the last ten blocks are included, then the gaps double.
+    pub fn block_locator(&self) -> Vec<[u8; 32]> {
+        let mut hashes = vec![];
+        let mut height = self.tip_height();
+        let mut step = 1;
+        loop {
+            hashes.push(self.hash_at(height).unwrap());
+            if height == self.checkpoint.height {
+                return hashes;
+            }
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step).max(self.checkpoint.height);
+        }
+    }
+
+    /// The target of the last block up to the given height which was not
+    /// mined with the lowest difficulty, or of the first block of the period.
+    /// The walk back stops at the checkpoint, which knows the result.
+    fn last_bits(&self, mut height: u32) -> u32 {
+        let interval = self.params.difficulty_adjustment_interval();
+        while height > self.checkpoint.height
+            && !height.is_multiple_of(interval)
+            && self.header_at(height).unwrap().compact_target() == self.params.pow_limit_bits
+        {
+            height -= 1;
+        }
+        if height == self.checkpoint.height {
+            return self.checkpoint.last_bits;
+        }
+        self.header_at(height).unwrap().compact_target()
+    }
+
+    /// A checkpoint at the tip, from which the verification can be resumed.
+    pub fn tip_checkpoint(&self) -> Checkpoint {
+        let height = self.tip_height();
+        let interval = self.params.difficulty_adjustment_interval();
+        Checkpoint {
+            height,
+            header: *self.tip_header(),
+            chain_work: self.chain_work,
+            recent_times: self.recent_times(),
+            epoch_start_time: self.epoch_start_time(height - height % interval),
+            last_bits: self.last_bits(height),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::encode::{deserialize, serialize};
+
+    // Headers of the blocks 1 and 2 of the main network
+    const MAINNET_HEADERS: [&str; 2] = [
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+    ];
+
+    /// Mine a regtest header on top of the given one, ten minutes later.
+    pub(crate) fn mine_regtest_header(
+        previous: &BlockHeader,
+        merkle_root: [u8; 32],
+    ) -> BlockHeader {
+        mine_regtest_header_at(previous, merkle_root, previous.timestamp() + 600)
+    }
+
+    pub(crate) fn mine_regtest_header_at(
+        previous: &BlockHeader,
+        merkle_root: [u8; 32],
+        time: u32,
+    ) -> BlockHeader {
+        let params = ChainParams::regtest();
+        let mut header = BlockHeader {
+            version: 0x20000000u32.to_le_bytes(),
+            previous_block: previous.block_hash(),
+            merkle_root,
+            time: time.to_le_bytes(),
+            bits: params.pow_limit_bits.to_le_bytes(),
+            nonce: [0; 4],
+        };
+        while !check_proof_of_work(&header.block_hash(), params.pow_limit_bits, &params) {
+            header.nonce = (u32::from_le_bytes(header.nonce) + 1).to_le_bytes();
+        }
+        header
+    }
+
+    #[test]
+    pub fn test_mainnet_first_headers() {
+        let params = ChainParams::mainnet();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let headers: Vec<BlockHeader> = MAINNET_HEADERS
+            .iter()
+            .map(|h| deserialize(&hex::decode(h).unwrap()).unwrap())
+            .collect();
+        chain.extend(headers.clone()).unwrap();
+        assert_eq!(chain.tip_height(), 2);
+        let mut hash = chain.tip_hash();
+        hash.reverse();
+        assert_eq!(
+            hex::encode(hash),
+            "000000006a625f06636b8bb6ac7b960a8d03705d1ace08b1a19da3fdcc99ddbd"
+        );
+        assert_eq!(chain.chain_work(), U256::from_u64(3 * 0x100010001));
+        // A header can not be added twice
+        assert_eq!(
+            chain.push(headers[1]),
+            Err(HeaderError::PreviousBlockMismatch)
+        );
+    }
+
+    #[test]
+    pub fn test_invalid_headers() {
+        let params = ChainParams::mainnet();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let mut header: BlockHeader =
+            deserialize(&hex::decode(MAINNET_HEADERS[0]).unwrap()).unwrap();
+        header.nonce = [0; 4];
+        assert_eq!(chain.push(header), Err(HeaderError::HighHash));
+
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let genesis = params.genesis_header;
+        let header = mine_regtest_header_at(&genesis, [1; 32], genesis.timestamp());
+        assert_eq!(chain.push(header), Err(HeaderError::TimeTooOld));
+        let mut header = mine_regtest_header(&genesis, [1; 32]);
+        header.bits = 0x1d00ffffu32.to_le_bytes();
+        assert_eq!(chain.push(header), Err(HeaderError::HighHash));
+    }
+
+    #[test]
+    pub fn test_min_difficulty_checkpoint() {
+        // A testnet checkpoint at a block mined with the lowest difficulty,
+        // after blocks of a higher one
+        let params = ChainParams::testnet();
+        let mut header = params.genesis_header;
+        header.time = 1_700_000_000u32.to_le_bytes();
+        let checkpoint = Checkpoint {
+            height: 1000,
+            header,
+            chain_work: U256::from_u64(1 << 40),
+            recent_times: vec![header.timestamp()],
+            epoch_start_time: header.timestamp() - 1000 * 600,
+            last_bits: 0x1b0404cb,
+        };
+        assert_eq!(header.compact_target(), params.pow_limit_bits);
+        let chain = HeaderChain::new(params.clone(), checkpoint.clone());
+        assert_eq!(chain.tip_checkpoint(), checkpoint);
+
+        // The next block uses the difficulty before the checkpoint, unless it
+        // is found more than twenty minutes later
+        let mut next = header;
+        next.previous_block = header.block_hash();
+        next.time = (header.timestamp() + 600).to_le_bytes();
+        assert_eq!(chain.next_work_required(&next), 0x1b0404cb);
+        next.time = (header.timestamp() + 1201).to_le_bytes();
+        assert_eq!(chain.next_work_required(&next), params.pow_limit_bits);
+    }
+
+    #[test]
+    pub fn test_timestamps_near_the_maximum() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let first = mine_regtest_header_at(&params.genesis_header, [1; 32], u32::MAX - 100);
+        chain.push(first).unwrap();
+        let second = mine_regtest_header_at(&first, [2; 32], u32::MAX);
+        assert_eq!(chain.next_work_required(&second), params.pow_limit_bits);
+        chain.push(second).unwrap();
+    }
+
+    #[test]
+    pub fn test_resume_from_checkpoint() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let mut headers = vec![];
+        let mut tip = params.genesis_header;
+        for i in 0..20 {
+            tip = mine_regtest_header(&tip, [i; 32]);
+            headers.push(tip);
+        }
+        chain.extend(headers[..12].iter().copied()).unwrap();
+        let checkpoint = chain.tip_checkpoint();
+        assert_eq!(checkpoint.recent_times.len(), MEDIAN_TIME_SPAN);
+        let checkpoint: Checkpoint = deserialize(&serialize(&checkpoint)).unwrap();
+
+        let mut resumed = HeaderChain::new(params, checkpoint);
+        resumed.extend(headers[12..].iter().copied()).unwrap();
+        chain.extend(headers[12..].iter().copied()).unwrap();
+        assert_eq!(resumed.tip_hash(), chain.tip_hash());
+        assert_eq!(resumed.chain_work(), chain.chain_work());
+        assert_eq!(resumed.median_time_past(), chain.median_time_past());
+        assert_eq!(resumed.height_of(&headers[15].block_hash()), Some(16));
+
+        // The number of recent times must be between 1 and MEDIAN_TIME_SPAN
+        let mut invalid = resumed.checkpoint().clone();
+        invalid.recent_times.clear();
+        assert!(deserialize::<Checkpoint>(&serialize(&invalid)).is_err());
+        invalid.recent_times = vec![0; MEDIAN_TIME_SPAN + 1];
+        assert!(deserialize::<Checkpoint>(&serialize(&invalid)).is_err());
+    }
+
+    #[test]
+    pub fn test_truncate() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let mut tip = params.genesis_header;
+        for i in 0..5 {
+            tip = mine_regtest_header(&tip, [i; 32]);
+            chain.push(tip).unwrap();
+        }
+        let mut fork = chain.clone();
+        fork.truncate(2);
+        assert_eq!(fork.tip_height(), 2);
+        assert_eq!(fork.tip_hash(), chain.hash_at(2).unwrap());
+        let header = mine_regtest_header(fork.tip_header(), [0xff; 32]);
+        fork.push(header).unwrap();
+        assert!(fork.chain_work() < chain.chain_work());
+        fork.truncate(0);
+        assert_eq!(fork.tip_hash(), params.genesis_header.block_hash());
+        assert_eq!(fork.chain_work(), Checkpoint::genesis(&params).chain_work);
+        // Truncating above the tip does nothing
+        chain.truncate(10);
+        assert_eq!(chain.tip_height(), 5);
+    }
+
+    #[test]
+    pub fn test_block_locator() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        assert_eq!(
+            chain.block_locator(),
+            vec![params.genesis_header.block_hash()]
+        );
+        let mut tip = params.genesis_header;
+        for i in 0..30 {
+            tip = mine_regtest_header(&tip, [i; 32]);
+            chain.push(tip).unwrap();
+        }
+        let heights: Vec<u32> = chain
+            .block_locator()
+            .iter()
+            .map(|hash| chain.height_of(hash).unwrap())
+            .collect();
+        assert_eq!(
+            heights,
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+
+        // It stops at the checkpoint
+        let resumed = HeaderChain::new(params, chain.tip_checkpoint());
+        assert_eq!(resumed.block_locator(), vec![tip.block_hash()]);
+    }
+}
@@ -0,0 +1,540 @@
+//! The state of a fully validated chain: the headers, the undo data of the
+//! last blocks connected on top of a checkpoint, and the set of unspent
+//! outputs.
+//!
+//! Like the reference implementation, the state follows the branch with the
+//! most work. Switching to a heavier fork disconnects the blocks down to the
+//! fork point, using their undo data, then connects the blocks of the fork.
+//! The blocks themselves are not kept: the ones to disconnect are asked for,
+//! as the reference implementation reads them from the disk. Subscribers are
+//! notified of each connected and disconnected block, e.g. to maintain an
+//! index.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::block::Block;
+use crate::chain::{Checkpoint, HeaderChain, HeaderError};
+use crate::params::ChainParams;
+use crate::utxo::{BlockUndo, UtxoError, UtxoSet};
+use crate::validation::BlockValidationError;
+
+/// Notified of the changes of the active chain, like `CValidationInterface` in
+/// the reference implementation.
+pub trait ChainSubscriber {
+    /// The block was connected at the given height.
+    fn block_connected(&mut self, _block: &Block, _height: u32) {}
+
+    /// The block at the given height was disconnected. Blocks are
+    /// disconnected from the tip, in the reverse order of their connection.
+    fn block_disconnected(&mut self, _block: &Block, _height: u32) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainStateError<E> {
+    /// The previous block of the first block is not in the active chain, or
+    /// is below the checkpoint.
+    UnknownParent,
+    /// The header at the given height is invalid.
+    Header { height: u32, error: HeaderError },
+    /// The block at the given height is invalid.
+    Block {
+        height: u32,
+        error: BlockValidationError,
+    },
+    /// The set of unspent outputs could not be updated.
+    Utxo(UtxoError<E>),
+    /// The fork point is deeper than the undo data kept, see
+    /// [ChainState::set_max_reorg_depth].
+    ReorgTooDeep,
+    /// The block of the active chain with the given hash, needed to
+    /// disconnect it, was not provided.
+    MissingBlock([u8; 32]),
+}
+
+impl<E: fmt::Display> fmt::Display for ChainStateError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainStateError::UnknownParent => write!(f, "unknown previous block"),
+            ChainStateError::Header { height, error } => {
+                write!(f, "invalid header at height {}: {:?}", height, error)
+            }
+            ChainStateError::Block { height, error } => {
+                write!(f, "invalid block at height {}: {}", height, error)
+            }
+            ChainStateError::Utxo(e) => write!(f, "{}", e),
+            ChainStateError::ReorgTooDeep => write!(f, "reorganization too deep"),
+            ChainStateError::MissingBlock(hash) => {
+                let mut hash = *hash;
+                hash.reverse();
+                write!(f, "missing block {}", hex::encode(hash))
+            }
+        }
+    }
+}
+
+/// The default number of blocks which can be disconnected, as
+/// `MIN_BLOCKS_TO_KEEP` in the reference implementation.
+pub const DEFAULT_MAX_REORG_DEPTH: u32 = 288;
+
+/// A chain of validated blocks from a checkpoint.
+pub struct ChainState<S: UtxoSet> {
+    headers: HeaderChain,
+    utxos: S,
+    /// The undo data of the last blocks, the oldest first.
+    undo: VecDeque<BlockUndo>,
+    max_reorg_depth: u32,
+    subscribers: Vec<Box<dyn ChainSubscriber>>,
+}
+
+impl<S: UtxoSet> ChainState<S> {
+    /// Start from the checkpoint, where `utxos` are the unspent outputs after
+    /// the block of the checkpoint.
+    pub fn new(params: ChainParams, checkpoint: Checkpoint, utxos: S) -> Self {
+        ChainState {
+            headers: HeaderChain::new(params, checkpoint),
+            utxos,
+            undo: VecDeque::new(),
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Keep the undo data of the last `depth` blocks only, so that at most
+    /// `depth` blocks can be disconnected by a reorganization.
+    pub fn set_max_reorg_depth(&mut self, depth: u32) {
+        self.max_reorg_depth = depth;
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let excess = self
+            .undo
+            .len()
+            .saturating_sub(self.max_reorg_depth as usize);
+        self.undo.drain(..excess);
+    }
+
+    pub fn subscribe(&mut self, subscriber: Box<dyn ChainSubscriber>) {
+        self.subscribers.push(subscriber);
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    pub fn utxos(&self) -> &S {
+        &self.utxos
+    }
+
+    pub fn tip_height(&self) -> u32 {
+        self.headers.tip_height()
+    }
+
+    pub fn tip_hash(&self) -> [u8; 32] {
+        self.headers.tip_hash()
+    }
+
+    /// The lowest height from which blocks can be disconnected.
+    fn min_fork_height(&self) -> u32 {
+        self.tip_height() - self.undo.len() as u32
+    }
+
+    /// Validate the block and connect it on top of the tip.
+    pub fn connect_block(&mut self, block: &Block) -> Result<(), ChainStateError<S::Error>> {
+        self.connect(block)?;
+        self.prune();
+        Ok(())
+    }
+
+    /// Connect the block, keeping its undo data even past the maximum depth,
+    /// so that a reorganization can be rolled back.
+    fn connect(&mut self, block: &Block) -> Result<(), ChainStateError<S::Error>> {
+        let height = self.tip_height() + 1;
+        self.headers
+            .push(block.header)
+            .map_err(|error| ChainStateError::Header { height, error })?;
+        if let Err(error) = block.validate(self.headers.params(), height, &self.utxos) {
+            self.headers.truncate(height - 1);
+            return Err(ChainStateError::Block { height, error });
+        }
+        let undo = match self.utxos.connect_block(block, height) {
+            Ok(undo) => undo,
+            Err(e) => {
+                self.headers.truncate(height - 1);
+                return Err(ChainStateError::Utxo(e));
+            }
+        };
+        for subscriber in &mut self.subscribers {
+            subscriber.block_connected(block, height);
+        }
+        self.undo.push_back(undo);
+        Ok(())
+    }
+
+    /// Disconnect the block at the tip, which must be the given one.
+    fn disconnect_tip(&mut self, block: &Block) -> Result<(), ChainStateError<S::Error>> {
+        let Some(undo) = self.undo.back() else {
+            return Err(ChainStateError::ReorgTooDeep);
+        };
+        debug_assert_eq!(block.header.block_hash(), self.tip_hash());
+        self.utxos
+            .disconnect_block(block, undo)
+            .map_err(ChainStateError::Utxo)?;
+        self.undo.pop_back();
+        let height = self.tip_height();
+        self.headers.truncate(height - 1);
+        for subscriber in &mut self.subscribers {
+            subscriber.block_disconnected(block, height);
+        }
+        Ok(())
+    }
+
+    /// Switch to the branch made of the given blocks if it has more work than
+    /// the active chain, and return whether it did. The first block must build
+    /// on a block of the active chain, at most
+    /// [DEFAULT_MAX_REORG_DEPTH] blocks below the tip by default.
+    ///
+    /// The blocks of the active chain above the fork point are needed to
+    /// disconnect them: they are asked to `active_block` by hash.
+    ///
+    /// If a block of the branch is invalid, the blocks of the branch which
+    /// were connected are disconnected, and the previous active chain is
+    /// connected back. The error of the invalid block is returned even if
+    /// this fails, leaving the chain state at some point in between.
+    pub fn reorganize<F>(
+        &mut self,
+        blocks: Vec<Block>,
+        mut active_block: F,
+    ) -> Result<bool, ChainStateError<S::Error>>
+    where
+        F: FnMut(&[u8; 32]) -> Option<Block>,
+    {
+        let Some(first) = blocks.first() else {
+            return Ok(false);
+        };
+        let fork_height = self
+            .headers
+            .height_of(&first.header.previous_block)
+            .ok_or(ChainStateError::UnknownParent)?;
+        if fork_height < self.min_fork_height() {
+            return Err(ChainStateError::ReorgTooDeep);
+        }
+
+        // Check the headers first, to not disconnect anything for a branch
+        // without enough work
+        let mut branch = self.headers.clone();
+        branch.truncate(fork_height);
+        for block in &blocks {
+            branch
+                .push(block.header)
+                .map_err(|error| ChainStateError::Header {
+                    height: branch.tip_height() + 1,
+                    error,
+                })?;
+        }
+        if branch.chain_work() <= self.headers.chain_work() {
+            return Ok(false);
+        }
+
+        // Get all the blocks to disconnect first, to not stop in the middle,
+        // the tip first
+        let mut disconnected = Vec::new();
+        for height in (fork_height + 1..=self.tip_height()).rev() {
+            let hash = self.headers.hash_at(height).unwrap();
+            match active_block(&hash) {
+                Some(block) if block.header.block_hash() == hash => disconnected.push(block),
+                _ => return Err(ChainStateError::MissingBlock(hash)),
+            }
+        }
+        for block in &disconnected {
+            self.disconnect_tip(block)?;
+        }
+        // The undo data are pruned at the end only, as a branch longer than
+        // the maximum depth must still be disconnected if a block is invalid
+        for (i, block) in blocks.iter().enumerate() {
+            if let Err(e) = self.connect(block) {
+                // Go back to the previous active chain, which was valid
+                let _ = self.restore(&blocks[..i], &disconnected);
+                self.prune();
+                return Err(e);
+            }
+        }
+        self.prune();
+        Ok(true)
+    }
+
+    /// Disconnect the `connected` blocks, then connect back the
+    /// `disconnected` ones, given from the tip.
+    fn restore(
+        &mut self,
+        connected: &[Block],
+        disconnected: &[Block],
+    ) -> Result<(), ChainStateError<S::Error>> {
+        for block in connected.iter().rev() {
+            self.disconnect_tip(block)?;
+        }
+        for block in disconnected.iter().rev() {
+            self.connect(block)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::block::BlockHeader;
+    use crate::chain::tests::mine_regtest_header;
+    use crate::merkle::merkle_root;
+    use crate::transaction::tests::coinbase;
+    use crate::utxo::{MemoryUtxoSet, OutPoint, UtxoView};
+    use crate::validation::block_subsidy;
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Event {
+        Connected([u8; 32], u32),
+        Disconnected([u8; 32], u32),
+    }
+
+    struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+    impl ChainSubscriber for Recorder {
+        fn block_connected(&mut self, block: &Block, height: u32) {
+            let event = Event::Connected(block.header.block_hash(), height);
+            self.0.borrow_mut().push(event);
+        }
+
+        fn block_disconnected(&mut self, block: &Block, height: u32) {
+            let event = Event::Disconnected(block.header.block_hash(), height);
+            self.0.borrow_mut().push(event);
+        }
+    }
+
+    /// Mine a block with only a coinbase on top of the given header. The tag
+    /// makes the coinbases of different branches distinct.
+    fn mine_block(previous: &BlockHeader, height: u32, tag: u8, amount: Amount) -> Block {
+        let coinbase = coinbase(height, tag, amount);
+        let merkle_root = merkle_root(&[coinbase.txid()]).unwrap();
+        Block {
+            header: mine_regtest_header(previous, merkle_root),
+            transactions: vec![coinbase],
+        }
+    }
+
+    pub(crate) fn mine_branch(
+        previous: &BlockHeader,
+        height: u32,
+        tag: u8,
+        count: u32,
+    ) -> Vec<Block> {
+        let params = ChainParams::regtest();
+        let mut blocks: Vec<Block> = vec![];
+        for h in height..height + count {
+            let previous = blocks.last().map_or(previous, |block| &block.header);
+            blocks.push(mine_block(previous, h, tag, block_subsidy(h, &params)));
+        }
+        blocks
+    }
+
+    fn coinbase_outpoint(block: &Block) -> OutPoint {
+        OutPoint::new(block.transactions[0].txid(), 0)
+    }
+
+    fn connected(blocks: &[Block], height: u32) -> Vec<Event> {
+        (height..)
+            .zip(blocks)
+            .map(|(h, block)| Event::Connected(block.header.block_hash(), h))
+            .collect()
+    }
+
+    /// Provide the blocks of the active chain from the given ones.
+    fn from(blocks: &[Block]) -> impl FnMut(&[u8; 32]) -> Option<Block> + '_ {
+        |hash| {
+            blocks
+                .iter()
+                .find(|block| block.header.block_hash() == *hash)
+                .cloned()
+        }
+    }
+
+    fn disconnected(blocks: &[Block], height: u32) -> Vec<Event> {
+        let mut events: Vec<Event> = (height..)
+            .zip(blocks)
+            .map(|(h, block)| Event::Disconnected(block.header.block_hash(), h))
+            .collect();
+        events.reverse();
+        events
+    }
+
+    #[test]
+    pub fn test_reorganize() {
+        let params = ChainParams::regtest();
+        let checkpoint = Checkpoint::genesis(&params);
+        let mut state = ChainState::new(params.clone(), checkpoint, MemoryUtxoSet::new());
+        let events = Rc::new(RefCell::new(vec![]));
+        state.subscribe(Box::new(Recorder(events.clone())));
+
+        let main = mine_branch(&params.genesis_header, 1, 0, 3);
+        for block in &main {
+            state.connect_block(block).unwrap();
+        }
+        assert_eq!(state.tip_hash(), main[2].header.block_hash());
+        assert_eq!(state.utxos().len(), 3);
+        assert_eq!(*events.borrow(), connected(&main, 1));
+        events.borrow_mut().clear();
+
+        // A branch with the same work is ignored
+        let fork = mine_branch(&main[0].header, 2, 1, 3);
+        assert_eq!(state.reorganize(fork[..2].to_vec(), from(&main)), Ok(false));
+        assert_eq!(state.tip_hash(), main[2].header.block_hash());
+        assert!(events.borrow().is_empty());
+
+        assert_eq!(
+            state.reorganize(fork.clone(), from(&[])),
+            Err(ChainStateError::MissingBlock(main[2].header.block_hash()))
+        );
+        assert_eq!(state.tip_hash(), main[2].header.block_hash());
+        assert!(events.borrow().is_empty());
+        assert_eq!(state.reorganize(fork.clone(), from(&main)), Ok(true));
+        assert_eq!(state.tip_height(), 4);
+        assert_eq!(state.tip_hash(), fork[2].header.block_hash());
+        assert_eq!(
+            state.headers().hash_at(2),
+            Some(fork[0].header.block_hash())
+        );
+        let mut expected = disconnected(&main[1..], 2);
+        expected.extend(connected(&fork, 2));
+        assert_eq!(*events.borrow(), expected);
+        let utxos = state.utxos();
+        assert_eq!(utxos.len(), 4);
+        assert!(utxos.get_coin(&coinbase_outpoint(&main[0])).is_some());
+        assert!(utxos.get_coin(&coinbase_outpoint(&main[1])).is_none());
+        assert!(utxos.get_coin(&coinbase_outpoint(&fork[2])).is_some());
+
+        // Back to the first branch, extended
+        events.borrow_mut().clear();
+        let mut extended = main[1..].to_vec();
+        extended.extend(mine_branch(&main[2].header, 4, 0, 2));
+        assert_eq!(state.reorganize(extended.clone(), from(&fork)), Ok(true));
+        let mut expected = disconnected(&fork, 2);
+        expected.extend(connected(&extended, 2));
+        assert_eq!(*events.borrow(), expected);
+        assert_eq!(state.tip_height(), 5);
+        assert_eq!(state.utxos().len(), 5);
+    }
+
+    #[test]
+    pub fn test_reorganize_to_invalid_branch() {
+        let params = ChainParams::regtest();
+        let checkpoint = Checkpoint::genesis(&params);
+        let mut state = ChainState::new(params.clone(), checkpoint, MemoryUtxoSet::new());
+        let main = mine_branch(&params.genesis_header, 1, 0, 2);
+        for block in &main {
+            state.connect_block(block).unwrap();
+        }
+        let utxos = state.utxos().clone();
+        let events = Rc::new(RefCell::new(vec![]));
+        state.subscribe(Box::new(Recorder(events.clone())));
+
+        // The last block of the fork claims too much
+        let mut fork = mine_branch(&params.genesis_header, 1, 1, 2);
+        let greedy = mine_block(
+            &fork[1].header,
+            3,
+            1,
+            block_subsidy(3, &params)
+                .checked_add(Amount::ONE_SAT)
+                .unwrap(),
+        );
+        fork.push(greedy);
+        assert_eq!(
+            state.reorganize(fork.clone(), from(&main)),
+            Err(ChainStateError::Block {
+                height: 3,
+                error: BlockValidationError::CoinbaseAmount
+            })
+        );
+        assert_eq!(state.tip_hash(), main[1].header.block_hash());
+        assert_eq!(state.utxos(), &utxos);
+        let mut expected = disconnected(&main, 1);
+        expected.extend(connected(&fork[..2], 1));
+        expected.extend(disconnected(&fork[..2], 1));
+        expected.extend(connected(&main, 1));
+        assert_eq!(*events.borrow(), expected);
+
+        // Blocks which do not build on the active chain
+        assert_eq!(
+            state.reorganize(fork[1..].to_vec(), from(&main)),
+            Err(ChainStateError::UnknownParent)
+        );
+        assert_eq!(
+            state.connect_block(&fork[0]),
+            Err(ChainStateError::Header {
+                height: 3,
+                error: HeaderError::PreviousBlockMismatch
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_max_reorg_depth() {
+        let params = ChainParams::regtest();
+        let checkpoint = Checkpoint::genesis(&params);
+        let mut state = ChainState::new(params.clone(), checkpoint, MemoryUtxoSet::new());
+        state.set_max_reorg_depth(2);
+        let main = mine_branch(&params.genesis_header, 1, 0, 4);
+        for block in &main {
+            state.connect_block(block).unwrap();
+        }
+        assert_eq!(state.undo.len(), 2);
+
+        // The fork point is three blocks below the tip
+        let fork = mine_branch(&main[0].header, 2, 1, 4);
+        assert_eq!(
+            state.reorganize(fork.clone(), from(&main)),
+            Err(ChainStateError::ReorgTooDeep)
+        );
+        assert_eq!(state.tip_hash(), main[3].header.block_hash());
+        // Two blocks below the tip
+        let fork = mine_branch(&main[1].header, 3, 1, 3);
+        assert_eq!(state.reorganize(fork.clone(), from(&main)), Ok(true));
+        assert_eq!(state.tip_hash(), fork[2].header.block_hash());
+        assert_eq!(state.undo.len(), 2);
+
+        // A branch longer than the maximum depth, whose last block is
+        // invalid, is still rolled back
+        let utxos = state.utxos().clone();
+        let mut long = mine_branch(&fork[0].header, 4, 2, 4);
+        let greedy = mine_block(
+            &long[3].header,
+            8,
+            2,
+            block_subsidy(8, &params)
+                .checked_add(Amount::ONE_SAT)
+                .unwrap(),
+        );
+        long.push(greedy);
+        let active: Vec<Block> = main[..2].iter().chain(&fork).cloned().collect();
+        assert_eq!(
+            state.reorganize(long.clone(), from(&active)),
+            Err(ChainStateError::Block {
+                height: 8,
+                error: BlockValidationError::CoinbaseAmount
+            })
+        );
+        assert_eq!(state.tip_hash(), fork[2].header.block_hash());
+        assert_eq!(state.utxos(), &utxos);
+        assert_eq!(state.undo.len(), 2);
+        long.pop();
+        assert_eq!(state.reorganize(long.clone(), from(&active)), Ok(true));
+        assert_eq!(state.tip_hash(), long[3].header.block_hash());
+        assert_eq!(state.undo.len(), 2);
+    }
+}
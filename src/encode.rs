@@ -0,0 +1,253 @@
+//! Consensus encoding of the Bitcoin data structures, i.e. the format used on
+//! the wire and to compute hashes like the transaction ID or the block hash.
+//!
+//! The reader and writer abstractions do not depend on `std::io`, so the
+//! encoding is available without std.
+
+use crate::utils::{VarInt, MAX_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Maximum number of bytes allocated at once when decoding a vector. It avoids
+/// allocating a huge buffer when the announced length is larger than the
+/// available data.
+const MAX_ALLOCATION_CHUNK: usize = 64 * 1024;
+
+/// Errors that can occur while decoding consensus-encoded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The reader does not contain enough bytes.
+    UnexpectedEof,
+    /// Bytes are left once the value has been decoded.
+    TrailingBytes,
+    /// A compact size does not use the smallest encoding of its value.
+    NonMinimalVarInt,
+    /// A length or a number of elements is larger than
+    /// [MAX_SIZE].
+    OversizedLength(u64),
+    /// The decoded data does not represent a valid value.
+    ParseFailed(&'static str),
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "unexpected end of data"),
+            Error::TrailingBytes => write!(f, "data not consumed entirely"),
+            Error::NonMinimalVarInt => write!(f, "non-minimal compact size"),
+            Error::OversizedLength(length) => write!(f, "length {} too large", length),
+            Error::ParseFailed(msg) => write!(f, "parse failed: {}", msg),
+        }
+    }
+}
+
+/// A sink of bytes.
+pub trait Write {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error>;
+}
+
+impl Write for Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// A sink discarding the bytes, to compute the size of an encoding.
+pub(crate) struct Sink;
+
+impl Write for Sink {
+    fn write_all(&mut self, _buf: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A source of bytes.
+pub trait Read {
+    /// Fill `buf` entirely, or fail with [Error::UnexpectedEof].
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error>;
+}
+
+/// Reading from a slice consumes it.
+impl Read for &[u8] {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if self.len() < buf.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+}
+
+/// Data which can be encoded in a consensus-consistent way.
+pub trait Encodable {
+    /// Encode the value into the writer, and return the number of bytes
+    /// written.
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error>;
+}
+
+/// Data which can be decoded from its consensus encoding.
+pub trait Decodable: Sized {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>;
+}
+
+/// Encode a value into a vector of bytes.
+pub fn serialize<T: Encodable + ?Sized>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![];
+    // Writing into a vector never fails
+    value.consensus_encode(&mut bytes).unwrap();
+    bytes
+}
+
+/// Decode a value, failing if the bytes are not consumed entirely.
+pub fn deserialize<T: Decodable>(bytes: &[u8]) -> Result<T, Error> {
+    let mut reader = bytes;
+    let value = T::consensus_decode(&mut reader)?;
+    if !reader.is_empty() {
+        return Err(Error::TrailingBytes);
+    }
+    Ok(value)
+}
+
+macro_rules! impl_int_encodable {
+    ($ty:ty) => {
+        impl Encodable for $ty {
+            fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+                let bytes = self.to_le_bytes();
+                w.write_all(&bytes)?;
+                Ok(bytes.len())
+            }
+        }
+
+        impl Decodable for $ty {
+            fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+                let mut bytes = [0; core::mem::size_of::<$ty>()];
+                r.read_exact(&mut bytes)?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+        }
+    };
+}
+
+impl_int_encodable!(u8);
+impl_int_encodable!(u16);
+impl_int_encodable!(u32);
+impl_int_encodable!(u64);
+impl_int_encodable!(i32);
+impl_int_encodable!(i64);
+
+impl<const N: usize> Encodable for [u8; N] {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        w.write_all(self)?;
+        Ok(N)
+    }
+}
+
+impl<const N: usize> Decodable for [u8; N] {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let mut bytes = [0; N];
+        r.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Byte vectors are prefixed by their length as a compact size.
+impl Encodable for Vec<u8> {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        encode_bytes(self, w)
+    }
+}
+
+impl Decodable for Vec<u8> {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let length = read_length(r)?;
+        let mut bytes = vec![];
+        while bytes.len() < length {
+            let chunk = (length - bytes.len()).min(MAX_ALLOCATION_CHUNK);
+            let start = bytes.len();
+            bytes.resize(start + chunk, 0);
+            r.read_exact(&mut bytes[start..])?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Encode a slice of bytes prefixed by its length.
+pub(crate) fn encode_bytes<W: Write + ?Sized>(bytes: &[u8], w: &mut W) -> Result<usize, Error> {
+    let len = write_length(bytes.len(), w)?;
+    w.write_all(bytes)?;
+    Ok(len + bytes.len())
+}
+
+/// Encode the elements of a slice prefixed by their number.
+pub(crate) fn encode_vec<T: Encodable, W: Write + ?Sized>(
+    values: &[T],
+    w: &mut W,
+) -> Result<usize, Error> {
+    let mut len = write_length(values.len(), w)?;
+    for value in values {
+        len += value.consensus_encode(w)?;
+    }
+    Ok(len)
+}
+
+/// Decode a vector of elements prefixed by their number.
+pub(crate) fn decode_vec<T: Decodable, R: Read + ?Sized>(r: &mut R) -> Result<Vec<T>, Error> {
+    let count = read_length(r)?;
+    // Do not trust the announced number of elements to reserve memory
+    let mut values =
+        Vec::with_capacity(count.min(MAX_ALLOCATION_CHUNK / core::mem::size_of::<T>().max(1)));
+    for _ in 0..count {
+        values.push(T::consensus_decode(r)?);
+    }
+    Ok(values)
+}
+
+/// Write a length or a number of elements as a compact size.
+pub(crate) fn write_length<W: Write + ?Sized>(length: usize, w: &mut W) -> Result<usize, Error> {
+    VarInt(length as u64).consensus_encode(w)
+}
+
+/// Read a length or a number of elements encoded as a compact size, rejecting
+/// values larger than [MAX_SIZE].
+pub(crate) fn read_length<R: Read + ?Sized>(r: &mut R) -> Result<usize, Error> {
+    let VarInt(length) = VarInt::consensus_decode(r)?;
+    if length > MAX_SIZE {
+        return Err(Error::OversizedLength(length));
+    }
+    Ok(length as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_integers_are_little_endian() {
+        assert_eq!(serialize(&0x01020304u32), vec![0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(serialize(&-1i32), vec![0xff; 4]);
+        assert_eq!(deserialize::<u16>(&[0x01, 0x02]), Ok(0x0201));
+        assert_eq!(deserialize::<u64>(&[0x01, 0x02]), Err(Error::UnexpectedEof));
+        assert_eq!(deserialize::<u8>(&[0x01, 0x02]), Err(Error::TrailingBytes));
+    }
+
+    #[test]
+    pub fn test_bytes_are_length_prefixed() {
+        let bytes = vec![0xab; 300];
+        let encoded = serialize(&bytes);
+        assert_eq!(encoded[..3], [0xfd, 0x2c, 0x01]);
+        assert_eq!(encoded.len(), 303);
+        assert_eq!(deserialize::<Vec<u8>>(&encoded), Ok(bytes));
+        // The announced length is larger than the data
+        assert_eq!(
+            deserialize::<Vec<u8>>(&[0xfe, 0xff, 0xff, 0xff, 0x01, 0x01]),
+            Err(Error::UnexpectedEof)
+        );
+        assert_eq!(
+            deserialize::<Vec<u8>>(&[0xfe, 0x01, 0x00, 0x00, 0x02]),
+            Err(Error::OversizedLength(0x02000001))
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Fee rates, to compare the fees of transactions of different sizes.
+
+use core::fmt;
+
+use crate::amount::Amount;
+
+/// The number of weight units of a virtual byte.
+pub const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// A fee rate, stored in satoshis per 1000 weight units (sat/kWU). One sat/vB
+/// is 250 sat/kWU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const ZERO: FeeRate = FeeRate(0);
+    /// The minimum relay fee of the reference implementation, 1 sat/vB.
+    pub const MIN_RELAY: FeeRate = FeeRate(250);
+
+    pub const fn from_sat_per_kwu(sat_per_kwu: u64) -> Self {
+        FeeRate(sat_per_kwu)
+    }
+
+    /// A fee rate in sat/vB, or `None` on overflow.
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> Option<Self> {
+        sat_per_vb
+            .checked_mul(1000 / WITNESS_SCALE_FACTOR)
+            .map(FeeRate)
+    }
+
+    /// The fee rate of paying `fee` for `weight` weight units, rounded down,
+    /// or `None` if the weight is zero.
+    pub fn from_fee_and_weight(fee: Amount, weight: u64) -> Option<Self> {
+        let rate = (fee.to_sat() as u128 * 1000).checked_div(weight as u128)?;
+        Some(FeeRate(u64::try_from(rate).unwrap_or(u64::MAX)))
+    }
+
+    pub const fn to_sat_per_kwu(self) -> u64 {
+        self.0
+    }
+
+    pub fn to_sat_per_vb_floor(self) -> u64 {
+        self.0 / (1000 / WITNESS_SCALE_FACTOR)
+    }
+
+    pub fn to_sat_per_vb_ceil(self) -> u64 {
+        self.0.div_ceil(1000 / WITNESS_SCALE_FACTOR)
+    }
+
+    /// The fee to pay for `weight` weight units at this rate, rounded up, or
+    /// `None` if it is larger than
+    /// [MAX_MONEY](crate::amount::MAX_MONEY).
+    pub fn fee_wu(self, weight: u64) -> Option<Amount> {
+        let fee = (self.0 as u128 * weight as u128).div_ceil(1000);
+        Amount::ZERO.checked_add(Amount::from_sat(u64::try_from(fee).ok()?))
+    }
+
+    /// The fee to pay for `vsize` virtual bytes at this rate.
+    pub fn fee_vb(self, vsize: u64) -> Option<Amount> {
+        self.fee_wu(vsize.checked_mul(WITNESS_SCALE_FACTOR)?)
+    }
+}
+
+/// Written in sat/vB, with up to three decimals.
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A virtual byte is four weight units, so 1 sat/kWU is 4 sat/kvB
+        let sat_per_kvb = self.0 as u128 * WITNESS_SCALE_FACTOR as u128;
+        write!(f, "{}", sat_per_kvb / 1000)?;
+        if !sat_per_kvb.is_multiple_of(1000) {
+            write!(f, ".{:03}", sat_per_kvb % 1000)?;
+        }
+        write!(f, " sat/vB")
+    }
+}
+
+/// Reasons for the fee of a transaction not to be computable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeError {
+    /// The number of spent amounts is not the number of inputs.
+    SpentAmountsMismatch { inputs: usize, amounts: usize },
+    /// The sum of the inputs or of the outputs is larger than
+    /// [MAX_MONEY](crate::amount::MAX_MONEY).
+    ValueOutOfRange,
+    /// The outputs spend more than the inputs.
+    NegativeFee,
+}
+
+impl fmt::Display for FeeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeeError::SpentAmountsMismatch { inputs, amounts } => {
+                write!(f, "{} spent amounts given for {} inputs", amounts, inputs)
+            }
+            FeeError::ValueOutOfRange => write!(f, "value out of range"),
+            FeeError::NegativeFee => write!(f, "outputs larger than inputs"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    pub fn test_fee_rate_conversions() {
+        let rate = FeeRate::from_sat_per_vb(2).unwrap();
+        assert_eq!(rate.to_sat_per_kwu(), 500);
+        assert_eq!(rate.to_string(), "2 sat/vB");
+        assert_eq!(rate.fee_vb(141), Some(Amount::from_sat(282)));
+        assert_eq!(FeeRate::from_sat_per_vb(u64::MAX), None);
+
+        let rate = FeeRate::from_fee_and_weight(Amount::from_sat(1000), 1042).unwrap();
+        assert_eq!(rate.to_sat_per_kwu(), 959);
+        assert_eq!(rate.to_sat_per_vb_floor(), 3);
+        assert_eq!(rate.to_sat_per_vb_ceil(), 4);
+        assert_eq!(rate.to_string(), "3.836 sat/vB");
+        // The fee is rounded up
+        assert_eq!(rate.fee_wu(1042), Some(Amount::from_sat(1000)));
+        assert_eq!(FeeRate::MIN_RELAY.fee_wu(1), Some(Amount::ONE_SAT));
+        assert_eq!(FeeRate::from_fee_and_weight(Amount::ONE_SAT, 0), None);
+        assert_eq!(FeeRate::from_sat_per_kwu(u64::MAX).fee_wu(u64::MAX), None);
+    }
+}
@@ -1,3 +1,10 @@
+use crate::locktime::{LockTime, RelativeLockTime};
+use crate::script::Opcode;
+use crate::transaction::Transaction;
+use alloc::vec::Vec;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
 /// Signature type hash/flags
 #[allow(non_camel_case_types, non_snake_case)]
 pub enum SignatureType {
@@ -12,3 +19,184 @@ pub enum SignatureType {
     SIGHASH_OUTPUT_MASK,
     SIGHASH_INPUT_MASK,
 }
+
+/// The context in which a script is executed, which determines the rules
+/// applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigVersion {
+    /// Legacy scripts, i.e. scriptSig, scriptPubKey and P2SH redeem scripts.
+    Base,
+    /// Witness scripts of segwit version 0 outputs (BIP 141).
+    WitnessV0,
+    /// Scripts of taproot script path spends (BIP 342).
+    Tapscript,
+}
+
+/// Reasons for the execution of a script to fail, named after the errors of
+/// the reference implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ScriptError {
+    /// The script is larger than [MAX_SCRIPT_SIZE](crate::script::MAX_SCRIPT_SIZE).
+    ScriptSize,
+    /// A pushed value is larger than
+    /// [MAX_SCRIPT_ELEMENT_SIZE](crate::script::MAX_SCRIPT_ELEMENT_SIZE).
+    PushSize,
+    /// The script has more than
+    /// [MAX_OPS_PER_SCRIPT](crate::script::MAX_OPS_PER_SCRIPT) operations.
+    OpCount,
+    /// The stacks have more than
+    /// [MAX_STACK_SIZE](crate::script::MAX_STACK_SIZE) elements.
+    StackSize,
+    /// An opcode is invalid, or some bytes can not be decoded.
+    BadOpcode,
+    /// A disabled opcode, like OP_CAT, is used, even in a skipped branch.
+    DisabledOpcode,
+    /// An OP_ELSE or OP_ENDIF has no matching OP_IF, or an OP_IF is not closed.
+    UnbalancedConditional,
+    /// An operation requires more elements than the stack has.
+    InvalidStackOperation,
+    /// OP_FROMALTSTACK is used while the alt stack is empty.
+    InvalidAltstackOperation,
+    /// OP_RETURN was executed.
+    OpReturn,
+    /// The top stack element is false after OP_VERIFY.
+    Verify,
+    /// The values compared by OP_EQUALVERIFY differ.
+    EqualVerify,
+    /// A number used by an operation is larger than allowed.
+    NumberOverflow,
+    /// The lock time of OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY is
+    /// negative.
+    NegativeLockTime,
+    /// The lock time of OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY is
+    /// not satisfied by the spending transaction.
+    UnsatisfiedLockTime,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::ScriptSize => write!(f, "Script is too big"),
+            ScriptError::PushSize => write!(f, "Push value size limit exceeded"),
+            ScriptError::OpCount => write!(f, "Operation limit exceeded"),
+            ScriptError::StackSize => write!(f, "Stack size limit exceeded"),
+            ScriptError::BadOpcode => write!(f, "Opcode missing or not understood"),
+            ScriptError::DisabledOpcode => write!(f, "Attempted to use a disabled opcode"),
+            ScriptError::UnbalancedConditional => write!(f, "Invalid OP_IF construction"),
+            ScriptError::InvalidStackOperation => {
+                write!(f, "Operation not valid with the current stack size")
+            }
+            ScriptError::InvalidAltstackOperation => {
+                write!(f, "Operation not valid with the current altstack size")
+            }
+            ScriptError::OpReturn => write!(f, "OP_RETURN was encountered"),
+            ScriptError::Verify => write!(f, "Script failed an OP_VERIFY operation"),
+            ScriptError::EqualVerify => {
+                write!(f, "Script failed an OP_EQUALVERIFY operation")
+            }
+            ScriptError::NumberOverflow => write!(f, "Script number overflow"),
+            ScriptError::NegativeLockTime => write!(f, "Negative locktime"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "Locktime requirement not satisfied"),
+        }
+    }
+}
+
+/// The checks of a script depending on the transaction spending the output,
+/// like `BaseSignatureChecker` in the reference implementation. The default
+/// implementations fail.
+pub trait SignatureChecker {
+    /// Whether the lock time required by OP_CHECKLOCKTIMEVERIFY is satisfied.
+    fn check_lock_time(&self, _lock_time: LockTime) -> bool {
+        false
+    }
+
+    /// Whether the relative lock time required by OP_CHECKSEQUENCEVERIFY is
+    /// satisfied.
+    fn check_sequence(&self, _lock_time: RelativeLockTime) -> bool {
+        false
+    }
+}
+
+/// A checker for scripts executed without a transaction, failing every check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BaseSignatureChecker;
+
+impl SignatureChecker for BaseSignatureChecker {}
+
+/// A checker for the scripts of an input of a transaction.
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    pub fn new(tx: &'a Transaction, input_index: usize) -> Self {
+        TransactionSignatureChecker { tx, input_index }
+    }
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    /// BIP 65: the lock time of the transaction must be of the same unit and
+    /// at least the required one, and the input must not be final, which would
+    /// disable the lock time of the transaction.
+    fn check_lock_time(&self, lock_time: LockTime) -> bool {
+        let Some(input) = self.tx.inputs.get(self.input_index) else {
+            return false;
+        };
+        lock_time.is_satisfied_by_lock(self.tx.lock_time)
+            && input.sequence.enables_absolute_lock_time()
+    }
+
+    /// BIP 112: the transaction must be of version 2 or more, and the
+    /// relative lock time of the input must be enabled, of the same unit and
+    /// at least the required one.
+    fn check_sequence(&self, lock_time: RelativeLockTime) -> bool {
+        let Some(input) = self.tx.inputs.get(self.input_index) else {
+            return false;
+        };
+        if u32::from_le_bytes(self.tx.version) < 2 {
+            return false;
+        }
+        input
+            .sequence
+            .to_relative_lock_time()
+            .is_some_and(|input_lock| lock_time.is_satisfied_by_lock(input_lock))
+    }
+}
+
+/// The state of the interpreter after a step of the execution of a script.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TraceStep {
+    /// The offset of the term in the script.
+    pub pc: usize,
+    /// The opcode of the term, or `None` for pushed data and undecodable
+    /// bytes.
+    pub opcode: Option<Opcode>,
+    /// Whether the term was executed, i.e. it is not in a skipped branch.
+    pub executed: bool,
+    /// The main stack, the top element last.
+    pub stack: Vec<Vec<u8>>,
+    /// The alt stack, the top element last.
+    pub alt_stack: Vec<Vec<u8>>,
+    /// The conditions of the enclosing OP_IF/OP_NOTIF/OP_ELSE branches, the
+    /// innermost one last.
+    pub exec_stack: Vec<bool>,
+    /// The error making the execution fail at this step, if any. Such a step
+    /// is the last one of the trace.
+    pub error: Option<ScriptError>,
+}
+
+/// The steps of the execution of a script, one per visited term. The trace
+/// only depends on the script and the initial stack, so traces of different
+/// runs can be compared.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExecutionTrace {
+    pub steps: Vec<TraceStep>,
+}
+
+impl ExecutionTrace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
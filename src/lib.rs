@@ -1,6 +1,41 @@
+//! A Bitcoin client without std.
+//!
+//! The crate only requires `alloc`, so that it can be compiled for targets
+//! like `riscv32i-unknown-none-elf` to run inside a zkVM. The `std` feature,
+//! enabled by default, only adds conveniences like debug output and file
+//! storage.
+#![no_std]
+
+extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
 pub mod address;
+pub mod amount;
+#[cfg(feature = "bip324")]
+pub mod bip324;
 pub mod block;
+pub mod blockfile;
+pub mod blockfilter;
+pub mod bloom;
+pub mod bridge;
+pub mod chain;
+pub mod chainstate;
+pub mod encode;
+pub mod fee_rate;
 pub mod interpreter;
+pub mod locktime;
+pub mod merkle;
+#[cfg(feature = "std")]
+pub mod net;
+pub mod p2p;
+pub mod params;
+pub mod peer;
+pub mod pow;
 pub mod script;
 pub mod transaction;
+pub mod uint;
 pub mod utils;
+pub mod utxo;
+pub mod validation;
+pub mod zkvm;
@@ -0,0 +1,359 @@
+//! Absolute lock times (`nLockTime`, BIP 65) and relative lock times encoded
+//! in the sequence of inputs (BIP 68, BIP 112).
+
+use crate::encode::{Decodable, Encodable, Error, Read, Write};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Lock times below this value are block heights, and Unix timestamps above.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// The sequence of an input which disables the lock time of the transaction
+/// and OP_CHECKLOCKTIMEVERIFY.
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+
+/// If set, the sequence is not interpreted as a relative lock time.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+
+/// If set, the relative lock time is in units of 512 seconds, and in blocks
+/// otherwise.
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+
+/// The bits of the sequence holding the value of the relative lock time.
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+
+/// Relative lock times in time are expressed in units of 2^9 = 512 seconds.
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// An absolute lock time, either a block height or a Unix timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockTime {
+    Blocks(u32),
+    Seconds(u32),
+}
+
+impl LockTime {
+    /// No lock time: the transaction can be included in any block.
+    pub const ZERO: LockTime = LockTime::Blocks(0);
+
+    /// A lock time at the given block height, or `None` if it is not below
+    /// [LOCKTIME_THRESHOLD].
+    pub fn from_height(height: u32) -> Option<Self> {
+        (height < LOCKTIME_THRESHOLD).then_some(LockTime::Blocks(height))
+    }
+
+    /// A lock time at the given Unix timestamp, or `None` if it is below
+    /// [LOCKTIME_THRESHOLD].
+    pub fn from_time(time: u32) -> Option<Self> {
+        (time >= LOCKTIME_THRESHOLD).then_some(LockTime::Seconds(time))
+    }
+
+    /// Interpret a lock time as encoded in transactions.
+    pub fn from_consensus(value: u32) -> Self {
+        if value < LOCKTIME_THRESHOLD {
+            LockTime::Blocks(value)
+        } else {
+            LockTime::Seconds(value)
+        }
+    }
+
+    pub fn to_consensus_u32(self) -> u32 {
+        match self {
+            LockTime::Blocks(n) | LockTime::Seconds(n) => n,
+        }
+    }
+
+    pub fn is_block_height(self) -> bool {
+        matches!(self, LockTime::Blocks(_))
+    }
+
+    pub fn is_block_time(self) -> bool {
+        matches!(self, LockTime::Seconds(_))
+    }
+
+    /// Whether a transaction with this lock time can be included in a block at
+    /// the given height, with the given median time past. As in `IsFinalTx`
+    /// of the reference implementation, the lock time is the last height or
+    /// time at which the transaction is not valid.
+    pub fn is_satisfied_by(self, height: u32, median_time_past: u32) -> bool {
+        match self {
+            LockTime::Blocks(n) => n < height,
+            LockTime::Seconds(n) => n < median_time_past,
+        }
+    }
+
+    pub fn is_same_unit(self, other: LockTime) -> bool {
+        matches!(
+            (self, other),
+            (LockTime::Blocks(_), LockTime::Blocks(_))
+                | (LockTime::Seconds(_), LockTime::Seconds(_))
+        )
+    }
+
+    /// Whether a transaction with the lock time `other` satisfies this lock
+    /// time, as required by OP_CHECKLOCKTIMEVERIFY.
+    pub fn is_satisfied_by_lock(self, other: LockTime) -> bool {
+        self.is_same_unit(other) && self.to_consensus_u32() <= other.to_consensus_u32()
+    }
+}
+
+impl Default for LockTime {
+    fn default() -> Self {
+        LockTime::ZERO
+    }
+}
+
+impl Encodable for LockTime {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        self.to_consensus_u32().consensus_encode(w)
+    }
+}
+
+impl Decodable for LockTime {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(LockTime::from_consensus(u32::consensus_decode(r)?))
+    }
+}
+
+/// Serialized as its consensus value.
+impl Serialize for LockTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u32(self.to_consensus_u32())
+    }
+}
+
+impl<'de> Deserialize<'de> for LockTime {
+    fn deserialize<D>(deserializer: D) -> Result<LockTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(LockTime::from_consensus(u32::deserialize(deserializer)?))
+    }
+}
+
+/// The sequence of a transaction input. Besides disabling the lock time of the
+/// transaction when all inputs are final, it signals replaceability (BIP 125)
+/// and encodes a relative lock time (BIP 68).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct Sequence(pub u32);
+
+impl Sequence {
+    pub const ZERO: Sequence = Sequence(0);
+    /// The input is final. If all the inputs are final, the lock time of the
+    /// transaction is disabled.
+    pub const FINAL: Sequence = Sequence(SEQUENCE_FINAL);
+    /// The largest sequence enabling the lock time of the transaction, without
+    /// signaling replaceability.
+    pub const ENABLE_LOCKTIME_NO_RBF: Sequence = Sequence(0xfffffffe);
+    /// The largest sequence signaling replaceability, with no relative lock
+    /// time.
+    pub const ENABLE_RBF_NO_LOCKTIME: Sequence = Sequence(0xfffffffd);
+
+    pub fn from_consensus(value: u32) -> Self {
+        Sequence(value)
+    }
+
+    pub fn to_consensus_u32(self) -> u32 {
+        self.0
+    }
+
+    /// A relative lock time of the given number of blocks.
+    pub fn from_height(blocks: u16) -> Self {
+        Sequence(RelativeLockTime::Blocks(blocks).to_sequence())
+    }
+
+    /// A relative lock time of the given number of intervals of 512 seconds.
+    pub fn from_512_second_intervals(intervals: u16) -> Self {
+        Sequence(RelativeLockTime::Time(intervals).to_sequence())
+    }
+
+    /// A relative lock time of at least the given number of seconds, or
+    /// `None` if it can not be represented.
+    pub fn from_seconds_ceil(seconds: u32) -> Option<Self> {
+        let intervals = seconds.div_ceil(1 << SEQUENCE_LOCKTIME_GRANULARITY);
+        u16::try_from(intervals)
+            .ok()
+            .map(Sequence::from_512_second_intervals)
+    }
+
+    pub fn is_final(self) -> bool {
+        self == Sequence::FINAL
+    }
+
+    /// Whether the input signals that the transaction can be replaced (BIP
+    /// 125).
+    pub fn is_rbf(self) -> bool {
+        self.0 < Sequence::ENABLE_LOCKTIME_NO_RBF.0
+    }
+
+    /// Whether the input enables the lock time of the transaction.
+    pub fn enables_absolute_lock_time(self) -> bool {
+        !self.is_final()
+    }
+
+    /// Whether the disable flag is unset, so that the sequence is a relative
+    /// lock time for transactions of version 2 or more.
+    pub fn is_relative_lock_time(self) -> bool {
+        self.0 & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0
+    }
+
+    pub fn is_height_locked(self) -> bool {
+        self.is_relative_lock_time() && self.0 & SEQUENCE_LOCKTIME_TYPE_FLAG == 0
+    }
+
+    pub fn is_time_locked(self) -> bool {
+        self.is_relative_lock_time() && self.0 & SEQUENCE_LOCKTIME_TYPE_FLAG != 0
+    }
+
+    /// The relative lock time, or `None` if the disable flag is set.
+    pub fn to_relative_lock_time(self) -> Option<RelativeLockTime> {
+        RelativeLockTime::from_sequence(self.0)
+    }
+}
+
+impl Default for Sequence {
+    /// Like the reference implementation, inputs are final by default.
+    fn default() -> Self {
+        Sequence::FINAL
+    }
+}
+
+impl Encodable for Sequence {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        self.0.consensus_encode(w)
+    }
+}
+
+impl Decodable for Sequence {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(Sequence(u32::consensus_decode(r)?))
+    }
+}
+
+/// A relative lock time, either a number of blocks or a number of intervals of
+/// 512 seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RelativeLockTime {
+    Blocks(u16),
+    Time(u16),
+}
+
+impl RelativeLockTime {
+    /// Interpret a sequence as a relative lock time, or `None` if the disable
+    /// flag is set. The bits outside of the type flag and the value are
+    /// ignored.
+    pub fn from_sequence(sequence: u32) -> Option<Self> {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = (sequence & SEQUENCE_LOCKTIME_MASK) as u16;
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLockTime::Time(value))
+        } else {
+            Some(RelativeLockTime::Blocks(value))
+        }
+    }
+
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLockTime::Blocks(n) => n as u32,
+            RelativeLockTime::Time(n) => SEQUENCE_LOCKTIME_TYPE_FLAG | n as u32,
+        }
+    }
+
+    /// The relative lock time in seconds, if it is expressed in time.
+    pub fn seconds(self) -> Option<u32> {
+        match self {
+            RelativeLockTime::Blocks(_) => None,
+            RelativeLockTime::Time(n) => Some((n as u32) << SEQUENCE_LOCKTIME_GRANULARITY),
+        }
+    }
+
+    /// Whether an input with the relative lock time `other` satisfies this
+    /// one, as required by OP_CHECKSEQUENCEVERIFY.
+    pub fn is_satisfied_by_lock(self, other: RelativeLockTime) -> bool {
+        match (self, other) {
+            (RelativeLockTime::Blocks(n), RelativeLockTime::Blocks(m)) => n <= m,
+            (RelativeLockTime::Time(n), RelativeLockTime::Time(m)) => n <= m,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{deserialize, serialize};
+
+    #[test]
+    pub fn test_lock_time_units() {
+        assert_eq!(
+            LockTime::from_consensus(499_999_999),
+            LockTime::Blocks(499_999_999)
+        );
+        assert_eq!(
+            LockTime::from_consensus(LOCKTIME_THRESHOLD),
+            LockTime::Seconds(LOCKTIME_THRESHOLD)
+        );
+        let height = LockTime::from_consensus(100);
+        assert!(height.is_satisfied_by_lock(LockTime::Blocks(100)));
+        assert!(!height.is_satisfied_by_lock(LockTime::Blocks(99)));
+        assert!(!height.is_satisfied_by_lock(LockTime::Seconds(1_600_000_000)));
+        assert!(height.is_satisfied_by(101, 0));
+        assert!(!height.is_satisfied_by(100, u32::MAX));
+        assert_eq!(LockTime::from_height(LOCKTIME_THRESHOLD), None);
+        assert_eq!(
+            LockTime::from_time(1_600_000_000),
+            Some(LockTime::Seconds(1_600_000_000))
+        );
+        for lock_time in [LockTime::ZERO, LockTime::Seconds(1_600_000_000)] {
+            let bytes = serialize(&lock_time);
+            assert_eq!(bytes, lock_time.to_consensus_u32().to_le_bytes());
+            assert_eq!(deserialize::<LockTime>(&bytes), Ok(lock_time));
+        }
+    }
+
+    #[test]
+    pub fn test_sequence() {
+        assert!(Sequence::FINAL.is_final());
+        assert!(!Sequence::FINAL.is_relative_lock_time());
+        assert!(!Sequence::ENABLE_LOCKTIME_NO_RBF.is_rbf());
+        assert!(Sequence::ENABLE_LOCKTIME_NO_RBF.enables_absolute_lock_time());
+        assert!(Sequence::ENABLE_RBF_NO_LOCKTIME.is_rbf());
+        assert!(!Sequence::ENABLE_RBF_NO_LOCKTIME.is_relative_lock_time());
+        let sequence = Sequence::from_height(144);
+        assert!(sequence.is_height_locked() && sequence.is_rbf());
+        assert_eq!(
+            sequence.to_relative_lock_time(),
+            Some(RelativeLockTime::Blocks(144))
+        );
+        let sequence = Sequence::from_seconds_ceil(3600).unwrap();
+        assert!(sequence.is_time_locked());
+        assert_eq!(
+            sequence.to_relative_lock_time(),
+            Some(RelativeLockTime::Time(8))
+        );
+        assert_eq!(Sequence::from_seconds_ceil(512 * 65536), None);
+        let bytes = serialize(&sequence);
+        assert_eq!(bytes, [0x08, 0x00, 0x40, 0x00]);
+        assert_eq!(deserialize::<Sequence>(&bytes), Ok(sequence));
+    }
+
+    #[test]
+    pub fn test_relative_lock_time_from_sequence() {
+        assert_eq!(RelativeLockTime::from_sequence(SEQUENCE_FINAL), None);
+        assert_eq!(
+            RelativeLockTime::from_sequence(10),
+            Some(RelativeLockTime::Blocks(10))
+        );
+        // Bits outside of the type flag and the mask are ignored
+        let lock = RelativeLockTime::from_sequence(0x0040_0002 | 1 << 20).unwrap();
+        assert_eq!(lock, RelativeLockTime::Time(2));
+        assert_eq!(lock.seconds(), Some(1024));
+        assert_eq!(lock.to_sequence(), 0x0040_0002);
+        assert!(lock.is_satisfied_by_lock(RelativeLockTime::Time(3)));
+        assert!(!lock.is_satisfied_by_lock(RelativeLockTime::Blocks(3)));
+    }
+}
@@ -0,0 +1,550 @@
+//! Merkle trees of transactions, committed to by the `merkle_root` field of
+//! block headers.
+
+use crate::block::{Block, BlockHeader};
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::utils::sha256d;
+use crate::validation::MAX_BLOCK_WEIGHT;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// The weight of the smallest transaction, bounding the number of
+/// transactions of a block, as `MIN_TRANSACTION_WEIGHT` in the reference
+/// implementation.
+const MIN_TRANSACTION_WEIGHT: u64 = 4 * 60;
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(left);
+    bytes[32..].copy_from_slice(right);
+    sha256d(&bytes)
+}
+
+/// Compute the next level of the tree. When the number of nodes is odd, the
+/// last one is paired with itself.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// Compute the merkle root of the given hashes, or `None` if there is none.
+///
+/// Like in the reference implementation, the duplication of the last node of
+/// odd levels makes different lists share the same root (CVE-2012-2459).
+pub fn merkle_root(hashes: &[[u8; 32]]) -> Option<[u8; 32]> {
+    merkle_root_with_mutation(hashes).map(|(root, _)| root)
+}
+
+/// Compute the merkle root of the given hashes, and whether two identical
+/// hashes are paired at some level. In that case, another list of hashes with
+/// duplicates removed has the same root, so a block with this list of
+/// transactions must be rejected as mutated, as `ComputeMerkleRoot` in the
+/// reference implementation does.
+pub fn merkle_root_with_mutation(hashes: &[[u8; 32]]) -> Option<([u8; 32], bool)> {
+    if hashes.is_empty() {
+        return None;
+    }
+    let mut mutated = false;
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        mutated |= level.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        level = next_level(&level);
+    }
+    Some((level[0], mutated))
+}
+
+/// A proof that a hash is a leaf of a merkle tree: the siblings of the nodes on
+/// the path from the leaf to the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBranch {
+    /// The position of the leaf in the tree.
+    pub index: u32,
+    /// The siblings, starting from the one of the leaf.
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl MerkleBranch {
+    /// Build the branch of the leaf at the given index, or `None` if it is out
+    /// of bounds.
+    pub fn from_hashes(hashes: &[[u8; 32]], index: u32) -> Option<Self> {
+        if index as usize >= hashes.len() {
+            return None;
+        }
+        let mut branch = Vec::new();
+        let mut level = hashes.to_vec();
+        let mut position = index as usize;
+        while level.len() > 1 {
+            let sibling = (position ^ 1).min(level.len() - 1);
+            branch.push(level[sibling]);
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(MerkleBranch {
+            index,
+            hashes: branch,
+        })
+    }
+
+    /// The root of the tree if `leaf` is at the position of the branch.
+    pub fn compute_root(&self, leaf: &[u8; 32]) -> [u8; 32] {
+        let mut node = *leaf;
+        for (depth, sibling) in self.hashes.iter().enumerate() {
+            node = if (self.index >> depth) & 1 == 0 {
+                hash_pair(&node, sibling)
+            } else {
+                hash_pair(sibling, &node)
+            };
+        }
+        node
+    }
+}
+
+impl Encodable for MerkleBranch {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.index.consensus_encode(w)? + encode::encode_vec(&self.hashes, w)?)
+    }
+}
+
+impl Decodable for MerkleBranch {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(MerkleBranch {
+            index: Decodable::consensus_decode(r)?,
+            hashes: encode::decode_vec(r)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkleBlockError {
+    /// The tree has no transactions, or more than a block can contain.
+    InvalidTransactionCount(u32),
+    /// There are more hashes than transactions.
+    TooManyHashes,
+    /// The flags or the hashes are exhausted before the end of the tree.
+    Truncated,
+    /// Flags or hashes are left after the end of the tree.
+    UnusedData,
+    /// Two identical children are hashed together, which could prove the
+    /// inclusion of duplicated transactions (CVE-2012-2459).
+    IdenticalChildren,
+    /// The root of the tree is not the merkle root of the header.
+    MerkleRootMismatch,
+    /// The transaction is not among the matches of the tree.
+    NotMatched,
+}
+
+impl core::fmt::Display for MerkleBlockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MerkleBlockError::InvalidTransactionCount(count) => {
+                write!(f, "invalid number of transactions {}", count)
+            }
+            MerkleBlockError::TooManyHashes => write!(f, "more hashes than transactions"),
+            MerkleBlockError::Truncated => write!(f, "truncated partial merkle tree"),
+            MerkleBlockError::UnusedData => write!(f, "unused flags or hashes"),
+            MerkleBlockError::IdenticalChildren => write!(f, "identical children"),
+            MerkleBlockError::MerkleRootMismatch => write!(f, "merkle root mismatch"),
+            MerkleBlockError::NotMatched => write!(f, "transaction not matched"),
+        }
+    }
+}
+
+/// The part of the merkle tree of a block needed to prove the inclusion of
+/// some of its transactions, as sent in `merkleblock` messages (BIP 37).
+///
+/// The tree is walked depth first. For each node, a flag tells whether it is
+/// the ancestor of a matched transaction. The hashes of the nodes which are
+/// not, and of the matched transactions, are given, from which the others are
+/// computed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<bool>,
+}
+
+/// The transactions whose inclusion is proven by a [PartialMerkleTree].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Matches {
+    /// The root of the tree.
+    pub root: [u8; 32],
+    /// The matched transaction IDs, with their index in the block.
+    pub txids: Vec<(u32, [u8; 32])>,
+}
+
+/// The matches found while walking a partial merkle tree.
+struct Extraction {
+    matches: Matches,
+    /// The hashes of the visited nodes, by height and position.
+    nodes: BTreeMap<(u32, u32), [u8; 32]>,
+}
+
+impl PartialMerkleTree {
+    /// Build the tree proving the inclusion of the transactions whose match
+    /// flag is set, given as pairs of the transaction ID and the flag.
+    pub fn from_txids<I: IntoIterator<Item = ([u8; 32], bool)>>(txids: I) -> Self {
+        let (txids, matches): (Vec<[u8; 32]>, Vec<bool>) = txids.into_iter().unzip();
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        if !txids.is_empty() {
+            tree.build(tree.height(), 0, &txids, &matches);
+        }
+        // As when decoded, the flags are padded to full bytes
+        tree.flags.resize(tree.flags.len().div_ceil(8) * 8, false);
+        tree
+    }
+
+    /// The number of nodes at the given height, the leaves being at height 0.
+    fn width(&self, height: u32) -> u32 {
+        ((self.total_transactions as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    /// The height of the root.
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn compute_hash(&self, height: u32, position: u32, txids: &[[u8; 32]]) -> [u8; 32] {
+        if height == 0 {
+            return txids[position as usize];
+        }
+        let left = self.compute_hash(height - 1, position * 2, txids);
+        let right = if position * 2 + 1 < self.width(height - 1) {
+            self.compute_hash(height - 1, position * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, position: u32, txids: &[[u8; 32]], matches: &[bool]) {
+        let start = (position as usize) << height;
+        let end = ((position as usize + 1) << height).min(txids.len());
+        let parent_of_match = matches[start..end].iter().any(|m| *m);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.compute_hash(height, position, txids);
+            self.hashes.push(hash);
+        } else {
+            self.build(height - 1, position * 2, txids, matches);
+            if position * 2 + 1 < self.width(height - 1) {
+                self.build(height - 1, position * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    fn extract(&self) -> Result<Extraction, MerkleBlockError> {
+        let max_transactions = MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT;
+        if self.total_transactions == 0 || self.total_transactions as u64 > max_transactions {
+            return Err(MerkleBlockError::InvalidTransactionCount(
+                self.total_transactions,
+            ));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(MerkleBlockError::TooManyHashes);
+        }
+        let mut extraction = Extraction {
+            matches: Matches {
+                root: [0; 32],
+                txids: Vec::new(),
+            },
+            nodes: BTreeMap::new(),
+        };
+        let mut used = (0, 0);
+        extraction.matches.root = self.walk(self.height(), 0, &mut used, &mut extraction)?;
+        // The flags are padded to full bytes
+        let (flags_used, hashes_used) = used;
+        if flags_used.div_ceil(8) != self.flags.len().div_ceil(8)
+            || hashes_used != self.hashes.len()
+        {
+            return Err(MerkleBlockError::UnusedData);
+        }
+        Ok(extraction)
+    }
+
+    /// Compute the hash of a node, counting the flags and hashes used.
+    fn walk(
+        &self,
+        height: u32,
+        position: u32,
+        used: &mut (usize, usize),
+        extraction: &mut Extraction,
+    ) -> Result<[u8; 32], MerkleBlockError> {
+        let parent_of_match = *self.flags.get(used.0).ok_or(MerkleBlockError::Truncated)?;
+        used.0 += 1;
+        let hash = if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(used.1).ok_or(MerkleBlockError::Truncated)?;
+            used.1 += 1;
+            if height == 0 && parent_of_match {
+                extraction.matches.txids.push((position, hash));
+            }
+            hash
+        } else {
+            let left = self.walk(height - 1, position * 2, used, extraction)?;
+            let right = if position * 2 + 1 < self.width(height - 1) {
+                let right = self.walk(height - 1, position * 2 + 1, used, extraction)?;
+                if right == left {
+                    return Err(MerkleBlockError::IdenticalChildren);
+                }
+                right
+            } else {
+                left
+            };
+            hash_pair(&left, &right)
+        };
+        extraction.nodes.insert((height, position), hash);
+        Ok(hash)
+    }
+
+    /// Verify the tree, and return its root with the matched transactions.
+    pub fn extract_matches(&self) -> Result<Matches, MerkleBlockError> {
+        Ok(self.extract()?.matches)
+    }
+
+    /// The branch of a matched transaction, as used by the proofs of deposits
+    /// to the bridge.
+    pub fn merkle_branch(&self, txid: &[u8; 32]) -> Result<MerkleBranch, MerkleBlockError> {
+        let extraction = self.extract()?;
+        let (index, _) = extraction
+            .matches
+            .txids
+            .iter()
+            .find(|(_, hash)| hash == txid)
+            .ok_or(MerkleBlockError::NotMatched)?;
+        // The ancestors of a match are walked, and so are their children
+        let mut hashes = Vec::new();
+        let mut position = *index;
+        for height in 0..self.height() {
+            let sibling = (position ^ 1).min(self.width(height) - 1);
+            hashes.push(extraction.nodes[&(height, sibling)]);
+            position /= 2;
+        }
+        Ok(MerkleBranch {
+            index: *index,
+            hashes,
+        })
+    }
+}
+
+impl Encodable for PartialMerkleTree {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        // The flags are packed, the first one in the lowest bit
+        let mut bytes = alloc::vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, flag) in self.flags.iter().enumerate() {
+            bytes[i / 8] |= (*flag as u8) << (i % 8);
+        }
+        Ok(self.total_transactions.consensus_encode(w)?
+            + encode::encode_vec(&self.hashes, w)?
+            + encode::encode_bytes(&bytes, w)?)
+    }
+}
+
+impl Decodable for PartialMerkleTree {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let total_transactions = Decodable::consensus_decode(r)?;
+        let hashes = encode::decode_vec(r)?;
+        let bytes: Vec<u8> = Decodable::consensus_decode(r)?;
+        let flags = (0..bytes.len() * 8)
+            .map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+            .collect();
+        Ok(PartialMerkleTree {
+            total_transactions,
+            hashes,
+            flags,
+        })
+    }
+}
+
+/// A block header with the proof of inclusion of some of its transactions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub txn: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    /// Prove the inclusion of the transactions of the block for which `matches`
+    /// returns true, given their ID.
+    pub fn from_block<F: FnMut(&[u8; 32]) -> bool>(block: &Block, mut matches: F) -> Self {
+        let txids = block.transactions.iter().map(|tx| {
+            let txid = tx.txid();
+            (txid, matches(&txid))
+        });
+        MerkleBlock {
+            header: block.header,
+            txn: PartialMerkleTree::from_txids(txids),
+        }
+    }
+
+    /// Verify the proof against the merkle root of the header, and return the
+    /// matched transaction IDs with their index in the block.
+    pub fn extract_matches(&self) -> Result<Vec<(u32, [u8; 32])>, MerkleBlockError> {
+        let matches = self.txn.extract_matches()?;
+        if matches.root != self.header.merkle_root {
+            return Err(MerkleBlockError::MerkleRootMismatch);
+        }
+        Ok(matches.txids)
+    }
+}
+
+impl Encodable for MerkleBlock {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.header.consensus_encode(w)? + self.txn.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for MerkleBlock {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(MerkleBlock {
+            header: Decodable::consensus_decode(r)?,
+            txn: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::tests::GENESIS_BLOCK;
+    use crate::encode::{deserialize, serialize};
+    use alloc::vec;
+
+    #[test]
+    pub fn test_genesis_merkle_root() {
+        let bytes = hex::decode(GENESIS_BLOCK).unwrap();
+        let block: Block = deserialize(&bytes).unwrap();
+        let txids = [block.transactions[0].txid()];
+        assert_eq!(merkle_root(&txids), Some(block.header.merkle_root));
+        assert_eq!(merkle_root(&[]), None);
+    }
+
+    #[test]
+    pub fn test_merkle_branches() {
+        let hashes: Vec<[u8; 32]> = (0..5u8).map(|i| [i; 32]).collect();
+        let root = merkle_root(&hashes).unwrap();
+        for (i, hash) in hashes.iter().enumerate() {
+            let branch = MerkleBranch::from_hashes(&hashes, i as u32).unwrap();
+            assert_eq!(branch.hashes.len(), 3);
+            assert_eq!(branch.compute_root(hash), root);
+            assert_eq!(deserialize::<MerkleBranch>(&serialize(&branch)), Ok(branch));
+        }
+        let branch = MerkleBranch::from_hashes(&hashes, 1).unwrap();
+        assert_ne!(branch.compute_root(&hashes[0]), root);
+        assert_eq!(MerkleBranch::from_hashes(&hashes, 5), None);
+    }
+
+    #[test]
+    pub fn test_merkle_root_mutation() {
+        let hashes: Vec<[u8; 32]> = (0..3u8).map(|i| [i; 32]).collect();
+        assert_eq!(
+            merkle_root_with_mutation(&hashes),
+            Some((merkle_root(&hashes).unwrap(), false))
+        );
+        // Duplicating the last hash gives the same root
+        let mut mutated = hashes.clone();
+        mutated.push(hashes[2]);
+        assert_eq!(
+            merkle_root_with_mutation(&mutated),
+            Some((merkle_root(&hashes).unwrap(), true))
+        );
+    }
+
+    #[test]
+    pub fn test_partial_merkle_tree() {
+        for count in 1..=20u8 {
+            let txids: Vec<[u8; 32]> = (0..count).map(|i| [i; 32]).collect();
+            let root = merkle_root(&txids).unwrap();
+            for pattern in [0u32, 1, 0b101, 0xaaaa, 0xfffff, 1 << (count - 1)] {
+                let matches: Vec<bool> = (0..count).map(|i| pattern >> i & 1 == 1).collect();
+                let tree =
+                    PartialMerkleTree::from_txids(txids.iter().copied().zip(matches.clone()));
+                let expected: Vec<(u32, [u8; 32])> = (0..count as u32)
+                    .filter(|i| matches[*i as usize])
+                    .map(|i| (i, txids[i as usize]))
+                    .collect();
+                assert_eq!(
+                    tree.extract_matches(),
+                    Ok(Matches {
+                        root,
+                        txids: expected.clone()
+                    })
+                );
+                let decoded: PartialMerkleTree = deserialize(&serialize(&tree)).unwrap();
+                assert_eq!(decoded, tree);
+                for (index, txid) in expected {
+                    assert_eq!(
+                        tree.merkle_branch(&txid),
+                        Ok(MerkleBranch::from_hashes(&txids, index).unwrap())
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_invalid_partial_merkle_trees() {
+        let txids: Vec<[u8; 32]> = (0..7).map(|i| [i; 32]).collect();
+        let matches = [false, true, false, false, true, false, false];
+        let tree = PartialMerkleTree::from_txids(txids.iter().copied().zip(matches));
+        assert_eq!(
+            tree.merkle_branch(&txids[0]),
+            Err(MerkleBlockError::NotMatched)
+        );
+
+        let mut tampered = tree.clone();
+        tampered.hashes.push([9; 32]);
+        assert_eq!(
+            tampered.extract_matches(),
+            Err(MerkleBlockError::UnusedData)
+        );
+        let mut tampered = tree.clone();
+        tampered.hashes.pop();
+        assert_eq!(tampered.extract_matches(), Err(MerkleBlockError::Truncated));
+        let mut tampered = tree.clone();
+        tampered.flags.extend([false; 8]);
+        assert_eq!(
+            tampered.extract_matches(),
+            Err(MerkleBlockError::UnusedData)
+        );
+        let mut tampered = tree.clone();
+        tampered.total_transactions = 0;
+        assert_eq!(
+            tampered.extract_matches(),
+            Err(MerkleBlockError::InvalidTransactionCount(0))
+        );
+        let mut tampered = tree.clone();
+        tampered.total_transactions = 3;
+        assert_eq!(
+            tampered.extract_matches(),
+            Err(MerkleBlockError::TooManyHashes)
+        );
+
+        // The last transaction duplicated, which has the same root
+        let mut duplicated = txids[..6].to_vec();
+        duplicated.extend([txids[5]; 2]);
+        let tree = PartialMerkleTree::from_txids(duplicated.into_iter().zip([true; 8]));
+        assert_eq!(
+            tree.extract_matches(),
+            Err(MerkleBlockError::IdenticalChildren)
+        );
+
+        let block: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let mut merkle_block = MerkleBlock::from_block(&block, |_| true);
+        let txid = block.transactions[0].txid();
+        assert_eq!(merkle_block.extract_matches(), Ok(vec![(0, txid)]));
+        merkle_block.header.merkle_root = [0; 32];
+        assert_eq!(
+            merkle_block.extract_matches(),
+            Err(MerkleBlockError::MerkleRootMismatch)
+        );
+    }
+}
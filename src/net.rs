@@ -0,0 +1,705 @@
+//! Blocking connection to a node over TCP, driving a [Peer].
+//!
+//! The connection performs the handshake, synchronizes the headers and
+//! downloads blocks, which are returned by an iterator. Requests are sent in
+//! batches of [MAX_BLOCKS_IN_FLIGHT] blocks, as the reference implementation
+//! does.
+//!
+//! With the `bip324` feature, [Connection::connect_v2] encrypts the
+//! connection with the v2 transport, and falls back to the v1 transport for
+//! nodes which do not support it.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[cfg(feature = "bip324")]
+use crate::bip324::{Transport, TransportError};
+use crate::block::Block;
+use crate::chain::HeaderChain;
+use crate::p2p::Inventory;
+use crate::peer::{Peer, PeerConfig, PeerError, PeerEvent};
+use crate::validation::{check_block_commitments, BlockValidationError};
+
+/// The maximum number of blocks requested at once, as
+/// `MAX_BLOCKS_IN_TRANSIT_PER_PEER` in the reference implementation.
+pub const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Peer(PeerError),
+    #[cfg(feature = "bip324")]
+    Transport(TransportError),
+    /// The peer closed the connection.
+    Disconnected,
+    /// The peer does not have the block with the given hash.
+    BlockNotFound([u8; 32]),
+    /// The peer sent a block whose transactions are not the ones committed
+    /// to by its header. The connection is closed.
+    InvalidBlock([u8; 32], BlockValidationError),
+}
+
+impl core::fmt::Display for NetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "{}", e),
+            NetError::Peer(e) => write!(f, "{}", e),
+            #[cfg(feature = "bip324")]
+            NetError::Transport(e) => write!(f, "{}", e),
+            NetError::Disconnected => write!(f, "disconnected by the peer"),
+            NetError::BlockNotFound(hash) => {
+                let mut hash = *hash;
+                hash.reverse();
+                write!(f, "block {} not found", hex::encode(hash))
+            }
+            NetError::InvalidBlock(hash, e) => {
+                let mut hash = *hash;
+                hash.reverse();
+                write!(f, "invalid block {}: {}", hex::encode(hash), e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        NetError::Io(e)
+    }
+}
+
+impl From<PeerError> for NetError {
+    fn from(e: PeerError) -> Self {
+        NetError::Peer(e)
+    }
+}
+
+#[cfg(feature = "bip324")]
+impl From<TransportError> for NetError {
+    fn from(e: TransportError) -> Self {
+        NetError::Transport(e)
+    }
+}
+
+/// An outbound connection to a node.
+pub struct Connection {
+    stream: TcpStream,
+    peer: Peer,
+    /// Requested blocks received before the ones requested first.
+    blocks: BTreeMap<[u8; 32], Block>,
+    /// The v2 transport, if the connection is encrypted.
+    #[cfg(feature = "bip324")]
+    transport: Option<Transport>,
+}
+
+impl Connection {
+    /// Connect to the node and perform the handshake. The headers of `chain`
+    /// are used to synchronize with the node. Connecting, and every read and
+    /// write afterwards, fail with an I/O error after `timeout`, which can be
+    /// changed with [Connection::set_timeout].
+    pub fn connect<A: ToSocketAddrs>(
+        address: A,
+        config: PeerConfig,
+        chain: HeaderChain,
+        timeout: Duration,
+    ) -> Result<Self, NetError> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let stream = open(&addresses, timeout)?;
+        let mut connection = Connection {
+            stream,
+            peer: Peer::new(config, chain),
+            blocks: BTreeMap::new(),
+            #[cfg(feature = "bip324")]
+            transport: None,
+        };
+        connection.handshake()?;
+        Ok(connection)
+    }
+
+    /// Connect to the node with the v2 transport, whose role must be
+    /// [Role::Initiator](crate::bip324::Role::Initiator). If the node closes
+    /// the connection before the keys are exchanged, as nodes without v2
+    /// support do, connect again with the v1 transport. The `timeout` is used
+    /// as in [Connection::connect].
+    #[cfg(feature = "bip324")]
+    pub fn connect_v2<A: ToSocketAddrs>(
+        address: A,
+        config: PeerConfig,
+        chain: HeaderChain,
+        transport: Transport,
+        timeout: Duration,
+    ) -> Result<Self, NetError> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let stream = open(&addresses, timeout)?;
+        let mut connection = Connection {
+            stream,
+            peer: Peer::new(config.clone(), chain.clone()),
+            blocks: BTreeMap::new(),
+            transport: Some(transport),
+        };
+        match connection.handshake() {
+            Ok(()) => Ok(connection),
+            Err(NetError::Disconnected) | Err(NetError::Io(_))
+                if connection.session_id().is_none() =>
+            {
+                Self::connect(&addresses[..], config, chain, timeout)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handshake(&mut self) -> Result<(), NetError> {
+        while !self.peer.is_connected() {
+            self.next_event()?;
+        }
+        Ok(())
+    }
+
+    /// The identifier of the session, if the connection is encrypted.
+    #[cfg(feature = "bip324")]
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        self.transport.as_ref().and_then(Transport::session_id)
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    pub fn chain(&self) -> &HeaderChain {
+        self.peer.chain()
+    }
+
+    pub fn into_chain(self) -> HeaderChain {
+        self.peer.into_chain()
+    }
+
+    /// Fail reads and writes with an I/O error if they do not complete in
+    /// the given duration, or never if `None`.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Send the queued messages, then wait for the next event of the peer.
+    fn next_event(&mut self) -> Result<PeerEvent, NetError> {
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            self.flush()?;
+            if let Some(event) = self.peer.poll_event() {
+                return Ok(event);
+            }
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(NetError::Disconnected);
+            }
+            self.receive(&buffer[..n])?;
+        }
+    }
+
+    /// Send the messages queued by the peer.
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "bip324")]
+        if let Some(transport) = &mut self.transport {
+            while let Some(message) = self.peer.poll_message() {
+                transport.send(&message);
+            }
+            while let Some(bytes) = transport.poll_transmit() {
+                self.stream.write_all(&bytes)?;
+            }
+            return Ok(());
+        }
+        while let Some(bytes) = self.peer.poll_transmit() {
+            self.stream.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, bytes: &[u8]) -> Result<(), NetError> {
+        #[cfg(feature = "bip324")]
+        if let Some(transport) = &mut self.transport {
+            transport.receive(bytes)?;
+            while let Some(message) = transport.poll_message() {
+                self.peer.handle_message(message)?;
+            }
+            return Ok(());
+        }
+        self.peer.receive(bytes)?;
+        Ok(())
+    }
+
+    /// Download the headers of the node, and return the height of the tip.
+    pub fn sync_headers(&mut self) -> Result<u32, NetError> {
+        loop {
+            if let PeerEvent::Synced = self.next_event()? {
+                return Ok(self.chain().tip_height());
+            }
+        }
+    }
+
+    /// Download the blocks with the given hashes, which are returned in the
+    /// same order.
+    pub fn download_blocks(&mut self, hashes: Vec<[u8; 32]>) -> Blocks<'_> {
+        Blocks {
+            connection: self,
+            hashes,
+            next: 0,
+            requested: 0,
+        }
+    }
+
+    /// Download the blocks of the chain from the given height to the tip.
+    pub fn download_chain(&mut self, from_height: u32) -> Blocks<'_> {
+        let chain = self.chain();
+        let hashes = (from_height..=chain.tip_height())
+            .filter_map(|height| chain.hash_at(height))
+            .collect();
+        self.download_blocks(hashes)
+    }
+}
+
+/// Open a TCP stream to the first of the addresses accepting the connection,
+/// with the given timeout for connecting, reading and writing.
+fn open(addresses: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+    for address in addresses {
+        match TcpStream::connect_timeout(address, timeout) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Iterator over downloaded blocks. It stops after the first error.
+pub struct Blocks<'a> {
+    connection: &'a mut Connection,
+    hashes: Vec<[u8; 32]>,
+    /// The index of the next block to return.
+    next: usize,
+    /// The number of blocks requested so far.
+    requested: usize,
+}
+
+impl Blocks<'_> {
+    fn next_block(&mut self) -> Result<Block, NetError> {
+        let hash = self.hashes[self.next];
+        if self.requested == self.next {
+            let end = (self.next + MAX_BLOCKS_IN_FLIGHT).min(self.hashes.len());
+            self.connection
+                .peer
+                .request_blocks(&self.hashes[self.next..end]);
+            self.requested = end;
+        }
+        loop {
+            if let Some(block) = self.connection.blocks.remove(&hash) {
+                return Ok(block);
+            }
+            match self.connection.next_event()? {
+                // Blocks may arrive in another order than requested. The
+                // blocks which were not requested are dropped, so that a
+                // peer can not fill the memory. A peer sending a block which
+                // does not match its header is dropped.
+                PeerEvent::Block(block) => {
+                    let hash = block.header.block_hash();
+                    if !self.hashes[self.next..self.requested].contains(&hash) {
+                        continue;
+                    }
+                    if let Err(e) = check_block_commitments(&block) {
+                        self.connection.blocks.clear();
+                        let _ = self.connection.stream.shutdown(Shutdown::Both);
+                        return Err(NetError::InvalidBlock(hash, e));
+                    }
+                    self.connection.blocks.insert(hash, block);
+                }
+                PeerEvent::NotFound(inventory) => {
+                    let missing = inventory.iter().find_map(|inv| match inv {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => Some(*hash),
+                        _ => None,
+                    });
+                    if let Some(hash) = missing {
+                        return Err(NetError::BlockNotFound(hash));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = Result<Block, NetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.hashes.len() {
+            return None;
+        }
+        let result = self.next_block();
+        self.next = if result.is_ok() {
+            self.next + 1
+        } else {
+            self.hashes.len()
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    #[cfg(feature = "bip324")]
+    use crate::bip324::Role;
+    use crate::block::BlockHeader;
+    use crate::chain::Checkpoint;
+    use crate::chainstate::tests::mine_branch;
+    use crate::chainstate::ChainState;
+    use crate::encode;
+    use crate::p2p::{
+        self, Address, NetworkMessage, VersionMessage, MAX_HEADERS, NODE_NETWORK, NODE_WITNESS,
+        PROTOCOL_VERSION,
+    };
+    use crate::params::{ChainParams, Network};
+    use crate::utxo::MemoryUtxoSet;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::vec;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The replies of a regtest node serving the given blocks.
+    fn replies(message: NetworkMessage, blocks: &[Block]) -> Vec<NetworkMessage> {
+        let genesis = ChainParams::regtest().genesis_header;
+        let mut headers: Vec<BlockHeader> = vec![genesis];
+        headers.extend(blocks.iter().map(|block| block.header));
+        let hashes: Vec<[u8; 32]> = headers.iter().map(|h| h.block_hash()).collect();
+        match message {
+            NetworkMessage::Version(_) => vec![
+                NetworkMessage::Version(VersionMessage {
+                    version: PROTOCOL_VERSION,
+                    services: NODE_NETWORK | NODE_WITNESS,
+                    timestamp: 1_700_000_000,
+                    receiver: Address::default(),
+                    sender: Address::default(),
+                    nonce: 2,
+                    user_agent: "/fake:0.1/".into(),
+                    start_height: blocks.len() as i32,
+                    relay: true,
+                }),
+                NetworkMessage::WtxidRelay,
+                NetworkMessage::Verack,
+                // Checks that the peer answers while syncing
+                NetworkMessage::Ping(9),
+            ],
+            NetworkMessage::GetHeaders(request) => {
+                let start = request
+                    .locator_hashes
+                    .iter()
+                    .find_map(|hash| hashes.iter().position(|h| h == hash))
+                    .map_or(headers.len(), |i| i + 1);
+                let end = (start + MAX_HEADERS).min(headers.len());
+                vec![NetworkMessage::Headers(headers[start..end].to_vec())]
+            }
+            NetworkMessage::GetData(inventory) => inventory
+                .iter()
+                .map(|inv| {
+                    let Inventory::WitnessBlock(hash) = inv else {
+                        panic!("unexpected request");
+                    };
+                    match blocks.iter().find(|b| b.header.block_hash() == *hash) {
+                        Some(block) => NetworkMessage::Block(block.clone()),
+                        None => NetworkMessage::NotFound(vec![*inv]),
+                    }
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Serve the given blocks to a single v1 connection, until it is closed
+    /// or invalid bytes are received.
+    fn serve(listener: TcpListener, blocks: Vec<Block>) {
+        serve_with(listener, blocks, replies)
+    }
+
+    /// Like [serve], with the replies given by `reply`.
+    fn serve_with(
+        listener: TcpListener,
+        blocks: Vec<Block>,
+        reply: fn(NetworkMessage, &[Block]) -> Vec<NetworkMessage>,
+    ) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let message = match p2p::decode_message(Network::Regtest, &received) {
+                Ok(Some((message, size))) => {
+                    received.drain(..size);
+                    message
+                }
+                Ok(None) => {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received.extend(&buffer[..n]),
+                    }
+                    continue;
+                }
+                Err(_) => return,
+            };
+            for reply in reply(message, &blocks) {
+                let bytes = p2p::encode_message(Network::Regtest, &reply);
+                stream.write_all(&bytes).unwrap();
+            }
+        }
+    }
+
+    /// Serve the given blocks to a single v2 connection, until it is closed.
+    #[cfg(feature = "bip324")]
+    fn serve_v2(listener: TcpListener, blocks: Vec<Block>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut transport = Transport::new(
+            Network::Regtest,
+            Role::Responder,
+            [3; 32],
+            [4; 32],
+            vec![5; 20],
+        )
+        .unwrap();
+        let mut buffer = [0; 4096];
+        loop {
+            while let Some(bytes) = transport.poll_transmit() {
+                stream.write_all(&bytes).unwrap();
+            }
+            let n = match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            transport.receive(&buffer[..n]).unwrap();
+            while let Some(message) = transport.poll_message() {
+                for reply in replies(message, &blocks) {
+                    transport.send(&reply);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_download_from_fake_peer() {
+        let params = ChainParams::regtest();
+        let blocks = mine_branch(&params.genesis_header, 1, 0, 40);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = blocks.clone();
+        let server = thread::spawn(move || serve(listener, served));
+
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let mut connection = Connection::connect(address, config, chain, TIMEOUT).unwrap();
+        assert!(connection.peer().features().wtxid_relay);
+        assert_eq!(connection.sync_headers().unwrap(), 40);
+        assert_eq!(
+            connection.chain().tip_hash(),
+            blocks[39].header.block_hash()
+        );
+
+        // The downloaded blocks are validated on top of the genesis block
+        let checkpoint = Checkpoint::genesis(&params);
+        let mut state = ChainState::new(params, checkpoint, MemoryUtxoSet::new());
+        for block in connection.download_chain(1) {
+            state.connect_block(&block.unwrap()).unwrap();
+        }
+        assert_eq!(state.tip_height(), 40);
+        assert_eq!(state.utxos().len(), 40);
+
+        let hashes = vec![blocks[3].header.block_hash(), [7; 32]];
+        let mut downloaded = connection.download_blocks(hashes);
+        // Witnesses are decoded as empty stacks, hence the comparison of the
+        // encodings
+        let block = downloaded.next().unwrap().unwrap();
+        assert_eq!(encode::serialize(&block), encode::serialize(&blocks[3]));
+        assert!(matches!(
+            downloaded.next(),
+            Some(Err(NetError::BlockNotFound([7, ..])))
+        ));
+        assert!(downloaded.next().is_none());
+
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_unsolicited_blocks_are_dropped() {
+        let params = ChainParams::regtest();
+        let blocks = mine_branch(&params.genesis_header, 1, 0, 3);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // Every requested block comes with a block of another branch
+        let server = thread::spawn(move || {
+            serve_with(listener, blocks, |message, blocks| {
+                let mut messages = replies(message, blocks);
+                if let Some(NetworkMessage::Block(_)) = messages.first() {
+                    let genesis = ChainParams::regtest().genesis_header;
+                    let other = mine_branch(&genesis, 1, 1, 1).remove(0);
+                    messages.insert(0, NetworkMessage::Block(other));
+                }
+                messages
+            })
+        });
+
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let mut connection = Connection::connect(address, config, chain, TIMEOUT).unwrap();
+        assert_eq!(connection.sync_headers().unwrap(), 3);
+        assert_eq!(
+            connection.download_chain(1).filter(Result::is_ok).count(),
+            3
+        );
+        assert!(connection.blocks.is_empty());
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_tampered_block() {
+        let params = ChainParams::regtest();
+        let blocks = mine_branch(&params.genesis_header, 1, 0, 3);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let tampered = blocks[1].header.block_hash();
+        // The coinbase of the second block pays more than committed to
+        let server = thread::spawn(move || {
+            serve_with(listener, blocks, |message, blocks| {
+                replies(message, blocks)
+                    .into_iter()
+                    .map(|message| match message {
+                        NetworkMessage::Block(mut block)
+                            if block.header.block_hash() == blocks[1].header.block_hash() =>
+                        {
+                            block.transactions[0].outputs[0].amount = Amount::from_sat(1);
+                            NetworkMessage::Block(block)
+                        }
+                        message => message,
+                    })
+                    .collect()
+            })
+        });
+
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let mut connection = Connection::connect(address, config, chain, TIMEOUT).unwrap();
+        assert_eq!(connection.sync_headers().unwrap(), 3);
+        let mut downloaded = connection.download_chain(1);
+        assert!(downloaded.next().unwrap().is_ok());
+        assert!(matches!(
+            downloaded.next(),
+            Some(Err(NetError::InvalidBlock(hash, BlockValidationError::MerkleRootMismatch)))
+                if hash == tampered
+        ));
+        assert!(downloaded.next().is_none());
+        // The peer is dropped
+        assert!(connection.blocks.is_empty());
+        assert!(connection.sync_headers().is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_connection_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The peer closes the connection without answering
+        let server = thread::spawn(move || drop(listener.accept().unwrap()));
+        let params = ChainParams::regtest();
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let result = Connection::connect(address, config, chain, TIMEOUT);
+        assert!(matches!(
+            result,
+            Err(NetError::Disconnected) | Err(NetError::Io(_))
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_silent_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The peer accepts the connection and never answers
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while matches!(stream.read(&mut [0; 256]), Ok(n) if n > 0) {}
+        });
+        let params = ChainParams::regtest();
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let result = Connection::connect(address, config, chain, Duration::from_millis(200));
+        let Err(NetError::Io(e)) = result else {
+            panic!("the handshake should time out");
+        };
+        assert!(matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        server.join().unwrap();
+    }
+
+    #[cfg(feature = "bip324")]
+    #[test]
+    pub fn test_v2_connection() {
+        let params = ChainParams::regtest();
+        let blocks = mine_branch(&params.genesis_header, 1, 0, 5);
+        let chain = HeaderChain::new(params.clone(), Checkpoint::genesis(&params));
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let transport = || {
+            Transport::new(
+                Network::Regtest,
+                Role::Initiator,
+                [1; 32],
+                [2; 32],
+                vec![6; 30],
+            )
+            .unwrap()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = blocks.clone();
+        let server = thread::spawn(move || serve_v2(listener, served));
+        let mut connection =
+            Connection::connect_v2(address, config.clone(), chain.clone(), transport(), TIMEOUT)
+                .unwrap();
+        assert!(connection.session_id().is_some());
+        assert_eq!(connection.sync_headers().unwrap(), 5);
+        assert_eq!(
+            connection.download_chain(1).filter(Result::is_ok).count(),
+            5
+        );
+        drop(connection);
+        server.join().unwrap();
+
+        // A node without v2 support closes the connection on the public key,
+        // then the v1 transport is used
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = blocks.clone();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 64]);
+            drop(stream);
+            serve(listener, served)
+        });
+        let mut connection =
+            Connection::connect_v2(address, config, chain, transport(), TIMEOUT).unwrap();
+        assert_eq!(connection.session_id(), None);
+        assert_eq!(connection.sync_headers().unwrap(), 5);
+        drop(connection);
+        server.join().unwrap();
+    }
+}
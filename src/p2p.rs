@@ -0,0 +1,1074 @@
+//! Messages of the peer-to-peer protocol.
+//!
+//! Each message is framed by a header containing the magic of the network, the
+//! command naming the message, the size of the payload and its checksum. The
+//! payloads reuse the consensus encoding, so that blocks and transactions are
+//! sent as they are hashed.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::block::{Block, BlockHeader};
+use crate::blockfilter;
+use crate::bloom::{BloomFilter, MAX_FILTER_ADD_SIZE};
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::merkle::MerkleBlock;
+use crate::params::Network;
+use crate::transaction::Transaction;
+use crate::utils::{sha256d, VarInt};
+
+/// The version of the protocol announced to the peers, which supports
+/// `wtxidrelay` (BIP 339).
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// The largest payload accepted, as `MAX_PROTOCOL_MESSAGE_LENGTH` in the
+/// reference implementation.
+pub const MAX_MESSAGE_SIZE: usize = 4_000_000;
+
+/// The maximum number of headers in a `headers` message.
+pub const MAX_HEADERS: usize = 2000;
+
+/// The maximum number of filters requested by a `getcfilters` message.
+pub const MAX_CFILTERS: u32 = 1000;
+
+/// The maximum number of filter hashes in a `cfheaders` message.
+pub const MAX_CFHEADERS: usize = 2000;
+
+/// The maximum number of entries in an `inv`, `getdata` or `notfound` message.
+pub const MAX_INV_SIZE: usize = 50_000;
+
+/// The maximum number of addresses in an `addr` or `addrv2` message.
+pub const MAX_ADDR_SIZE: usize = 1000;
+
+/// The maximum size of an address in an `addrv2` message (BIP 155).
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+/// The maximum size of the user agent of a `version` message.
+pub const MAX_USER_AGENT_SIZE: usize = 256;
+
+/// Service flags, announced in `version` and `addr` messages.
+pub const NODE_NETWORK: u64 = 1 << 0;
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+pub const NODE_P2P_V2: u64 = 1 << 11;
+
+/// The size of the header framing a message.
+pub const HEADER_SIZE: usize = 24;
+
+/// The address of a node, with the services it offers. IPv4 addresses are
+/// mapped into IPv6.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Address {
+    pub services: u64,
+    pub ip: [u8; 16],
+    pub port: u16,
+}
+
+impl Address {
+    pub fn from_ipv4(services: u64, ip: [u8; 4], port: u16) -> Self {
+        let mut mapped = [0; 16];
+        mapped[10] = 0xff;
+        mapped[11] = 0xff;
+        mapped[12..].copy_from_slice(&ip);
+        Address {
+            services,
+            ip: mapped,
+            port,
+        }
+    }
+}
+
+/// The port is big-endian, unlike the other integers.
+impl Encodable for Address {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.services.consensus_encode(w)?
+            + self.ip.consensus_encode(w)?
+            + self.port.to_be_bytes().consensus_encode(w)?)
+    }
+}
+
+impl Decodable for Address {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(Address {
+            services: Decodable::consensus_decode(r)?,
+            ip: Decodable::consensus_decode(r)?,
+            port: u16::from_be_bytes(Decodable::consensus_decode(r)?),
+        })
+    }
+}
+
+/// An entry of an `addr` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedAddress {
+    /// The last time the node was seen.
+    pub time: u32,
+    pub address: Address,
+}
+
+impl Encodable for TimestampedAddress {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.time.consensus_encode(w)? + self.address.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for TimestampedAddress {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(TimestampedAddress {
+            time: Decodable::consensus_decode(r)?,
+            address: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// The address of a node in an `addrv2` message (BIP 155), which supports
+/// networks with addresses longer than IPv6 ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkAddress {
+    Ipv4([u8; 4]),
+    Ipv6([u8; 16]),
+    TorV3([u8; 32]),
+    I2p([u8; 32]),
+    Cjdns([u8; 16]),
+    /// An address of a network that is not known, which must be ignored.
+    Unknown {
+        network_id: u8,
+        address: Vec<u8>,
+    },
+}
+
+impl Encodable for NetworkAddress {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        let (network_id, address): (u8, &[u8]) = match self {
+            NetworkAddress::Ipv4(address) => (1, address),
+            NetworkAddress::Ipv6(address) => (2, address),
+            NetworkAddress::TorV3(address) => (4, address),
+            NetworkAddress::I2p(address) => (5, address),
+            NetworkAddress::Cjdns(address) => (6, address),
+            NetworkAddress::Unknown {
+                network_id,
+                address,
+            } => (*network_id, address),
+        };
+        Ok(network_id.consensus_encode(w)? + encode::encode_bytes(address, w)?)
+    }
+}
+
+impl Decodable for NetworkAddress {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let network_id = u8::consensus_decode(r)?;
+        let length = encode::read_length(r)?;
+        if length > MAX_ADDRV2_SIZE {
+            return Err(Error::OversizedLength(length as u64));
+        }
+        let mut address = alloc::vec![0; length];
+        r.read_exact(&mut address)?;
+        let invalid = || Error::ParseFailed("invalid address size for the network");
+        Ok(match network_id {
+            1 => NetworkAddress::Ipv4(address.try_into().map_err(|_| invalid())?),
+            2 => NetworkAddress::Ipv6(address.try_into().map_err(|_| invalid())?),
+            4 => NetworkAddress::TorV3(address.try_into().map_err(|_| invalid())?),
+            5 => NetworkAddress::I2p(address.try_into().map_err(|_| invalid())?),
+            6 => NetworkAddress::Cjdns(address.try_into().map_err(|_| invalid())?),
+            _ => NetworkAddress::Unknown {
+                network_id,
+                address,
+            },
+        })
+    }
+}
+
+/// An entry of an `addrv2` message. The services are encoded as a compact
+/// size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressV2 {
+    pub time: u32,
+    pub services: u64,
+    pub address: NetworkAddress,
+    pub port: u16,
+}
+
+impl Encodable for AddressV2 {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.time.consensus_encode(w)?
+            + VarInt(self.services).consensus_encode(w)?
+            + self.address.consensus_encode(w)?
+            + self.port.to_be_bytes().consensus_encode(w)?)
+    }
+}
+
+impl Decodable for AddressV2 {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(AddressV2 {
+            time: Decodable::consensus_decode(r)?,
+            services: VarInt::consensus_decode(r)?.0,
+            address: Decodable::consensus_decode(r)?,
+            port: u16::from_be_bytes(Decodable::consensus_decode(r)?),
+        })
+    }
+}
+
+/// The first message sent on a connection, describing the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: u32,
+    pub services: u64,
+    /// The time of the node, in seconds since the Unix epoch.
+    pub timestamp: i64,
+    /// The address of the peer, as seen by the node.
+    pub receiver: Address,
+    /// The address of the node, ignored by the reference implementation.
+    pub sender: Address,
+    /// A random number to detect connections to self.
+    pub nonce: u64,
+    pub user_agent: String,
+    /// The height of the best chain of the node.
+    pub start_height: i32,
+    /// Whether the peer should announce transactions (BIP 37).
+    pub relay: bool,
+}
+
+/// The relay flag can be omitted, in which case it is true.
+impl Encodable for VersionMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.version.consensus_encode(w)?
+            + self.services.consensus_encode(w)?
+            + self.timestamp.consensus_encode(w)?
+            + self.receiver.consensus_encode(w)?
+            + self.sender.consensus_encode(w)?
+            + self.nonce.consensus_encode(w)?
+            + encode::encode_bytes(self.user_agent.as_bytes(), w)?
+            + self.start_height.consensus_encode(w)?
+            + (self.relay as u8).consensus_encode(w)?)
+    }
+}
+
+impl Decodable for VersionMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let version = Decodable::consensus_decode(r)?;
+        let services = Decodable::consensus_decode(r)?;
+        let timestamp = Decodable::consensus_decode(r)?;
+        let receiver = Decodable::consensus_decode(r)?;
+        let sender = Decodable::consensus_decode(r)?;
+        let nonce = Decodable::consensus_decode(r)?;
+        let user_agent = Vec::<u8>::consensus_decode(r)?;
+        if user_agent.len() > MAX_USER_AGENT_SIZE {
+            return Err(Error::OversizedLength(user_agent.len() as u64));
+        }
+        let user_agent =
+            String::from_utf8(user_agent).map_err(|_| Error::ParseFailed("invalid user agent"))?;
+        let start_height = Decodable::consensus_decode(r)?;
+        let relay = match u8::consensus_decode(r) {
+            Ok(relay) => relay != 0,
+            Err(Error::UnexpectedEof) => true,
+            Err(e) => return Err(e),
+        };
+        Ok(VersionMessage {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent,
+            start_height,
+            relay,
+        })
+    }
+}
+
+/// The flag added to the type of an inventory to request the witnesses.
+pub const WITNESS_FLAG: u32 = 1 << 30;
+
+/// An object announced by `inv` messages, or requested by `getdata` ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Error,
+    Transaction([u8; 32]),
+    Block([u8; 32]),
+    /// A `merkleblock` message (BIP 37).
+    FilteredBlock([u8; 32]),
+    /// A compact block (BIP 152).
+    CompactBlock([u8; 32]),
+    /// A transaction identified by its wtxid (BIP 339).
+    WitnessTransactionId([u8; 32]),
+    /// A transaction requested with its witnesses.
+    WitnessTransaction([u8; 32]),
+    /// A block requested with the witnesses of its transactions.
+    WitnessBlock([u8; 32]),
+    Unknown {
+        inv_type: u32,
+        hash: [u8; 32],
+    },
+}
+
+impl Encodable for Inventory {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        let (inv_type, hash) = match *self {
+            Inventory::Error => (0, [0; 32]),
+            Inventory::Transaction(hash) => (1, hash),
+            Inventory::Block(hash) => (2, hash),
+            Inventory::FilteredBlock(hash) => (3, hash),
+            Inventory::CompactBlock(hash) => (4, hash),
+            Inventory::WitnessTransactionId(hash) => (5, hash),
+            Inventory::WitnessTransaction(hash) => (WITNESS_FLAG | 1, hash),
+            Inventory::WitnessBlock(hash) => (WITNESS_FLAG | 2, hash),
+            Inventory::Unknown { inv_type, hash } => (inv_type, hash),
+        };
+        Ok(inv_type.consensus_encode(w)? + hash.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for Inventory {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let inv_type = u32::consensus_decode(r)?;
+        let hash = <[u8; 32]>::consensus_decode(r)?;
+        Ok(match inv_type {
+            0 => Inventory::Error,
+            1 => Inventory::Transaction(hash),
+            2 => Inventory::Block(hash),
+            3 => Inventory::FilteredBlock(hash),
+            4 => Inventory::CompactBlock(hash),
+            5 => Inventory::WitnessTransactionId(hash),
+            t if t == WITNESS_FLAG | 1 => Inventory::WitnessTransaction(hash),
+            t if t == WITNESS_FLAG | 2 => Inventory::WitnessBlock(hash),
+            _ => Inventory::Unknown { inv_type, hash },
+        })
+    }
+}
+
+/// A request for the headers following the first hash of the locator that
+/// is in the best chain of the peer, up to the stop hash or [MAX_HEADERS]
+/// headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    /// Hashes of blocks, from the tip of the chain of the node backwards.
+    pub locator_hashes: Vec<[u8; 32]>,
+    /// The hash of the last header requested, or zero for as many as
+    /// possible.
+    pub stop_hash: [u8; 32],
+}
+
+impl Encodable for GetHeadersMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.version.consensus_encode(w)?
+            + encode::encode_vec(&self.locator_hashes, w)?
+            + self.stop_hash.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for GetHeadersMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(GetHeadersMessage {
+            version: Decodable::consensus_decode(r)?,
+            locator_hashes: encode::decode_vec(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// A request for the filters of the blocks from the start height to the
+/// block with the stop hash, at most [MAX_CFILTERS] of them (BIP 157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFiltersMessage {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+impl Encodable for GetCFiltersMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)?
+            + self.start_height.consensus_encode(w)?
+            + self.stop_hash.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for GetCFiltersMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(GetCFiltersMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            start_height: Decodable::consensus_decode(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// The filter of a block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFilterMessage {
+    pub filter_type: u8,
+    pub block_hash: [u8; 32],
+    pub filter: Vec<u8>,
+}
+
+impl Encodable for CFilterMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)?
+            + self.block_hash.consensus_encode(w)?
+            + self.filter.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for CFilterMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(CFilterMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            block_hash: Decodable::consensus_decode(r)?,
+            filter: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// A request for the filter hashes of the blocks from the start height to
+/// the block with the stop hash, at most [MAX_CFHEADERS] of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFHeadersMessage {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+impl Encodable for GetCFHeadersMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)?
+            + self.start_height.consensus_encode(w)?
+            + self.stop_hash.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for GetCFHeadersMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(GetCFHeadersMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            start_height: Decodable::consensus_decode(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// The filter hashes of consecutive blocks, with the filter header of the
+/// block before the first one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFHeadersMessage {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub previous_filter_header: [u8; 32],
+    pub filter_hashes: Vec<[u8; 32]>,
+}
+
+impl CFHeadersMessage {
+    /// The filter headers of the blocks, chained from the previous one.
+    pub fn filter_headers(&self) -> Vec<[u8; 32]> {
+        let mut previous = self.previous_filter_header;
+        self.filter_hashes
+            .iter()
+            .map(|hash| {
+                previous = blockfilter::filter_header(hash, &previous);
+                previous
+            })
+            .collect()
+    }
+}
+
+impl Encodable for CFHeadersMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)?
+            + self.stop_hash.consensus_encode(w)?
+            + self.previous_filter_header.consensus_encode(w)?
+            + encode::encode_vec(&self.filter_hashes, w)?)
+    }
+}
+
+impl Decodable for CFHeadersMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(CFHeadersMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+            previous_filter_header: Decodable::consensus_decode(r)?,
+            filter_hashes: decode_limited_vec(r, MAX_CFHEADERS)?,
+        })
+    }
+}
+
+/// A request for the filter headers of every 1000th block up to the block
+/// with the stop hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFCheckptMessage {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+}
+
+impl Encodable for GetCFCheckptMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)? + self.stop_hash.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for GetCFCheckptMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(GetCFCheckptMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// The filter headers of every 1000th block, starting at height 1000.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFCheckptMessage {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub filter_headers: Vec<[u8; 32]>,
+}
+
+impl Encodable for CFCheckptMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.filter_type.consensus_encode(w)?
+            + self.stop_hash.consensus_encode(w)?
+            + encode::encode_vec(&self.filter_headers, w)?)
+    }
+}
+
+impl Decodable for CFCheckptMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(CFCheckptMessage {
+            filter_type: Decodable::consensus_decode(r)?,
+            stop_hash: Decodable::consensus_decode(r)?,
+            filter_headers: encode::decode_vec(r)?,
+        })
+    }
+}
+
+/// A message of the protocol, without its framing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkMessage {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    GetHeaders(GetHeadersMessage),
+    /// Headers following each other. Each header is followed by a number of
+    /// transactions, which is always zero.
+    Headers(Vec<BlockHeader>),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    Addr(Vec<TimestampedAddress>),
+    AddrV2(Vec<AddressV2>),
+    /// Ask for `addrv2` messages instead of `addr` ones (BIP 155).
+    SendAddrV2,
+    /// Ask for new blocks to be announced with `headers` messages (BIP 130).
+    SendHeaders,
+    /// The minimum fee rate of the transactions to announce, in satoshis per
+    /// 1000 virtual bytes (BIP 133).
+    FeeFilter(u64),
+    /// Ask for transactions to be announced by wtxid (BIP 339).
+    WtxidRelay,
+    /// Set the bloom filter of the transactions to relay (BIP 37).
+    FilterLoad(BloomFilter),
+    /// Insert an element in the bloom filter.
+    FilterAdd(Vec<u8>),
+    /// Remove the bloom filter, so that all transactions are relayed.
+    FilterClear,
+    /// A block header with the transactions matching the bloom filter, which
+    /// are sent in `tx` messages following it.
+    MerkleBlock(MerkleBlock),
+    GetCFilters(GetCFiltersMessage),
+    CFilter(CFilterMessage),
+    GetCFHeaders(GetCFHeadersMessage),
+    CFHeaders(CFHeadersMessage),
+    GetCFCheckpt(GetCFCheckptMessage),
+    CFCheckpt(CFCheckptMessage),
+    /// A message that is not known, which must be ignored.
+    Unknown {
+        command: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl NetworkMessage {
+    /// The command of the header of the message.
+    pub fn command(&self) -> &str {
+        match self {
+            NetworkMessage::Version(_) => "version",
+            NetworkMessage::Verack => "verack",
+            NetworkMessage::Ping(_) => "ping",
+            NetworkMessage::Pong(_) => "pong",
+            NetworkMessage::GetHeaders(_) => "getheaders",
+            NetworkMessage::Headers(_) => "headers",
+            NetworkMessage::Inv(_) => "inv",
+            NetworkMessage::GetData(_) => "getdata",
+            NetworkMessage::NotFound(_) => "notfound",
+            NetworkMessage::Block(_) => "block",
+            NetworkMessage::Tx(_) => "tx",
+            NetworkMessage::Addr(_) => "addr",
+            NetworkMessage::AddrV2(_) => "addrv2",
+            NetworkMessage::SendAddrV2 => "sendaddrv2",
+            NetworkMessage::SendHeaders => "sendheaders",
+            NetworkMessage::FeeFilter(_) => "feefilter",
+            NetworkMessage::WtxidRelay => "wtxidrelay",
+            NetworkMessage::FilterLoad(_) => "filterload",
+            NetworkMessage::FilterAdd(_) => "filteradd",
+            NetworkMessage::FilterClear => "filterclear",
+            NetworkMessage::MerkleBlock(_) => "merkleblock",
+            NetworkMessage::GetCFilters(_) => "getcfilters",
+            NetworkMessage::CFilter(_) => "cfilter",
+            NetworkMessage::GetCFHeaders(_) => "getcfheaders",
+            NetworkMessage::CFHeaders(_) => "cfheaders",
+            NetworkMessage::GetCFCheckpt(_) => "getcfcheckpt",
+            NetworkMessage::CFCheckpt(_) => "cfcheckpt",
+            NetworkMessage::Unknown { command, .. } => command,
+        }
+    }
+
+    /// The encoding of the message, without the header.
+    pub fn encode_payload(&self) -> Vec<u8> {
+        match self {
+            NetworkMessage::Version(version) => encode::serialize(version),
+            NetworkMessage::Verack
+            | NetworkMessage::SendAddrV2
+            | NetworkMessage::SendHeaders
+            | NetworkMessage::WtxidRelay
+            | NetworkMessage::FilterClear => Vec::new(),
+            NetworkMessage::Ping(nonce) | NetworkMessage::Pong(nonce) => encode::serialize(nonce),
+            NetworkMessage::GetHeaders(get_headers) => encode::serialize(get_headers),
+            NetworkMessage::Headers(headers) => {
+                let mut payload = Vec::new();
+                encode::write_length(headers.len(), &mut payload).unwrap();
+                for header in headers {
+                    header.consensus_encode(&mut payload).unwrap();
+                    payload.push(0);
+                }
+                payload
+            }
+            NetworkMessage::Inv(inventory)
+            | NetworkMessage::GetData(inventory)
+            | NetworkMessage::NotFound(inventory) => serialize_vec(inventory),
+            NetworkMessage::Block(block) => encode::serialize(block),
+            NetworkMessage::Tx(transaction) => encode::serialize(transaction),
+            NetworkMessage::Addr(addresses) => serialize_vec(addresses),
+            NetworkMessage::AddrV2(addresses) => serialize_vec(addresses),
+            NetworkMessage::FeeFilter(fee_rate) => encode::serialize(fee_rate),
+            NetworkMessage::FilterLoad(filter) => encode::serialize(filter),
+            NetworkMessage::FilterAdd(data) => encode::serialize(data),
+            NetworkMessage::MerkleBlock(merkle_block) => encode::serialize(merkle_block),
+            NetworkMessage::GetCFilters(request) => encode::serialize(request),
+            NetworkMessage::CFilter(filter) => encode::serialize(filter),
+            NetworkMessage::GetCFHeaders(request) => encode::serialize(request),
+            NetworkMessage::CFHeaders(headers) => encode::serialize(headers),
+            NetworkMessage::GetCFCheckpt(request) => encode::serialize(request),
+            NetworkMessage::CFCheckpt(checkpoint) => encode::serialize(checkpoint),
+            NetworkMessage::Unknown { payload, .. } => payload.clone(),
+        }
+    }
+
+    /// Decode the payload of a message with the given command. The payload
+    /// must be consumed entirely.
+    pub fn decode_payload(command: &str, payload: &[u8]) -> Result<Self, Error> {
+        let r = &mut &payload[..];
+        let message = match command {
+            "version" => NetworkMessage::Version(Decodable::consensus_decode(r)?),
+            "verack" => NetworkMessage::Verack,
+            "ping" => NetworkMessage::Ping(Decodable::consensus_decode(r)?),
+            "pong" => NetworkMessage::Pong(Decodable::consensus_decode(r)?),
+            "getheaders" => NetworkMessage::GetHeaders(Decodable::consensus_decode(r)?),
+            "headers" => {
+                let count = encode::read_length(r)?;
+                if count > MAX_HEADERS {
+                    return Err(Error::OversizedLength(count as u64));
+                }
+                let mut headers = Vec::with_capacity(count);
+                for _ in 0..count {
+                    headers.push(BlockHeader::consensus_decode(r)?);
+                    if VarInt::consensus_decode(r)?.0 != 0 {
+                        return Err(Error::ParseFailed("headers with transactions"));
+                    }
+                }
+                NetworkMessage::Headers(headers)
+            }
+            "inv" => NetworkMessage::Inv(decode_limited_vec(r, MAX_INV_SIZE)?),
+            "getdata" => NetworkMessage::GetData(decode_limited_vec(r, MAX_INV_SIZE)?),
+            "notfound" => NetworkMessage::NotFound(decode_limited_vec(r, MAX_INV_SIZE)?),
+            "block" => NetworkMessage::Block(Decodable::consensus_decode(r)?),
+            "tx" => NetworkMessage::Tx(Decodable::consensus_decode(r)?),
+            "addr" => NetworkMessage::Addr(decode_limited_vec(r, MAX_ADDR_SIZE)?),
+            "addrv2" => NetworkMessage::AddrV2(decode_limited_vec(r, MAX_ADDR_SIZE)?),
+            "sendaddrv2" => NetworkMessage::SendAddrV2,
+            "sendheaders" => NetworkMessage::SendHeaders,
+            "feefilter" => NetworkMessage::FeeFilter(Decodable::consensus_decode(r)?),
+            "wtxidrelay" => NetworkMessage::WtxidRelay,
+            "filterload" => NetworkMessage::FilterLoad(Decodable::consensus_decode(r)?),
+            "filteradd" => {
+                let data: Vec<u8> = Decodable::consensus_decode(r)?;
+                if data.len() > MAX_FILTER_ADD_SIZE {
+                    return Err(Error::OversizedLength(data.len() as u64));
+                }
+                NetworkMessage::FilterAdd(data)
+            }
+            "filterclear" => NetworkMessage::FilterClear,
+            "merkleblock" => NetworkMessage::MerkleBlock(Decodable::consensus_decode(r)?),
+            "getcfilters" => NetworkMessage::GetCFilters(Decodable::consensus_decode(r)?),
+            "cfilter" => NetworkMessage::CFilter(Decodable::consensus_decode(r)?),
+            "getcfheaders" => NetworkMessage::GetCFHeaders(Decodable::consensus_decode(r)?),
+            "cfheaders" => NetworkMessage::CFHeaders(Decodable::consensus_decode(r)?),
+            "getcfcheckpt" => NetworkMessage::GetCFCheckpt(Decodable::consensus_decode(r)?),
+            "cfcheckpt" => NetworkMessage::CFCheckpt(Decodable::consensus_decode(r)?),
+            _ => {
+                return Ok(NetworkMessage::Unknown {
+                    command: command.into(),
+                    payload: payload.to_vec(),
+                })
+            }
+        };
+        if !r.is_empty() {
+            return Err(Error::TrailingBytes);
+        }
+        Ok(message)
+    }
+}
+
+fn serialize_vec<T: Encodable>(values: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    encode::encode_vec(values, &mut bytes).unwrap();
+    bytes
+}
+
+fn decode_limited_vec<T: Decodable, R: Read + ?Sized>(
+    r: &mut R,
+    max: usize,
+) -> Result<Vec<T>, Error> {
+    let count = encode::read_length(r)?;
+    if count > max {
+        return Err(Error::OversizedLength(count as u64));
+    }
+    (0..count).map(|_| T::consensus_decode(r)).collect()
+}
+
+/// The first 4 bytes of the double SHA-256 of the payload.
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    sha256d(payload)[..4].try_into().unwrap()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    /// The message does not start with the magic of the network.
+    BadMagic([u8; 4]),
+    /// The command is not ASCII padded with zeros.
+    BadCommand,
+    /// The payload is larger than [MAX_MESSAGE_SIZE].
+    Oversized(u32),
+    BadChecksum,
+    /// The payload can not be decoded.
+    Decode {
+        command: String,
+        error: Error,
+    },
+}
+
+impl core::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            MessageError::BadMagic(magic) => write!(f, "bad magic {}", hex::encode(magic)),
+            MessageError::BadCommand => write!(f, "invalid command"),
+            MessageError::Oversized(size) => write!(f, "message of {} bytes too large", size),
+            MessageError::BadChecksum => write!(f, "bad checksum"),
+            MessageError::Decode { command, error } => {
+                write!(f, "invalid {} message: {}", command, error)
+            }
+        }
+    }
+}
+
+/// Frame a message to send it to a peer of the network.
+pub fn encode_message(network: Network, message: &NetworkMessage) -> Vec<u8> {
+    let payload = message.encode_payload();
+    let mut command = [0u8; 12];
+    let name = message.command().as_bytes();
+    let length = name.len().min(12);
+    command[..length].copy_from_slice(&name[..length]);
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend(network.magic());
+    bytes.extend(command);
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(checksum(&payload));
+    bytes.extend(payload);
+    bytes
+}
+
+/// Decode the first message of bytes received from a peer, and return it with
+/// the number of bytes it takes, or `None` if the message is not received
+/// entirely yet.
+pub fn decode_message(
+    network: Network,
+    bytes: &[u8],
+) -> Result<Option<(NetworkMessage, usize)>, MessageError> {
+    if bytes.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    let magic: [u8; 4] = bytes[..4].try_into().unwrap();
+    if magic != network.magic() {
+        return Err(MessageError::BadMagic(magic));
+    }
+    let command = &bytes[4..16];
+    let end = command.iter().position(|b| *b == 0).unwrap_or(12);
+    if command[end..].iter().any(|b| *b != 0) || !command[..end].is_ascii() {
+        return Err(MessageError::BadCommand);
+    }
+    // Checked to be ASCII
+    let command = core::str::from_utf8(&command[..end]).unwrap();
+    let length = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+    if length as usize > MAX_MESSAGE_SIZE {
+        return Err(MessageError::Oversized(length));
+    }
+    let size = HEADER_SIZE + length as usize;
+    if bytes.len() < size {
+        return Ok(None);
+    }
+    let payload = &bytes[HEADER_SIZE..size];
+    if checksum(payload) != bytes[20..24] {
+        return Err(MessageError::BadChecksum);
+    }
+    let message =
+        NetworkMessage::decode_payload(command, payload).map_err(|error| MessageError::Decode {
+            command: command.into(),
+            error,
+        })?;
+    Ok(Some((message, size)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::tests::GENESIS_BLOCK;
+    use crate::params::ChainParams;
+    use alloc::vec;
+
+    fn round_trip(message: NetworkMessage) {
+        let bytes = encode_message(Network::Regtest, &message);
+        assert_eq!(
+            decode_message(Network::Regtest, &bytes),
+            Ok(Some((message, bytes.len())))
+        );
+    }
+
+    #[test]
+    pub fn test_verack() {
+        let bytes = encode_message(Network::Mainnet, &NetworkMessage::Verack);
+        assert_eq!(
+            hex::encode(&bytes),
+            "f9beb4d976657261636b000000000000000000005df6e0e2"
+        );
+        assert_eq!(
+            decode_message(Network::Mainnet, &bytes),
+            Ok(Some((NetworkMessage::Verack, 24)))
+        );
+        assert_eq!(decode_message(Network::Mainnet, &bytes[..23]), Ok(None));
+        assert_eq!(
+            decode_message(Network::Testnet, &bytes),
+            Err(MessageError::BadMagic(Network::Mainnet.magic()))
+        );
+    }
+
+    #[test]
+    pub fn test_decode_version() {
+        // A version message of Satoshi 0.7.2, without the relay flag
+        let bytes = hex::decode(
+            "f9beb4d976657273696f6e0000000000640000003b648d5a62ea0000010000000000000011b2d05000000000010000000000000000000000000000000000ffff000000000000010000000000000000000000000000000000ffff0000000000003b2eb35d8ce617650f2f5361746f7368693a302e372e322fc03e0300",
+        )
+        .unwrap();
+        let (message, size) = decode_message(Network::Mainnet, &bytes).unwrap().unwrap();
+        assert_eq!(size, bytes.len());
+        let NetworkMessage::Version(version) = message else {
+            panic!("not a version message");
+        };
+        assert_eq!(version.version, 60002);
+        assert_eq!(version.services, NODE_NETWORK);
+        assert_eq!(version.timestamp, 1355854353);
+        assert_eq!(
+            version.receiver,
+            Address::from_ipv4(NODE_NETWORK, [0; 4], 0)
+        );
+        assert_eq!(version.nonce, 0x6517e68c5db32e3b);
+        assert_eq!(version.user_agent, "/Satoshi:0.7.2/");
+        assert_eq!(version.start_height, 212672);
+        assert!(version.relay);
+
+        let mut corrupted = bytes.clone();
+        corrupted[30] ^= 1;
+        assert_eq!(
+            decode_message(Network::Mainnet, &corrupted),
+            Err(MessageError::BadChecksum)
+        );
+        let mut corrupted = bytes.clone();
+        corrupted[13] = b'x';
+        assert_eq!(
+            decode_message(Network::Mainnet, &corrupted),
+            Err(MessageError::BadCommand)
+        );
+        let mut corrupted = bytes;
+        corrupted[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        assert_eq!(
+            decode_message(Network::Mainnet, &corrupted),
+            Err(MessageError::Oversized(MAX_MESSAGE_SIZE as u32 + 1))
+        );
+    }
+
+    #[test]
+    pub fn test_round_trips() {
+        let genesis: Block = encode::deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let address = Address::from_ipv4(NODE_NETWORK | NODE_WITNESS, [127, 0, 0, 1], 18444);
+        round_trip(NetworkMessage::Version(VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK | NODE_WITNESS,
+            timestamp: 1_700_000_000,
+            receiver: address,
+            sender: Address::default(),
+            nonce: 42,
+            user_agent: "/bitcoin-rs:0.1.0/".into(),
+            start_height: 0,
+            relay: false,
+        }));
+        round_trip(NetworkMessage::Ping(7));
+        round_trip(NetworkMessage::Pong(7));
+        round_trip(NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: vec![genesis.header.block_hash(), [1; 32]],
+            stop_hash: [0; 32],
+        }));
+        round_trip(NetworkMessage::Headers(vec![
+            ChainParams::regtest().genesis_header,
+            genesis.header,
+        ]));
+        round_trip(NetworkMessage::Inv(vec![
+            Inventory::Block([2; 32]),
+            Inventory::WitnessTransactionId([3; 32]),
+            Inventory::Unknown {
+                inv_type: 7,
+                hash: [4; 32],
+            },
+        ]));
+        round_trip(NetworkMessage::GetData(vec![
+            Inventory::WitnessBlock([2; 32]),
+            Inventory::WitnessTransaction([5; 32]),
+        ]));
+        round_trip(NetworkMessage::NotFound(vec![Inventory::Transaction(
+            [5; 32],
+        )]));
+        round_trip(NetworkMessage::Tx(genesis.transactions[0].clone()));
+        round_trip(NetworkMessage::MerkleBlock(MerkleBlock::from_block(
+            &genesis,
+            |_| true,
+        )));
+        round_trip(NetworkMessage::Block(genesis));
+        round_trip(NetworkMessage::Addr(vec![TimestampedAddress {
+            time: 1_700_000_000,
+            address,
+        }]));
+        round_trip(NetworkMessage::AddrV2(vec![
+            AddressV2 {
+                time: 1_700_000_000,
+                services: NODE_NETWORK | NODE_P2P_V2,
+                address: NetworkAddress::TorV3([6; 32]),
+                port: 8333,
+            },
+            AddressV2 {
+                time: 1_700_000_000,
+                services: 0,
+                address: NetworkAddress::Unknown {
+                    network_id: 42,
+                    address: vec![1, 2, 3],
+                },
+                port: 8333,
+            },
+        ]));
+        round_trip(NetworkMessage::SendAddrV2);
+        round_trip(NetworkMessage::SendHeaders);
+        round_trip(NetworkMessage::FeeFilter(1000));
+        round_trip(NetworkMessage::WtxidRelay);
+        round_trip(NetworkMessage::FilterLoad(BloomFilter {
+            data: vec![0x61, 0x4e, 0x9b],
+            hash_funcs: 5,
+            tweak: 0,
+            flags: 1,
+        }));
+        round_trip(NetworkMessage::FilterAdd(vec![0x42; 20]));
+        round_trip(NetworkMessage::FilterClear);
+        round_trip(NetworkMessage::GetCFilters(GetCFiltersMessage {
+            filter_type: 0,
+            start_height: 1,
+            stop_hash: [7; 32],
+        }));
+        round_trip(NetworkMessage::CFilter(CFilterMessage {
+            filter_type: 0,
+            block_hash: [7; 32],
+            filter: vec![1, 0x9d, 0xfc, 0xa8],
+        }));
+        round_trip(NetworkMessage::GetCFHeaders(GetCFHeadersMessage {
+            filter_type: 0,
+            start_height: 1,
+            stop_hash: [7; 32],
+        }));
+        round_trip(NetworkMessage::CFHeaders(CFHeadersMessage {
+            filter_type: 0,
+            stop_hash: [7; 32],
+            previous_filter_header: [0; 32],
+            filter_hashes: vec![[1; 32], [2; 32]],
+        }));
+        round_trip(NetworkMessage::GetCFCheckpt(GetCFCheckptMessage {
+            filter_type: 0,
+            stop_hash: [7; 32],
+        }));
+        round_trip(NetworkMessage::CFCheckpt(CFCheckptMessage {
+            filter_type: 0,
+            stop_hash: [7; 32],
+            filter_headers: vec![[3; 32]],
+        }));
+        round_trip(NetworkMessage::Unknown {
+            command: "sendcmpct".into(),
+            payload: vec![0, 2, 0, 0, 0, 0, 0, 0, 0],
+        });
+    }
+
+    #[test]
+    pub fn test_decode_invalid_payloads() {
+        // Ports are big-endian
+        let address = Address::from_ipv4(0, [10, 0, 0, 1], 8333);
+        assert_eq!(&encode::serialize(&address)[24..], &[0x20, 0x8d]);
+        // A header followed by transactions
+        let mut payload = vec![1];
+        payload.extend(encode::serialize(&ChainParams::regtest().genesis_header));
+        payload.push(1);
+        assert!(NetworkMessage::decode_payload("headers", &payload).is_err());
+        payload.pop();
+        payload.push(0);
+        assert!(NetworkMessage::decode_payload("headers", &payload).is_ok());
+        payload.push(0);
+        assert_eq!(
+            NetworkMessage::decode_payload("headers", &payload),
+            Err(Error::TrailingBytes)
+        );
+        // An IPv4 address of 5 bytes
+        let mut payload = vec![1];
+        payload.extend([0, 0, 0, 0, 0, 1, 5, 1, 2, 3, 4, 5, 0x20, 0x8d]);
+        assert!(NetworkMessage::decode_payload("addrv2", &payload).is_err());
+        payload[7] = 4;
+        payload.remove(12);
+        assert!(NetworkMessage::decode_payload("addrv2", &payload).is_ok());
+        assert!(NetworkMessage::decode_payload("verack", &[0]).is_err());
+        // Elements added to bloom filters are at most 520 bytes
+        let payload = encode::serialize(&vec![0u8; 521]);
+        assert!(NetworkMessage::decode_payload("filteradd", &payload).is_err());
+        // A bloom filter with too many hash functions
+        let mut payload = vec![1, 0];
+        payload.extend(51u32.to_le_bytes());
+        payload.extend([0, 0, 0, 0, 0]);
+        assert!(NetworkMessage::decode_payload("filterload", &payload).is_err());
+        payload[2] = 50;
+        assert!(NetworkMessage::decode_payload("filterload", &payload).is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+//! Consensus parameters of the different Bitcoin networks.
+
+use crate::block::BlockHeader;
+use crate::encode::{Decodable, Encodable, Error, Read, Write};
+use crate::uint::U256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    /// The bytes starting the messages of the network, also used to delimit
+    /// the blocks stored by the reference implementation.
+    pub fn magic(self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xf9, 0xbe, 0xb4, 0xd9],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+            Network::Regtest => [0xfa, 0xbf, 0xb5, 0xda],
+        }
+    }
+}
+
+/// Encoded as a single byte.
+impl Encodable for Network {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        let id: u8 = match self {
+            Network::Mainnet => 0,
+            Network::Testnet => 1,
+            Network::Regtest => 2,
+        };
+        id.consensus_encode(w)
+    }
+}
+
+impl Decodable for Network {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        match u8::consensus_decode(r)? {
+            0 => Ok(Network::Mainnet),
+            1 => Ok(Network::Testnet),
+            2 => Ok(Network::Regtest),
+            _ => Err(Error::ParseFailed("unknown network")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub network: Network,
+    /// The genesis block header of the network.
+    pub genesis_header: BlockHeader,
+    /// The compact representation of the highest target allowed.
+    pub pow_limit_bits: u32,
+    /// The expected number of seconds between two blocks.
+    pub pow_target_spacing: u32,
+    /// The expected duration in seconds of a difficulty adjustment period.
+    pub pow_target_timespan: u32,
+    /// Allow a block to be mined with the lowest difficulty if no block has
+    /// been found for two times the target spacing.
+    pub allow_min_difficulty_blocks: bool,
+    /// Never change the difficulty.
+    pub no_retargeting: bool,
+    /// The height from which coinbases must start with the block height (BIP
+    /// 34).
+    pub bip34_height: u32,
+    /// The height from which segwit is enforced (BIP 141).
+    pub segwit_height: u32,
+    /// The number of blocks after which the subsidy is halved.
+    pub subsidy_halving_interval: u32,
+}
+
+/// The merkle root of the genesis block, common to all networks.
+const GENESIS_MERKLE_ROOT: [u8; 32] = [
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+];
+
+fn genesis_header(time: u32, bits: u32, nonce: u32) -> BlockHeader {
+    BlockHeader {
+        version: 1u32.to_le_bytes(),
+        previous_block: [0; 32],
+        merkle_root: GENESIS_MERKLE_ROOT,
+        time: time.to_le_bytes(),
+        bits: bits.to_le_bytes(),
+        nonce: nonce.to_le_bytes(),
+    }
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            genesis_header: genesis_header(1231006505, 0x1d00ffff, 2083236893),
+            pow_limit_bits: 0x1d00ffff,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: false,
+            no_retargeting: false,
+            bip34_height: 227931,
+            segwit_height: 481824,
+            subsidy_halving_interval: 210000,
+        }
+    }
+
+    /// Parameters of testnet3.
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            genesis_header: genesis_header(1296688602, 0x1d00ffff, 414098458),
+            pow_limit_bits: 0x1d00ffff,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: true,
+            no_retargeting: false,
+            bip34_height: 21111,
+            segwit_height: 834624,
+            subsidy_halving_interval: 210000,
+        }
+    }
+
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            genesis_header: genesis_header(1296688602, 0x207fffff, 2),
+            pow_limit_bits: 0x207fffff,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: true,
+            no_retargeting: true,
+            bip34_height: 1,
+            segwit_height: 0,
+            subsidy_halving_interval: 150,
+        }
+    }
+
+    pub fn new(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::mainnet(),
+            Network::Testnet => Self::testnet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    /// The highest target allowed.
+    pub fn pow_limit(&self) -> U256 {
+        U256::from_compact(self.pow_limit_bits).0
+    }
+
+    /// The number of blocks between two difficulty adjustments.
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+}
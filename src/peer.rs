@@ -0,0 +1,881 @@
+//! The protocol with a peer, independent of how the bytes are exchanged.
+//!
+//! A [Peer] is a state machine: the bytes or messages received from the peer
+//! are fed to it, and it queues the messages to send back and the events for
+//! the application. It performs the version handshake, negotiates the
+//! optional features, and synchronizes the headers of the peer into a
+//! [HeaderChain] as the reference implementation does: `getheaders` requests
+//! are sent until a `headers` message has less than [MAX_HEADERS] headers.
+//!
+//! Without sockets and clocks, it runs in a zkVM and can be tested with
+//! scripted transcripts. Timeouts and pings are left to the caller.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::block::{Block, BlockHeader};
+use crate::blockfilter::BASIC_FILTER;
+use crate::chain::{HeaderChain, HeaderError};
+use crate::merkle::MerkleBlock;
+use crate::p2p::{
+    self, Address, CFCheckptMessage, CFHeadersMessage, CFilterMessage, GetCFHeadersMessage,
+    GetCFiltersMessage, GetHeadersMessage, Inventory, MessageError, NetworkMessage, VersionMessage,
+    MAX_HEADERS, NODE_WITNESS, PROTOCOL_VERSION,
+};
+use crate::pow::block_proof;
+use crate::transaction::Transaction;
+use crate::uint::U256;
+
+/// The oldest version of the protocol supported, as `MIN_PEER_PROTO_VERSION`
+/// in the reference implementation.
+pub const MIN_PEER_PROTOCOL_VERSION: u32 = 31800;
+
+/// The version from which `sendheaders` is supported (BIP 130).
+pub const SENDHEADERS_VERSION: u32 = 70012;
+
+/// The version from which `wtxidrelay` is supported (BIP 339).
+pub const WTXID_RELAY_VERSION: u32 = 70016;
+
+/// What the node announces in its `version` message, and what it expects from
+/// the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerConfig {
+    /// The services offered by the node.
+    pub services: u64,
+    /// The services the peer must offer.
+    pub required_services: u64,
+    pub user_agent: String,
+    /// Whether the peer should announce transactions.
+    pub relay: bool,
+    /// A random number, to detect connections to self.
+    pub nonce: u64,
+    /// The current time, in seconds since the Unix epoch.
+    pub timestamp: i64,
+    /// The address of the peer.
+    pub receiver: Address,
+}
+
+impl PeerConfig {
+    /// A client only interested in blocks, requiring the peer to serve them
+    /// with their witnesses.
+    pub fn new(nonce: u64, timestamp: i64) -> Self {
+        PeerConfig {
+            services: 0,
+            required_services: NODE_WITNESS,
+            user_agent: "/bitcoin-rs:0.1.0/".into(),
+            relay: false,
+            nonce,
+            timestamp,
+            receiver: Address::default(),
+        }
+    }
+}
+
+/// Features negotiated during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    /// The peer announces transactions by wtxid.
+    pub wtxid_relay: bool,
+    /// The peer sends `addrv2` messages.
+    pub addrv2: bool,
+    /// The peer announces new blocks with `headers` messages.
+    pub send_headers: bool,
+    /// The minimum fee rate of the transactions to announce to the peer, in
+    /// satoshis per 1000 virtual bytes.
+    pub fee_filter: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    AwaitingVersion,
+    AwaitingVerack,
+    Ready,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The handshake is done.
+    Connected,
+    /// Headers were added to the chain, from the given height. If it is not
+    /// above the previous tip, the chain switched to a branch with more work.
+    HeadersConnected {
+        from_height: u32,
+        tip_height: u32,
+    },
+    /// The peer has no more headers to send.
+    Synced,
+    Block(Block),
+    Transaction(Transaction),
+    /// The peer does not have the requested objects.
+    NotFound(Vec<Inventory>),
+    /// A block header with the transactions matching the bloom filter, which
+    /// follow as [PeerEvent::Transaction] events.
+    MerkleBlock(MerkleBlock),
+    /// A compact block filter, as requested by [Peer::request_filters].
+    Filter(CFilterMessage),
+    FilterHeaders(CFHeadersMessage),
+    FilterCheckpoint(CFCheckptMessage),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerError {
+    Message(MessageError),
+    /// The message with the given command is not expected in the current
+    /// state of the connection.
+    UnexpectedMessage(String),
+    /// The version of the protocol of the peer is too old.
+    ObsoleteVersion(u32),
+    /// The peer does not offer the required services, which are given.
+    MissingServices(u64),
+    /// The peer is the node itself.
+    SelfConnection,
+    /// A header sent by the peer is invalid.
+    Header {
+        height: u32,
+        error: HeaderError,
+    },
+    /// A full `headers` message does not extend the chain, so asking for more
+    /// would give the same headers again.
+    NoProgress,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerError::Message(e) => write!(f, "{}", e),
+            PeerError::UnexpectedMessage(command) => write!(f, "unexpected {} message", command),
+            PeerError::ObsoleteVersion(version) => write!(f, "obsolete version {}", version),
+            PeerError::MissingServices(services) => {
+                write!(f, "missing services {:#x}", services)
+            }
+            PeerError::SelfConnection => write!(f, "connected to self"),
+            PeerError::Header { height, error } => {
+                write!(f, "invalid header at height {}: {:?}", height, error)
+            }
+            PeerError::NoProgress => write!(f, "headers do not extend the chain"),
+        }
+    }
+}
+
+impl From<MessageError> for PeerError {
+    fn from(e: MessageError) -> Self {
+        PeerError::Message(e)
+    }
+}
+
+/// An outbound connection to a peer.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    config: PeerConfig,
+    chain: HeaderChain,
+    state: State,
+    remote: Option<VersionMessage>,
+    features: Features,
+    /// Bytes received which do not form a complete message yet.
+    buffer: Vec<u8>,
+    outbound: VecDeque<NetworkMessage>,
+    events: VecDeque<PeerEvent>,
+}
+
+impl Peer {
+    /// Start the connection, which queues the `version` message.
+    pub fn new(config: PeerConfig, chain: HeaderChain) -> Self {
+        let version = VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: config.services,
+            timestamp: config.timestamp,
+            receiver: config.receiver,
+            sender: Address::default(),
+            nonce: config.nonce,
+            user_agent: config.user_agent.clone(),
+            start_height: chain.tip_height() as i32,
+            relay: config.relay,
+        };
+        let mut peer = Peer {
+            config,
+            chain,
+            state: State::AwaitingVersion,
+            remote: None,
+            features: Features::default(),
+            buffer: Vec::new(),
+            outbound: VecDeque::new(),
+            events: VecDeque::new(),
+        };
+        peer.send(NetworkMessage::Version(version));
+        peer
+    }
+
+    pub fn chain(&self) -> &HeaderChain {
+        &self.chain
+    }
+
+    pub fn into_chain(self) -> HeaderChain {
+        self.chain
+    }
+
+    /// The `version` message of the peer, once received.
+    pub fn remote_version(&self) -> Option<&VersionMessage> {
+        self.remote.as_ref()
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Whether the handshake is done.
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    /// Queue a message to send to the peer.
+    pub fn send(&mut self, message: NetworkMessage) {
+        self.outbound.push_back(message);
+    }
+
+    /// The next message to send to the peer.
+    pub fn poll_message(&mut self) -> Option<NetworkMessage> {
+        self.outbound.pop_front()
+    }
+
+    /// The bytes of the next message to send to the peer.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        let message = self.outbound.pop_front()?;
+        Some(p2p::encode_message(self.chain.params().network, &message))
+    }
+
+    pub fn poll_event(&mut self) -> Option<PeerEvent> {
+        self.events.pop_front()
+    }
+
+    /// Request blocks with the witnesses of their transactions. They are
+    /// returned as [PeerEvent::Block] events.
+    pub fn request_blocks(&mut self, hashes: &[[u8; 32]]) {
+        let inventory = hashes.iter().map(|h| Inventory::WitnessBlock(*h)).collect();
+        self.send(NetworkMessage::GetData(inventory));
+    }
+
+    /// Request the blocks with the transactions matching the bloom filter
+    /// loaded with a `filterload` message (BIP 37). They are returned as
+    /// [PeerEvent::MerkleBlock] events.
+    pub fn request_merkle_blocks(&mut self, hashes: &[[u8; 32]]) {
+        let inventory = hashes
+            .iter()
+            .map(|h| Inventory::FilteredBlock(*h))
+            .collect();
+        self.send(NetworkMessage::GetData(inventory));
+    }
+
+    /// Request the basic filters of the blocks from the start height to the
+    /// block with the stop hash, at most
+    /// [MAX_CFILTERS](crate::p2p::MAX_CFILTERS) of them. The peer must offer
+    /// [NODE_COMPACT_FILTERS](crate::p2p::NODE_COMPACT_FILTERS). They are
+    /// returned as [PeerEvent::Filter] events.
+    pub fn request_filters(&mut self, start_height: u32, stop_hash: [u8; 32]) {
+        self.send(NetworkMessage::GetCFilters(GetCFiltersMessage {
+            filter_type: BASIC_FILTER,
+            start_height,
+            stop_hash,
+        }));
+    }
+
+    /// Request the hashes of the basic filters of the blocks from the start
+    /// height to the block with the stop hash, returned as a
+    /// [PeerEvent::FilterHeaders] event.
+    pub fn request_filter_headers(&mut self, start_height: u32, stop_hash: [u8; 32]) {
+        self.send(NetworkMessage::GetCFHeaders(GetCFHeadersMessage {
+            filter_type: BASIC_FILTER,
+            start_height,
+            stop_hash,
+        }));
+    }
+
+    /// Handle the bytes received from the peer. They do not need to contain
+    /// complete messages, the rest is kept until the next call.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<(), PeerError> {
+        self.buffer.extend_from_slice(bytes);
+        let network = self.chain.params().network;
+        let mut consumed = 0;
+        let result = loop {
+            match p2p::decode_message(network, &self.buffer[consumed..]) {
+                Ok(Some((message, size))) => {
+                    consumed += size;
+                    if let Err(e) = self.handle_message(message) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.buffer.drain(..consumed);
+        result
+    }
+
+    /// Handle a message received from the peer. An error means that the
+    /// connection should be closed.
+    pub fn handle_message(&mut self, message: NetworkMessage) -> Result<(), PeerError> {
+        let unexpected =
+            |message: &NetworkMessage| Err(PeerError::UnexpectedMessage(message.command().into()));
+        match (self.state, message) {
+            (State::AwaitingVersion, NetworkMessage::Version(version)) => {
+                self.handle_version(version)
+            }
+            (State::AwaitingVersion, message) => unexpected(&message),
+            (_, message @ NetworkMessage::Version(_)) => unexpected(&message),
+            (State::AwaitingVerack, NetworkMessage::Verack) => {
+                self.state = State::Ready;
+                self.events.push_back(PeerEvent::Connected);
+                if self.remote_version_number() >= SENDHEADERS_VERSION {
+                    self.send(NetworkMessage::SendHeaders);
+                }
+                self.send_getheaders();
+                Ok(())
+            }
+            // Features are negotiated between `version` and `verack`
+            (State::AwaitingVerack, NetworkMessage::WtxidRelay) => {
+                self.features.wtxid_relay = true;
+                Ok(())
+            }
+            (State::AwaitingVerack, NetworkMessage::SendAddrV2) => {
+                self.features.addrv2 = true;
+                Ok(())
+            }
+            (
+                State::Ready,
+                message @ (NetworkMessage::Verack
+                | NetworkMessage::WtxidRelay
+                | NetworkMessage::SendAddrV2),
+            ) => unexpected(&message),
+            // Nothing else is expected before the handshake is done
+            (State::AwaitingVerack, _) => Ok(()),
+            (State::Ready, message) => self.handle_ready_message(message),
+        }
+    }
+
+    fn remote_version_number(&self) -> u32 {
+        self.remote.as_ref().map_or(0, |version| version.version)
+    }
+
+    fn handle_version(&mut self, version: VersionMessage) -> Result<(), PeerError> {
+        if version.nonce == self.config.nonce {
+            return Err(PeerError::SelfConnection);
+        }
+        if version.version < MIN_PEER_PROTOCOL_VERSION {
+            return Err(PeerError::ObsoleteVersion(version.version));
+        }
+        let missing = self.config.required_services & !version.services;
+        if missing != 0 {
+            return Err(PeerError::MissingServices(missing));
+        }
+        if version.version >= WTXID_RELAY_VERSION {
+            self.send(NetworkMessage::WtxidRelay);
+        }
+        self.send(NetworkMessage::SendAddrV2);
+        self.send(NetworkMessage::Verack);
+        self.remote = Some(version);
+        self.state = State::AwaitingVerack;
+        Ok(())
+    }
+
+    fn handle_ready_message(&mut self, message: NetworkMessage) -> Result<(), PeerError> {
+        match message {
+            NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce)),
+            NetworkMessage::SendHeaders => self.features.send_headers = true,
+            NetworkMessage::FeeFilter(fee_rate) => self.features.fee_filter = fee_rate,
+            NetworkMessage::Headers(headers) => return self.handle_headers(headers),
+            NetworkMessage::Inv(inventory) => {
+                // Blocks are downloaded headers first
+                let announces_block = inventory
+                    .iter()
+                    .any(|inv| matches!(inv, Inventory::Block(_) | Inventory::WitnessBlock(_)));
+                if announces_block {
+                    self.send_getheaders();
+                }
+            }
+            NetworkMessage::Block(block) => self.events.push_back(PeerEvent::Block(block)),
+            NetworkMessage::Tx(tx) => self.events.push_back(PeerEvent::Transaction(tx)),
+            NetworkMessage::NotFound(inventory) => {
+                self.events.push_back(PeerEvent::NotFound(inventory))
+            }
+            NetworkMessage::MerkleBlock(merkle_block) => {
+                self.events.push_back(PeerEvent::MerkleBlock(merkle_block))
+            }
+            NetworkMessage::CFilter(filter) => self.events.push_back(PeerEvent::Filter(filter)),
+            NetworkMessage::CFHeaders(headers) => {
+                self.events.push_back(PeerEvent::FilterHeaders(headers))
+            }
+            NetworkMessage::CFCheckpt(checkpoint) => self
+                .events
+                .push_back(PeerEvent::FilterCheckpoint(checkpoint)),
+            // Requests and addresses are not served
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn send_getheaders(&mut self) {
+        self.send(NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: self.chain.block_locator(),
+            stop_hash: [0; 32],
+        }));
+    }
+
+    fn handle_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(), PeerError> {
+        let Some(first) = headers.first() else {
+            self.events.push_back(PeerEvent::Synced);
+            return Ok(());
+        };
+        let Some(fork_height) = self.chain.height_of(&first.previous_block) else {
+            // Announced headers which do not connect, as blocks were missed
+            self.send_getheaders();
+            return Ok(());
+        };
+        let full = headers.len() == MAX_HEADERS;
+        let tip_height = self.chain.tip_height();
+        // Headers already known are skipped
+        let known = headers
+            .iter()
+            .zip(fork_height + 1..=tip_height)
+            .take_while(|(header, height)| self.chain.hash_at(*height) == Some(header.block_hash()))
+            .count();
+        let from_height = fork_height + known as u32 + 1;
+        let new_headers = &headers[known..];
+        // A branch with less work is not followed, as the reference
+        // implementation does. The work of the headers is compared before
+        // they are verified, so that only the replaced headers are copied.
+        let replaced: Vec<BlockHeader> = (from_height..=tip_height)
+            .filter_map(|height| self.chain.header_at(height).copied())
+            .collect();
+        let work = |headers: &[BlockHeader]| {
+            headers.iter().fold(U256::ZERO, |work, header| {
+                work + block_proof(header.compact_target())
+            })
+        };
+        let progress = !new_headers.is_empty() && work(new_headers) > work(&replaced);
+        if progress {
+            self.chain.truncate(from_height - 1);
+            for header in new_headers {
+                if let Err(error) = self.chain.push(*header) {
+                    let height = self.chain.tip_height() + 1;
+                    // Restore the branch which was followed
+                    self.chain.truncate(from_height - 1);
+                    self.chain
+                        .extend(replaced)
+                        .expect("the replaced headers were valid");
+                    return Err(PeerError::Header { height, error });
+                }
+            }
+            self.events.push_back(PeerEvent::HeadersConnected {
+                from_height,
+                tip_height: self.chain.tip_height(),
+            });
+        }
+        if full && !progress {
+            return Err(PeerError::NoProgress);
+        } else if full {
+            self.send_getheaders();
+        } else {
+            self.events.push_back(PeerEvent::Synced);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::tests::mine_regtest_header;
+    use crate::chain::Checkpoint;
+    use crate::p2p::{NODE_NETWORK, NODE_P2P_V2};
+    use crate::params::{ChainParams, Network};
+    use alloc::vec;
+
+    fn regtest_chain() -> HeaderChain {
+        let params = ChainParams::regtest();
+        HeaderChain::new(params.clone(), Checkpoint::genesis(&params))
+    }
+
+    fn mine_headers(previous: &BlockHeader, count: usize, tag: u8) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for _ in 0..count {
+            let previous = headers.last().unwrap_or(previous);
+            headers.push(mine_regtest_header(previous, [tag; 32]));
+        }
+        headers
+    }
+
+    fn remote_version(version: u32, services: u64) -> VersionMessage {
+        VersionMessage {
+            version,
+            services,
+            timestamp: 1_700_000_000,
+            receiver: Address::default(),
+            sender: Address::default(),
+            nonce: 0xabcd,
+            user_agent: "/Satoshi:27.0.0/".into(),
+            start_height: 10,
+            relay: true,
+        }
+    }
+
+    fn drain_messages(peer: &mut Peer) -> Vec<NetworkMessage> {
+        core::iter::from_fn(|| peer.poll_message()).collect()
+    }
+
+    fn drain_events(peer: &mut Peer) -> Vec<PeerEvent> {
+        core::iter::from_fn(|| peer.poll_event()).collect()
+    }
+
+    fn getheaders(chain: &HeaderChain) -> NetworkMessage {
+        NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: chain.block_locator(),
+            stop_hash: [0; 32],
+        })
+    }
+
+    /// A peer which completed the handshake, with the messages sent so far
+    /// discarded.
+    fn connected_peer() -> Peer {
+        let mut peer = Peer::new(PeerConfig::new(1, 1_700_000_000), regtest_chain());
+        let services = NODE_NETWORK | NODE_WITNESS;
+        peer.handle_message(NetworkMessage::Version(remote_version(70016, services)))
+            .unwrap();
+        peer.handle_message(NetworkMessage::Verack).unwrap();
+        drain_messages(&mut peer);
+        drain_events(&mut peer);
+        peer
+    }
+
+    #[test]
+    pub fn test_handshake_transcript() {
+        let chain = regtest_chain();
+        let mut peer = Peer::new(PeerConfig::new(1, 1_700_000_000), chain.clone());
+        let Some(NetworkMessage::Version(version)) = peer.poll_message() else {
+            panic!("the version message is sent first");
+        };
+        assert_eq!(version.version, PROTOCOL_VERSION);
+        assert_eq!(version.nonce, 1);
+        assert_eq!(version.start_height, 0);
+        assert!(!version.relay);
+        assert_eq!(peer.poll_message(), None);
+
+        // The messages of the peer arrive in arbitrary chunks
+        let mut bytes = vec![];
+        let remote = remote_version(70016, NODE_NETWORK | NODE_WITNESS | NODE_P2P_V2);
+        for message in [
+            NetworkMessage::Version(remote.clone()),
+            NetworkMessage::WtxidRelay,
+            NetworkMessage::SendAddrV2,
+            // Ignored until the handshake is done
+            NetworkMessage::Ping(5),
+            NetworkMessage::Verack,
+            NetworkMessage::SendHeaders,
+            NetworkMessage::FeeFilter(1000),
+            NetworkMessage::Ping(6),
+        ] {
+            bytes.extend(p2p::encode_message(Network::Regtest, &message));
+        }
+        for chunk in bytes.chunks(7) {
+            peer.receive(chunk).unwrap();
+        }
+        assert!(peer.is_connected());
+        assert_eq!(peer.remote_version(), Some(&remote));
+        assert_eq!(
+            peer.features(),
+            Features {
+                wtxid_relay: true,
+                addrv2: true,
+                send_headers: true,
+                fee_filter: 1000,
+            }
+        );
+        assert_eq!(
+            drain_messages(&mut peer),
+            vec![
+                NetworkMessage::WtxidRelay,
+                NetworkMessage::SendAddrV2,
+                NetworkMessage::Verack,
+                NetworkMessage::SendHeaders,
+                getheaders(&chain),
+                NetworkMessage::Pong(6),
+            ]
+        );
+        assert_eq!(drain_events(&mut peer), vec![PeerEvent::Connected]);
+
+        // Sent as bytes
+        peer.send(NetworkMessage::Verack);
+        assert_eq!(
+            peer.poll_transmit(),
+            Some(p2p::encode_message(
+                Network::Regtest,
+                &NetworkMessage::Verack
+            ))
+        );
+    }
+
+    #[test]
+    pub fn test_handshake_failures() {
+        let config = PeerConfig::new(1, 1_700_000_000);
+        let mut peer = Peer::new(config.clone(), regtest_chain());
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Verack),
+            Err(PeerError::UnexpectedMessage("verack".into()))
+        );
+
+        let mut peer = Peer::new(config.clone(), regtest_chain());
+        let mut version = remote_version(70016, NODE_NETWORK | NODE_WITNESS);
+        version.nonce = 1;
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Version(version)),
+            Err(PeerError::SelfConnection)
+        );
+
+        let mut peer = Peer::new(config.clone(), regtest_chain());
+        let version = remote_version(31000, NODE_NETWORK | NODE_WITNESS);
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Version(version)),
+            Err(PeerError::ObsoleteVersion(31000))
+        );
+
+        let mut peer = Peer::new(config.clone(), regtest_chain());
+        let version = remote_version(70016, NODE_NETWORK);
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Version(version)),
+            Err(PeerError::MissingServices(NODE_WITNESS))
+        );
+
+        // An old peer does not negotiate the features
+        let mut peer = Peer::new(config, regtest_chain());
+        peer.poll_message();
+        let version = remote_version(70001, NODE_NETWORK | NODE_WITNESS);
+        peer.handle_message(NetworkMessage::Version(version.clone()))
+            .unwrap();
+        assert_eq!(
+            drain_messages(&mut peer),
+            vec![NetworkMessage::SendAddrV2, NetworkMessage::Verack]
+        );
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Version(version)),
+            Err(PeerError::UnexpectedMessage("version".into()))
+        );
+        peer.handle_message(NetworkMessage::Verack).unwrap();
+        assert_eq!(drain_messages(&mut peer), vec![getheaders(peer.chain())]);
+        assert_eq!(
+            peer.handle_message(NetworkMessage::WtxidRelay),
+            Err(PeerError::UnexpectedMessage("wtxidrelay".into()))
+        );
+    }
+
+    #[test]
+    pub fn test_sync_headers() {
+        let mut peer = connected_peer();
+        let genesis = ChainParams::regtest().genesis_header;
+        let headers = mine_headers(&genesis, MAX_HEADERS + 5, 0);
+
+        // A full batch asks for more
+        peer.handle_message(NetworkMessage::Headers(headers[..MAX_HEADERS].to_vec()))
+            .unwrap();
+        assert_eq!(peer.chain().tip_height(), MAX_HEADERS as u32);
+        assert_eq!(
+            drain_events(&mut peer),
+            vec![PeerEvent::HeadersConnected {
+                from_height: 1,
+                tip_height: MAX_HEADERS as u32
+            }]
+        );
+        assert_eq!(drain_messages(&mut peer), vec![getheaders(peer.chain())]);
+
+        peer.handle_message(NetworkMessage::Headers(headers[MAX_HEADERS..].to_vec()))
+            .unwrap();
+        peer.handle_message(NetworkMessage::Headers(vec![]))
+            .unwrap();
+        assert_eq!(
+            drain_events(&mut peer),
+            vec![
+                PeerEvent::HeadersConnected {
+                    from_height: MAX_HEADERS as u32 + 1,
+                    tip_height: MAX_HEADERS as u32 + 5
+                },
+                PeerEvent::Synced,
+                PeerEvent::Synced,
+            ]
+        );
+        assert_eq!(drain_messages(&mut peer), vec![]);
+        assert_eq!(
+            peer.chain().tip_hash(),
+            headers.last().unwrap().block_hash()
+        );
+    }
+
+    #[test]
+    pub fn test_announcements_and_forks() {
+        let mut peer = connected_peer();
+        let genesis = ChainParams::regtest().genesis_header;
+        let headers = mine_headers(&genesis, 5, 0);
+        peer.handle_message(NetworkMessage::Headers(headers[..3].to_vec()))
+            .unwrap();
+        drain_events(&mut peer);
+
+        // An announcement which does not connect triggers a request
+        let announcement = NetworkMessage::Headers(headers[4..].to_vec());
+        peer.handle_message(announcement).unwrap();
+        assert_eq!(peer.chain().tip_height(), 3);
+        assert_eq!(drain_messages(&mut peer), vec![getheaders(peer.chain())]);
+        // So does a block announced by inventory
+        let inv = NetworkMessage::Inv(vec![Inventory::Block(headers[3].block_hash())]);
+        peer.handle_message(inv).unwrap();
+        assert_eq!(drain_messages(&mut peer), vec![getheaders(peer.chain())]);
+        let inv = NetworkMessage::Inv(vec![Inventory::WitnessTransactionId([1; 32])]);
+        peer.handle_message(inv).unwrap();
+        assert_eq!(drain_messages(&mut peer), vec![]);
+
+        // Known headers are skipped
+        peer.handle_message(NetworkMessage::Headers(headers.clone()))
+            .unwrap();
+        assert_eq!(
+            drain_events(&mut peer),
+            vec![
+                PeerEvent::HeadersConnected {
+                    from_height: 4,
+                    tip_height: 5
+                },
+                PeerEvent::Synced
+            ]
+        );
+
+        // A fork with less work is ignored, one with more work is followed
+        let fork = mine_headers(&headers[1], 4, 1);
+        peer.handle_message(NetworkMessage::Headers(fork[..3].to_vec()))
+            .unwrap();
+        assert_eq!(peer.chain().tip_hash(), headers[4].block_hash());
+        assert_eq!(drain_events(&mut peer), vec![PeerEvent::Synced]);
+        peer.handle_message(NetworkMessage::Headers(fork.clone()))
+            .unwrap();
+        assert_eq!(peer.chain().tip_height(), 6);
+        assert_eq!(peer.chain().tip_hash(), fork[3].block_hash());
+        assert_eq!(
+            drain_events(&mut peer),
+            vec![
+                PeerEvent::HeadersConnected {
+                    from_height: 3,
+                    tip_height: 6
+                },
+                PeerEvent::Synced
+            ]
+        );
+
+        // An invalid header
+        let mut invalid = mine_regtest_header(&fork[3], [2; 32]);
+        invalid.bits = 0x1d00ffffu32.to_le_bytes();
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Headers(vec![invalid])),
+            Err(PeerError::Header {
+                height: 7,
+                error: HeaderError::HighHash
+            })
+        );
+        assert_eq!(peer.chain().tip_height(), 6);
+
+        // The followed branch is restored when a fork with more work has an
+        // invalid header
+        let mut fork2 = mine_headers(&fork[0], 5, 3);
+        fork2[4].bits = 0x1d00ffffu32.to_le_bytes();
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Headers(fork2)),
+            Err(PeerError::Header {
+                height: 8,
+                error: HeaderError::HighHash
+            })
+        );
+        assert_eq!(peer.chain().tip_height(), 6);
+        assert_eq!(peer.chain().tip_hash(), fork[3].block_hash());
+        assert_eq!(drain_events(&mut peer), vec![]);
+    }
+
+    #[test]
+    pub fn test_full_batch_without_progress() {
+        let mut peer = connected_peer();
+        let genesis = ChainParams::regtest().genesis_header;
+        let headers = mine_headers(&genesis, MAX_HEADERS, 0);
+        peer.handle_message(NetworkMessage::Headers(headers.clone()))
+            .unwrap();
+        drain_messages(&mut peer);
+        drain_events(&mut peer);
+
+        // Sending the same batch again does not make the peer ask for it
+        // again
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Headers(headers)),
+            Err(PeerError::NoProgress)
+        );
+        assert_eq!(drain_messages(&mut peer), vec![]);
+        // Neither does a full branch with as much work as the chain
+        let fork = mine_headers(&genesis, MAX_HEADERS, 1);
+        assert_eq!(
+            peer.handle_message(NetworkMessage::Headers(fork)),
+            Err(PeerError::NoProgress)
+        );
+        assert_eq!(drain_messages(&mut peer), vec![]);
+        assert_eq!(peer.chain().tip_height(), MAX_HEADERS as u32);
+    }
+
+    #[test]
+    pub fn test_download_blocks() {
+        let mut peer = connected_peer();
+        peer.request_blocks(&[[1; 32], [2; 32]]);
+        assert_eq!(
+            drain_messages(&mut peer),
+            vec![NetworkMessage::GetData(vec![
+                Inventory::WitnessBlock([1; 32]),
+                Inventory::WitnessBlock([2; 32])
+            ])]
+        );
+        let block = Block {
+            header: ChainParams::regtest().genesis_header,
+            transactions: vec![],
+        };
+        peer.handle_message(NetworkMessage::Block(block.clone()))
+            .unwrap();
+        let not_found = vec![Inventory::WitnessBlock([2; 32])];
+        peer.handle_message(NetworkMessage::NotFound(not_found.clone()))
+            .unwrap();
+        assert_eq!(
+            drain_events(&mut peer),
+            vec![PeerEvent::Block(block), PeerEvent::NotFound(not_found)]
+        );
+    }
+
+    #[test]
+    pub fn test_download_filters() {
+        let mut peer = connected_peer();
+        peer.request_filter_headers(1, [1; 32]);
+        peer.request_filters(1, [1; 32]);
+        assert_eq!(
+            drain_messages(&mut peer),
+            vec![
+                NetworkMessage::GetCFHeaders(GetCFHeadersMessage {
+                    filter_type: BASIC_FILTER,
+                    start_height: 1,
+                    stop_hash: [1; 32],
+                }),
+                NetworkMessage::GetCFilters(GetCFiltersMessage {
+                    filter_type: BASIC_FILTER,
+                    start_height: 1,
+                    stop_hash: [1; 32],
+                }),
+            ]
+        );
+        let filter = CFilterMessage {
+            filter_type: BASIC_FILTER,
+            block_hash: [1; 32],
+            filter: vec![0],
+        };
+        peer.handle_message(NetworkMessage::CFilter(filter.clone()))
+            .unwrap();
+        assert_eq!(drain_events(&mut peer), vec![PeerEvent::Filter(filter)]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Proof of work: checking block hashes against their target and computing the
+//! difficulty adjustments, as in `pow.cpp` of the reference implementation.
+
+use crate::params::ChainParams;
+use crate::uint::U256;
+
+/// Check that the hash satisfies the target encoded in `bits`, and that the
+/// target is valid for the network.
+pub fn check_proof_of_work(hash: &[u8; 32], bits: u32, params: &ChainParams) -> bool {
+    let (target, negative, overflow) = U256::from_compact(bits);
+    if negative || overflow || target.is_zero() || target > params.pow_limit() {
+        return false;
+    }
+    U256::from_le_bytes(*hash) <= target
+}
+
+/// The expected number of hashes needed to find a block with the given target,
+/// i.e. `2**256 / (target + 1)`.
+pub fn block_proof(bits: u32) -> U256 {
+    let (target, negative, overflow) = U256::from_compact(bits);
+    if negative || overflow || target.is_zero() {
+        return U256::ZERO;
+    }
+    // 2**256 does not fit, but it is equal to
+    // (2**256 - target - 1) / (target + 1) + 1
+    (!target / (target + U256::ONE)) + U256::ONE
+}
+
+/// Compute the target of the first block of a difficulty adjustment period,
+/// given the target of the previous block, its time and the time of the first
+/// block of the previous period.
+pub fn calculate_next_work_required(
+    last_bits: u32,
+    last_time: u32,
+    first_time: u32,
+    params: &ChainParams,
+) -> u32 {
+    if params.no_retargeting {
+        return last_bits;
+    }
+    // Limit the adjustment
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan = (last_time as i64 - first_time as i64).clamp(timespan / 4, timespan * 4);
+
+    let target = U256::from_compact(last_bits).0;
+    let target = target * actual_timespan as u64 / U256::from_u64(timespan as u64);
+    target.min(params.pow_limit()).to_compact()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples from pow_tests.cpp in the reference implementation
+    #[test]
+    pub fn test_calculate_next_work_required() {
+        let params = ChainParams::mainnet();
+        // Block 32255
+        assert_eq!(
+            calculate_next_work_required(0x1d00ffff, 1262152739, 1261130161, &params),
+            0x1d00d86a
+        );
+        // Block 2015, limited by the proof of work limit
+        assert_eq!(
+            calculate_next_work_required(0x1d00ffff, 1233061996, 1231006505, &params),
+            0x1d00ffff
+        );
+        // Block 68543, limited by the lower bound of the timespan
+        assert_eq!(
+            calculate_next_work_required(0x1c05a3f4, 1279297671, 1279008237, &params),
+            0x1c0168fd
+        );
+        // Block 48383, limited by the upper bound of the timespan
+        assert_eq!(
+            calculate_next_work_required(0x1c387f6f, 1269211443, 1263163443, &params),
+            0x1d00e1fd
+        );
+    }
+
+    #[test]
+    pub fn test_block_proof() {
+        // The difficulty 1 target requires 0x100010001 hashes
+        assert_eq!(block_proof(0x1d00ffff), U256::from_u64(0x100010001));
+        assert_eq!(block_proof(0x207fffff), U256::from_u64(2));
+        assert_eq!(block_proof(0x04923456), U256::ZERO);
+    }
+
+    #[test]
+    pub fn test_check_proof_of_work() {
+        let params = ChainParams::mainnet();
+        let genesis = params.genesis_header;
+        assert!(check_proof_of_work(
+            &genesis.block_hash(),
+            0x1d00ffff,
+            &params
+        ));
+        // Above the limit of the network
+        assert!(!check_proof_of_work(&[0; 32], 0x1d01ffff, &params));
+        assert!(!check_proof_of_work(&[0xff; 32], 0x1d00ffff, &params));
+    }
+}
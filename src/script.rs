@@ -2,7 +2,28 @@
 
 use core::convert::From;
 use core::convert::Into;
-
+use core::fmt;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::encode;
+use crate::encode::Decodable;
+use crate::encode::Encodable;
+use crate::encode::Read;
+use crate::encode::Write;
+use crate::interpreter::BaseSignatureChecker;
+use crate::interpreter::ExecutionTrace;
+use crate::interpreter::ScriptError;
+use crate::interpreter::SigVersion;
+use crate::interpreter::SignatureChecker;
+use crate::interpreter::TraceStep;
+use crate::locktime::LockTime;
+use crate::locktime::RelativeLockTime;
+use crate::locktime::SEQUENCE_LOCKTIME_DISABLE_FLAG;
 use ripemd::Digest;
 use ripemd::Ripemd160;
 use serde::Deserialize;
@@ -11,7 +32,19 @@ use serde::Serialize;
 use serde::Serializer;
 use sha2::Sha256;
 
-#[derive(Clone)]
+/// Maximum number of bytes of a script, outside of tapscript.
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Maximum number of bytes of a pushed value.
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+/// Maximum number of non-push operations per script, outside of tapscript.
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+
+/// Maximum number of elements on the main and the alt stacks together.
+pub const MAX_STACK_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stack(Vec<Vec<u8>>);
 
 impl Stack {
@@ -22,6 +55,23 @@ impl Stack {
     pub fn push(&mut self, v: Vec<u8>) {
         self.0.push(v)
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The elements, the top one last.
+    pub fn as_slice(&self) -> &[Vec<u8>] {
+        &self.0
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.0.pop().ok_or(ScriptError::InvalidStackOperation)
+    }
 }
 
 impl Default for Stack {
@@ -32,7 +82,7 @@ impl Default for Stack {
 
 // IMPROVEME: make a typed AST. I suggest to move it in `typed_script.rs`
 #[allow(non_camel_case_types, non_snake_case)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Opcode {
     // push value
     /// An empty array of bytes is pushed onto the stack. (This is not a no-op:
@@ -336,12 +386,16 @@ pub enum Opcode {
     // Opcode added by BIP 342 (Tapscript)
     OP_CHECKSIGADD,
 
+    /// Opcodes 0xbb to 0xfe are not assigned. They make the script fail when
+    /// executed, but are valid when occuring in an unexecuted OP_IF branch. In
+    /// tapscript, they are redefined as OP_SUCCESSx by BIP 342.
+    OP_UNKNOWN(u8),
+
     OP_INVALIDOPCODE,
 }
 
-// FIXME: ignore if riscv32i
-impl std::fmt::Display for Opcode {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             // push value
             Opcode::OP_0 => write!(f, "OP_0"),
@@ -483,6 +537,8 @@ impl std::fmt::Display for Opcode {
             // Opcode added by BIP 342 (Tapscript)
             Opcode::OP_CHECKSIGADD => write!(f, "OP_CHECKSIGADD"),
 
+            Opcode::OP_UNKNOWN(_) => write!(f, "OP_UNKNOWN"),
+
             Opcode::OP_INVALIDOPCODE => write!(f, "OP_INVALIDOPCODE"),
         }
     }
@@ -628,7 +684,7 @@ impl From<u8> for Opcode {
 
             // Instruction from 0xbb and 0xfe are reserved for future use
             0xff => Opcode::OP_INVALIDOPCODE,
-            _ => panic!("Invalid opcode"),
+            x => Opcode::OP_UNKNOWN(x),
         }
     }
 }
@@ -786,6 +842,8 @@ impl From<Opcode> for u8 {
             // Opcode added by BIP 342 (Tapscript)
             Opcode::OP_CHECKSIGADD => 0xba,
 
+            Opcode::OP_UNKNOWN(x) => x,
+
             Opcode::OP_INVALIDOPCODE => 0xff,
         }
     }
@@ -821,15 +879,293 @@ impl Opcode {
 pub enum Term {
     Instruction(Opcode),
     Data(Vec<u8>),
+    /// Trailing bytes that can not be decoded into an instruction, e.g. a push
+    /// announcing more bytes than available. Like in the reference
+    /// implementation, such a script is still valid to carry around, but its
+    /// execution fails when reaching these bytes.
+    Invalid(Vec<u8>),
+}
+
+/// Errors that can occur while decoding a script from its raw bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptDecodeError {
+    /// The length prefix of the OP_PUSHDATA{1,2,4} instruction at the given
+    /// offset is cut.
+    TruncatedPushLength { offset: usize },
+    /// The push instruction at the given offset announces more bytes than the
+    /// script contains.
+    TruncatedPush {
+        offset: usize,
+        expected: usize,
+        available: usize,
+    },
+    /// The push instruction at the given offset uses a larger OP_PUSHDATA
+    /// opcode than required for the length of the data.
+    NonMinimalPush { offset: usize },
+}
+
+impl ScriptDecodeError {
+    /// The offset of the instruction that could not be decoded.
+    pub fn offset(&self) -> usize {
+        match self {
+            ScriptDecodeError::TruncatedPushLength { offset }
+            | ScriptDecodeError::TruncatedPush { offset, .. }
+            | ScriptDecodeError::NonMinimalPush { offset } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for ScriptDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptDecodeError::TruncatedPushLength { offset } => {
+                write!(f, "truncated push length at offset {}", offset)
+            }
+            ScriptDecodeError::TruncatedPush {
+                offset,
+                expected,
+                available,
+            } => write!(
+                f,
+                "truncated push at offset {}: expected {} bytes, {} available",
+                offset, expected, available
+            ),
+            ScriptDecodeError::NonMinimalPush { offset } => {
+                write!(f, "non-minimal push at offset {}", offset)
+            }
+        }
+    }
+}
+
+/// Decode the instruction starting at `offset`. On success, return the opcode,
+/// the range of the pushed data if the instruction is a push, and the offset
+/// of the next instruction.
+fn decode_instruction(
+    bytes: &[u8],
+    offset: usize,
+) -> Result<(Opcode, Option<core::ops::Range<usize>>, usize), ScriptDecodeError> {
+    let opcode = bytes[offset];
+    let (opcode, start, length) = match opcode {
+        0x01..=0x4b => (Opcode::OP_PUSHBYTES(opcode), offset + 1, opcode as usize),
+        0x4c => {
+            let b = bytes
+                .get(offset + 1)
+                .ok_or(ScriptDecodeError::TruncatedPushLength { offset })?;
+            (Opcode::OP_PUSHDATA1(*b), offset + 2, *b as usize)
+        }
+        0x4d => {
+            let b: [u8; 2] = bytes
+                .get(offset + 1..offset + 3)
+                .ok_or(ScriptDecodeError::TruncatedPushLength { offset })?
+                .try_into()
+                .unwrap();
+            let length = u16::from_le_bytes(b) as usize;
+            (Opcode::OP_PUSHDATA2(b), offset + 3, length)
+        }
+        0x4e => {
+            let b: [u8; 4] = bytes
+                .get(offset + 1..offset + 5)
+                .ok_or(ScriptDecodeError::TruncatedPushLength { offset })?
+                .try_into()
+                .unwrap();
+            let length = u32::from_le_bytes(b) as usize;
+            (Opcode::OP_PUSHDATA4(b), offset + 5, length)
+        }
+        _ => return Ok((Opcode::from(opcode), None, offset + 1)),
+    };
+    let available = bytes.len() - start.min(bytes.len());
+    if length > available {
+        return Err(ScriptDecodeError::TruncatedPush {
+            offset,
+            expected: length,
+            available,
+        });
+    }
+    Ok((opcode, Some(start..start + length), start + length))
+}
+
+/// Check that the push opcode is the smallest one able to encode `length`
+/// bytes. Using OP_0 or OP_1..OP_16 for small values is not required.
+fn is_minimal_push_encoding(opcode: Opcode, length: usize) -> bool {
+    match opcode {
+        Opcode::OP_PUSHDATA1(_) => length > 0x4b,
+        Opcode::OP_PUSHDATA2(_) => length > 0xff,
+        Opcode::OP_PUSHDATA4(_) => length > 0xffff,
+        _ => true,
+    }
+}
+
+/// A borrowed view over the raw bytes of a script. Contrary to [Script], it
+/// does not allocate: the instructions are decoded lazily and the pushed data
+/// are slices of the underlying bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptRef<'a>(&'a [u8]);
+
+impl<'a> ScriptRef<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the instructions of the script. Each item is the opcode
+    /// with the data it pushes, if any. Decoding stops after the first
+    /// instruction that can not be decoded.
+    pub fn instructions(&self) -> Instructions<'a> {
+        Instructions {
+            bytes: self.0,
+            offset: 0,
+        }
+    }
+
+    /// Same as [ScriptRef::instructions], but also fails on pushes that do not
+    /// use the smallest OP_PUSHDATA opcode possible.
+    pub fn instructions_minimal(
+        &self,
+    ) -> impl Iterator<Item = <Instructions<'a> as Iterator>::Item> {
+        let mut offset = 0;
+        self.instructions().map(move |instruction| {
+            let (opcode, data) = instruction?;
+            if let Some(data) = data {
+                if !is_minimal_push_encoding(opcode, data.len()) {
+                    return Err(ScriptDecodeError::NonMinimalPush { offset });
+                }
+            }
+            offset += instruction_size(opcode, data);
+            Ok((opcode, data))
+        })
+    }
+
+    /// Allocate an owned [Script], decoded as [Script::of_bytes] does.
+    pub fn to_script(&self) -> Script {
+        Script::of_bytes(self.0.to_vec())
+    }
+
+    /// Count the signature operations, as `GetSigOpCount` in the reference
+    /// implementation. OP_CHECKMULTISIG counts as 20 operations, or as the
+    /// number of public keys when `accurate` is set and it is preceded by
+    /// OP_1 to OP_16. Counting stops at the first invalid instruction.
+    pub fn sigop_count(&self, accurate: bool) -> usize {
+        let mut count = 0;
+        let mut last = None;
+        for instruction in self.instructions() {
+            let Ok((opcode, _)) = instruction else {
+                break;
+            };
+            match opcode {
+                Opcode::OP_CHECKSIG | Opcode::OP_CHECKSIGVERIFY => count += 1,
+                Opcode::OP_CHECKMULTISIG | Opcode::OP_CHECKMULTISIGVERIFY => {
+                    count += match last.map(u8::from) {
+                        Some(n @ 0x51..=0x60) if accurate => (n - 0x50) as usize,
+                        _ => 20,
+                    }
+                }
+                _ => {}
+            }
+            last = Some(opcode);
+        }
+        count
+    }
+
+    /// Whether the script only pushes data. As in the reference
+    /// implementation, OP_RESERVED counts as a push.
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| {
+            matches!(instruction, Ok((opcode, _)) if u8::from(opcode) <= u8::from(Opcode::OP_16))
+        })
+    }
+
+    /// Whether the script is a pay-to-script-hash output (BIP 16):
+    /// `OP_HASH160 <20 bytes> OP_EQUAL`.
+    pub fn is_p2sh(&self) -> bool {
+        self.0.len() == 23 && self.0[0] == 0xa9 && self.0[1] == 0x14 && self.0[22] == 0x87
+    }
+
+    /// The version and the program of a witness program output (BIP 141): a
+    /// push of the version, from OP_0 to OP_16, followed by a push of 2 to 40
+    /// bytes.
+    pub fn witness_program(&self) -> Option<(u8, &'a [u8])> {
+        let bytes = self.0;
+        if !(4..=42).contains(&bytes.len()) || bytes[1] as usize + 2 != bytes.len() {
+            return None;
+        }
+        match bytes[0] {
+            0x00 => Some((0, &bytes[2..])),
+            v @ 0x51..=0x60 => Some((v - 0x50, &bytes[2..])),
+            _ => None,
+        }
+    }
+
+    /// The data pushed by the last instruction, which is empty for opcodes
+    /// pushing no data such as OP_1. For a pay-to-script-hash input, this is
+    /// the redeem script.
+    pub fn last_push(&self) -> Option<&'a [u8]> {
+        let mut last = None;
+        for instruction in self.instructions() {
+            let (_, data) = instruction.ok()?;
+            last = Some(data.unwrap_or(&[]));
+        }
+        last
+    }
+}
+
+/// The number of bytes used to encode the given instruction.
+fn instruction_size(opcode: Opcode, data: Option<&[u8]>) -> usize {
+    let prefix = match opcode {
+        Opcode::OP_PUSHDATA1(_) => 2,
+        Opcode::OP_PUSHDATA2(_) => 3,
+        Opcode::OP_PUSHDATA4(_) => 5,
+        _ => 1,
+    };
+    prefix + data.map_or(0, |d| d.len())
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Iterator over the instructions of a [ScriptRef].
+#[derive(Debug, Clone)]
+pub struct Instructions<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<(Opcode, Option<&'a [u8]>), ScriptDecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        match decode_instruction(self.bytes, self.offset) {
+            Ok((opcode, data, next)) => {
+                self.offset = next;
+                Some(Ok((opcode, data.map(|r| &self.bytes[r]))))
+            }
+            Err(e) => {
+                // Nothing can be decoded after an invalid instruction
+                self.offset = self.bytes.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl core::iter::FusedIterator for Instructions<'_> {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Script(Vec<Term>);
 
-// FIXME: ignore if riscv32i
-impl std::fmt::Display for Script {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let mut s = Vec::new();
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s: Vec<String> = Vec::new();
         for term in &self.0 {
             match term {
                 Term::Instruction(op) => s.push(format!("{}", op)),
@@ -837,6 +1173,7 @@ impl std::fmt::Display for Script {
                     let data = hex::encode(data);
                     s.push(format!("0x{}", data));
                 }
+                Term::Invalid(_) => s.push("[error]".to_string()),
             }
         }
         write!(f, "{}", s.join(" "))
@@ -848,34 +1185,7 @@ impl Serialize for Script {
     where
         S: Serializer,
     {
-        let mut t: Vec<u8> = vec![];
-        self.0.iter().for_each(|c| match c {
-            Term::Instruction(op) => match op {
-                Opcode::OP_PUSHDATA1(x) => {
-                    t.push(u8::from(*op));
-                    t.push(*x);
-                }
-                Opcode::OP_PUSHDATA2([x1, x2]) => {
-                    t.push(u8::from(*op));
-                    t.push(*x1);
-                    t.push(*x2);
-                }
-                Opcode::OP_PUSHDATA4([x1, x2, x3, x4]) => {
-                    t.push(u8::from(*op));
-                    t.push(*x1);
-                    t.push(*x2);
-                    t.push(*x3);
-                    t.push(*x4);
-                }
-                _ => {
-                    t.push(u8::from(*op));
-                }
-            },
-            Term::Data(data) => {
-                t.extend(data);
-            }
-        });
-        serializer.serialize_bytes(&t)
+        serializer.serialize_bytes(&self.to_bytes())
     }
 }
 
@@ -885,144 +1195,413 @@ impl<'de> Deserialize<'de> for Script {
         D: Deserializer<'de>,
     {
         let data = Vec::<u8>::deserialize(deserializer)?;
-        let mut terms = vec![];
-        let mut i = 0;
-        while i < data.len() {
-            let opcode = data[i];
-            if opcode == 0 {
-                terms.push(Term::Instruction(Opcode::OP_0));
-                i += 1;
-            } else if opcode <= 75 {
-                // This is a OP_PUSHBYTES. We create the OP_PUSHBYTES opcode and the
-                // next {opcode} bytes are the data
-                terms.push(Term::Instruction(Opcode::OP_PUSHBYTES(opcode)));
-                i += 1;
-                let local_data = data[i..i + opcode as usize].to_vec();
-                i += opcode as usize;
-                terms.push(Term::Data(local_data));
-            } else if opcode == 0x4c {
-                let nb_bytes = data[i + 1];
-                assert!(nb_bytes >= 76);
-                let local_data = data[i + 2..i + 2 + nb_bytes as usize].to_vec();
-                i += 1 + 1 + nb_bytes as usize;
-                terms.push(Term::Instruction(Opcode::OP_PUSHDATA1(nb_bytes)));
-                terms.push(Term::Data(local_data));
-            } else if opcode == 0x4d {
-                let b1 = data[i + 1];
-                let b2 = data[i + 2];
-                let mut nb_bytes: u64 = b1.into();
-                nb_bytes = (nb_bytes << 8) + (b2 as u64);
-                nb_bytes <<= 8;
-                let local_data = data[i + 3..i + 3 + nb_bytes as usize].to_vec();
-                i += 2 + 1 + nb_bytes as usize;
-                terms.push(Term::Instruction(Opcode::OP_PUSHDATA2([b1, b2])));
-                terms.push(Term::Data(local_data));
-            } else if opcode == 0x4e {
-                let b1 = data[i + 1];
-                let b2 = data[i + 2];
-                let b3 = data[i + 3];
-                let b4 = data[i + 4];
-                let mut nb_bytes: u64 = b1.into();
-                nb_bytes = (nb_bytes << 8) + (b2 as u64);
-                nb_bytes = (nb_bytes << 8) + (b3 as u64);
-                nb_bytes = (nb_bytes << 8) + (b4 as u64);
-                nb_bytes <<= 8;
-                let local_data = data[i + 5..i + 5 + nb_bytes as usize].to_vec();
-                i += 5 + 1 + nb_bytes as usize;
-                terms.push(Term::Instruction(Opcode::OP_PUSHDATA4([b1, b2, b3, b4])));
-                terms.push(Term::Data(local_data));
-            } else {
-                terms.push(Term::Instruction(Opcode::from(opcode)));
-                i += 1;
-            }
-        }
-        Ok(Script(terms))
+        Script::try_from_bytes(&data).map_err(serde::de::Error::custom)
     }
 }
 
 impl Script {
     pub fn to_bytes(&self) -> Vec<u8> {
-        let x = bincode::serialize(&self).unwrap();
-        x[8..].to_vec()
+        let mut t: Vec<u8> = vec![];
+        self.0.iter().for_each(|c| match c {
+            Term::Instruction(op) => {
+                t.push(u8::from(*op));
+                match op {
+                    Opcode::OP_PUSHDATA1(x) => t.push(*x),
+                    Opcode::OP_PUSHDATA2(x) => t.extend(x),
+                    Opcode::OP_PUSHDATA4(x) => t.extend(x),
+                    _ => (),
+                }
+            }
+            Term::Data(data) | Term::Invalid(data) => {
+                t.extend(data);
+            }
+        });
+        t
     }
 
+    /// Decode a script as the reference implementation does: pushes using a
+    /// non-minimal encoding are kept as is, and the bytes starting at the
+    /// first instruction that can not be decoded are kept as a
+    /// [Term::Invalid]. It never fails, and re-encoding the script with
+    /// [Script::to_bytes] gives back the original bytes.
     pub fn of_bytes(bytes: Vec<u8>) -> Self {
-        let length: u64 = bytes.len().try_into().unwrap();
-        let mut bytes_with_length: Vec<u8> = length.to_le_bytes().to_vec();
-        bytes_with_length.extend(bytes);
-        bincode::deserialize(&bytes_with_length).unwrap()
+        let mut terms = vec![];
+        for instruction in ScriptRef::new(&bytes).instructions() {
+            match instruction {
+                Ok((opcode, data)) => {
+                    terms.push(Term::Instruction(opcode));
+                    if let Some(data) = data {
+                        terms.push(Term::Data(data.to_vec()));
+                    }
+                }
+                Err(e) => terms.push(Term::Invalid(bytes[e.offset()..].to_vec())),
+            }
+        }
+        Self(terms)
+    }
+
+    /// Decode a script, failing if an instruction is truncated or if a push
+    /// does not use the smallest OP_PUSHDATA opcode possible.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ScriptDecodeError> {
+        let mut terms = vec![];
+        for instruction in ScriptRef::new(bytes).instructions_minimal() {
+            let (opcode, data) = instruction?;
+            terms.push(Term::Instruction(opcode));
+            if let Some(data) = data {
+                terms.push(Term::Data(data.to_vec()));
+            }
+        }
+        Ok(Self(terms))
     }
 
     pub fn new(instr: Vec<Term>) -> Self {
         Self(instr)
     }
 
+    /// Interpret the script as a legacy script, succeeding if the stack is
+    /// empty at the end.
     pub fn interpret(&self, stack: Stack) -> bool {
-        let mut stack = stack.clone();
-        let mut exp_bytes: Option<usize> = None;
-        // FIXME: remove clone
-        for c in self.0.clone() {
-            println!("Interpreting {:?}", c);
-            match c {
-                Term::Data(v) => {
-                    if exp_bytes.is_none() {
-                        // A "push value" pcode should have been used just before.
-                        return false;
-                    } else {
-                        let data = v.to_vec();
-                        let exp_data_length = exp_bytes.unwrap();
-                        if exp_data_length != data.len() {
-                            // Wrong data length
-                            return false;
-                        } else {
-                            stack.0.push(data)
-                        }
+        matches!(self.eval(stack, SigVersion::Base, &BaseSignatureChecker, None), Ok(stack) if stack.is_empty())
+    }
+
+    /// Interpret the script like [Script::interpret], recording the state of
+    /// the interpreter after each term.
+    pub fn interpret_with_trace(&self, stack: Stack) -> (bool, ExecutionTrace) {
+        let mut trace = ExecutionTrace::new();
+        let result = self.eval(
+            stack,
+            SigVersion::Base,
+            &BaseSignatureChecker,
+            Some(&mut trace),
+        );
+        (matches!(result, Ok(stack) if stack.is_empty()), trace)
+    }
+
+    /// Execute the script on the given stack and return the resulting stack,
+    /// like `EvalScript` in the reference implementation. The resource limits
+    /// depend on `sig_version`: tapscript is exempt from the script size and
+    /// the operations count limits (BIP 342). The checks depending on the
+    /// spending transaction are delegated to `checker`. If `trace` is given,
+    /// the state of the interpreter is recorded after each term.
+    pub fn eval<C: SignatureChecker + ?Sized>(
+        &self,
+        stack: Stack,
+        sig_version: SigVersion,
+        checker: &C,
+        mut trace: Option<&mut ExecutionTrace>,
+    ) -> Result<Stack, ScriptError> {
+        let legacy_limits = sig_version != SigVersion::Tapscript;
+        if legacy_limits && self.0.iter().map(term_size).sum::<usize>() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize);
+        }
+        // The witness stack is checked before the execution, as in
+        // `ExecuteWitnessScript` of the reference implementation
+        if sig_version != SigVersion::Base {
+            if stack.0.iter().any(|v| v.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+                return Err(ScriptError::PushSize);
+            }
+            if sig_version == SigVersion::Tapscript && stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptError::StackSize);
+            }
+        }
+        let mut state = ExecutionState {
+            stack,
+            alt_stack: Stack::new(),
+            exec_stack: vec![],
+            exp_bytes: None,
+        };
+        let mut pc = 0;
+        let mut op_count = 0;
+        for term in &self.0 {
+            let executed = state.exec_stack.iter().all(|b| *b);
+            let mut result = state.step(term, executed, checker);
+            if let Term::Instruction(opcode) = term {
+                // Operations are counted even in skipped branches
+                if legacy_limits && u8::from(*opcode) > u8::from(Opcode::OP_16) {
+                    op_count += 1;
+                    if op_count > MAX_OPS_PER_SCRIPT && result.is_ok() {
+                        result = Err(ScriptError::OpCount);
+                    }
+                }
+            }
+            if result.is_ok() && state.stack.len() + state.alt_stack.len() > MAX_STACK_SIZE {
+                result = Err(ScriptError::StackSize);
+            }
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.steps.push(TraceStep {
+                    pc,
+                    opcode: match term {
+                        Term::Instruction(opcode) => Some(*opcode),
+                        _ => None,
+                    },
+                    executed,
+                    stack: state.stack.0.clone(),
+                    alt_stack: state.alt_stack.0.clone(),
+                    exec_stack: state.exec_stack.clone(),
+                    error: result.err(),
+                });
+            }
+            result?;
+            pc += term_size(term);
+        }
+        // Every OP_IF and OP_NOTIF must be closed by an OP_ENDIF
+        if !state.exec_stack.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        Ok(state.stack)
+    }
+}
+
+/// The number of bytes used to encode the term.
+fn term_size(term: &Term) -> usize {
+    match term {
+        Term::Instruction(opcode) => instruction_size(*opcode, None),
+        Term::Data(data) | Term::Invalid(data) => data.len(),
+    }
+}
+
+/// Interpret the top stack element as a boolean. Any non-zero value is true,
+/// except the negative zero.
+fn cast_to_bool(value: &[u8]) -> bool {
+    match value.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// Decode a number of at most `max_size` bytes, encoded in little endian with
+/// the sign in the most significant bit, like `CScriptNum`. Non-minimal
+/// encodings are accepted.
+fn decode_script_num(bytes: &[u8], max_size: usize) -> Result<i64, ScriptError> {
+    if bytes.len() > max_size {
+        return Err(ScriptError::NumberOverflow);
+    }
+    let Some(last) = bytes.last() else {
+        return Ok(0);
+    };
+    let mut value: i64 = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        value |= (*b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        let sign_bit = 0x80i64 << (8 * (bytes.len() - 1));
+        Ok(-(value & !sign_bit))
+    } else {
+        Ok(value)
+    }
+}
+
+struct ExecutionState {
+    stack: Stack,
+    alt_stack: Stack,
+    /// The conditions of the enclosing branches.
+    exec_stack: Vec<bool>,
+    /// The number of bytes the previous push instruction announced.
+    exp_bytes: Option<usize>,
+}
+
+impl ExecutionState {
+    /// Execute a term.
+    fn step<C: SignatureChecker + ?Sized>(
+        &mut self,
+        term: &Term,
+        executed: bool,
+        checker: &C,
+    ) -> Result<(), ScriptError> {
+        match term {
+            Term::Data(v) => {
+                if v.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(ScriptError::PushSize);
+                }
+                // A "push value" opcode announcing the length of the data
+                // should have been used just before.
+                if self.exp_bytes.take() != Some(v.len()) {
+                    return Err(ScriptError::BadOpcode);
+                }
+                if executed {
+                    self.stack.0.push(v.to_vec())
+                }
+            }
+            // Undecodable bytes make the execution fail
+            Term::Invalid(_) => return Err(ScriptError::BadOpcode),
+            Term::Instruction(opcode) => match opcode {
+                Opcode::OP_PUSHBYTES(n) => {
+                    self.exp_bytes = Some((*n).into());
+                }
+                Opcode::OP_PUSHDATA1(n) => {
+                    self.exp_bytes = Some((*n).into());
+                }
+                Opcode::OP_PUSHDATA2(n) => {
+                    self.exp_bytes = Some(u16::from_le_bytes(*n).into());
+                }
+                Opcode::OP_PUSHDATA4(n) => {
+                    self.exp_bytes = Some(u32::from_le_bytes(*n) as usize);
+                }
+                Opcode::OP_IF | Opcode::OP_NOTIF => {
+                    let mut condition = false;
+                    if executed {
+                        let top = self.stack.pop()?;
+                        condition = cast_to_bool(&top) == (*opcode == Opcode::OP_IF);
                     }
+                    self.exec_stack.push(condition);
                 }
-                Term::Instruction(opcode) => match opcode {
-                    Opcode::OP_0 => stack.0.push(vec![0]),
-                    Opcode::OP_FALSE => stack.0.push(vec![0]),
-                    Opcode::OP_PUSHBYTES(n) => {
-                        exp_bytes = Some(n.into());
+                Opcode::OP_ELSE => match self.exec_stack.last_mut() {
+                    Some(condition) => *condition = !*condition,
+                    None => return Err(ScriptError::UnbalancedConditional),
+                },
+                Opcode::OP_ENDIF => {
+                    if self.exec_stack.pop().is_none() {
+                        return Err(ScriptError::UnbalancedConditional);
                     }
-                    Opcode::OP_DUP => {
-                        let hd = stack.0[0].clone();
-                        stack.0.push(hd);
+                }
+                // Invalid even in a skipped branch
+                Opcode::OP_VERIF | Opcode::OP_VERNOTIF => return Err(ScriptError::BadOpcode),
+                Opcode::OP_CAT
+                | Opcode::OP_SUBSTR
+                | Opcode::OP_LEFT
+                | Opcode::OP_RIGHT
+                | Opcode::OP_INVERT
+                | Opcode::OP_AND
+                | Opcode::OP_OR
+                | Opcode::OP_XOR
+                | Opcode::OP_2MUL
+                | Opcode::OP_2DIV
+                | Opcode::OP_MUL
+                | Opcode::OP_DIV
+                | Opcode::OP_MOD
+                | Opcode::OP_LSHIFT
+                | Opcode::OP_RSHIFT => return Err(ScriptError::DisabledOpcode),
+                _ if !executed => (),
+                Opcode::OP_0 => self.stack.0.push(vec![0]),
+                Opcode::OP_FALSE => self.stack.0.push(vec![0]),
+                Opcode::OP_1NEGATE => self.stack.0.push(vec![0x81]),
+                // OP_1 to OP_16
+                op if (0x51..=0x60).contains(&u8::from(*op)) => {
+                    self.stack.0.push(vec![u8::from(*op) - 0x50])
+                }
+                Opcode::OP_NOP => (),
+                // Reserved for upgrades, as OP_NOP2 and OP_NOP3 were
+                Opcode::OP_NOP1
+                | Opcode::OP_NOP4
+                | Opcode::OP_NOP5
+                | Opcode::OP_NOP6
+                | Opcode::OP_NOP7
+                | Opcode::OP_NOP8
+                | Opcode::OP_NOP9
+                | Opcode::OP_NOP10 => (),
+                Opcode::OP_DROP => {
+                    self.stack.pop()?;
+                }
+                Opcode::OP_CHECKLOCKTIMEVERIFY | Opcode::OP_NOP2 => {
+                    // The lock time is left on the stack, as the opcode was a
+                    // no-op before BIP 65. Numbers of 5 bytes are allowed for
+                    // timestamps beyond 2^31.
+                    let top = self
+                        .stack
+                        .0
+                        .last()
+                        .ok_or(ScriptError::InvalidStackOperation)?;
+                    let lock_time = decode_script_num(top, 5)?;
+                    if lock_time < 0 {
+                        return Err(ScriptError::NegativeLockTime);
                     }
-                    Opcode::OP_HASH160 => {
-                        let hd = stack.0.pop().unwrap();
-                        let res = Sha256::digest(&hd);
-                        let mut hasher = Ripemd160::new();
-                        hasher.update(res);
-                        let result = hasher.finalize();
-                        stack.0.push(result.to_vec());
+                    let satisfied = u32::try_from(lock_time)
+                        .is_ok_and(|n| checker.check_lock_time(LockTime::from_consensus(n)));
+                    if !satisfied {
+                        return Err(ScriptError::UnsatisfiedLockTime);
                     }
-                    Opcode::OP_EQUALVERIFY => {
-                        let lhs = stack.0.pop().unwrap();
-                        println!("Lhs: {:?}", lhs);
-                        let rhs = stack.0.pop().unwrap();
-                        println!("Rhs: {:?}", rhs);
-                        let is_equal = lhs.len() == rhs.len()
-                            && lhs.iter().zip(rhs.iter()).all(|(x, y)| x == y);
-                        println!("Is_equal: {is_equal}");
-                        stack.0.push(vec![is_equal as u8]);
-                        let res = stack.0.pop().unwrap();
-                        let is_true = res.len() == 1 && res[0] == 1;
-                        if !is_true {
-                            return false;
+                }
+                Opcode::OP_CHECKSEQUENCEVERIFY | Opcode::OP_NOP3 => {
+                    let top = self
+                        .stack
+                        .0
+                        .last()
+                        .ok_or(ScriptError::InvalidStackOperation)?;
+                    let sequence = decode_script_num(top, 5)?;
+                    if sequence < 0 {
+                        return Err(ScriptError::NegativeLockTime);
+                    }
+                    // With the disable flag, the opcode behaves as a no-op.
+                    // Only the low 32 bits are used otherwise.
+                    let sequence = sequence as u32;
+                    if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0 {
+                        let lock_time = RelativeLockTime::from_sequence(sequence).unwrap();
+                        if !checker.check_sequence(lock_time) {
+                            return Err(ScriptError::UnsatisfiedLockTime);
                         }
                     }
-                    _ => unimplemented!("The opcode {opcode} is not implemented"),
+                }
+                Opcode::OP_TOALTSTACK => {
+                    let v = self.stack.pop()?;
+                    self.alt_stack.0.push(v);
+                }
+                Opcode::OP_FROMALTSTACK => match self.alt_stack.0.pop() {
+                    Some(v) => self.stack.0.push(v),
+                    None => return Err(ScriptError::InvalidAltstackOperation),
                 },
-            }
+                Opcode::OP_DUP => {
+                    let hd = self
+                        .stack
+                        .0
+                        .last()
+                        .ok_or(ScriptError::InvalidStackOperation)?
+                        .clone();
+                    self.stack.0.push(hd);
+                }
+                Opcode::OP_HASH160 => {
+                    let hd = self.stack.pop()?;
+                    let res = Sha256::digest(&hd);
+                    let mut hasher = Ripemd160::new();
+                    hasher.update(res);
+                    let result = hasher.finalize();
+                    self.stack.0.push(result.to_vec());
+                }
+                Opcode::OP_VERIFY => {
+                    if !cast_to_bool(&self.stack.pop()?) {
+                        return Err(ScriptError::Verify);
+                    }
+                }
+                Opcode::OP_RETURN => return Err(ScriptError::OpReturn),
+                Opcode::OP_EQUAL => {
+                    let lhs = self.stack.pop()?;
+                    let rhs = self.stack.pop()?;
+                    self.stack.0.push(if lhs == rhs { vec![1] } else { vec![] });
+                }
+                Opcode::OP_EQUALVERIFY => {
+                    let lhs = self.stack.pop()?;
+                    let rhs = self.stack.pop()?;
+                    if lhs != rhs {
+                        return Err(ScriptError::EqualVerify);
+                    }
+                }
+                // Unknown and invalid opcodes, and the opcodes not supported
+                // yet, make the script fail
+                _ => return Err(ScriptError::BadOpcode),
+            },
         }
-        stack.0.is_empty()
+        Ok(())
+    }
+}
+
+/// A script is encoded as its raw bytes prefixed by their length.
+impl Encodable for Script {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, encode::Error> {
+        encode::encode_bytes(&self.to_bytes(), w)
+    }
+}
+
+impl Decodable for Script {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        Ok(Script::of_bytes(Vec::<u8>::consensus_decode(r)?))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::interpreter::TransactionSignatureChecker;
+    use crate::locktime::Sequence;
+    use crate::transaction::{Transaction, TransactionInput};
     use bincode::{deserialize, serialize};
     use hex;
 
@@ -1125,6 +1704,179 @@ mod tests {
         assert_eq!(Script::of_bytes(script), exp_script)
     }
 
+    #[test]
+    pub fn test_try_from_bytes_truncated_push() {
+        // OP_DUP OP_HASH160 OP_PUSHBYTES20 followed by only 3 bytes
+        let bytes = hex::decode("76a91455ae51").unwrap();
+        assert_eq!(
+            Script::try_from_bytes(&bytes),
+            Err(ScriptDecodeError::TruncatedPush {
+                offset: 2,
+                expected: 20,
+                available: 3
+            })
+        );
+        // OP_PUSHDATA2 with a single length byte
+        let bytes = hex::decode("4d01").unwrap();
+        assert_eq!(
+            Script::try_from_bytes(&bytes),
+            Err(ScriptDecodeError::TruncatedPushLength { offset: 0 })
+        );
+    }
+
+    #[test]
+    pub fn test_try_from_bytes_non_minimal_push() {
+        // 2 bytes pushed with OP_PUSHDATA1 instead of OP_PUSHBYTES2
+        let bytes = hex::decode("4c02abcd").unwrap();
+        assert_eq!(
+            Script::try_from_bytes(&bytes),
+            Err(ScriptDecodeError::NonMinimalPush { offset: 0 })
+        );
+        // The consensus decoding keeps it as is
+        let script = Script::of_bytes(bytes.clone());
+        assert_eq!(
+            script,
+            Script::new(vec![
+                Term::Instruction(Opcode::OP_PUSHDATA1(2)),
+                Term::Data(vec![0xab, 0xcd]),
+            ])
+        );
+        assert_eq!(script.to_bytes(), bytes);
+    }
+
+    #[test]
+    pub fn test_of_bytes_trailing_invalid_bytes() {
+        // OP_0 OP_PUSHBYTES5 followed by only 2 bytes
+        let bytes = hex::decode("000501ff").unwrap();
+        let script = Script::of_bytes(bytes.clone());
+        assert_eq!(
+            script,
+            Script::new(vec![
+                Term::Instruction(Opcode::OP_0),
+                Term::Invalid(vec![0x05, 0x01, 0xff]),
+            ])
+        );
+        assert_eq!(script.to_bytes(), bytes);
+        assert!(!script.interpret(Stack::new()));
+    }
+
+    #[test]
+    pub fn test_of_bytes_unknown_opcode() {
+        let bytes = vec![0xbb, 0xfe, 0xff];
+        let script = Script::of_bytes(bytes.clone());
+        assert_eq!(
+            script,
+            Script::new(vec![
+                Term::Instruction(Opcode::OP_UNKNOWN(0xbb)),
+                Term::Instruction(Opcode::OP_UNKNOWN(0xfe)),
+                Term::Instruction(Opcode::OP_INVALIDOPCODE),
+            ])
+        );
+        assert_eq!(script.to_bytes(), bytes);
+    }
+
+    #[test]
+    pub fn test_script_ref_instructions() {
+        let bytes = hex::decode("76a91455ae51684c43435da751ac8d2173b2652eb6410588ac").unwrap();
+        let script = ScriptRef::new(&bytes);
+        let instructions: Vec<_> = script.instructions().map(|i| i.unwrap()).collect();
+        assert_eq!(
+            instructions,
+            vec![
+                (Opcode::OP_DUP, None),
+                (Opcode::OP_HASH160, None),
+                (Opcode::OP_PUSHBYTES(20), Some(&bytes[3..23])),
+                (Opcode::OP_EQUALVERIFY, None),
+                (Opcode::OP_CHECKSIG, None),
+            ]
+        );
+        // The pushed data is borrowed from the script bytes
+        assert_eq!(instructions[2].1.unwrap().as_ptr(), bytes[3..].as_ptr());
+        assert_eq!(script.to_script(), Script::of_bytes(bytes.clone()));
+    }
+
+    #[test]
+    pub fn test_script_ref_instructions_stop_on_error() {
+        // OP_0 OP_PUSHDATA1 (3 bytes) with 2 bytes left, then OP_1
+        let bytes = hex::decode("004c03abcd51").unwrap();
+        let mut instructions = ScriptRef::new(&bytes).instructions();
+        assert_eq!(instructions.next(), Some(Ok((Opcode::OP_0, None))));
+        assert_eq!(
+            instructions.next(),
+            Some(Ok((Opcode::OP_PUSHDATA1(3), Some(&bytes[3..6]))))
+        );
+        assert_eq!(instructions.next(), None);
+
+        let bytes = hex::decode("004c04abcd51").unwrap();
+        let mut instructions = ScriptRef::new(&bytes).instructions();
+        assert_eq!(instructions.next(), Some(Ok((Opcode::OP_0, None))));
+        assert_eq!(
+            instructions.next(),
+            Some(Err(ScriptDecodeError::TruncatedPush {
+                offset: 1,
+                expected: 4,
+                available: 3
+            }))
+        );
+        assert_eq!(instructions.next(), None);
+
+        let mut instructions = ScriptRef::new(&bytes[..4]).instructions_minimal();
+        assert_eq!(instructions.next(), Some(Ok((Opcode::OP_0, None))));
+        assert!(matches!(
+            instructions.next(),
+            Some(Err(ScriptDecodeError::TruncatedPush { offset: 1, .. }))
+        ));
+    }
+
+    #[test]
+    pub fn test_script_ref_classification() {
+        // 2-of-3 multisig
+        let mut multisig = vec![0x52];
+        for _ in 0..3 {
+            multisig.push(33);
+            multisig.extend([0x02; 33]);
+        }
+        multisig.extend([0x53, 0xae]);
+        let multisig = ScriptRef::new(&multisig);
+        assert_eq!(multisig.sigop_count(true), 3);
+        assert_eq!(multisig.sigop_count(false), 20);
+        assert!(!multisig.is_push_only());
+        // Counting stops at the truncated push
+        assert_eq!(ScriptRef::new(&[0xac, 0xad, 0x4c]).sigop_count(false), 2);
+
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend([0x42; 20]);
+        p2sh.push(0x87);
+        assert!(ScriptRef::new(&p2sh).is_p2sh());
+        assert_eq!(ScriptRef::new(&p2sh).witness_program(), None);
+
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([0x42; 20]);
+        assert_eq!(
+            ScriptRef::new(&p2wpkh).witness_program(),
+            Some((0, &[0x42; 20][..]))
+        );
+        let p2tr = [0x51, 0x20]
+            .into_iter()
+            .chain([0x07; 32])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ScriptRef::new(&p2tr).witness_program(),
+            Some((1, &[0x07; 32][..]))
+        );
+
+        let script_sig = [0x00, 0x51, 0x02, 0xab, 0xcd];
+        let script_sig = ScriptRef::new(&script_sig);
+        assert!(script_sig.is_push_only());
+        assert_eq!(script_sig.last_push(), Some(&[0xab, 0xcd][..]));
+        assert_eq!(
+            ScriptRef::new(&[0x01, 0xab, 0x51]).last_push(),
+            Some(&[][..])
+        );
+        assert_eq!(ScriptRef::new(&[]).last_push(), None);
+        assert_eq!(ScriptRef::new(&[0x02, 0xab]).last_push(), None);
+    }
+
     #[test]
     pub fn test_decode_pushdata1() {
         let data = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
@@ -1221,7 +1973,6 @@ mod tests {
         assert_eq!(script, script2);
     }
 
-    // FIXME: ignore if riscv32i
     #[test]
     pub fn test_display_asm() {
         let data = "5468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73";
@@ -1241,6 +1992,353 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn test_interpreter_trace() {
+        // OP_IF 0xaa OP_TOALTSTACK OP_ELSE 0xbb OP_ENDIF OP_FROMALTSTACK 0xaa
+        // OP_EQUALVERIFY
+        let script = Script::of_bytes(hex::decode("6301aa6b6701bb686c01aa88").unwrap());
+        let mut initial_stack = Stack::new();
+        initial_stack.push(vec![1]);
+        let (success, trace) = script.interpret_with_trace(initial_stack.clone());
+        assert!(success);
+        assert_eq!(trace.steps.len(), 12);
+        let pcs: Vec<usize> = trace.steps.iter().map(|s| s.pc).collect();
+        assert_eq!(pcs, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(trace.steps[0].opcode, Some(Opcode::OP_IF));
+        assert_eq!(trace.steps[0].exec_stack, vec![true]);
+        assert_eq!(trace.steps[2].opcode, None);
+        assert_eq!(trace.steps[2].stack, vec![vec![0xaa]]);
+        assert_eq!(trace.steps[3].alt_stack, vec![vec![0xaa]]);
+        assert_eq!(trace.steps[4].exec_stack, vec![false]);
+        // The other branch is skipped
+        assert!(!trace.steps[6].executed);
+        assert!(trace.steps[6].stack.is_empty());
+        assert!(trace.steps[7].exec_stack.is_empty());
+        assert_eq!(trace.steps[8].stack, vec![vec![0xaa]]);
+        assert!(trace.steps[11].stack.is_empty());
+
+        // The trace is deterministic and can be serialized
+        assert_eq!(script.interpret_with_trace(initial_stack).1, trace);
+        let bytes = serialize(&trace).unwrap();
+        assert_eq!(deserialize::<ExecutionTrace>(&bytes).unwrap(), trace);
+
+        let mut initial_stack = Stack::new();
+        initial_stack.push(vec![0x80]);
+        let (success, trace) = script.interpret_with_trace(initial_stack);
+        assert!(!success);
+        // The failing step is the last one
+        let last = trace.steps.last().unwrap();
+        assert_eq!(last.opcode, Some(Opcode::OP_FROMALTSTACK));
+        assert_eq!(last.error, Some(ScriptError::InvalidAltstackOperation));
+        assert!(trace.steps[..trace.steps.len() - 1]
+            .iter()
+            .all(|s| s.error.is_none()));
+
+        // Unknown opcodes give a trace as well: OP_1 OP_UNKNOWN
+        let script = Script::of_bytes(hex::decode("51bb").unwrap());
+        let (success, trace) = script.interpret_with_trace(Stack::new());
+        assert!(!success);
+        assert_eq!(trace.steps.len(), 2);
+        assert_eq!(trace.steps[1].pc, 1);
+        assert_eq!(trace.steps[1].opcode, Some(Opcode::OP_UNKNOWN(0xbb)));
+        assert_eq!(trace.steps[1].stack, vec![vec![1]]);
+        assert_eq!(trace.steps[1].error, Some(ScriptError::BadOpcode));
+    }
+
+    #[test]
+    pub fn test_interpreter_unbalanced_conditional() {
+        let mut initial_stack = Stack::new();
+        initial_stack.push(vec![1]);
+        let script = Script::of_bytes(hex::decode("63").unwrap());
+        assert!(!script.interpret(initial_stack.clone()));
+        let script = Script::of_bytes(hex::decode("6768").unwrap());
+        assert!(!script.interpret(initial_stack));
+    }
+
+    fn stack_of(elements: Vec<Vec<u8>>) -> Stack {
+        let mut stack = Stack::new();
+        for element in elements {
+            stack.push(element);
+        }
+        stack
+    }
+
+    #[test]
+    pub fn test_interpreter_script_size_and_op_count() {
+        let stack = stack_of(vec![vec![1]]);
+        // OP_TOALTSTACK OP_FROMALTSTACK, repeated
+        let script = Script::of_bytes(hex::decode("6b6c".repeat(5001)).unwrap());
+        assert_eq!(
+            script.eval(stack.clone(), SigVersion::Base, &BaseSignatureChecker, None),
+            Err(ScriptError::ScriptSize)
+        );
+        assert_eq!(
+            script.eval(
+                stack.clone(),
+                SigVersion::Tapscript,
+                &BaseSignatureChecker,
+                None
+            ),
+            Ok(stack.clone())
+        );
+
+        let script = Script::of_bytes(hex::decode("6b6c".repeat(101)).unwrap());
+        assert_eq!(
+            script.eval(
+                stack.clone(),
+                SigVersion::WitnessV0,
+                &BaseSignatureChecker,
+                None
+            ),
+            Err(ScriptError::OpCount)
+        );
+        assert_eq!(
+            script.eval(
+                stack.clone(),
+                SigVersion::Tapscript,
+                &BaseSignatureChecker,
+                None
+            ),
+            Ok(stack)
+        );
+        let script = Script::of_bytes(hex::decode("6b6c".repeat(100)).unwrap());
+        assert!(script
+            .eval(
+                stack_of(vec![vec![1]]),
+                SigVersion::Base,
+                &BaseSignatureChecker,
+                None
+            )
+            .is_ok());
+        // Operations in skipped branches are counted
+        let script = Script::of_bytes(hex::decode(format!("63{}68", "6b6c".repeat(100))).unwrap());
+        assert_eq!(
+            script.eval(
+                stack_of(vec![vec![]]),
+                SigVersion::Base,
+                &BaseSignatureChecker,
+                None
+            ),
+            Err(ScriptError::OpCount)
+        );
+    }
+
+    #[test]
+    pub fn test_interpreter_element_and_stack_size() {
+        // OP_PUSHDATA2 with 521 bytes
+        let script = Script::of_bytes(hex::decode(format!("4d0902{}", "aa".repeat(521))).unwrap());
+        for sig_version in [SigVersion::Base, SigVersion::Tapscript] {
+            assert_eq!(
+                script.eval(Stack::new(), sig_version, &BaseSignatureChecker, None),
+                Err(ScriptError::PushSize)
+            );
+        }
+        let empty = Script::new(vec![]);
+        let large = stack_of(vec![vec![0; MAX_SCRIPT_ELEMENT_SIZE + 1]]);
+        assert!(empty
+            .eval(large.clone(), SigVersion::Base, &BaseSignatureChecker, None)
+            .is_ok());
+        assert_eq!(
+            empty.eval(large, SigVersion::WitnessV0, &BaseSignatureChecker, None),
+            Err(ScriptError::PushSize)
+        );
+
+        let full = stack_of(vec![vec![]; MAX_STACK_SIZE]);
+        let script = Script::of_bytes(hex::decode("6b6c").unwrap());
+        assert!(script
+            .eval(full.clone(), SigVersion::Base, &BaseSignatureChecker, None)
+            .is_ok());
+        let script = Script::of_bytes(hex::decode("01aa").unwrap());
+        for sig_version in [SigVersion::Base, SigVersion::Tapscript] {
+            assert_eq!(
+                script.eval(full.clone(), sig_version, &BaseSignatureChecker, None),
+                Err(ScriptError::StackSize)
+            );
+        }
+        let overfull = stack_of(vec![vec![]; MAX_STACK_SIZE + 1]);
+        assert!(empty
+            .eval(
+                overfull.clone(),
+                SigVersion::Base,
+                &BaseSignatureChecker,
+                None
+            )
+            .is_ok());
+        assert_eq!(
+            empty.eval(overfull, SigVersion::Tapscript, &BaseSignatureChecker, None),
+            Err(ScriptError::StackSize)
+        );
+    }
+
+    #[test]
+    pub fn test_interpreter_errors() {
+        let eval = |script: &str, stack: Vec<Vec<u8>>| {
+            Script::of_bytes(hex::decode(script).unwrap()).eval(
+                stack_of(stack),
+                SigVersion::Base,
+                &BaseSignatureChecker,
+                None,
+            )
+        };
+        assert_eq!(eval("76", vec![]), Err(ScriptError::InvalidStackOperation));
+        assert_eq!(
+            eval("6c", vec![]),
+            Err(ScriptError::InvalidAltstackOperation)
+        );
+        assert_eq!(
+            eval("01aa88", vec![vec![0xbb]]),
+            Err(ScriptError::EqualVerify)
+        );
+        assert_eq!(eval("68", vec![]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(eval("0002aa", vec![]), Err(ScriptError::BadOpcode));
+        assert_eq!(
+            eval("76", vec![vec![1], vec![2]]),
+            Ok(stack_of(vec![vec![1], vec![2], vec![2]]))
+        );
+    }
+
+    #[test]
+    pub fn test_interpreter_failing_scripts() {
+        let eval = |script: &str| {
+            Script::of_bytes(hex::decode(script).unwrap()).eval(
+                Stack::new(),
+                SigVersion::Base,
+                &BaseSignatureChecker,
+                None,
+            )
+        };
+        // OP_1 OP_RETURN
+        assert_eq!(eval("516a"), Err(ScriptError::OpReturn));
+        // OP_1 OP_1 OP_EQUAL, OP_1 OP_2 OP_EQUAL
+        assert_eq!(eval("515187"), Ok(stack_of(vec![vec![1]])));
+        assert_eq!(eval("515287"), Ok(stack_of(vec![vec![]])));
+        // OP_1 OP_VERIFY, OP_0 OP_VERIFY
+        assert_eq!(eval("5169"), Ok(Stack::new()));
+        assert_eq!(eval("0069"), Err(ScriptError::Verify));
+        // OP_UNKNOWN, OP_INVALIDOPCODE, and OP_UNKNOWN in an executed branch
+        assert_eq!(eval("bb"), Err(ScriptError::BadOpcode));
+        assert_eq!(eval("ff"), Err(ScriptError::BadOpcode));
+        assert_eq!(eval("5163bb68"), Err(ScriptError::BadOpcode));
+        // Disabled opcodes fail even in a skipped branch: OP_CAT, and
+        // OP_0 OP_IF OP_CAT OP_ENDIF OP_1
+        assert_eq!(eval("7e"), Err(ScriptError::DisabledOpcode));
+        assert_eq!(eval("00637e6851"), Err(ScriptError::DisabledOpcode));
+        assert_eq!(eval("00639568"), Err(ScriptError::DisabledOpcode));
+        // An unknown opcode in a skipped branch is fine
+        assert_eq!(eval("0063bb6851"), Ok(stack_of(vec![vec![1]])));
+        // OP_1 followed by OP_NOP, OP_NOP1 and OP_NOP4 to OP_NOP10
+        assert_eq!(eval("5161b0b3b4b5b6b7b8b9"), Ok(stack_of(vec![vec![1]])));
+        for script in [
+            "516a",
+            "515187",
+            "515287",
+            "0069",
+            "bb",
+            "ff",
+            "5163bb68",
+            "00637e6851",
+        ] {
+            let script = Script::of_bytes(hex::decode(script).unwrap());
+            assert!(!script.interpret(Stack::new()));
+        }
+    }
+
+    fn spending_transaction(version: u32, sequence: u32, lock_time: u32) -> Transaction {
+        Transaction {
+            version: version.to_le_bytes(),
+            inputs: vec![TransactionInput {
+                txid: [0; 32],
+                vout: [0; 4],
+                script_sig: Script::new(vec![]),
+                sequence: Sequence(sequence),
+            }],
+            outputs: vec![],
+            witnesses: vec![vec![]],
+            lock_time: LockTime::from_consensus(lock_time),
+        }
+    }
+
+    #[test]
+    pub fn test_interpreter_check_lock_time_verify() {
+        let tx = spending_transaction(1, 0xfffffffe, 500);
+        let checker = TransactionSignatureChecker::new(&tx, 0);
+        let eval = |script: &str, tx: &Transaction| {
+            Script::of_bytes(hex::decode(script).unwrap()).eval(
+                Stack::new(),
+                SigVersion::Base,
+                &TransactionSignatureChecker::new(tx, 0),
+                None,
+            )
+        };
+        // <500> OP_CHECKLOCKTIMEVERIFY OP_DROP
+        assert_eq!(eval("02f401b175", &tx), Ok(Stack::new()));
+        let script = Script::of_bytes(hex::decode("02f401b1").unwrap());
+        assert_eq!(
+            script.eval(Stack::new(), SigVersion::Base, &checker, None),
+            Ok(stack_of(vec![vec![0xf4, 0x01]]))
+        );
+        assert_eq!(
+            script.eval(Stack::new(), SigVersion::Base, &BaseSignatureChecker, None),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        // <501>
+        assert_eq!(
+            eval("02f501b175", &tx),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        // A timestamp can not be satisfied by a height
+        assert_eq!(
+            eval("040065cd1db175", &tx),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        assert_eq!(eval("4fb175", &tx), Err(ScriptError::NegativeLockTime));
+        assert_eq!(eval("b1", &tx), Err(ScriptError::InvalidStackOperation));
+        assert_eq!(
+            eval("06000000000001b1", &tx),
+            Err(ScriptError::NumberOverflow)
+        );
+        // A final input disables the lock time
+        let tx = spending_transaction(1, 0xffffffff, 500);
+        assert_eq!(
+            eval("02f401b175", &tx),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+    }
+
+    #[test]
+    pub fn test_interpreter_check_sequence_verify() {
+        let eval = |script: &str, tx: &Transaction| {
+            Script::of_bytes(hex::decode(script).unwrap()).eval(
+                Stack::new(),
+                SigVersion::WitnessV0,
+                &TransactionSignatureChecker::new(tx, 0),
+                None,
+            )
+        };
+        let tx = spending_transaction(2, 10, 0);
+        // OP_10 OP_CHECKSEQUENCEVERIFY OP_DROP
+        assert_eq!(eval("5ab275", &tx), Ok(Stack::new()));
+        assert_eq!(eval("5bb275", &tx), Err(ScriptError::UnsatisfiedLockTime));
+        // A relative lock time in time is not satisfied by blocks
+        assert_eq!(
+            eval("03010040b275", &tx),
+            Err(ScriptError::UnsatisfiedLockTime)
+        );
+        assert_eq!(eval("4fb275", &tx), Err(ScriptError::NegativeLockTime));
+        // The disable flag makes the opcode a no-op
+        let script = Script::of_bytes(hex::decode("050000008000b275").unwrap());
+        assert_eq!(
+            script.eval(Stack::new(), SigVersion::Base, &BaseSignatureChecker, None),
+            Ok(Stack::new())
+        );
+        // Relative lock times are only enforced from version 2
+        let tx = spending_transaction(1, 10, 0);
+        assert_eq!(eval("5ab275", &tx), Err(ScriptError::UnsatisfiedLockTime));
+        // The input must not disable its relative lock time
+        let tx = spending_transaction(2, 0xffffffff, 0);
+        assert_eq!(eval("5ab275", &tx), Err(ScriptError::UnsatisfiedLockTime));
+    }
+
     #[test]
     #[ignore]
     pub fn test_interpreter_p2pkh() {
@@ -1251,7 +2349,7 @@ mod tests {
             .unwrap();
         let mut initial_stack = Stack::new();
         initial_stack.push(addr);
-        println!("Script is {script}");
+        std::println!("Script is {script}");
         assert!(script.interpret(initial_stack));
     }
 }
@@ -1,61 +1,435 @@
+use crate::amount::Amount;
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::fee_rate::{FeeError, FeeRate, WITNESS_SCALE_FACTOR};
+use crate::locktime::{LockTime, Sequence};
 use crate::script::Script;
-use crate::utils::CompactBytes;
+use crate::utils::sha256d;
+use alloc::vec;
+use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TransactionInput {
     /// The TXID of the transaction containing the output you want to spend.
     pub txid: [u8; 32],
     /// The index number of the output you want to spend.
     pub vout: [u8; 4],
-    /// The size in bytes of the upcoming ScriptSig.
-    pub script_sig_size: CompactBytes,
     /// The unlocking code for the output you want to spend.
     pub script_sig: Script,
     /// Set whether the transaction can be replaced or when it can be mined.
-    pub sequence: [u8; 4],
+    pub sequence: Sequence,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TransactionOutput {
     /// The value of the output in satoshis.
-    pub amount: u64,
-    /// The size in bytes of the upcoming ScriptPubKey.
-    pub script_sig_size: u8,
+    pub amount: Amount,
     /// The locking code for this output.
-    pub script_sig: Script,
+    pub script_pubkey: Script,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct StackItem {
-    /// The size of the upcoming stack item.
-    pub size: CompactBytes,
-    /// The data to be pushed on to the stack.
-    pub item: Vec<u8>,
-}
+/// The items pushed on to the stack as part of the unlocking code of a segwit
+/// input.
+pub type Witness = Vec<Vec<u8>>;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Transaction {
     /// The version number for the transaction. Used to enable new features.
     pub version: [u8; 4],
-    /// Used to indicate a segwit transaction. Must be 00.
-    pub marker: u8,
-    /// Used to indicate a segwit transaction. Must be 01 or greater.
-    pub flag: u8,
-    /// Indicates the number of inputs.
-    pub input_count: CompactBytes,
+    pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
-    /// The first arg is the number of items to be pushed on to the stack as
-    /// part of the unlocking code.
-    /// The second arg is each stack iterm.
-    /// The list should be the same size than the number of outputs.
-    pub witnesses: Vec<(CompactBytes, StackItem)>,
+    /// The witness of each input, in the same order than the inputs. Inputs
+    /// spending a non-segwit output have an empty witness.
+    pub witnesses: Vec<Witness>,
     /// Set a time or height after which the transaction can be mined.
-    pub lock_time: [u8; 4],
+    pub lock_time: LockTime,
+}
+
+impl TransactionInput {
+    /// Whether the input spends no output, as the input of a coinbase
+    /// transaction: the transaction ID is zero and the index is 0xffffffff.
+    pub fn is_null_prevout(&self) -> bool {
+        self.txid == [0; 32] && self.vout == [0xff; 4]
+    }
 }
 
 impl Transaction {
+    /// Whether the transaction is a coinbase, i.e. it has a single input
+    /// spending no output.
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].is_null_prevout()
+    }
+
+    /// Whether the transaction can be included in a block at the given height
+    /// with the given median time past, as `IsFinalTx` in the reference
+    /// implementation: its lock time is satisfied, or all its inputs are
+    /// final.
+    pub fn is_final(&self, height: u32, median_time_past: u32) -> bool {
+        self.lock_time == LockTime::ZERO
+            || self.lock_time.is_satisfied_by(height, median_time_past)
+            || self.inputs.iter().all(|input| input.sequence.is_final())
+    }
+
     pub fn is_segregated_witness(&self) -> bool {
-        self.marker == 0 && self.flag == 1
+        self.witnesses.iter().any(|w| !w.is_empty())
+    }
+
+    /// The identifier of the transaction, i.e. the hash of its serialization
+    /// without the witnesses, in internal byte order.
+    pub fn txid(&self) -> [u8; 32] {
+        let mut bytes = vec![];
+        // Writing into a vector never fails
+        self.encode_with_witness(&mut bytes, false).unwrap();
+        sha256d(&bytes)
+    }
+
+    /// The hash of the transaction including the witnesses, as defined in BIP
+    /// 141. It is equal to the [txid](Transaction::txid) for transactions
+    /// without witness.
+    pub fn wtxid(&self) -> [u8; 32] {
+        sha256d(&encode::serialize(self))
+    }
+
+    /// The size of the transaction serialized without its witnesses.
+    pub fn base_size(&self) -> usize {
+        // Writing into a sink never fails
+        self.encode_with_witness(&mut encode::Sink, false).unwrap()
+    }
+
+    /// The size of the transaction serialized with its witnesses, if any.
+    pub fn total_size(&self) -> usize {
+        self.consensus_encode(&mut encode::Sink).unwrap()
+    }
+
+    /// The weight of the transaction as defined in BIP 141: the base size
+    /// counts three times more than the total size.
+    pub fn weight(&self) -> u64 {
+        (self.base_size() * (WITNESS_SCALE_FACTOR as usize - 1) + self.total_size()) as u64
+    }
+
+    /// The virtual size, i.e. the weight divided by four and rounded up.
+    pub fn vsize(&self) -> u64 {
+        self.weight().div_ceil(WITNESS_SCALE_FACTOR)
+    }
+
+    /// The fee paid by the transaction, given the amounts of the outputs
+    /// spent by its inputs, in the same order.
+    pub fn fee(&self, spent_amounts: &[Amount]) -> Result<Amount, FeeError> {
+        if spent_amounts.len() != self.inputs.len() {
+            return Err(FeeError::SpentAmountsMismatch {
+                inputs: self.inputs.len(),
+                amounts: spent_amounts.len(),
+            });
+        }
+        let value_in =
+            Amount::checked_sum(spent_amounts.iter().copied()).ok_or(FeeError::ValueOutOfRange)?;
+        let value_out = Amount::checked_sum(self.outputs.iter().map(|output| output.amount))
+            .ok_or(FeeError::ValueOutOfRange)?;
+        value_in.checked_sub(value_out).ok_or(FeeError::NegativeFee)
+    }
+
+    /// The effective fee rate of the transaction, given the amounts of the
+    /// outputs spent by its inputs.
+    pub fn fee_rate(&self, spent_amounts: &[Amount]) -> Result<FeeRate, FeeError> {
+        let fee = self.fee(spent_amounts)?;
+        // A transaction has at least a version and a lock time
+        Ok(FeeRate::from_fee_and_weight(fee, self.weight()).unwrap())
+    }
+
+    fn encode_with_witness<W: Write + ?Sized>(
+        &self,
+        w: &mut W,
+        segwit: bool,
+    ) -> Result<usize, Error> {
+        let mut len = self.version.consensus_encode(w)?;
+        if segwit {
+            len += 0u8.consensus_encode(w)?;
+            len += 1u8.consensus_encode(w)?;
+        }
+        len += encode::encode_vec(&self.inputs, w)?;
+        len += encode::encode_vec(&self.outputs, w)?;
+        if segwit {
+            for i in 0..self.inputs.len() {
+                let witness = self.witnesses.get(i).map_or(&[][..], |w| &w[..]);
+                len += encode::encode_vec(witness, w)?;
+            }
+        }
+        len += self.lock_time.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Encodable for TransactionInput {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.txid.consensus_encode(w)?
+            + self.vout.consensus_encode(w)?
+            + self.script_sig.consensus_encode(w)?
+            + self.sequence.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for TransactionInput {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(TransactionInput {
+            txid: Decodable::consensus_decode(r)?,
+            vout: Decodable::consensus_decode(r)?,
+            script_sig: Decodable::consensus_decode(r)?,
+            sequence: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+impl Encodable for TransactionOutput {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.amount.consensus_encode(w)? + self.script_pubkey.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for TransactionOutput {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(TransactionOutput {
+            amount: Decodable::consensus_decode(r)?,
+            script_pubkey: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// The segwit serialization defined in BIP 144 is used when at least one input
+/// has a witness: the marker 0x00 and the flag 0x01 follow the version, and the
+/// witnesses are put before the lock time.
+impl Encodable for Transaction {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        self.encode_with_witness(w, self.is_segregated_witness())
+    }
+}
+
+impl Decodable for Transaction {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let version = Decodable::consensus_decode(r)?;
+        let mut inputs: Vec<TransactionInput> = encode::decode_vec(r)?;
+        let mut outputs = vec![];
+        let mut flag = 0u8;
+        // An empty list of inputs is the segwit marker. As in the reference
+        // implementation, a null flag is read as an empty list of outputs.
+        if inputs.is_empty() {
+            flag = u8::consensus_decode(r)?;
+            if flag != 0 {
+                inputs = encode::decode_vec(r)?;
+                outputs = encode::decode_vec(r)?;
+            }
+        } else {
+            outputs = encode::decode_vec(r)?;
+        }
+        let mut witnesses = vec![vec![]; inputs.len()];
+        if flag & 1 != 0 {
+            flag ^= 1;
+            for witness in witnesses.iter_mut() {
+                *witness = encode::decode_vec(r)?;
+            }
+            if witnesses.iter().all(|w| w.is_empty()) {
+                return Err(Error::ParseFailed("superfluous witness record"));
+            }
+        }
+        if flag != 0 {
+            return Err(Error::ParseFailed("unknown transaction optional data"));
+        }
+        Ok(Transaction {
+            version,
+            inputs,
+            outputs,
+            witnesses,
+            lock_time: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block::coinbase_height_prefix;
+    use crate::encode::{deserialize, serialize};
+    use crate::utxo::OutPoint;
+
+    /// A coinbase transaction at the given height, paying `amount` to
+    /// OP_TRUE. The tag makes the coinbases of different branches distinct.
+    pub(crate) fn coinbase(height: u32, tag: u8, amount: Amount) -> Transaction {
+        let mut script_sig = coinbase_height_prefix(height);
+        script_sig.extend([0x01, tag]);
+        Transaction {
+            version: 2u32.to_le_bytes(),
+            inputs: vec![TransactionInput {
+                txid: [0; 32],
+                vout: [0xff; 4],
+                script_sig: Script::of_bytes(script_sig),
+                sequence: Sequence::FINAL,
+            }],
+            outputs: vec![TransactionOutput {
+                amount,
+                script_pubkey: Script::of_bytes(vec![0x51]),
+            }],
+            witnesses: vec![],
+            lock_time: LockTime::ZERO,
+        }
+    }
+
+    /// A transaction spending the given outputs without script, and paying
+    /// each amount to OP_TRUE.
+    pub(crate) fn spend(outpoints: &[OutPoint], amounts: &[Amount]) -> Transaction {
+        Transaction {
+            version: 2u32.to_le_bytes(),
+            inputs: outpoints
+                .iter()
+                .map(|outpoint| TransactionInput {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout.to_le_bytes(),
+                    script_sig: Script::new(vec![]),
+                    sequence: Sequence::FINAL,
+                })
+                .collect(),
+            outputs: amounts
+                .iter()
+                .map(|amount| TransactionOutput {
+                    amount: *amount,
+                    script_pubkey: Script::of_bytes(vec![0x51]),
+                })
+                .collect(),
+            witnesses: vec![],
+            lock_time: LockTime::ZERO,
+        }
+    }
+
+    // Signed transaction of the native P2WPKH example of BIP 143
+    pub(crate) const SEGWIT_TX: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+
+    #[test]
+    pub fn test_decode_transaction_without_inputs() {
+        // Without inputs nor outputs, the lock time follows the null flag
+        let bytes = hex::decode("02000000000011223344").unwrap();
+        let tx: Transaction = deserialize(&bytes).unwrap();
+        assert!(tx.inputs.is_empty());
+        assert!(tx.outputs.is_empty());
+        assert_eq!(tx.lock_time, LockTime::from_consensus(0x44332211));
+        assert_eq!(serialize(&tx), bytes);
+    }
+
+    #[test]
+    pub fn test_segwit_transaction_round_trip() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let tx: Transaction = deserialize(&bytes).unwrap();
+        assert!(tx.is_segregated_witness());
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(tx.outputs[0].amount, Amount::from_sat(112340000));
+        assert!(tx.witnesses[0].is_empty());
+        assert_eq!(tx.witnesses[1].len(), 2);
+        assert_eq!(tx.lock_time, LockTime::Blocks(0x11));
+        assert_eq!(tx.inputs[0].sequence, Sequence(0xffffffee));
+        assert_eq!(tx.inputs[1].sequence, Sequence::FINAL);
+        assert_eq!(serialize(&tx), bytes);
+    }
+
+    #[test]
+    pub fn test_legacy_transaction_round_trip() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let mut tx: Transaction = deserialize(&bytes).unwrap();
+        tx.witnesses = vec![vec![], vec![]];
+        let legacy = serialize(&tx);
+        assert_eq!(legacy.len(), bytes.len() - 2 - 1 - 1 - 0x48 - 1 - 0x21);
+        assert_eq!(deserialize::<Transaction>(&legacy), Ok(tx.clone()));
+        assert_eq!(tx.txid(), sha256d(&legacy));
+        assert_eq!(tx.wtxid(), tx.txid());
+    }
+
+    #[test]
+    pub fn test_weight_and_fee_rate() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let tx: Transaction = deserialize(&bytes).unwrap();
+        assert_eq!(tx.total_size(), 343);
+        assert_eq!(tx.base_size(), 343 - 110);
+        assert_eq!(tx.weight(), 233 * 3 + 343);
+        assert_eq!(tx.vsize(), 261);
+
+        // The amounts of the spent outputs are given in BIP 143
+        let spent = [Amount::from_sat(625_000_000), Amount::from_sat(600_000_000)];
+        let fee = Amount::from_sat(1_225_000_000 - 112_340_000 - 223_450_000);
+        assert_eq!(tx.fee(&spent), Ok(fee));
+        let rate = tx.fee_rate(&spent).unwrap();
+        assert_eq!(rate.to_sat_per_kwu(), fee.to_sat() * 1000 / 1042);
+        assert_eq!(
+            tx.fee(&spent[..1]),
+            Err(FeeError::SpentAmountsMismatch {
+                inputs: 2,
+                amounts: 1
+            })
+        );
+        assert_eq!(
+            tx.fee(&[Amount::ONE_SAT, Amount::ONE_SAT]),
+            Err(FeeError::NegativeFee)
+        );
+        assert_eq!(
+            tx.fee(&[Amount::MAX_MONEY, Amount::ONE_SAT]),
+            Err(FeeError::ValueOutOfRange)
+        );
+
+        let mut legacy = tx.clone();
+        legacy.witnesses = vec![vec![], vec![]];
+        assert_eq!(legacy.weight(), 4 * legacy.total_size() as u64);
+        assert_eq!(legacy.vsize(), legacy.base_size() as u64);
+    }
+
+    #[test]
+    pub fn test_is_final() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let mut tx: Transaction = deserialize(&bytes).unwrap();
+        assert!(!tx.is_final(0x11, 0));
+        assert!(tx.is_final(0x12, 0));
+        tx.lock_time = LockTime::Seconds(1_600_000_000);
+        assert!(!tx.is_final(u32::MAX, 1_600_000_000));
+        assert!(tx.is_final(0, 1_600_000_001));
+        tx.inputs[0].sequence = Sequence::FINAL;
+        assert!(tx.is_final(0, 0));
+        tx.lock_time = LockTime::ZERO;
+        tx.inputs[0].sequence = Sequence::ZERO;
+        assert!(tx.is_final(0, 0));
+    }
+
+    #[test]
+    pub fn test_txid_ignores_witnesses() {
+        let bytes = hex::decode(SEGWIT_TX).unwrap();
+        let tx: Transaction = deserialize(&bytes).unwrap();
+        let mut txid = tx.txid();
+        txid.reverse();
+        assert_eq!(
+            hex::encode(txid),
+            "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609"
+        );
+        assert_eq!(tx.wtxid(), sha256d(&bytes));
+    }
+
+    #[test]
+    pub fn test_decode_invalid_witness_flags() {
+        // Version, marker and flag, then an input without witness
+        let mut bytes = hex::decode("01000000000101").unwrap();
+        bytes.extend(&serialize(&TransactionInput {
+            txid: [0; 32],
+            vout: [0; 4],
+            script_sig: Script::new(vec![]),
+            sequence: Sequence::FINAL,
+        }));
+        bytes.extend([0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            deserialize::<Transaction>(&bytes),
+            Err(Error::ParseFailed("superfluous witness record"))
+        );
+        bytes[5] = 0x02;
+        bytes.pop();
+        assert_eq!(
+            deserialize::<Transaction>(&bytes),
+            Err(Error::ParseFailed("unknown transaction optional data"))
+        );
+        assert_eq!(
+            deserialize::<Transaction>(&bytes[..20]),
+            Err(Error::UnexpectedEof)
+        );
     }
 }
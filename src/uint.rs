@@ -0,0 +1,286 @@
+//! 256-bit unsigned integer, used for proof of work targets and chain work.
+
+use core::cmp::Ordering;
+use core::ops::{Add, Div, Mul, Not, Shl, Shr, Sub};
+
+use crate::encode::{Decodable, Encodable, Error, Read, Write};
+
+/// A 256-bit unsigned integer. The limbs are stored with the least significant
+/// one first. Like in the reference implementation, arithmetic operations wrap
+/// around on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct U256(pub [u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0, 0, 0, 0]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+
+    /// Interpret bytes in little endian order, like a hash in its internal
+    /// byte order.
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// The lowest 64 bits.
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    /// The number of bits needed to represent the value.
+    pub fn bits(&self) -> u32 {
+        for i in (0..4).rev() {
+            if self.0[i] != 0 {
+                return 64 * i as u32 + 64 - self.0[i].leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// Decode the compact representation used by the `bits` field of block
+    /// headers. Return the value, and whether it is negative or overflows, as
+    /// `SetCompact` does in the reference implementation.
+    pub fn from_compact(compact: u32) -> (U256, bool, bool) {
+        let size = compact >> 24;
+        let mut word = compact & 0x007fffff;
+        let value = if size <= 3 {
+            word >>= 8 * (3 - size);
+            U256::from_u64(word as u64)
+        } else {
+            U256::from_u64(word as u64) << (8 * (size - 3))
+        };
+        let negative = word != 0 && (compact & 0x00800000) != 0;
+        let overflow =
+            word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+        (value, negative, overflow)
+    }
+
+    /// Encode the value in the compact representation used by the `bits` field
+    /// of block headers.
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).low_u64() as u32
+        };
+        // The 0x00800000 bit denotes the sign, so if it is already set, divide
+        // the mantissa by 256 and increase the exponent.
+        if compact & 0x00800000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+
+    fn overflowing_add(self, other: U256) -> U256 {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, r) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *r = sum;
+            carry = c1 || c2;
+        }
+        U256(result)
+    }
+
+    /// Divide by `other`, returning the quotient and the remainder.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `other` is zero.
+    pub fn div_rem(self, other: U256) -> (U256, U256) {
+        assert!(!other.is_zero(), "division by zero");
+        if self < other {
+            return (U256::ZERO, self);
+        }
+        let shift = self.bits() - other.bits();
+        let mut divisor = other << shift;
+        let mut remainder = self;
+        let mut quotient = U256::ZERO;
+        for i in (0..=shift).rev() {
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+            divisor = divisor >> 1;
+        }
+        (quotient, remainder)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.overflowing_add(other)
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        self.overflowing_add(!other).overflowing_add(U256::ONE)
+    }
+}
+
+impl Mul<u64> for U256 {
+    type Output = U256;
+
+    fn mul(self, other: u64) -> U256 {
+        let mut result = [0u64; 4];
+        let mut carry = 0u128;
+        for (i, r) in result.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *r = product as u64;
+            carry = product >> 64;
+        }
+        U256(result)
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, other: U256) -> U256 {
+        self.div_rem(other).0
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for (i, r) in result.iter_mut().enumerate().skip(limbs) {
+            *r = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *r |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut result = [0u64; 4];
+        let limbs = (shift / 64) as usize;
+        let bits = shift % 64;
+        for (i, r) in result
+            .iter_mut()
+            .take(4usize.saturating_sub(limbs))
+            .enumerate()
+        {
+            *r = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                *r |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        U256(result)
+    }
+}
+
+/// Encoded as 32 bytes in little endian order.
+impl Encodable for U256 {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        self.to_le_bytes().consensus_encode(w)
+    }
+}
+
+impl Decodable for U256 {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(U256::from_le_bytes(Decodable::consensus_decode(r)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples from arith_uint256_tests.cpp in the reference implementation
+    #[test]
+    pub fn test_compact_encoding() {
+        assert_eq!(U256::from_compact(0x01003456), (U256::ZERO, false, false));
+        assert_eq!(U256::from_compact(0x01123456).0, U256::from_u64(0x12));
+        assert_eq!(U256::from_u64(0x80).to_compact(), 0x02008000);
+        assert_eq!(
+            U256::from_compact(0x04923456),
+            (U256::from_u64(0x12345600), true, false)
+        );
+        assert_eq!(U256::from_compact(0x05009234).0, U256::from_u64(0x92340000));
+        let (value, negative, overflow) = U256::from_compact(0x20123456);
+        assert_eq!(value, U256::from_u64(0x123456) << 232);
+        assert!(!negative && !overflow);
+        assert_eq!(value.to_compact(), 0x20123456);
+        assert!(U256::from_compact(0xff123456).2);
+        assert_eq!(U256::from_compact(0x1d00ffff).0.to_compact(), 0x1d00ffff);
+    }
+
+    #[test]
+    pub fn test_arithmetic() {
+        let a = U256([u64::MAX, 1, 0, 0]);
+        assert_eq!(a + U256::ONE, U256([0, 2, 0, 0]));
+        assert_eq!(U256([0, 2, 0, 0]) - U256::ONE, a);
+        assert_eq!(U256::ZERO - U256::ONE, U256::MAX);
+        assert_eq!(a * 2, U256([u64::MAX - 1, 3, 0, 0]));
+        assert_eq!(U256::ONE << 255 >> 255, U256::ONE);
+        assert_eq!((U256::ONE << 100).bits(), 101);
+        let (q, r) = (a * 7 + U256::from_u64(3)).div_rem(a);
+        assert_eq!((q, r), (U256::from_u64(7), U256::from_u64(3)));
+        assert_eq!(U256::MAX / U256::MAX, U256::ONE);
+        assert_eq!(U256::from_le_bytes(a.to_le_bytes()), a);
+    }
+}
@@ -1,4 +1,17 @@
+use crate::encode::{Decodable, Encodable, Error, Read, Write};
+use alloc::vec::Vec;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// Hash the data two times with SHA-256, as done to compute the identifier of
+/// transactions and blocks.
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+/// The maximum value of a compact size used as a length or a number of
+/// elements, as defined by `MAX_SIZE` in the reference implementation.
+pub const MAX_SIZE: u64 = 0x02000000;
 
 /// A compact size field is used in network messages to indicate the size of an
 /// upcoming field or the number of upcoming fields.
@@ -7,84 +20,133 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 /// other words, smaller numbers take up less space. This means you don't have
 /// to use a larger fixed-size field at all times to accommodate the largest
 /// acceptable number.
-#[derive(Debug, PartialEq, Eq)]
-pub enum CompactBytes {
-    B1(u8),
-    B2([u8; 2]),
-    B4([u8; 4]),
-    B8([u8; 8]),
+///
+/// Only the smallest encoding of a number is accepted when decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VarInt(pub u64);
+
+impl VarInt {
+    /// The number of bytes used to encode the value.
+    pub fn size(&self) -> usize {
+        match self.0 {
+            0..=0xFC => 1,
+            0xFD..=0xFFFF => 3,
+            0x10000..=0xFFFF_FFFF => 5,
+            _ => 9,
+        }
+    }
+}
+
+impl From<u64> for VarInt {
+    fn from(value: u64) -> Self {
+        VarInt(value)
+    }
+}
+
+impl From<VarInt> for u64 {
+    fn from(value: VarInt) -> Self {
+        value.0
+    }
+}
+
+impl Encodable for VarInt {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        match self.size() {
+            1 => (self.0 as u8).consensus_encode(w),
+            3 => Ok(0xFDu8.consensus_encode(w)? + (self.0 as u16).consensus_encode(w)?),
+            5 => Ok(0xFEu8.consensus_encode(w)? + (self.0 as u32).consensus_encode(w)?),
+            _ => Ok(0xFFu8.consensus_encode(w)? + self.0.consensus_encode(w)?),
+        }
+    }
 }
 
-impl Serialize for CompactBytes {
+impl Decodable for VarInt {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let (value, min) = match u8::consensus_decode(r)? {
+            0xFD => (u16::consensus_decode(r)? as u64, 0xFD),
+            0xFE => (u32::consensus_decode(r)? as u64, 0x10000),
+            0xFF => (u64::consensus_decode(r)?, 0x1_0000_0000),
+            b => (b as u64, 0),
+        };
+        if value < min {
+            return Err(Error::NonMinimalVarInt);
+        }
+        Ok(VarInt(value))
+    }
+}
+
+impl Serialize for VarInt {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let res = match self {
-            CompactBytes::B1(b) => vec![*b],
-            CompactBytes::B2(b) => vec![0xFD, b[0], b[1]],
-            CompactBytes::B4(b) => vec![0xFE, b[0], b[1], b[2], b[3]],
-            CompactBytes::B8(b) => vec![0xFF, b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]],
-        };
-        serializer.serialize_bytes(&res)
+        serializer.serialize_bytes(&crate::encode::serialize(self))
     }
 }
 
-// FIXME: handle correctly the error instead of panicking
-impl<'de> Deserialize<'de> for CompactBytes {
-    fn deserialize<D>(deserializer: D) -> Result<CompactBytes, D::Error>
+impl<'de> Deserialize<'de> for VarInt {
+    fn deserialize<D>(deserializer: D) -> Result<VarInt, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = Vec::<u8>::deserialize(deserializer)?;
-        if s.len() == 1 {
-            Ok(CompactBytes::B1(s[0]))
-        } else if s.len() == 3 {
-            assert_eq!(s[0], 0xFD, "The leading byte must be 0xFD");
-            Ok(CompactBytes::B2([s[1], s[2]]))
-        } else if s.len() == 5 {
-            assert_eq!(s[0], 0xFE, "The leading byte must be 0xFE");
-            Ok(CompactBytes::B4([s[1], s[2], s[3], s[4]]))
-        } else if s.len() == 9 {
-            assert_eq!(s[0], 0xFF, "The leading byte must be 0xFF");
-            Ok(CompactBytes::B8([
-                s[1], s[2], s[3], s[4], s[5], s[6], s[7], s[8],
-            ]))
-        } else {
-            panic!("Unsupported number of bytes")
-        }
+        crate::encode::deserialize(&s).map_err(serde::de::Error::custom)
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::encode::{deserialize, serialize};
+    use alloc::vec;
 
     #[test]
-    pub fn test_serialize_deserialize() {
-        {
-            let b1 = CompactBytes::B1(0x01);
-            let serialize = bincode::serialize(&b1).unwrap();
-            let deserialize: CompactBytes = bincode::deserialize(&serialize).unwrap();
-            assert_eq!(b1, deserialize);
+    pub fn test_consensus_encoding() {
+        assert_eq!(serialize(&VarInt(0xFC)), vec![0xFC]);
+        assert_eq!(serialize(&VarInt(0xFD)), vec![0xFD, 0xFD, 0x00]);
+        assert_eq!(
+            serialize(&VarInt(0x10000)),
+            vec![0xFE, 0x00, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(
+            serialize(&VarInt(0x1_0000_0000)),
+            vec![0xFF, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+        for value in [0, 0xFC, 0xFD, 0xFFFF, 0x10000, 0x1_0000_0000, u64::MAX] {
+            let encoded = serialize(&VarInt::from(value));
+            assert_eq!(encoded.len(), VarInt(value).size());
+            assert_eq!(deserialize::<VarInt>(&encoded).map(u64::from), Ok(value));
         }
-        {
-            let b2 = CompactBytes::B2([0x01, 0x02]);
-            let serialize = bincode::serialize(&b2).unwrap();
-            let deserialize: CompactBytes = bincode::deserialize(&serialize).unwrap();
-            assert_eq!(b2, deserialize);
-        }
-        {
-            let b4 = CompactBytes::B4([0x01, 0x02, 0x03, 0x04]);
-            let serialize = bincode::serialize(&b4).unwrap();
-            let deserialize: CompactBytes = bincode::deserialize(&serialize).unwrap();
-            assert_eq!(b4, deserialize);
+        assert_eq!(
+            deserialize::<VarInt>(&[0xFE, 0x00]),
+            Err(Error::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    pub fn test_reject_non_minimal_encoding() {
+        for encoded in [
+            vec![0xFD, 0xFC, 0x00],
+            vec![0xFE, 0xFF, 0xFF, 0x00, 0x00],
+            vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00],
+        ] {
+            assert_eq!(
+                deserialize::<VarInt>(&encoded),
+                Err(Error::NonMinimalVarInt)
+            );
         }
-        {
-            let b8 = CompactBytes::B8([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
-            let serialize = bincode::serialize(&b8).unwrap();
-            let deserialize: CompactBytes = bincode::deserialize(&serialize).unwrap();
-            assert_eq!(b8, deserialize);
+    }
+
+    #[test]
+    pub fn test_serialize_deserialize() {
+        for value in [0x01, 0x0201, 0x04030201, 0x0807060504030201] {
+            let v = VarInt(value);
+            let serialize = bincode::serialize(&v).unwrap();
+            let deserialize: VarInt = bincode::deserialize(&serialize).unwrap();
+            assert_eq!(v, deserialize);
         }
+        // A non-minimal encoding is an error, not a panic
+        let serialize = bincode::serialize(&vec![0xFDu8, 0x01, 0x00]).unwrap();
+        assert!(bincode::deserialize::<VarInt>(&serialize).is_err());
     }
 }
@@ -0,0 +1,591 @@
+//! Unspent transaction outputs, needed to validate the inputs spending them.
+//!
+//! A [UtxoSet] is updated by connecting the blocks of the chain one after the
+//! other. Connecting a block returns the outputs it spent as a [BlockUndo], so
+//! that the block can be disconnected when the chain is reorganized.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::fmt;
+
+use crate::block::Block;
+use crate::encode::{self, Decodable, Encodable, Error, Read, Write};
+use crate::script::MAX_SCRIPT_SIZE;
+use crate::transaction::{TransactionInput, TransactionOutput};
+
+/// A reference to an output of a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutPoint {
+    /// The ID of the transaction, in internal byte order.
+    pub txid: [u8; 32],
+    /// The index of the output in the transaction.
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+}
+
+impl From<&TransactionInput> for OutPoint {
+    /// The output spent by the input.
+    fn from(input: &TransactionInput) -> Self {
+        OutPoint::new(input.txid, u32::from_le_bytes(input.vout))
+    }
+}
+
+impl fmt::Display for OutPoint {
+    /// The transaction ID in the usual hexadecimal representation, followed by
+    /// the index of the output.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.txid.iter().rev() {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ":{}", self.vout)
+    }
+}
+
+impl Encodable for OutPoint {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        Ok(self.txid.consensus_encode(w)? + self.vout.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for OutPoint {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        Ok(OutPoint {
+            txid: Decodable::consensus_decode(r)?,
+            vout: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// An unspent output, with the information needed to validate its spending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub output: TransactionOutput,
+    /// The height of the block including the transaction.
+    pub height: u32,
+    /// Whether the transaction is a coinbase, whose outputs can only be spent
+    /// after some confirmations.
+    pub is_coinbase: bool,
+}
+
+/// The height and the coinbase flag are packed together, as in the reference
+/// implementation: the height can not exceed 2^31 - 1.
+impl Encodable for Coin {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        let code = (self.height << 1) | self.is_coinbase as u32;
+        Ok(code.consensus_encode(w)? + self.output.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for Coin {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let code = u32::consensus_decode(r)?;
+        Ok(Coin {
+            output: Decodable::consensus_decode(r)?,
+            height: code >> 1,
+            is_coinbase: code & 1 == 1,
+        })
+    }
+}
+
+/// Whether the output can never be spent, so that it is not added to the set
+/// of unspent outputs: its script starts with OP_RETURN or is too large.
+fn is_unspendable(output: &TransactionOutput) -> bool {
+    let script = output.script_pubkey.to_bytes();
+    script.first() == Some(&0x6a) || script.len() > MAX_SCRIPT_SIZE
+}
+
+/// A view on a set of unspent outputs, like `CCoinsView` in the reference
+/// implementation.
+pub trait UtxoView {
+    /// The unspent output, or `None` if it does not exist or is spent.
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin>;
+}
+
+impl UtxoView for BTreeMap<OutPoint, Coin> {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.get(outpoint).cloned()
+    }
+}
+
+/// The outputs spent by the transactions of a block, except the coinbase, in
+/// the order of their inputs. Like the `rev*.dat` files of the reference
+/// implementation, this is what is needed to disconnect the block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub spent_coins: Vec<Vec<Coin>>,
+}
+
+impl Encodable for BlockUndo {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, Error> {
+        let mut len = encode::write_length(self.spent_coins.len(), w)?;
+        for coins in &self.spent_coins {
+            len += encode::encode_vec(coins, w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for BlockUndo {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error> {
+        let count = encode::read_length(r)?;
+        let mut spent_coins = Vec::new();
+        for _ in 0..count {
+            spent_coins.push(encode::decode_vec(r)?);
+        }
+        Ok(BlockUndo { spent_coins })
+    }
+}
+
+/// Reasons for a block to not be connected or disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError<E> {
+    /// An input spends an output which does not exist or is already spent.
+    MissingCoin(OutPoint),
+    /// The undo data do not match the transactions of the block.
+    UndoMismatch,
+    /// The storage of the set failed.
+    Store(E),
+}
+
+impl<E: fmt::Display> fmt::Display for UtxoError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UtxoError::MissingCoin(outpoint) => write!(f, "missing or spent output {}", outpoint),
+            UtxoError::UndoMismatch => write!(f, "undo data do not match the block"),
+            UtxoError::Store(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+/// A set of unspent outputs which can be updated.
+pub trait UtxoSet: UtxoView {
+    /// The error of the underlying storage.
+    type Error;
+
+    fn add_coin(&mut self, outpoint: OutPoint, coin: Coin) -> Result<(), Self::Error>;
+
+    /// Remove the output from the set, and return it if it was there.
+    fn spend_coin(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, Self::Error>;
+
+    /// Spend the outputs spent by the block and add the ones it creates. The
+    /// block is expected to be valid, see [Block::validate], but the set is
+    /// left unchanged if an input is missing. On storage errors, it may be
+    /// partially updated.
+    fn connect_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+    ) -> Result<BlockUndo, UtxoError<Self::Error>> {
+        // Collect the spent outputs first, taking into account the ones
+        // created and spent by the previous transactions of the block
+        let mut created = BTreeMap::new();
+        let mut spent = BTreeSet::new();
+        let mut undo = BlockUndo::default();
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                let mut coins = Vec::with_capacity(tx.inputs.len());
+                for input in &tx.inputs {
+                    let outpoint = OutPoint::from(input);
+                    let coin = if spent.insert(outpoint) {
+                        created
+                            .remove(&outpoint)
+                            .or_else(|| self.get_coin(&outpoint))
+                    } else {
+                        None
+                    };
+                    coins.push(coin.ok_or(UtxoError::MissingCoin(outpoint))?);
+                }
+                undo.spent_coins.push(coins);
+            }
+            let txid = tx.txid();
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                if !is_unspendable(output) {
+                    let coin = Coin {
+                        output: output.clone(),
+                        height,
+                        is_coinbase: tx.is_coinbase(),
+                    };
+                    created.insert(OutPoint::new(txid, vout as u32), coin);
+                }
+            }
+        }
+
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for input in &tx.inputs {
+                    self.spend_coin(&OutPoint::from(input))
+                        .map_err(UtxoError::Store)?;
+                }
+            }
+            let txid = tx.txid();
+            for (vout, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                // Outputs spent in the same block are not added
+                if !is_unspendable(output) && !spent.contains(&outpoint) {
+                    let coin = Coin {
+                        output: output.clone(),
+                        height,
+                        is_coinbase: tx.is_coinbase(),
+                    };
+                    self.add_coin(outpoint, coin).map_err(UtxoError::Store)?;
+                }
+            }
+        }
+        Ok(undo)
+    }
+
+    /// Undo [UtxoSet::connect_block]: remove the outputs created by the block
+    /// and restore the ones it spent. The set is left unchanged if the undo
+    /// data do not match the block.
+    fn disconnect_block(
+        &mut self,
+        block: &Block,
+        undo: &BlockUndo,
+    ) -> Result<(), UtxoError<Self::Error>> {
+        let spending: Vec<_> = block
+            .transactions
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .collect();
+        if spending.len() != undo.spent_coins.len()
+            || spending
+                .iter()
+                .zip(&undo.spent_coins)
+                .any(|(tx, coins)| tx.inputs.len() != coins.len())
+        {
+            return Err(UtxoError::UndoMismatch);
+        }
+
+        let mut undo_coins = undo.spent_coins.iter().rev();
+        for tx in block.transactions.iter().rev() {
+            let txid = tx.txid();
+            for vout in 0..tx.outputs.len() {
+                self.spend_coin(&OutPoint::new(txid, vout as u32))
+                    .map_err(UtxoError::Store)?;
+            }
+            if !tx.is_coinbase() {
+                let coins = undo_coins.next().unwrap();
+                for (input, coin) in tx.inputs.iter().zip(coins).rev() {
+                    self.add_coin(OutPoint::from(input), coin.clone())
+                        .map_err(UtxoError::Store)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of unspent outputs kept in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUtxoSet {
+    coins: BTreeMap<OutPoint, Coin>,
+}
+
+impl MemoryUtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Coin)> {
+        self.coins.iter()
+    }
+}
+
+impl UtxoView for MemoryUtxoSet {
+    fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+        self.coins.get(outpoint).cloned()
+    }
+}
+
+impl UtxoSet for MemoryUtxoSet {
+    type Error = Infallible;
+
+    fn add_coin(&mut self, outpoint: OutPoint, coin: Coin) -> Result<(), Infallible> {
+        self.coins.insert(outpoint, coin);
+        Ok(())
+    }
+
+    fn spend_coin(&mut self, outpoint: &OutPoint) -> Result<Option<Coin>, Infallible> {
+        Ok(self.coins.remove(outpoint))
+    }
+}
+
+#[cfg(feature = "std")]
+pub use file::FileUtxoStore;
+
+#[cfg(feature = "std")]
+mod file {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read as _, Seek, SeekFrom, Write as _};
+    use std::path::Path;
+
+    const ADD: u8 = 0;
+    const SPEND: u8 = 1;
+
+    /// A set of unspent outputs stored in an append-only file.
+    ///
+    /// Each update is appended to the file as a record, and the set is kept
+    /// in memory to answer the queries. Opening the file replays the records;
+    /// an incomplete record at the end, left by an interrupted write, is
+    /// discarded.
+    #[derive(Debug)]
+    pub struct FileUtxoStore {
+        file: File,
+        coins: MemoryUtxoSet,
+    }
+
+    impl FileUtxoStore {
+        /// Open the store at the given path, creating it if needed.
+        pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+
+            let mut coins = MemoryUtxoSet::new();
+            let mut reader = &bytes[..];
+            let mut valid_len = 0;
+            while !reader.is_empty() {
+                match replay_record(&mut reader, &mut coins) {
+                    Ok(()) => valid_len = bytes.len() - reader.len(),
+                    Err(Error::UnexpectedEof) => break,
+                    Err(e) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            std::format!("{}", e),
+                        ))
+                    }
+                }
+            }
+            if valid_len < bytes.len() {
+                file.set_len(valid_len as u64)?;
+                file.seek(SeekFrom::End(0))?;
+            }
+            Ok(FileUtxoStore { file, coins })
+        }
+
+        /// The outputs, kept in memory.
+        pub fn coins(&self) -> &MemoryUtxoSet {
+            &self.coins
+        }
+
+        /// Write the data to the disk.
+        pub fn sync(&self) -> io::Result<()> {
+            self.file.sync_data()
+        }
+
+        fn append(&mut self, record: &[u8]) -> io::Result<()> {
+            self.file.write_all(record)
+        }
+    }
+
+    fn replay_record(r: &mut &[u8], coins: &mut MemoryUtxoSet) -> Result<(), Error> {
+        let tag = u8::consensus_decode(r)?;
+        let outpoint = OutPoint::consensus_decode(r)?;
+        match tag {
+            ADD => {
+                let coin = Coin::consensus_decode(r)?;
+                coins.coins.insert(outpoint, coin);
+            }
+            SPEND => {
+                coins.coins.remove(&outpoint);
+            }
+            _ => return Err(Error::ParseFailed("unknown UTXO record")),
+        }
+        Ok(())
+    }
+
+    impl UtxoView for FileUtxoStore {
+        fn get_coin(&self, outpoint: &OutPoint) -> Option<Coin> {
+            self.coins.get_coin(outpoint)
+        }
+    }
+
+    impl UtxoSet for FileUtxoStore {
+        type Error = io::Error;
+
+        fn add_coin(&mut self, outpoint: OutPoint, coin: Coin) -> io::Result<()> {
+            let mut record = encode::serialize(&ADD);
+            outpoint.consensus_encode(&mut record).unwrap();
+            coin.consensus_encode(&mut record).unwrap();
+            self.append(&record)?;
+            self.coins.coins.insert(outpoint, coin);
+            Ok(())
+        }
+
+        fn spend_coin(&mut self, outpoint: &OutPoint) -> io::Result<Option<Coin>> {
+            if !self.coins.coins.contains_key(outpoint) {
+                return Ok(None);
+            }
+            let mut record = encode::serialize(&SPEND);
+            outpoint.consensus_encode(&mut record).unwrap();
+            self.append(&record)?;
+            Ok(self.coins.coins.remove(outpoint))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::{Amount, COIN};
+    use crate::block::tests::GENESIS_BLOCK;
+    use crate::encode::{deserialize, serialize};
+    use crate::script::Script;
+    use crate::transaction::tests::spend;
+    use alloc::vec;
+
+    const PREVIOUS: OutPoint = OutPoint {
+        txid: [7; 32],
+        vout: 0,
+    };
+
+    /// The genesis block, with a transaction spending an output of a previous
+    /// block, and one spending it in the same block and creating an OP_RETURN
+    /// output.
+    fn block() -> Block {
+        let mut block: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let first = spend(
+            &[PREVIOUS],
+            &[Amount::from_sat(10 * COIN), Amount::from_sat(40 * COIN)],
+        );
+        let mut second = spend(
+            &[OutPoint::new(first.txid(), 1)],
+            &[Amount::from_sat(40 * COIN), Amount::ZERO],
+        );
+        second.outputs[1].script_pubkey = Script::of_bytes(vec![0x6a, 0x01, 0x42]);
+        block.transactions.extend([first, second]);
+        block
+    }
+
+    fn check_connect_disconnect<S: UtxoSet>(set: &mut S, snapshot: impl Fn(&S) -> MemoryUtxoSet)
+    where
+        S::Error: fmt::Debug,
+    {
+        let block = block();
+        assert!(matches!(
+            set.connect_block(&block, 1),
+            Err(UtxoError::MissingCoin(PREVIOUS))
+        ));
+        assert!(snapshot(set).is_empty());
+
+        let coin = Coin {
+            output: block.transactions[1].outputs[1].clone(),
+            height: 0,
+            is_coinbase: false,
+        };
+        set.add_coin(PREVIOUS, coin.clone()).unwrap();
+        let before = snapshot(set);
+        let undo = set.connect_block(&block, 1).unwrap();
+        assert_eq!(undo.spent_coins.len(), 2);
+        assert_eq!(undo.spent_coins[0], vec![coin]);
+        assert_eq!(undo.spent_coins[1][0].height, 1);
+        assert_eq!(
+            deserialize::<BlockUndo>(&serialize(&undo)),
+            Ok(undo.clone())
+        );
+
+        // The output spent in the block and the OP_RETURN output are skipped
+        let after = snapshot(set);
+        let first = block.transactions[1].txid();
+        let second = block.transactions[2].txid();
+        assert_eq!(after.len(), 3);
+        let coinbase = OutPoint::new(block.transactions[0].txid(), 0);
+        assert!(after.get_coin(&coinbase).unwrap().is_coinbase);
+        assert_eq!(
+            after
+                .get_coin(&OutPoint::new(first, 0))
+                .unwrap()
+                .output
+                .amount,
+            Amount::from_sat(10 * COIN)
+        );
+        assert_eq!(after.get_coin(&OutPoint::new(first, 1)), None);
+        assert!(after.get_coin(&OutPoint::new(second, 0)).is_some());
+        assert_eq!(after.get_coin(&OutPoint::new(second, 1)), None);
+        // The outputs can not be spent twice
+        assert!(matches!(
+            set.connect_block(&block, 2),
+            Err(UtxoError::MissingCoin(PREVIOUS))
+        ));
+
+        let mut truncated = undo.clone();
+        truncated.spent_coins.pop();
+        assert!(matches!(
+            set.disconnect_block(&block, &truncated),
+            Err(UtxoError::UndoMismatch)
+        ));
+        assert_eq!(snapshot(set), after);
+        set.disconnect_block(&block, &undo).unwrap();
+        assert_eq!(snapshot(set), before);
+    }
+
+    #[test]
+    pub fn test_memory_utxo_set() {
+        let mut set = MemoryUtxoSet::new();
+        check_connect_disconnect(&mut set, |set| set.clone());
+    }
+
+    #[test]
+    pub fn test_file_utxo_store() {
+        let path = std::env::temp_dir().join(std::format!("utxo-{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut store = FileUtxoStore::open(&path).unwrap();
+        check_connect_disconnect(&mut store, |store| store.coins().clone());
+        let coins = store.coins().clone();
+        let outpoint = *coins.iter().next().unwrap().0;
+        store.spend_coin(&outpoint).unwrap();
+        store.sync().unwrap();
+        drop(store);
+
+        // Replaying the records gives the same set, even with an incomplete
+        // record at the end
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, &[0, 1, 2]).unwrap();
+        drop(file);
+        let mut store = FileUtxoStore::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        let mut expected = coins.clone();
+        expected.spend_coin(&outpoint).unwrap();
+        assert_eq!(store.coins(), &expected);
+        store
+            .add_coin(outpoint, coins.get_coin(&outpoint).unwrap())
+            .unwrap();
+        drop(store);
+        assert_eq!(FileUtxoStore::open(&path).unwrap().coins(), &coins);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn test_outpoint_display() {
+        let block: Block = deserialize(&hex::decode(GENESIS_BLOCK).unwrap()).unwrap();
+        let outpoint = OutPoint::new(block.transactions[0].txid(), 0);
+        assert_eq!(
+            std::format!("{}", outpoint),
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b:0"
+        );
+        assert_eq!(deserialize::<OutPoint>(&serialize(&outpoint)), Ok(outpoint));
+    }
+}
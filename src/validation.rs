@@ -0,0 +1,838 @@
+//! Consensus checks of transactions and blocks.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::amount::{Amount, COIN, MAX_MONEY};
+use crate::block::Block;
+use crate::fee_rate::WITNESS_SCALE_FACTOR;
+use crate::merkle::merkle_root_with_mutation;
+use crate::params::ChainParams;
+use crate::script::ScriptRef;
+use crate::transaction::Transaction;
+use crate::utils::VarInt;
+use crate::utxo::{Coin, OutPoint, UtxoView};
+
+/// The maximum weight of a block (BIP 141).
+pub const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+
+/// The maximum signature operations cost of a block (BIP 141).
+pub const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+
+/// The number of blocks after which the outputs of a coinbase can be spent.
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// Reasons for a transaction to fail the context-free checks, named after the
+/// reject reasons of the reference implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxValidationError {
+    /// The transaction has no input (`bad-txns-vin-empty`).
+    EmptyInputs,
+    /// The transaction has no output (`bad-txns-vout-empty`).
+    EmptyOutputs,
+    /// The transaction without witnesses would not fit in a block
+    /// (`bad-txns-oversize`).
+    Oversize,
+    /// The amount of an output is negative once interpreted as a signed
+    /// integer, as in the reference implementation (`bad-txns-vout-negative`).
+    OutputNegative,
+    /// The amount of an output is larger than
+    /// [MAX_MONEY] (`bad-txns-vout-toolarge`).
+    OutputTooLarge,
+    /// The sum of the outputs is larger than
+    /// [MAX_MONEY] (`bad-txns-txouttotal-toolarge`).
+    OutputTotalTooLarge,
+    /// An output is spent twice (`bad-txns-inputs-duplicate`).
+    DuplicateInputs,
+    /// The script of the coinbase input is not between 2 and 100 bytes
+    /// (`bad-cb-length`).
+    CoinbaseScriptLength,
+    /// An input of a transaction which is not a coinbase spends no output
+    /// (`bad-txns-prevout-null`).
+    NullPrevout,
+}
+
+impl fmt::Display for TxValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxValidationError::EmptyInputs => write!(f, "bad-txns-vin-empty"),
+            TxValidationError::EmptyOutputs => write!(f, "bad-txns-vout-empty"),
+            TxValidationError::Oversize => write!(f, "bad-txns-oversize"),
+            TxValidationError::OutputNegative => write!(f, "bad-txns-vout-negative"),
+            TxValidationError::OutputTooLarge => write!(f, "bad-txns-vout-toolarge"),
+            TxValidationError::OutputTotalTooLarge => write!(f, "bad-txns-txouttotal-toolarge"),
+            TxValidationError::DuplicateInputs => write!(f, "bad-txns-inputs-duplicate"),
+            TxValidationError::CoinbaseScriptLength => write!(f, "bad-cb-length"),
+            TxValidationError::NullPrevout => write!(f, "bad-txns-prevout-null"),
+        }
+    }
+}
+
+/// The checks of a transaction which do not depend on the chain, as
+/// `CheckTransaction` in the reference implementation.
+pub fn check_transaction(tx: &Transaction) -> Result<(), TxValidationError> {
+    if tx.inputs.is_empty() {
+        return Err(TxValidationError::EmptyInputs);
+    }
+    if tx.outputs.is_empty() {
+        return Err(TxValidationError::EmptyOutputs);
+    }
+    if tx.base_size() as u64 * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT {
+        return Err(TxValidationError::Oversize);
+    }
+
+    let mut total: u64 = 0;
+    for output in &tx.outputs {
+        let amount = output.amount.to_sat();
+        if (amount as i64) < 0 {
+            return Err(TxValidationError::OutputNegative);
+        }
+        if amount > MAX_MONEY {
+            return Err(TxValidationError::OutputTooLarge);
+        }
+        // Both are at most MAX_MONEY, so the sum does not overflow
+        total += amount;
+        if total > MAX_MONEY {
+            return Err(TxValidationError::OutputTotalTooLarge);
+        }
+    }
+
+    let mut spent = BTreeSet::new();
+    for input in &tx.inputs {
+        if !spent.insert((input.txid, input.vout)) {
+            return Err(TxValidationError::DuplicateInputs);
+        }
+    }
+
+    if tx.is_coinbase() {
+        let length = tx.inputs[0].script_sig.to_bytes().len();
+        if !(2..=100).contains(&length) {
+            return Err(TxValidationError::CoinbaseScriptLength);
+        }
+    } else if tx.inputs.iter().any(|input| input.is_null_prevout()) {
+        return Err(TxValidationError::NullPrevout);
+    }
+    Ok(())
+}
+
+/// Reasons for a block to be invalid, named after the reject reasons of the
+/// reference implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockValidationError {
+    /// The block has no transaction, or too many data without the witnesses
+    /// (`bad-blk-length`).
+    BadLength,
+    /// The first transaction is not a coinbase (`bad-cb-missing`).
+    MissingCoinbase,
+    /// A transaction other than the first one is a coinbase
+    /// (`bad-cb-multiple`).
+    MultipleCoinbases,
+    /// The transaction at the given index fails the context-free checks.
+    Transaction {
+        index: usize,
+        error: TxValidationError,
+    },
+    /// The merkle root of the header does not commit to the transactions
+    /// (`bad-txnmrklroot`).
+    MerkleRootMismatch,
+    /// The list of transactions is mutated, see
+    /// [merkle_root_with_mutation] (`bad-txns-duplicate`).
+    DuplicateTransactions,
+    /// The signature operations cost more than
+    /// [MAX_BLOCK_SIGOPS_COST] (`bad-blk-sigops`).
+    TooManySigops,
+    /// The coinbase does not start with the height of the block (BIP 34,
+    /// `bad-cb-height`).
+    BadCoinbaseHeight,
+    /// The coinbase witness is not a single 32-byte item
+    /// (`bad-witness-nonce-size`).
+    WitnessNonceSize,
+    /// The witness commitment does not match the witnesses
+    /// (`bad-witness-merkle-match`).
+    WitnessMerkleMismatch,
+    /// A transaction has a witness but the coinbase has no witness commitment
+    /// (`unexpected-witness`).
+    UnexpectedWitness,
+    /// The weight of the block is larger than
+    /// [MAX_BLOCK_WEIGHT] (`bad-blk-weight`).
+    BadWeight,
+    /// The transaction at the given index spends an output which does not
+    /// exist or is already spent (`bad-txns-inputs-missingorspent`).
+    MissingOrSpentInputs { index: usize },
+    /// The transaction at the given index spends the outputs of a coinbase
+    /// with less than [COINBASE_MATURITY] confirmations
+    /// (`bad-txns-premature-spend-of-coinbase`).
+    PrematureCoinbaseSpend { index: usize },
+    /// The sum of the inputs of the transaction at the given index is larger
+    /// than [MAX_MONEY] (`bad-txns-inputvalues-outofrange`).
+    InputValuesOutOfRange { index: usize },
+    /// The transaction at the given index spends less than its outputs
+    /// (`bad-txns-in-belowout`).
+    InputsBelowOutputs { index: usize },
+    /// The sum of the fees is larger than [MAX_MONEY]
+    /// (`bad-txns-accumulated-fee-outofrange`).
+    FeesOutOfRange,
+    /// The coinbase pays more than the subsidy and the fees (`bad-cb-amount`).
+    CoinbaseAmount,
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockValidationError::BadLength => write!(f, "bad-blk-length"),
+            BlockValidationError::MissingCoinbase => write!(f, "bad-cb-missing"),
+            BlockValidationError::MultipleCoinbases => write!(f, "bad-cb-multiple"),
+            BlockValidationError::Transaction { index, error } => {
+                write!(f, "{} (transaction {})", error, index)
+            }
+            BlockValidationError::MerkleRootMismatch => write!(f, "bad-txnmrklroot"),
+            BlockValidationError::DuplicateTransactions => write!(f, "bad-txns-duplicate"),
+            BlockValidationError::TooManySigops => write!(f, "bad-blk-sigops"),
+            BlockValidationError::BadCoinbaseHeight => write!(f, "bad-cb-height"),
+            BlockValidationError::WitnessNonceSize => write!(f, "bad-witness-nonce-size"),
+            BlockValidationError::WitnessMerkleMismatch => write!(f, "bad-witness-merkle-match"),
+            BlockValidationError::UnexpectedWitness => write!(f, "unexpected-witness"),
+            BlockValidationError::BadWeight => write!(f, "bad-blk-weight"),
+            BlockValidationError::MissingOrSpentInputs { index } => {
+                write!(f, "bad-txns-inputs-missingorspent (transaction {})", index)
+            }
+            BlockValidationError::PrematureCoinbaseSpend { index } => {
+                write!(
+                    f,
+                    "bad-txns-premature-spend-of-coinbase (transaction {})",
+                    index
+                )
+            }
+            BlockValidationError::InputValuesOutOfRange { index } => {
+                write!(f, "bad-txns-inputvalues-outofrange (transaction {})", index)
+            }
+            BlockValidationError::InputsBelowOutputs { index } => {
+                write!(f, "bad-txns-in-belowout (transaction {})", index)
+            }
+            BlockValidationError::FeesOutOfRange => {
+                write!(f, "bad-txns-accumulated-fee-outofrange")
+            }
+            BlockValidationError::CoinbaseAmount => write!(f, "bad-cb-amount"),
+        }
+    }
+}
+
+/// The amount of new coins the coinbase of the block at the given height can
+/// create, halved every [subsidy_halving_interval] blocks.
+///
+/// [subsidy_halving_interval]: ChainParams::subsidy_halving_interval
+pub fn block_subsidy(height: u32, params: &ChainParams) -> Amount {
+    let halvings = height / params.subsidy_halving_interval;
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat((50 * COIN) >> halvings)
+}
+
+/// The number of signature operations in the scripts of the transaction,
+/// counted the legacy way, as `GetLegacySigOpCount` in the reference
+/// implementation.
+fn legacy_sigop_count(tx: &Transaction) -> u64 {
+    let inputs = tx
+        .inputs
+        .iter()
+        .map(|input| ScriptRef::new(&input.script_sig.to_bytes()).sigop_count(false));
+    let outputs = tx
+        .outputs
+        .iter()
+        .map(|output| ScriptRef::new(&output.script_pubkey.to_bytes()).sigop_count(false));
+    inputs.chain(outputs).sum::<usize>() as u64
+}
+
+/// The cost of the signature operations of a witness program, as
+/// `WitnessSigOps` in the reference implementation.
+fn witness_sigop_count(version: u8, program: &[u8], witness: &[Vec<u8>]) -> u64 {
+    match (version, program.len(), witness.last()) {
+        (0, 20, _) => 1,
+        (0, 32, Some(witness_script)) => ScriptRef::new(witness_script).sigop_count(true) as u64,
+        _ => 0,
+    }
+}
+
+/// The signature operations cost of a transaction which is not a coinbase,
+/// given the outputs spent by its inputs, as `GetTransactionSigOpCost` in the
+/// reference implementation. The operations of legacy and pay-to-script-hash
+/// scripts cost four times more than the ones of witness scripts.
+fn sigop_cost(tx: &Transaction, coins: &[Coin]) -> u64 {
+    let mut cost = legacy_sigop_count(tx) * WITNESS_SCALE_FACTOR;
+    for (i, (input, coin)) in tx.inputs.iter().zip(coins).enumerate() {
+        let script_sig = input.script_sig.to_bytes();
+        let script_sig = ScriptRef::new(&script_sig);
+        let script_pubkey = coin.output.script_pubkey.to_bytes();
+        let script_pubkey = ScriptRef::new(&script_pubkey);
+        let witness = tx.witnesses.get(i).map_or(&[][..], |w| &w[..]);
+        if let Some((version, program)) = script_pubkey.witness_program() {
+            cost += witness_sigop_count(version, program, witness);
+        } else if script_pubkey.is_p2sh() && script_sig.is_push_only() {
+            let redeem_script = ScriptRef::new(script_sig.last_push().unwrap_or(&[]));
+            cost += redeem_script.sigop_count(true) as u64 * WITNESS_SCALE_FACTOR;
+            if let Some((version, program)) = redeem_script.witness_program() {
+                cost += witness_sigop_count(version, program, witness);
+            }
+        }
+    }
+    cost
+}
+
+/// Check the witness commitment of the coinbase, if any, against the
+/// witnesses of the block (BIP 141).
+fn check_witness_commitment(block: &Block) -> Result<(), BlockValidationError> {
+    let Some(commitment) = block.witness_commitment() else {
+        // Without commitment, witnesses could be changed freely
+        if block
+            .transactions
+            .iter()
+            .any(|tx| tx.is_segregated_witness())
+        {
+            return Err(BlockValidationError::UnexpectedWitness);
+        }
+        return Ok(());
+    };
+    let reserved_value = block
+        .witness_reserved_value()
+        .ok_or(BlockValidationError::WitnessNonceSize)?;
+    let witness_root = block.witness_root().unwrap();
+    if Block::compute_witness_commitment(&witness_root, &reserved_value) != commitment {
+        return Err(BlockValidationError::WitnessMerkleMismatch);
+    }
+    Ok(())
+}
+
+/// Check the transactions of the block against the merkle root of the
+/// header, rejecting the mutated trees of CVE-2012-2459.
+fn check_merkle_root(block: &Block, txids: &[[u8; 32]]) -> Result<(), BlockValidationError> {
+    let (root, mutated) =
+        merkle_root_with_mutation(txids).ok_or(BlockValidationError::BadLength)?;
+    if root != block.header.merkle_root {
+        return Err(BlockValidationError::MerkleRootMismatch);
+    }
+    if mutated {
+        return Err(BlockValidationError::DuplicateTransactions);
+    }
+    Ok(())
+}
+
+/// Check that the transactions of the block, and their witnesses if any, are
+/// the ones committed to by the header. Unlike [check_block], it needs neither
+/// the height nor the outputs spent, so that it can be used to reject a
+/// tampered block as soon as it is received.
+pub fn check_block_commitments(block: &Block) -> Result<(), BlockValidationError> {
+    let txids: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.txid()).collect();
+    check_merkle_root(block, &txids)?;
+    if block
+        .transactions
+        .iter()
+        .any(|tx| tx.is_segregated_witness())
+    {
+        check_witness_commitment(block)?;
+    }
+    Ok(())
+}
+
+/// Validate the block as the one at the given height, spending outputs from
+/// `utxo_view`, as `CheckBlock`, `ContextualCheckBlock` and `ConnectBlock` of
+/// the reference implementation do.
+///
+/// The checks cover the structure of the block, the transactions and their
+/// merkle root, the coinbase height and witness commitment, the weight and
+/// signature operations limits, and the amounts spent, paid and created. The
+/// header is expected to be already validated, e.g. with
+/// [HeaderChain](crate::chain::HeaderChain), and the scripts of the inputs are
+/// not executed.
+pub fn check_block<U: UtxoView + ?Sized>(
+    block: &Block,
+    params: &ChainParams,
+    height: u32,
+    utxo_view: &U,
+) -> Result<(), BlockValidationError> {
+    let transactions = &block.transactions;
+    let txids: Vec<[u8; 32]> = transactions.iter().map(|tx| tx.txid()).collect();
+    check_merkle_root(block, &txids)?;
+
+    let base_size = 80
+        + VarInt(transactions.len() as u64).size() as u64
+        + transactions
+            .iter()
+            .map(|tx| tx.base_size() as u64)
+            .sum::<u64>();
+    if transactions.len() as u64 * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+        || base_size * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+    {
+        return Err(BlockValidationError::BadLength);
+    }
+    if !transactions[0].is_coinbase() {
+        return Err(BlockValidationError::MissingCoinbase);
+    }
+    if transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+        return Err(BlockValidationError::MultipleCoinbases);
+    }
+    for (index, tx) in transactions.iter().enumerate() {
+        check_transaction(tx)
+            .map_err(|error| BlockValidationError::Transaction { index, error })?;
+    }
+    let legacy_sigops: u64 = transactions.iter().map(legacy_sigop_count).sum();
+    if legacy_sigops * WITNESS_SCALE_FACTOR > MAX_BLOCK_SIGOPS_COST {
+        return Err(BlockValidationError::TooManySigops);
+    }
+
+    if height >= params.bip34_height && block.bip34_height() != Some(height) {
+        return Err(BlockValidationError::BadCoinbaseHeight);
+    }
+    if height >= params.segwit_height {
+        check_witness_commitment(block)?;
+    } else if transactions.iter().any(|tx| tx.is_segregated_witness()) {
+        return Err(BlockValidationError::UnexpectedWitness);
+    }
+    if block.weight() > MAX_BLOCK_WEIGHT {
+        return Err(BlockValidationError::BadWeight);
+    }
+
+    // The outputs created and spent by the previous transactions of the block
+    let mut created: BTreeMap<OutPoint, Coin> = BTreeMap::new();
+    let mut spent: BTreeSet<OutPoint> = BTreeSet::new();
+    let mut fees = Amount::ZERO;
+    let mut sigops = legacy_sigop_count(&transactions[0]) * WITNESS_SCALE_FACTOR;
+    for (index, tx) in transactions.iter().enumerate() {
+        if index > 0 {
+            let mut coins = Vec::with_capacity(tx.inputs.len());
+            for input in &tx.inputs {
+                let outpoint = OutPoint::from(input);
+                let coin = if spent.insert(outpoint) {
+                    created
+                        .remove(&outpoint)
+                        .or_else(|| utxo_view.get_coin(&outpoint))
+                } else {
+                    None
+                };
+                let coin = coin.ok_or(BlockValidationError::MissingOrSpentInputs { index })?;
+                if coin.is_coinbase && height.saturating_sub(coin.height) < COINBASE_MATURITY {
+                    return Err(BlockValidationError::PrematureCoinbaseSpend { index });
+                }
+                coins.push(coin);
+            }
+            let inputs = Amount::checked_sum(coins.iter().map(|coin| coin.output.amount))
+                .ok_or(BlockValidationError::InputValuesOutOfRange { index })?;
+            // Checked by check_transaction
+            let outputs =
+                Amount::checked_sum(tx.outputs.iter().map(|output| output.amount)).unwrap();
+            let fee = inputs
+                .checked_sub(outputs)
+                .ok_or(BlockValidationError::InputsBelowOutputs { index })?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(BlockValidationError::FeesOutOfRange)?;
+            sigops += sigop_cost(tx, &coins);
+            if sigops > MAX_BLOCK_SIGOPS_COST {
+                return Err(BlockValidationError::TooManySigops);
+            }
+        }
+        let txid = txids[index];
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let coin = Coin {
+                output: output.clone(),
+                height,
+                is_coinbase: index == 0,
+            };
+            created.insert(OutPoint::new(txid, vout as u32), coin);
+        }
+    }
+
+    let reward =
+        Amount::checked_sum(transactions[0].outputs.iter().map(|output| output.amount)).unwrap();
+    if reward
+        > fees
+            .checked_add(block_subsidy(height, params))
+            .unwrap_or(Amount::MAX_MONEY)
+    {
+        return Err(BlockValidationError::CoinbaseAmount);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, WITNESS_COMMITMENT_HEADER};
+    use crate::encode::deserialize;
+    use crate::merkle::merkle_root;
+    use crate::script::Script;
+    use crate::transaction::tests::{coinbase, spend};
+    use crate::transaction::TransactionOutput;
+    use alloc::vec;
+
+    fn segwit_transaction() -> Transaction {
+        deserialize(&hex::decode(crate::transaction::tests::SEGWIT_TX).unwrap()).unwrap()
+    }
+
+    #[test]
+    pub fn test_check_valid_transactions() {
+        assert_eq!(check_transaction(&segwit_transaction()), Ok(()));
+        let genesis: Block =
+            deserialize(&hex::decode(crate::block::tests::GENESIS_BLOCK).unwrap()).unwrap();
+        assert!(genesis.transactions[0].is_coinbase());
+        assert_eq!(check_transaction(&genesis.transactions[0]), Ok(()));
+    }
+
+    #[test]
+    pub fn test_check_invalid_transactions() {
+        let check = |f: &dyn Fn(&mut Transaction)| {
+            let mut tx = segwit_transaction();
+            f(&mut tx);
+            check_transaction(&tx)
+        };
+        assert_eq!(
+            check(&|tx| tx.inputs.clear()),
+            Err(TxValidationError::EmptyInputs)
+        );
+        assert_eq!(
+            check(&|tx| tx.outputs.clear()),
+            Err(TxValidationError::EmptyOutputs)
+        );
+        assert_eq!(
+            check(&|tx| {
+                // A push of 1MB, with OP_PUSHDATA4
+                let mut bytes = vec![0x4e, 0x40, 0x42, 0x0f, 0x00];
+                bytes.resize(5 + 1_000_000, 0);
+                tx.outputs[0].script_pubkey = Script::of_bytes(bytes);
+            }),
+            Err(TxValidationError::Oversize)
+        );
+        assert_eq!(
+            check(&|tx| tx.outputs[0].amount = Amount::from_sat(u64::MAX)),
+            Err(TxValidationError::OutputNegative)
+        );
+        assert_eq!(
+            check(&|tx| tx.outputs[0].amount = Amount::from_sat(MAX_MONEY + 1)),
+            Err(TxValidationError::OutputTooLarge)
+        );
+        assert_eq!(
+            check(&|tx| tx.outputs[1].amount = Amount::MAX_MONEY),
+            Err(TxValidationError::OutputTotalTooLarge)
+        );
+        assert_eq!(
+            check(&|tx| tx.inputs[1] = tx.inputs[0].clone()),
+            Err(TxValidationError::DuplicateInputs)
+        );
+        assert_eq!(
+            check(&|tx| {
+                tx.inputs[1].txid = [0; 32];
+                tx.inputs[1].vout = [0xff; 4];
+            }),
+            Err(TxValidationError::NullPrevout)
+        );
+        assert_eq!(
+            check(&|tx| {
+                tx.inputs.truncate(1);
+                tx.witnesses.truncate(1);
+                tx.inputs[0].txid = [0; 32];
+                tx.inputs[0].vout = [0xff; 4];
+                tx.inputs[0].script_sig = Script::of_bytes(vec![0x51]);
+            }),
+            Err(TxValidationError::CoinbaseScriptLength)
+        );
+    }
+
+    const OP_TRUE: u8 = 0x51;
+
+    fn output(amount: Amount, script_pubkey: Vec<u8>) -> TransactionOutput {
+        TransactionOutput {
+            amount,
+            script_pubkey: Script::of_bytes(script_pubkey),
+        }
+    }
+
+    /// Build a block with the given transactions, adding a witness commitment
+    /// to the coinbase if one of them has a witness.
+    fn block(transactions: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: ChainParams::regtest().genesis_header,
+            transactions,
+        };
+        if block
+            .transactions
+            .iter()
+            .any(|tx| tx.is_segregated_witness())
+        {
+            let witness_root = block.witness_root().unwrap();
+            let mut script_pubkey = WITNESS_COMMITMENT_HEADER.to_vec();
+            script_pubkey.extend(Block::compute_witness_commitment(&witness_root, &[0; 32]));
+            let coinbase = &mut block.transactions[0];
+            coinbase.outputs.push(output(Amount::ZERO, script_pubkey));
+            coinbase.witnesses = vec![vec![vec![0; 32]]];
+        }
+        update_merkle_root(&mut block);
+        block
+    }
+
+    /// Update the merkle root of the header after changing the transactions.
+    fn update_merkle_root(block: &mut Block) {
+        let txids: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.txid()).collect();
+        block.header.merkle_root = merkle_root(&txids).unwrap();
+    }
+
+    /// A view with a mature coinbase output paying 50 BTC to OP_TRUE, and an
+    /// output of a regular transaction paying 1 BTC to the given script.
+    fn utxo_view(script_pubkey: Vec<u8>) -> BTreeMap<OutPoint, Coin> {
+        let mut view = BTreeMap::new();
+        view.insert(
+            OutPoint::new([1; 32], 0),
+            Coin {
+                output: output(Amount::from_sat(50 * COIN), vec![OP_TRUE]),
+                height: 1,
+                is_coinbase: true,
+            },
+        );
+        view.insert(
+            OutPoint::new([2; 32], 0),
+            Coin {
+                output: output(Amount::ONE_BTC, script_pubkey),
+                height: 50,
+                is_coinbase: false,
+            },
+        );
+        view
+    }
+
+    #[test]
+    pub fn test_block_subsidy() {
+        let mainnet = ChainParams::mainnet();
+        assert_eq!(block_subsidy(0, &mainnet), Amount::from_sat(50 * COIN));
+        assert_eq!(
+            block_subsidy(209_999, &mainnet),
+            Amount::from_sat(50 * COIN)
+        );
+        assert_eq!(
+            block_subsidy(210_000, &mainnet),
+            Amount::from_sat(25 * COIN)
+        );
+        assert_eq!(
+            block_subsidy(840_000, &mainnet),
+            Amount::from_sat(312_500_000)
+        );
+        assert_eq!(block_subsidy(33 * 210_000, &mainnet), Amount::ZERO);
+        assert_eq!(block_subsidy(64 * 210_000, &mainnet), Amount::ZERO);
+        let regtest = ChainParams::regtest();
+        assert_eq!(block_subsidy(150, &regtest), Amount::from_sat(25 * COIN));
+        assert_eq!(block_subsidy(u32::MAX, &regtest), Amount::ZERO);
+    }
+
+    #[test]
+    pub fn test_validate_blocks() {
+        let params = ChainParams::regtest();
+        let view = utxo_view(vec![OP_TRUE]);
+        let fee = Amount::from_sat(1000);
+        let coin = OutPoint::new([1; 32], 0);
+        let spending = spend(&[coin], &[Amount::from_sat(50 * COIN - 1000)]);
+        let chained = spend(
+            &[OutPoint::new(spending.txid(), 0)],
+            &[Amount::from_sat(50 * COIN - 2000)],
+        );
+        let reward = Amount::from_sat(50 * COIN + 2000);
+        let valid = block(vec![coinbase(101, 0, reward), spending.clone(), chained]);
+        assert_eq!(valid.validate(&params, 101, &view), Ok(()));
+        // A block with only a coinbase, claiming less than allowed
+        let empty = block(vec![coinbase(1, 0, fee)]);
+        assert_eq!(empty.validate(&params, 1, &BTreeMap::new()), Ok(()));
+
+        assert_eq!(
+            valid.validate(&params, 100, &view),
+            Err(BlockValidationError::BadCoinbaseHeight)
+        );
+        let premature = block(vec![coinbase(100, 0, reward), spending.clone()]);
+        assert_eq!(
+            premature.validate(&params, 100, &view),
+            Err(BlockValidationError::PrematureCoinbaseSpend { index: 1 })
+        );
+        let greedy = block(vec![
+            coinbase(101, 0, Amount::from_sat(50 * COIN + 1001)),
+            spending.clone(),
+        ]);
+        assert_eq!(
+            greedy.validate(&params, 101, &view),
+            Err(BlockValidationError::CoinbaseAmount)
+        );
+        // The subsidy is halved after 150 blocks on regtest
+        let halved = block(vec![coinbase(150, 0, reward), spending.clone()]);
+        assert_eq!(
+            halved.validate(&params, 150, &view),
+            Err(BlockValidationError::CoinbaseAmount)
+        );
+        let double_spend = block(vec![
+            coinbase(101, 0, reward),
+            spending.clone(),
+            spend(&[coin], &[Amount::ONE_BTC]),
+        ]);
+        assert_eq!(
+            double_spend.validate(&params, 101, &view),
+            Err(BlockValidationError::MissingOrSpentInputs { index: 2 })
+        );
+        let missing = block(vec![coinbase(101, 0, reward), spending.clone()]);
+        assert_eq!(
+            missing.validate(&params, 101, &BTreeMap::new()),
+            Err(BlockValidationError::MissingOrSpentInputs { index: 1 })
+        );
+        let overspend = block(vec![
+            coinbase(101, 0, reward),
+            spend(&[coin], &[Amount::from_sat(50 * COIN + 1)]),
+        ]);
+        assert_eq!(
+            overspend.validate(&params, 101, &view),
+            Err(BlockValidationError::InputsBelowOutputs { index: 1 })
+        );
+
+        let mut tampered = valid.clone();
+        tampered.transactions.swap(1, 2);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::MerkleRootMismatch)
+        );
+        // Duplicating the last transaction keeps the merkle root
+        let mut tampered = valid.clone();
+        tampered.transactions.push(tampered.transactions[2].clone());
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::DuplicateTransactions)
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions.remove(0);
+        update_merkle_root(&mut tampered);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::MissingCoinbase)
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions[2] = coinbase(102, 0, Amount::ZERO);
+        update_merkle_root(&mut tampered);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::MultipleCoinbases)
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions[1].outputs.clear();
+        update_merkle_root(&mut tampered);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::Transaction {
+                index: 1,
+                error: TxValidationError::EmptyOutputs
+            })
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions.clear();
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::BadLength)
+        );
+    }
+
+    #[test]
+    pub fn test_validate_witness_commitment() {
+        let params = ChainParams::regtest();
+        let view = utxo_view(vec![OP_TRUE]);
+        let mut segwit = spend(
+            &[OutPoint::new([2; 32], 0)],
+            &[Amount::from_sat(COIN - 1000)],
+        );
+        segwit.witnesses = vec![vec![vec![0x42; 72]]];
+        let coinbase = coinbase(101, 0, Amount::from_sat(50 * COIN + 1000));
+        let valid = block(vec![coinbase.clone(), segwit.clone()]);
+        assert_eq!(valid.validate(&params, 101, &view), Ok(()));
+        assert_eq!(check_block_commitments(&valid), Ok(()));
+
+        // Witnesses are not committed to by the merkle root of the header
+        let mut tampered = valid.clone();
+        tampered.transactions[1].witnesses[0][0][0] = 0;
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::WitnessMerkleMismatch)
+        );
+        assert_eq!(
+            check_block_commitments(&tampered),
+            Err(BlockValidationError::WitnessMerkleMismatch)
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions[0].witnesses[0].push(vec![]);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::WitnessNonceSize)
+        );
+        let mut tampered = valid.clone();
+        tampered.transactions[0].outputs.pop();
+        tampered.transactions[0].witnesses.clear();
+        update_merkle_root(&mut tampered);
+        assert_eq!(
+            tampered.validate(&params, 101, &view),
+            Err(BlockValidationError::UnexpectedWitness)
+        );
+
+        // Witnesses are only limited by the weight of the block
+        segwit.witnesses[0].push(vec![0; 3_999_000]);
+        let heavy = block(vec![coinbase, segwit]);
+        assert!(heavy.weight() > MAX_BLOCK_WEIGHT);
+        assert_eq!(
+            heavy.validate(&params, 101, &view),
+            Err(BlockValidationError::BadWeight)
+        );
+    }
+
+    #[test]
+    pub fn test_validate_sigops() {
+        let params = ChainParams::regtest();
+        let coinbase = coinbase(101, 0, Amount::from_sat(50 * COIN));
+        let checksigs = vec![0xac; 20_001];
+        // A push of the given data with OP_PUSHDATA2
+        let push = |data: &[u8]| {
+            let mut bytes = vec![0x4d];
+            bytes.extend((data.len() as u16).to_le_bytes());
+            bytes.extend(data);
+            bytes
+        };
+
+        let mut legacy = spend(&[OutPoint::new([2; 32], 0)], &[Amount::ONE_BTC]);
+        legacy.outputs[0].script_pubkey = Script::of_bytes(checksigs.clone());
+        let view = utxo_view(vec![OP_TRUE]);
+        assert_eq!(
+            block(vec![coinbase.clone(), legacy]).validate(&params, 101, &view),
+            Err(BlockValidationError::TooManySigops)
+        );
+
+        let redeem_script = checksigs.clone();
+        let mut p2sh = vec![0xa9, 0x14];
+        p2sh.extend([0x42; 20]);
+        p2sh.push(0x87);
+        let mut spending = spend(&[OutPoint::new([2; 32], 0)], &[Amount::ONE_BTC]);
+        spending.inputs[0].script_sig = Script::of_bytes(push(&redeem_script));
+        assert_eq!(
+            block(vec![coinbase.clone(), spending.clone()]).validate(
+                &params,
+                101,
+                &utxo_view(p2sh)
+            ),
+            Err(BlockValidationError::TooManySigops)
+        );
+        // Witness operations are four times cheaper
+        let mut p2wsh = vec![0x00, 0x20];
+        p2wsh.extend([0x07; 32]);
+        spending.inputs[0].script_sig = Script::new(vec![]);
+        spending.witnesses = vec![vec![checksigs]];
+        assert_eq!(
+            block(vec![coinbase.clone(), spending.clone()]).validate(
+                &params,
+                101,
+                &utxo_view(p2wsh.clone())
+            ),
+            Ok(())
+        );
+        spending.witnesses = vec![vec![vec![0xac; 80_001]]];
+        assert_eq!(
+            block(vec![coinbase, spending]).validate(&params, 101, &utxo_view(p2wsh)),
+            Err(BlockValidationError::TooManySigops)
+        );
+    }
+}
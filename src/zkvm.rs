@@ -0,0 +1,309 @@
+//! Guest program of the light client, to be run inside a zkVM like
+//! [o1vm](https://github.com/o1-labs/proof-systems/tree/master/o1vm).
+//!
+//! The guest reads from its input channel a [HeaderRangeInput]: the network, a
+//! trusted checkpoint and the headers following it. The headers are verified
+//! with the [HeaderChain], and a [HeaderRangeOutput] is written as public
+//! output, binding the network and the checkpoint to the new tip and its
+//! cumulative work. As the checkpoint is private, the output commits to all
+//! its fields: the verifier must check the commitment against a checkpoint it
+//! trusts.
+//!
+//! The logic is independent of the channels, so that it can be run and tested
+//! natively. The `zkvm_headers` binary wires it to the channels of the zkVM.
+
+use alloc::vec::Vec;
+
+use crate::block::BlockHeader;
+use crate::chain::{Checkpoint, HeaderChain, HeaderError};
+use crate::encode::{self, Decodable, Encodable, Read, Write};
+use crate::params::{ChainParams, Network};
+use crate::uint::U256;
+
+/// The private input of the guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRangeInput {
+    pub network: Network,
+    pub checkpoint: Checkpoint,
+    /// The headers following the checkpoint, in order.
+    pub headers: Vec<BlockHeader>,
+}
+
+/// The public output of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderRangeOutput {
+    /// The network whose rules the headers were verified with.
+    pub network: Network,
+    /// The commitment to the checkpoint, see [Checkpoint::commitment].
+    pub checkpoint_commitment: [u8; 32],
+    /// The hash of the checkpoint the verification started from.
+    pub checkpoint_hash: [u8; 32],
+    pub checkpoint_height: u32,
+    /// The hash of the last verified header.
+    pub tip_hash: [u8; 32],
+    pub tip_height: u32,
+    /// The total work of the chain up to the tip.
+    pub chain_work: U256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestError {
+    /// The input can not be decoded.
+    Decode(encode::Error),
+    /// The header at the given height is invalid.
+    Header { height: u32, error: HeaderError },
+}
+
+impl Encodable for HeaderRangeInput {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, encode::Error> {
+        Ok(self.network.consensus_encode(w)?
+            + self.checkpoint.consensus_encode(w)?
+            + encode::encode_vec(&self.headers, w)?)
+    }
+}
+
+impl Decodable for HeaderRangeInput {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        Ok(HeaderRangeInput {
+            network: Decodable::consensus_decode(r)?,
+            checkpoint: Decodable::consensus_decode(r)?,
+            headers: encode::decode_vec(r)?,
+        })
+    }
+}
+
+impl Encodable for HeaderRangeOutput {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, encode::Error> {
+        Ok(self.network.consensus_encode(w)?
+            + self.checkpoint_commitment.consensus_encode(w)?
+            + self.checkpoint_hash.consensus_encode(w)?
+            + self.checkpoint_height.consensus_encode(w)?
+            + self.tip_hash.consensus_encode(w)?
+            + self.tip_height.consensus_encode(w)?
+            + self.chain_work.consensus_encode(w)?)
+    }
+}
+
+impl Decodable for HeaderRangeOutput {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        Ok(HeaderRangeOutput {
+            network: Decodable::consensus_decode(r)?,
+            checkpoint_commitment: Decodable::consensus_decode(r)?,
+            checkpoint_hash: Decodable::consensus_decode(r)?,
+            checkpoint_height: Decodable::consensus_decode(r)?,
+            tip_hash: Decodable::consensus_decode(r)?,
+            tip_height: Decodable::consensus_decode(r)?,
+            chain_work: Decodable::consensus_decode(r)?,
+        })
+    }
+}
+
+/// Verify the headers of the input on top of its checkpoint.
+pub fn verify_header_range(input: HeaderRangeInput) -> Result<HeaderRangeOutput, GuestError> {
+    let checkpoint_commitment = input.checkpoint.commitment();
+    let checkpoint_hash = input.checkpoint.header.block_hash();
+    let checkpoint_height = input.checkpoint.height;
+    let mut chain = HeaderChain::new(ChainParams::new(input.network), input.checkpoint);
+    for header in input.headers {
+        chain.push(header).map_err(|error| GuestError::Header {
+            height: chain.tip_height() + 1,
+            error,
+        })?;
+    }
+    Ok(HeaderRangeOutput {
+        network: input.network,
+        checkpoint_commitment,
+        checkpoint_hash,
+        checkpoint_height,
+        tip_hash: chain.tip_hash(),
+        tip_height: chain.tip_height(),
+        chain_work: chain.chain_work(),
+    })
+}
+
+/// Entry point of the guest: read the input from `input`, verify it, and write
+/// the public output to `output`. Nothing is written if the verification
+/// fails.
+pub fn run<R: Read + ?Sized, W: Write + ?Sized>(
+    input: &mut R,
+    output: &mut W,
+) -> Result<HeaderRangeOutput, GuestError> {
+    let input = HeaderRangeInput::consensus_decode(input).map_err(GuestError::Decode)?;
+    let result = verify_header_range(input)?;
+    result
+        .consensus_encode(output)
+        .map_err(GuestError::Decode)?;
+    Ok(result)
+}
+
+/// The input and output channels of the guest, using the `read` and `write`
+/// system calls on the standard file descriptors, following the Linux calling
+/// convention on RISC-V.
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+pub mod syscall {
+    use crate::encode::{Error, Read, Write};
+
+    const SYS_READ: usize = 63;
+    const SYS_WRITE: usize = 64;
+    const SYS_EXIT: usize = 93;
+
+    unsafe fn syscall3(number: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+        let mut ret = arg0;
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") ret,
+            in("a1") arg1,
+            in("a2") arg2,
+            in("a7") number,
+        );
+        ret
+    }
+
+    /// The standard input of the guest.
+    pub struct Stdin;
+
+    /// The standard output of the guest.
+    pub struct Stdout;
+
+    impl Read for Stdin {
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error> {
+            while !buf.is_empty() {
+                let n = unsafe { syscall3(SYS_READ, 0, buf.as_mut_ptr() as usize, buf.len()) };
+                // Zero means end of input, and negative values are errors
+                if n == 0 || (n as isize) < 0 {
+                    return Err(Error::UnexpectedEof);
+                }
+                buf = &mut buf[n..];
+            }
+            Ok(())
+        }
+    }
+
+    impl Write for Stdout {
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+            while !buf.is_empty() {
+                let n = unsafe { syscall3(SYS_WRITE, 1, buf.as_ptr() as usize, buf.len()) };
+                if n == 0 || (n as isize) < 0 {
+                    return Err(Error::ParseFailed("write to the output channel failed"));
+                }
+                buf = &buf[n..];
+            }
+            Ok(())
+        }
+    }
+
+    /// Terminate the guest with the given exit code.
+    pub fn exit(code: usize) -> ! {
+        unsafe {
+            syscall3(SYS_EXIT, code, 0, 0);
+        }
+        #[allow(clippy::empty_loop)]
+        loop {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::tests::{mine_regtest_header, mine_regtest_header_at};
+    use crate::encode::{deserialize, serialize};
+    use alloc::vec;
+
+    fn regtest_input(n: u8) -> HeaderRangeInput {
+        let params = ChainParams::regtest();
+        let mut headers = vec![];
+        let mut tip = params.genesis_header;
+        for i in 0..n {
+            tip = mine_regtest_header(&tip, [i; 32]);
+            headers.push(tip);
+        }
+        HeaderRangeInput {
+            network: Network::Regtest,
+            checkpoint: Checkpoint::genesis(&params),
+            headers,
+        }
+    }
+
+    #[test]
+    pub fn test_run_guest_natively() {
+        let input = regtest_input(30);
+        let last = *input.headers.last().unwrap();
+        let encoded = serialize(&input);
+        let mut output = vec![];
+        let result = run(&mut &encoded[..], &mut output).unwrap();
+        assert_eq!(deserialize::<HeaderRangeOutput>(&output), Ok(result));
+        assert_eq!(result.tip_hash, last.block_hash());
+        assert_eq!(result.tip_height, 30);
+        assert_eq!(result.network, Network::Regtest);
+        assert_eq!(
+            result.checkpoint_commitment,
+            Checkpoint::genesis(&ChainParams::regtest()).commitment()
+        );
+        assert_eq!(result.checkpoint_height, 0);
+        assert_eq!(
+            result.checkpoint_hash,
+            ChainParams::regtest().genesis_header.block_hash()
+        );
+        // Each regtest block represents 2 hashes
+        assert_eq!(result.chain_work, U256::from_u64(2 * 31));
+    }
+
+    #[test]
+    pub fn test_output_binds_network_and_checkpoint() {
+        // Easy headers verified with the rules of regtest on top of the
+        // genesis block of the main network
+        let mainnet = Checkpoint::genesis(&ChainParams::mainnet());
+        let mut headers = vec![];
+        let mut tip = mainnet.header;
+        for i in 0..3 {
+            tip = mine_regtest_header_at(&tip, [i; 32], tip.timestamp() + 1201);
+            headers.push(tip);
+        }
+        let input = HeaderRangeInput {
+            network: Network::Regtest,
+            checkpoint: mainnet.clone(),
+            headers,
+        };
+        let result = verify_header_range(input.clone()).unwrap();
+        assert_eq!(result.checkpoint_hash, mainnet.header.block_hash());
+        // The output tells that the rules of the main network were not used
+        assert_eq!(result.network, Network::Regtest);
+        assert_eq!(
+            verify_header_range(HeaderRangeInput {
+                network: Network::Mainnet,
+                ..input.clone()
+            }),
+            Err(GuestError::Header {
+                height: 1,
+                error: HeaderError::HighHash
+            })
+        );
+
+        // Inflating the work of the checkpoint changes the commitment
+        let mut inflated = input;
+        inflated.checkpoint.chain_work = U256::from_u64(1 << 40);
+        let forged = verify_header_range(inflated).unwrap();
+        assert_eq!(forged.checkpoint_hash, result.checkpoint_hash);
+        assert_ne!(forged.checkpoint_commitment, mainnet.commitment());
+    }
+
+    #[test]
+    pub fn test_run_guest_invalid_input() {
+        let mut input = regtest_input(5);
+        input.headers.swap(2, 3);
+        let encoded = serialize(&input);
+        let mut output = vec![];
+        assert_eq!(
+            run(&mut &encoded[..], &mut output),
+            Err(GuestError::Header {
+                height: 3,
+                error: HeaderError::PreviousBlockMismatch
+            })
+        );
+        assert!(output.is_empty());
+        assert!(matches!(
+            run(&mut &encoded[..10], &mut output),
+            Err(GuestError::Decode(encode::Error::UnexpectedEof))
+        ));
+    }
+}